@@ -1,3 +1,29 @@
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// Shipped under resources/ (see extraResources in client/package.json),
+/// as (path in resources, path in the repo relative to this crate).
+const SHIPPED: &[(&str, &str)] = &[
+    ("proxy/local-proxy.js", "../../src/local-proxy.js"),
+    ("certs/server.crt", "../../certs/server.crt"),
+    ("certs/server.key", "../../certs/server.key"),
+];
+
 fn main() {
+    write_integrity_manifest();
     tauri_build::build()
 }
+
+/// SHA-256 of each shipped file, included by src/integrity.rs.
+fn write_integrity_manifest() {
+    let mut manifest = String::from("&[\n");
+    for (shipped, source) in SHIPPED {
+        println!("cargo:rerun-if-changed={}", source);
+        let bytes = std::fs::read(source).unwrap_or_else(|e| panic!("read {}: {}", source, e));
+        let hash: String = Sha256::digest(&bytes).iter().map(|b| format!("{:02x}", b)).collect();
+        manifest.push_str(&format!("    ({:?}, {:?}),\n", shipped, hash));
+    }
+    manifest.push(']');
+    let out = Path::new(&std::env::var("OUT_DIR").unwrap()).join("integrity_manifest.rs");
+    std::fs::write(out, manifest).expect("write integrity manifest");
+}
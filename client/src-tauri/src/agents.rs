@@ -0,0 +1,195 @@
+//! Other software that intercepts TLS the way we do: corporate security
+//! agents (Zscaler, Netskope, ...), antivirus HTTPS scanning and debugging
+//! proxies. Stacked under our interception they make Windsurf's connections
+//! fail in confusing ways, so proxy_initialize lists the ones installed and
+//! proxy_run checks whether one actually intercepts the real origin.
+
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const ORIGIN_TIMEOUT: Duration = Duration::from_secs(3);
+
+struct Vendor {
+    name: &'static str,
+    /// Executable names, lowercase and without `.exe`. Only for tools whose
+    /// running at all means interception; antivirus only counts by its root.
+    processes: &'static [&'static str],
+    /// Local ports its interception listens on, counted only when one of
+    /// `processes` owns them.
+    ports: &'static [u16],
+    /// Lowercase fragments of the subject of its root, and so of the issuer
+    /// of the certificates it presents.
+    issuers: &'static [&'static str],
+}
+
+const VENDORS: &[Vendor] = &[
+    Vendor {
+        name: "Zscaler",
+        processes: &["zsatunnel", "zsaservice", "zsatray"],
+        ports: &[9000],
+        issuers: &["zscaler"],
+    },
+    Vendor { name: "Netskope", processes: &["stagentsvc", "stagentui"], ports: &[], issuers: &["netskope", "goskope"] },
+    Vendor {
+        name: "Palo Alto GlobalProtect",
+        processes: &["pangps", "pangpa"],
+        ports: &[],
+        issuers: &["palo alto networks", "prisma access"],
+    },
+    Vendor {
+        name: "Cisco Umbrella",
+        processes: &["acumbrellaagent", "csc_umbrellaagent"],
+        ports: &[],
+        issuers: &["cisco umbrella"],
+    },
+    Vendor { name: "Forcepoint", processes: &["fppsvc", "wepsvc"], ports: &[], issuers: &["forcepoint", "websense"] },
+    Vendor {
+        name: "Cloudflare WARP",
+        processes: &["warp-svc"],
+        ports: &[],
+        // Not plain "cloudflare": it issues public certificates too
+        issuers: &["cloudflare for teams", "gateway ca - cloudflare managed"],
+    },
+    Vendor { name: "Fortinet", processes: &["fortitray"], ports: &[], issuers: &["fortinet", "fortigate"] },
+    Vendor { name: "Symantec Web Security", processes: &[], ports: &[], issuers: &["blue coat", "symantec web"] },
+    Vendor { name: "Sophos", processes: &[], ports: &[], issuers: &["sophos"] },
+    Vendor { name: "Kaspersky", processes: &[], ports: &[], issuers: &["kaspersky anti-virus personal root"] },
+    Vendor {
+        name: "Avast/AVG",
+        processes: &[],
+        ports: &[],
+        issuers: &["avast web/mail shield", "avg web/mail shield"],
+    },
+    Vendor { name: "ESET", processes: &[], ports: &[], issuers: &["eset ssl filter"] },
+    Vendor { name: "Bitdefender", processes: &[], ports: &[], issuers: &["bitdefender personal ca"] },
+    Vendor {
+        name: "Fiddler",
+        processes: &["fiddler", "fiddler everywhere"],
+        ports: &[8888],
+        issuers: &["do_not_trust_fiddlerroot"],
+    },
+    Vendor { name: "Charles", processes: &["charles"], ports: &[8888], issuers: &["charles proxy"] },
+    Vendor {
+        name: "mitmproxy",
+        processes: &["mitmproxy", "mitmdump", "mitmweb"],
+        ports: &[8080],
+        issuers: &["mitmproxy"],
+    },
+    Vendor { name: "Burp Suite", processes: &["burpsuite"], ports: &[8080], issuers: &["portswigger"] },
+];
+
+impl Vendor {
+    fn runs(&self, process: &str) -> bool {
+        let process = process.to_ascii_lowercase();
+        self.processes.contains(&process.trim_end_matches(".exe"))
+    }
+
+    fn issued(&self, subject: &str) -> bool {
+        let subject = subject.to_ascii_lowercase();
+        self.issuers.iter().any(|fragment| subject.contains(fragment))
+    }
+}
+
+/// An agent found on this machine.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Agent {
+    pub name: &'static str,
+    /// What gave it away, e.g. `process ZSATunnel.exe`.
+    pub evidence: Vec<String>,
+}
+
+/// The certificate the real origin of a domain presents from here.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OriginCheck {
+    pub domain: String,
+    pub address: Option<String>,
+    pub issuer: Option<String>,
+    /// The vendor whose root issued it: that agent rewrites the traffic.
+    pub intercepted_by: Option<&'static str>,
+    /// Why the origin couldn't be checked.
+    pub error: Option<String>,
+}
+
+/// Known agents by their processes, listening ports and trusted roots.
+pub fn detect() -> Vec<Agent> {
+    let processes = crate::process::running_names();
+    let roots = crate::cert::trusted_root_subjects();
+    VENDORS
+        .iter()
+        .filter_map(|vendor| {
+            let mut evidence: Vec<String> =
+                processes.iter().filter(|p| vendor.runs(p)).map(|p| format!("process {}", p)).collect();
+            for port in vendor.ports {
+                let owner = crate::net::port_owner(*port).and_then(|owner| owner.name);
+                if owner.is_some_and(|name| vendor.runs(&name)) {
+                    evidence.push(format!("listens on port {}", port));
+                }
+            }
+            evidence.extend(roots.iter().filter(|s| vendor.issued(s)).map(|s| format!("trusted root {}", s)));
+            (!evidence.is_empty()).then_some(Agent { name: vendor.name, evidence })
+        })
+        .collect()
+}
+
+/// Connect to `domain`'s real address on 443 and see who issued the
+/// certificate it presents. Any certificate is accepted: only its issuer
+/// matters here.
+pub fn check_origin(domain: &str) -> OriginCheck {
+    let mut check = OriginCheck {
+        domain: domain.to_string(),
+        address: None,
+        issuer: None,
+        intercepted_by: None,
+        error: None,
+    };
+    let result = origin_address(domain).and_then(|address| {
+        check.address = Some(address.to_string());
+        presented_issuer(domain, address)
+    });
+    match result {
+        Ok(issuer) => {
+            check.intercepted_by = VENDORS.iter().find(|v| v.issued(&issuer)).map(|v| v.name);
+            check.issuer = Some(issuer);
+        }
+        Err(e) => check.error = Some(e),
+    }
+    check
+}
+
+/// Public DNS first: our hosts entries may already point the domain at us.
+fn origin_address(domain: &str) -> Result<IpAddr, String> {
+    crate::dns::resolve_public(domain).map(IpAddr::V4).or_else(|public| {
+        (domain, 443)
+            .to_socket_addrs()
+            .ok()
+            .into_iter()
+            .flatten()
+            .map(|addr| addr.ip())
+            .find(|ip| !ip.is_loopback())
+            .ok_or(public)
+    })
+}
+
+fn presented_issuer(domain: &str, address: IpAddr) -> Result<String, String> {
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let addr = SocketAddr::new(address, 443);
+    let tcp = TcpStream::connect_timeout(&addr, ORIGIN_TIMEOUT)
+        .map_err(|e| format!("Connect to {} failed: {}", addr, e))?;
+    let _ = tcp.set_read_timeout(Some(ORIGIN_TIMEOUT));
+    let _ = tcp.set_write_timeout(Some(ORIGIN_TIMEOUT));
+    let tls = connector.connect(domain, tcp).map_err(|e| format!("TLS handshake with {} failed: {}", addr, e))?;
+    let der = tls
+        .peer_certificate()
+        .ok()
+        .flatten()
+        .and_then(|cert| cert.to_der().ok())
+        .ok_or_else(|| format!("{} presented no certificate", addr))?;
+    crate::cert::der_issuer(&der).ok_or_else(|| format!("The certificate from {} can't be parsed", addr))
+}
@@ -0,0 +1,159 @@
+//! The gateways this build may forward to, so a participant (or malware)
+//! can't point the proxy at a server of their own. The list ships under
+//! resources/gateway-allowlist, signed with ed25519 by whoever distributes
+//! the app; the public key is compiled in. Passthrough to the official
+//! backend needs no entry. state_import can put a newer list, signed with
+//! the same key, into the app data dir.
+//!
+//! Builds with the `unsigned-allowlist` feature skip the signature, and
+//! without a list allow any gateway, for internal testing.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Under the resources dir.
+pub const DIR: &str = "gateway-allowlist";
+const FILE: &str = "allowlist.json";
+/// Base64 ed25519 signature over the exact bytes of FILE, written by
+/// scripts/sign-gateway-allowlist.mjs.
+const SIG_FILE: &str = "allowlist.json.sig";
+/// Base64 ed25519 public key, set in the environment of release builds.
+const PUBLIC_KEY: Option<&str> = option_env!("GATEWAY_ALLOWLIST_KEY");
+pub const UNSIGNED: bool = cfg!(feature = "unsigned-allowlist");
+
+#[derive(Deserialize)]
+struct Contents {
+    version: u64,
+    hosts: Vec<String>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Allowlist {
+    /// None when an unsigned build runs without a list.
+    pub version: Option<u64>,
+    /// Host names, lowercase; `*.example.com` matches any subdomain of
+    /// example.com but not example.com itself.
+    pub hosts: Vec<String>,
+    /// The signature wasn't checked (`unsigned-allowlist` build).
+    pub unsigned: bool,
+    /// The dir it was read from; None without a list.
+    #[serde(skip)]
+    pub dir: Option<PathBuf>,
+}
+
+impl Allowlist {
+    pub fn allows(&self, host: &str) -> bool {
+        if self.version.is_none() {
+            return true;
+        }
+        let host = host.trim_matches(['[', ']']).trim_end_matches('.').to_ascii_lowercase();
+        self.hosts.iter().any(|entry| match entry.strip_prefix("*.") {
+            Some(parent) => host.strip_suffix(parent).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => *entry == host,
+        })
+    }
+}
+
+/// Read and verify the list in `resources`, or the one imported into
+/// `app_data_dir` if it verifies and is newer.
+pub fn load(resources: &Path, app_data_dir: Option<&Path>) -> Result<Allowlist, String> {
+    let shipped = load_dir(&resources.join(DIR));
+    // Unsigned builds would take any imported list, so they only use their own
+    let imported = app_data_dir.filter(|_| !UNSIGNED).and_then(|dir| load_dir(&dir.join(DIR)).ok());
+    match (shipped, imported) {
+        (Ok(shipped), Some(imported)) if imported.version > shipped.version => Ok(imported),
+        (Err(_), Some(imported)) => Ok(imported),
+        (shipped, _) => shipped,
+    }
+}
+
+fn load_dir(dir: &Path) -> Result<Allowlist, String> {
+    let path = dir.join(FILE);
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound && UNSIGNED => {
+            return Ok(Allowlist { version: None, hosts: Vec::new(), unsigned: true, dir: None });
+        }
+        Err(e) => return Err(format!("Reading the gateway allowlist {} failed: {}", path.display(), e)),
+    };
+    if !UNSIGNED {
+        let sig_path = dir.join(SIG_FILE);
+        let sig = fs::read_to_string(&sig_path)
+            .map_err(|e| format!("Reading the gateway allowlist signature {} failed: {}", sig_path.display(), e))?;
+        verify(&bytes, &sig)?;
+    }
+    let contents: Contents = serde_json::from_slice(&bytes)
+        .map_err(|e| format!("{} is not a valid gateway allowlist: {}", path.display(), e))?;
+    Ok(Allowlist {
+        version: Some(contents.version),
+        hosts: contents.hosts.iter().map(|h| h.trim().trim_end_matches('.').to_ascii_lowercase()).collect(),
+        unsigned: UNSIGNED,
+        dir: Some(dir.to_path_buf()),
+    })
+}
+
+fn verify(bytes: &[u8], sig: &str) -> Result<(), String> {
+    let key = PUBLIC_KEY.ok_or("This build has no gateway allowlist key (GATEWAY_ALLOWLIST_KEY)")?;
+    let key = STANDARD
+        .decode(key.trim())
+        .map_err(|e| format!("The built-in gateway allowlist key is not base64: {}", e))?;
+    let sig = STANDARD
+        .decode(sig.trim())
+        .map_err(|e| format!("The gateway allowlist signature is not base64: {}", e))?;
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, key)
+        .verify(bytes, &sig)
+        .map_err(|_| "The gateway allowlist signature doesn't match; the list was modified".to_string())
+}
+
+/// The exact bytes of the list in `dir` and its base64 signature, for
+/// state_export.
+pub fn signed_files(dir: &Path) -> Result<(Vec<u8>, String), String> {
+    let read_error = |path: &Path, e: std::io::Error| format!("Reading {} failed: {}", path.display(), e);
+    let (path, sig_path) = (dir.join(FILE), dir.join(SIG_FILE));
+    let bytes = fs::read(&path).map_err(|e| read_error(&path, e))?;
+    let sig = fs::read_to_string(&sig_path).map_err(|e| read_error(&sig_path, e))?;
+    Ok((bytes, sig.trim().to_string()))
+}
+
+/// Check a list from another install against this build's key; its version.
+pub fn verify_signed(bytes: &[u8], sig: &str) -> Result<u64, String> {
+    verify(bytes, sig)?;
+    let contents: Contents =
+        serde_json::from_slice(bytes).map_err(|e| format!("Not a valid gateway allowlist: {}", e))?;
+    Ok(contents.version)
+}
+
+/// Write a list `verify_signed` accepted where `load` looks for an imported one.
+pub fn save_imported(app_data_dir: &Path, bytes: &[u8], sig: &str) -> std::io::Result<()> {
+    let dir = app_data_dir.join(DIR);
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(SIG_FILE), sig)?;
+    fs::write(dir.join(FILE), bytes)
+}
+
+/// Ok when `gateway_url` (normalized) may be used: passthrough, or a
+/// gateway whose host `list` allows.
+pub fn check(list: &Result<Allowlist, String>, gateway_url: &str) -> Result<(), String> {
+    if gateway_url.is_empty() {
+        return Ok(());
+    }
+    let list = list.as_ref().map_err(|e| format!("No gateway can be used: {}", e))?;
+    let host = url::Url::parse(gateway_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    if list.allows(&host) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} is not on the gateway allowlist (version {})",
+            host,
+            list.version.unwrap_or_default()
+        ))
+    }
+}
@@ -0,0 +1,134 @@
+//! Compliance record of when interception was active: one JSON object per
+//! line in the app data dir. Unlike proxy.log it is never rotated.
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const FILE_NAME: &str = "audit.jsonl";
+
+#[derive(Serialize)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum Event {
+    /// Written each time the app opens the log, so the records after it can
+    /// be tied to a build.
+    AppStarted { versions: crate::versions::Versions },
+    /// The hosts file was rewritten; hashes are SHA-256 of the whole file.
+    #[serde(rename_all = "camelCase")]
+    HostsChanged {
+        before_hash: String,
+        after_hash: String,
+        /// Lines of our block, as `address host`.
+        added: Vec<String>,
+        removed: Vec<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    ProxyStarted { gateway_url: String, pid: u32, port: u16 },
+    #[serde(rename_all = "camelCase")]
+    ProxyStopped { pid: u32, exit_code: Option<i32>, reason: String },
+    /// proxy_run passed its checks and is about to change the system.
+    #[serde(rename_all = "camelCase")]
+    ProxyRunAttempted {
+        gateway_url: String,
+        port: u16,
+        domains: Vec<String>,
+        routes: Vec<crate::config::Route>,
+        bypass_paths: Vec<String>,
+    },
+    /// That proxy_run failed; what undoing its changes achieved.
+    ProxyRunRolledBack {
+        code: &'static str,
+        message: String,
+        undone: Vec<&'static str>,
+        failed: Vec<&'static str>,
+    },
+    /// The watchdog stopped restarting a crashing proxy.
+    ProxyRestartFailed { attempts: u32, reason: String },
+    CertInstalled { fingerprint: Option<String> },
+    /// update_apply installed a new local-proxy.js.
+    ProxyScriptUpdated { version: String, sha256: String },
+    CertRemoved { fingerprint: Option<String> },
+    /// doh_disable turned system encrypted DNS off.
+    DohDisabled { settings: usize },
+    /// proxy_restore put those settings back.
+    DohRestored { settings: usize },
+    /// state_export wrote a provisioning bundle.
+    StateExported { path: String, included: Vec<&'static str> },
+    /// state_import applied one.
+    #[serde(rename_all = "camelCase")]
+    StateImported { path: String, imported: Vec<&'static str>, ca_fingerprint: Option<String> },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// RFC 3339, UTC.
+    ts: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    file: Option<File>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        AuditLog { path: None, file: None }
+    }
+
+    pub fn open(&mut self, dir: &Path) -> std::io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(FILE_NAME);
+        self.file = Some(OpenOptions::new().create(true).append(true).open(&path)?);
+        self.path = Some(path);
+        Ok(())
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Append `event` as one line. The line goes out in a single write, and
+    /// callers share the log behind a mutex, so concurrent records can't
+    /// interleave.
+    pub fn record(&mut self, event: &Event) -> std::io::Result<()> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        let ts = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        let mut line = serde_json::to_vec(&Record { ts, event }).map_err(std::io::Error::other)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        file.flush()
+    }
+
+    /// The last `n` records, oldest first. Lines that don't parse are skipped.
+    pub fn tail(&self, n: usize) -> std::io::Result<Vec<serde_json::Value>> {
+        let Some(path) = self.path.as_deref() else {
+            return Ok(Vec::new());
+        };
+        let text = fs::read_to_string(path)?;
+        let lines: Vec<&str> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+        Ok(lines[lines.len().saturating_sub(n)..]
+            .iter()
+            .filter_map(|l| serde_json::from_str(l).ok())
+            .collect())
+    }
+
+    /// Copy the log to `target`; returns the bytes copied.
+    pub fn export(&self, target: &Path) -> std::io::Result<u64> {
+        match self.path.as_deref() {
+            Some(path) => fs::copy(path, target),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "the audit log is not open")),
+        }
+    }
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,155 @@
+//! Starting the app at login for the current user: a value under the Run key
+//! on Windows, a LaunchAgent on macOS, an XDG autostart entry elsewhere.
+//! The app is started with LAUNCH_ARG so it can tell a login start apart.
+
+use std::path::Path;
+
+/// Passed by the login item; the only way a launch counts as an autostart.
+pub const LAUNCH_ARG: &str = "--autostart";
+
+#[cfg(target_os = "windows")]
+const RUN_KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Run";
+#[cfg(target_os = "windows")]
+const VALUE_NAME: &str = "WindClient";
+#[cfg(target_os = "macos")]
+const AGENT_LABEL: &str = "com.wind.client.autostart";
+#[cfg(all(unix, not(target_os = "macos")))]
+const DESKTOP_FILE: &str = "wind-client.desktop";
+
+/// Whether the app is registered to start at login.
+pub fn is_enabled() -> Result<bool, String> {
+    imp::is_enabled()
+}
+
+/// Register or unregister the current executable. Registering again
+/// replaces an entry pointing at an older location.
+pub fn set(enabled: bool) -> Result<(), String> {
+    if enabled {
+        let exe = std::env::current_exe().map_err(|e| format!("Failed to locate the app binary: {}", e))?;
+        imp::enable(&exe)
+    } else if imp::is_enabled()? {
+        imp::disable()
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod imp {
+    use super::*;
+
+    pub fn is_enabled() -> Result<bool, String> {
+        // reg exits non-zero both for a missing value and a failure; telling
+        // them apart isn't worth parsing its localized output
+        Ok(reg(&["query", RUN_KEY, "/v", VALUE_NAME]).is_ok())
+    }
+
+    pub fn enable(exe: &Path) -> Result<(), String> {
+        let command = format!("\"{}\" {}", exe.display(), LAUNCH_ARG);
+        reg(&["add", RUN_KEY, "/v", VALUE_NAME, "/t", "REG_SZ", "/d", &command, "/f"])
+    }
+
+    pub fn disable() -> Result<(), String> {
+        reg(&["delete", RUN_KEY, "/v", VALUE_NAME, "/f"])
+    }
+
+    fn reg(args: &[&str]) -> Result<(), String> {
+        let output = std::process::Command::new("reg")
+            .args(args)
+            .output()
+            .map_err(|e| format!("Failed to run reg: {}", e))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(format!("reg failed: {}", String::from_utf8_lossy(&output.stderr).trim()))
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    pub fn is_enabled() -> Result<bool, String> {
+        Ok(entry_path()?.is_file())
+    }
+
+    pub fn enable(exe: &Path) -> Result<(), String> {
+        let path = entry_path()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Creating {} failed: {}", dir.display(), e))?;
+        }
+        fs::write(&path, entry(exe)).map_err(|e| format!("Writing {} failed: {}", path.display(), e))
+    }
+
+    pub fn disable() -> Result<(), String> {
+        let path = entry_path()?;
+        fs::remove_file(&path).map_err(|e| format!("Removing {} failed: {}", path.display(), e))
+    }
+
+    fn home() -> Result<PathBuf, String> {
+        std::env::var_os("HOME")
+            .filter(|h| !h.is_empty())
+            .map(PathBuf::from)
+            .ok_or_else(|| "HOME is not set".to_string())
+    }
+
+    #[cfg(target_os = "macos")]
+    fn entry_path() -> Result<PathBuf, String> {
+        Ok(home()?.join("Library/LaunchAgents").join(format!("{}.plist", AGENT_LABEL)))
+    }
+
+    #[cfg(target_os = "macos")]
+    fn entry(exe: &Path) -> String {
+        let xml = |s: &str| s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{}</string>
+        <string>{}</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+</dict>
+</plist>
+"#,
+            AGENT_LABEL,
+            xml(&exe.to_string_lossy()),
+            LAUNCH_ARG
+        )
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn entry_path() -> Result<PathBuf, String> {
+        let config = match std::env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => home()?.join(".config"),
+        };
+        Ok(config.join("autostart").join(DESKTOP_FILE))
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn entry(exe: &Path) -> String {
+        // Exec quoting per the desktop entry spec
+        let mut quoted = String::from("\"");
+        for c in exe.to_string_lossy().chars() {
+            if matches!(c, '"' | '`' | '$' | '\\') {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        format!(
+            "[Desktop Entry]\nType=Application\nName=Wind Client\nExec={} {}\nX-GNOME-Autostart-enabled=true\n",
+            quoted, LAUNCH_ARG
+        )
+    }
+}
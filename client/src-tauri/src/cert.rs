@@ -0,0 +1,598 @@
+//! Per-install MITM certificate authority: generation, expiry and OS trust.
+
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// The CA that signed server.crt; this is what has to be trusted by the OS.
+pub const CA_CERT_FILE: &str = "ca.crt";
+const CA_KEY_FILE: &str = "ca.key";
+/// What local-proxy.js loads from --cert-dir.
+const SERVER_CERT_FILE: &str = "server.crt";
+const SERVER_KEY_FILE: &str = "server.key";
+
+const CA_VALID_DAYS: i64 = 825;
+const SERVER_VALID_DAYS: i64 = 397;
+
+/// ECDSA P-256 key usable by rcgen. Signing stays in pure Rust so the crate
+/// doesn't pull in a C crypto backend.
+struct EcdsaKey {
+    signing: p256::ecdsa::SigningKey,
+    public: Vec<u8>,
+}
+
+impl EcdsaKey {
+    fn generate() -> Self {
+        Self::from_signing(p256::ecdsa::SigningKey::random(&mut rand_core::OsRng))
+    }
+
+    fn from_signing(signing: p256::ecdsa::SigningKey) -> Self {
+        let public = signing.verifying_key().to_encoded_point(false).as_bytes().to_vec();
+        EcdsaKey { signing, public }
+    }
+
+    fn pkcs8_pem(&self) -> Result<String, String> {
+        use p256::pkcs8::EncodePrivateKey;
+        self.signing
+            .to_pkcs8_pem(p256::pkcs8::LineEnding::LF)
+            .map(|pem| pem.to_string())
+            .map_err(|e| format!("Failed to encode private key: {}", e))
+    }
+
+    fn pkcs8_der(&self) -> Result<Vec<u8>, String> {
+        use p256::pkcs8::EncodePrivateKey;
+        self.signing
+            .to_pkcs8_der()
+            .map(|der| der.as_bytes().to_vec())
+            .map_err(|e| format!("Failed to encode private key: {}", e))
+    }
+
+    fn into_key_pair(self) -> Result<rcgen::KeyPair, String> {
+        rcgen::KeyPair::from_remote(Box::new(self)).map_err(|e| e.to_string())
+    }
+}
+
+impl rcgen::RemoteKeyPair for EcdsaKey {
+    fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, rcgen::Error> {
+        use p256::ecdsa::signature::Signer;
+        let sig: p256::ecdsa::Signature = self.signing.sign(msg);
+        Ok(sig.to_der().as_bytes().to_vec())
+    }
+
+    fn algorithm(&self) -> &'static rcgen::SignatureAlgorithm {
+        &rcgen::PKCS_ECDSA_P256_SHA256
+    }
+}
+
+fn random_serial() -> rcgen::SerialNumber {
+    use rand_core::RngCore;
+    let mut bytes = [0u8; 16];
+    rand_core::OsRng.fill_bytes(&mut bytes);
+    bytes[0] &= 0x7f; // keep the DER integer positive
+    rcgen::SerialNumber::from_slice(&bytes)
+}
+
+fn write_private(path: &Path, content: &str) -> Result<(), String> {
+    fs::write(path, content).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("Failed to restrict {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Generate a fresh CA plus a server certificate for `domains` signed by it,
+/// replacing whatever is in `dir`.
+pub fn generate(dir: &Path, domains: &[&str]) -> Result<(), String> {
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyUsagePurpose};
+
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create cert dir: {}", e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(dir, fs::Permissions::from_mode(0o700));
+    }
+    let now = time::OffsetDateTime::now_utc();
+    let host = std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_default();
+
+    let ca_key = EcdsaKey::generate();
+    let ca_key_pem = ca_key.pkcs8_pem()?;
+    let ca_key = ca_key.into_key_pair()?;
+    let mut ca_params = CertificateParams::default();
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, format!("cyber-drill-safe local CA {}", host).trim());
+    ca_params.distinguished_name.push(DnType::OrganizationName, "cyber-drill-safe");
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    ca_params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    ca_params.not_before = now - time::Duration::days(1);
+    ca_params.not_after = now + time::Duration::days(CA_VALID_DAYS);
+    ca_params.serial_number = Some(random_serial());
+    let ca_cert = ca_params.self_signed(&ca_key).map_err(|e| format!("Failed to sign CA: {}", e))?;
+
+    let (server_pem, server_key_pem) = server_certificate(&ca_cert, &ca_key, domains)?;
+
+    write_private(&dir.join(CA_KEY_FILE), &ca_key_pem)?;
+    write_private(&dir.join(SERVER_KEY_FILE), &server_key_pem)?;
+    fs::write(dir.join(CA_CERT_FILE), ca_cert.pem()).map_err(|e| format!("Failed to write CA: {}", e))?;
+    fs::write(dir.join(SERVER_CERT_FILE), server_pem)
+        .map_err(|e| format!("Failed to write server certificate: {}", e))?;
+    Ok(())
+}
+
+/// A server certificate for `domains` signed by the CA: its PEM and its
+/// key's PKCS#8 PEM.
+fn server_certificate(
+    ca_cert: &rcgen::Certificate,
+    ca_key: &rcgen::KeyPair,
+    domains: &[&str],
+) -> Result<(String, String), String> {
+    use rcgen::{CertificateParams, DnType, ExtendedKeyUsagePurpose, KeyUsagePurpose};
+
+    let now = time::OffsetDateTime::now_utc();
+    let server_key = EcdsaKey::generate();
+    let server_key_pem = server_key.pkcs8_pem()?;
+    let server_key = server_key.into_key_pair()?;
+    let names: Vec<String> = domains.iter().map(|d| d.to_string()).collect();
+    let mut server_params =
+        CertificateParams::new(names).map_err(|e| format!("Invalid certificate domain: {}", e))?;
+    server_params
+        .distinguished_name
+        .push(DnType::CommonName, domains.first().copied().unwrap_or("localhost"));
+    server_params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    server_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    server_params.not_before = now - time::Duration::days(1);
+    server_params.not_after = now + time::Duration::days(SERVER_VALID_DAYS);
+    server_params.serial_number = Some(random_serial());
+    let server_cert = server_params
+        .signed_by(&server_key, ca_cert, ca_key)
+        .map_err(|e| format!("Failed to sign server certificate: {}", e))?;
+    Ok((server_cert.pem(), server_key_pem))
+}
+
+/// The CA key in `dir`, PKCS#8 PEM, for state_export.
+pub fn ca_key_pem(dir: &Path) -> Result<String, String> {
+    let path = dir.join(CA_KEY_FILE);
+    fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+}
+
+/// Replace the CA in `dir` with another install's (`ca_pem` and its key
+/// `key_pem`) and issue a server certificate for `domains` from it.
+pub fn import(dir: &Path, ca_pem: &str, key_pem: &str, domains: &[&str]) -> Result<(), String> {
+    use p256::pkcs8::DecodePrivateKey;
+
+    let der = pem_certificates(ca_pem).into_iter().next().ok_or("No certificate in the imported CA")?;
+    let (_, parsed) =
+        x509_parser::parse_x509_certificate(&der).map_err(|e| format!("Invalid imported CA certificate: {}", e))?;
+    if !parsed.is_ca() {
+        return Err("The imported certificate is not a CA".into());
+    }
+    let signing =
+        p256::ecdsa::SigningKey::from_pkcs8_pem(key_pem).map_err(|e| format!("Invalid imported CA key: {}", e))?;
+    let key = EcdsaKey::from_signing(signing);
+    if parsed.public_key().subject_public_key.data.as_ref() != key.public.as_slice() {
+        return Err("The imported CA key doesn't belong to the imported CA certificate".into());
+    }
+    let key_pem = key.pkcs8_pem()?;
+    let key = key.into_key_pair()?;
+    let params = rcgen::CertificateParams::from_ca_cert_pem(ca_pem)
+        .map_err(|e| format!("Invalid imported CA certificate: {}", e))?;
+    let ca_cert = params.self_signed(&key).map_err(|e| format!("Failed to load imported CA: {}", e))?;
+    let (server_pem, server_key_pem) = server_certificate(&ca_cert, &key, domains)?;
+
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create cert dir: {}", e))?;
+    write_private(&dir.join(CA_KEY_FILE), &key_pem)?;
+    write_private(&dir.join(SERVER_KEY_FILE), &server_key_pem)?;
+    fs::write(dir.join(CA_CERT_FILE), ca_pem).map_err(|e| format!("Failed to write CA: {}", e))?;
+    fs::write(dir.join(SERVER_CERT_FILE), server_pem)
+        .map_err(|e| format!("Failed to write server certificate: {}", e))?;
+    Ok(())
+}
+
+/// The CA in a cert dir, loaded to issue one leaf certificate per domain;
+/// the built-in proxy picks them by SNI instead of using server.crt.
+pub struct Authority {
+    /// Rebuilt from ca.crt; only its subject and key id end up in leaves.
+    cert: rcgen::Certificate,
+    key: rcgen::KeyPair,
+    /// ca.crt as installed, sent after every leaf.
+    der: Vec<u8>,
+}
+
+impl Authority {
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        use p256::pkcs8::DecodePrivateKey;
+
+        let cert_path = dir.join(CA_CERT_FILE);
+        let key_path = dir.join(CA_KEY_FILE);
+        let pem = fs::read_to_string(&cert_path).map_err(|e| format!("Failed to read {}: {}", cert_path.display(), e))?;
+        let key_pem =
+            fs::read_to_string(&key_path).map_err(|e| format!("Failed to read {}: {}", key_path.display(), e))?;
+        let der = pem_certificates(&pem)
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("No certificate in {}", cert_path.display()))?;
+        let signing = p256::ecdsa::SigningKey::from_pkcs8_pem(&key_pem)
+            .map_err(|e| format!("Invalid CA key {}: {}", key_path.display(), e))?;
+        let key = EcdsaKey::from_signing(signing).into_key_pair()?;
+        let params = rcgen::CertificateParams::from_ca_cert_pem(&pem)
+            .map_err(|e| format!("Invalid CA certificate {}: {}", cert_path.display(), e))?;
+        let cert = params.self_signed(&key).map_err(|e| format!("Failed to load CA: {}", e))?;
+        Ok(Authority { cert, key, der })
+    }
+
+    /// A fresh certificate for `domain`: the chain (leaf, then CA) as DER,
+    /// and the leaf's PKCS#8 key.
+    pub fn issue(&self, domain: &str) -> Result<(Vec<Vec<u8>>, Vec<u8>), String> {
+        use rcgen::{CertificateParams, DnType, ExtendedKeyUsagePurpose, KeyUsagePurpose};
+
+        let now = time::OffsetDateTime::now_utc();
+        let key = EcdsaKey::generate();
+        let key_der = key.pkcs8_der()?;
+        let key = key.into_key_pair()?;
+        let mut params = CertificateParams::new(vec![domain.to_string()])
+            .map_err(|e| format!("Invalid certificate domain: {}", e))?;
+        params.distinguished_name.push(DnType::CommonName, domain);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.not_before = now - time::Duration::days(1);
+        params.not_after = now + time::Duration::days(SERVER_VALID_DAYS);
+        params.serial_number = Some(random_serial());
+        let leaf = params
+            .signed_by(&key, &self.cert, &self.key)
+            .map_err(|e| format!("Failed to sign certificate for {}: {}", domain, e))?;
+        Ok((vec![leaf.der().to_vec(), self.der.clone()], key_der))
+    }
+}
+
+/// Whether `dir` holds a complete CA + server certificate set.
+pub fn exists(dir: &Path) -> bool {
+    [CA_CERT_FILE, CA_KEY_FILE, SERVER_CERT_FILE, SERVER_KEY_FILE]
+        .iter()
+        .all(|f| dir.join(f).is_file())
+}
+
+/// Generate the CA on first use; leaves an existing one alone.
+pub fn ensure(dir: &Path, domains: &[&str]) -> Result<(), String> {
+    if exists(dir) {
+        return Ok(());
+    }
+    generate(dir, domains)
+}
+
+/// notAfter of the CA in `dir`, as unix seconds.
+pub fn ca_not_after(dir: &Path) -> Option<i64> {
+    not_after_of(&dir.join(CA_CERT_FILE))
+}
+
+/// When the first of the CA and the server certificate in `dir` expires,
+/// as unix seconds. Leaves issued by the built-in proxy are short-lived
+/// copies and don't count.
+pub fn not_after(dir: &Path) -> Option<i64> {
+    match (ca_not_after(dir), not_after_of(&dir.join(SERVER_CERT_FILE))) {
+        (Some(ca), Some(server)) => Some(ca.min(server)),
+        (ca, server) => ca.or(server),
+    }
+}
+
+fn not_after_of(path: &Path) -> Option<i64> {
+    let pem = fs::read_to_string(path).ok()?;
+    let der = pem_certificates(&pem).into_iter().next()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&der).ok()?;
+    Some(cert.validity().not_after.timestamp())
+}
+
+/// DNS names the server certificate in `dir` is issued for.
+pub fn server_names(dir: &Path) -> Vec<String> {
+    use x509_parser::extensions::GeneralName;
+
+    let names = || -> Option<Vec<String>> {
+        let pem = fs::read_to_string(dir.join(SERVER_CERT_FILE)).ok()?;
+        let der = pem_certificates(&pem).into_iter().next()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(&der).ok()?;
+        let san = cert.subject_alternative_name().ok()??;
+        Some(
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(dns) => Some(dns.to_ascii_lowercase()),
+                    _ => None,
+                })
+                .collect(),
+        )
+    };
+    names().unwrap_or_default()
+}
+
+/// Whether the server certificate in `dir` is valid for every one of `domains`.
+pub fn covers(dir: &Path, domains: &[String]) -> bool {
+    let names = server_names(dir);
+    domains.iter().all(|d| names.iter().any(|n| n.eq_ignore_ascii_case(d)))
+}
+
+/// Decode every CERTIFICATE block in a PEM file into DER.
+pub fn pem_certificates(pem: &str) -> Vec<Vec<u8>> {
+    let mut certs = Vec::new();
+    let mut body: Option<String> = None;
+    for line in pem.lines() {
+        let line = line.trim();
+        if line == "-----BEGIN CERTIFICATE-----" {
+            body = Some(String::new());
+        } else if line == "-----END CERTIFICATE-----" {
+            if let Some(b64) = body.take() {
+                if let Ok(der) = base64::engine::general_purpose::STANDARD.decode(b64) {
+                    certs.push(der);
+                }
+            }
+        } else if let Some(ref mut b64) = body {
+            b64.push_str(line);
+        }
+    }
+    certs
+}
+
+fn sha1_hex(der: &[u8]) -> String {
+    Sha1::digest(der).iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Uppercase hex SHA-1 thumbprint of the CA in `certs_dir`, the form certutil
+/// and `security` print.
+pub fn ca_fingerprint(certs_dir: &Path) -> Option<String> {
+    pem_fingerprint(&fs::read_to_string(certs_dir.join(CA_CERT_FILE)).ok()?)
+}
+
+/// The same thumbprint, of the first certificate in `pem`.
+pub fn pem_fingerprint(pem: &str) -> Option<String> {
+    pem_certificates(pem).first().map(|der| sha1_hex(der))
+}
+
+/// Whether the CA in `certs_dir` is present in the OS trust store.
+pub fn is_installed(certs_dir: &Path) -> bool {
+    match ca_fingerprint(certs_dir) {
+        Some(fp) => store_contains(&fp),
+        None => false,
+    }
+}
+
+/// Outcome of a trust store change: whether the unprivileged attempt was
+/// refused and, if so, whether the elevated retry went through.
+pub struct TrustChange {
+    pub elevation_required: bool,
+    pub elevation_obtained: bool,
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let detail = if stderr.trim().is_empty() { stdout } else { stderr };
+        Err(format!("{} failed: {}", program, detail.trim()))
+    }
+}
+
+/// Try `direct` first and only fall back to `elevated` when it fails.
+fn with_elevation(
+    direct: impl FnOnce() -> Result<(), String>,
+    elevated: impl FnOnce() -> Result<(), String>,
+) -> Result<TrustChange, String> {
+    if direct().is_ok() {
+        return Ok(TrustChange { elevation_required: false, elevation_obtained: false });
+    }
+    elevated()?;
+    Ok(TrustChange { elevation_required: true, elevation_obtained: true })
+}
+
+/// Install the CA from `certs_dir` into the OS root store.
+pub fn install(certs_dir: &Path) -> Result<TrustChange, String> {
+    let ca = certs_dir.join(CA_CERT_FILE);
+    if ca_fingerprint(certs_dir).is_none() {
+        return Err(format!("CA certificate not found at {}", ca.display()));
+    }
+    let ca = ca.to_string_lossy().to_string();
+    add_to_store(&ca)
+}
+
+/// Remove the CA from `certs_dir` from the OS root store.
+pub fn uninstall(certs_dir: &Path) -> Result<TrustChange, String> {
+    let fp = ca_fingerprint(certs_dir)
+        .ok_or_else(|| format!("CA certificate not found at {}", certs_dir.join(CA_CERT_FILE).display()))?;
+    remove_from_store(&fp)
+}
+
+// Windows: LocalMachine needs admin, CurrentUser does not (it shows its own
+// confirmation dialog), so the user store is the "elevated" fallback in reverse.
+#[cfg(target_os = "windows")]
+fn add_to_store(ca: &str) -> Result<TrustChange, String> {
+    with_elevation(
+        || run("certutil", &["-addstore", "Root", ca]),
+        || run("certutil", &["-user", "-addstore", "Root", ca]),
+    )
+}
+
+#[cfg(target_os = "windows")]
+fn remove_from_store(fingerprint: &str) -> Result<TrustChange, String> {
+    let machine = run("certutil", &["-delstore", "Root", fingerprint]);
+    let user = run("certutil", &["-user", "-delstore", "Root", fingerprint]);
+    match (machine, user) {
+        (Err(e), Err(_)) => Err(e),
+        (machine, _) => Ok(TrustChange { elevation_required: machine.is_err(), elevation_obtained: false }),
+    }
+}
+
+#[cfg(target_os = "macos")]
+const MAC_SYSTEM_KEYCHAIN: &str = "/Library/Keychains/System.keychain";
+
+#[cfg(target_os = "macos")]
+fn add_to_store(ca: &str) -> Result<TrustChange, String> {
+    let args = ["add-trusted-cert", "-d", "-r", "trustRoot", "-k", MAC_SYSTEM_KEYCHAIN, ca];
+    with_elevation(
+        || run("security", &args),
+        || crate::elevate::osascript_admin(&format!("security {} '{}'", args[..6].join(" "), ca)),
+    )
+}
+
+#[cfg(target_os = "macos")]
+fn remove_from_store(fingerprint: &str) -> Result<TrustChange, String> {
+    let args = ["delete-certificate", "-Z", fingerprint, MAC_SYSTEM_KEYCHAIN];
+    with_elevation(
+        || run("security", &args),
+        || crate::elevate::osascript_admin(&format!("security {}", args.join(" "))),
+    )
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+const LINUX_ANCHOR: &str = "/usr/local/share/ca-certificates/cyber-drill-safe.crt";
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn add_to_store(ca: &str) -> Result<TrustChange, String> {
+    with_elevation(
+        || {
+            fs::copy(ca, LINUX_ANCHOR).map_err(|e| e.to_string())?;
+            run("update-ca-certificates", &[])
+        },
+        || {
+            run("pkexec", &["cp", ca, LINUX_ANCHOR])?;
+            run("pkexec", &["update-ca-certificates"])
+        },
+    )
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn remove_from_store(_fingerprint: &str) -> Result<TrustChange, String> {
+    if !Path::new(LINUX_ANCHOR).exists() {
+        return Ok(TrustChange { elevation_required: false, elevation_obtained: false });
+    }
+    with_elevation(
+        || {
+            fs::remove_file(LINUX_ANCHOR).map_err(|e| e.to_string())?;
+            run("update-ca-certificates", &["--fresh"])
+        },
+        || {
+            run("pkexec", &["rm", "-f", LINUX_ANCHOR])?;
+            run("pkexec", &["update-ca-certificates", "--fresh"])
+        },
+    )
+}
+
+#[cfg(target_os = "windows")]
+fn store_contains(fingerprint: &str) -> bool {
+    // CurrentUser first, then LocalMachine
+    [vec!["-user", "-verifystore", "Root", fingerprint], vec!["-verifystore", "Root", fingerprint]]
+        .iter()
+        .any(|args| {
+            Command::new("certutil")
+                .args(args)
+                .output()
+                .map(|o| o.status.success())
+                .unwrap_or(false)
+        })
+}
+
+#[cfg(target_os = "macos")]
+fn store_contains(fingerprint: &str) -> bool {
+    let output = Command::new("security")
+        .args(["find-certificate", "-a", "-Z", "/Library/Keychains/System.keychain"])
+        .output();
+    match output {
+        Ok(o) => String::from_utf8_lossy(&o.stdout)
+            .lines()
+            .filter_map(|l| l.trim().strip_prefix("SHA-1 hash:"))
+            .any(|h| h.trim().eq_ignore_ascii_case(fingerprint)),
+        Err(_) => false,
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn store_contains(fingerprint: &str) -> bool {
+    const STORE_DIRS: &[&str] = &["/usr/local/share/ca-certificates", "/etc/pki/ca-trust/source/anchors", "/etc/ssl/certs"];
+    STORE_DIRS.iter().any(|dir| dir_contains(Path::new(dir), fingerprint, 1))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn dir_contains(dir: &Path, fingerprint: &str, depth: u32) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    entries.filter_map(|e| e.ok()).any(|entry| {
+        let path = entry.path();
+        if path.is_dir() {
+            return depth > 0 && dir_contains(&path, fingerprint, depth - 1);
+        }
+        fs::read_to_string(&path)
+            .map(|pem| pem_certificates(&pem).iter().any(|der| sha1_hex(der) == fingerprint))
+            .unwrap_or(false)
+    })
+}
+
+/// Subject of a DER certificate, as x509-parser prints it (`CN=..., O=...`).
+pub fn der_subject(der: &[u8]) -> Option<String> {
+    x509_parser::parse_x509_certificate(der).ok().map(|(_, cert)| cert.subject().to_string())
+}
+
+/// Issuer of the same.
+pub fn der_issuer(der: &[u8]) -> Option<String> {
+    x509_parser::parse_x509_certificate(der).ok().map(|(_, cert)| cert.issuer().to_string())
+}
+
+/// Subjects of the roots in the OS trust store, both the machine's and the
+/// user's; empty where they can't be listed.
+#[cfg(target_os = "windows")]
+pub fn trusted_root_subjects() -> Vec<String> {
+    let script = "Get-ChildItem Cert:\\LocalMachine\\Root, Cert:\\CurrentUser\\Root | ForEach-Object { $_.Subject }";
+    match Command::new("powershell").args(["-NoProfile", "-NonInteractive", "-Command", script]).output() {
+        Ok(o) => String::from_utf8_lossy(&o.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Only the System keychain: that is where management tools put their roots.
+#[cfg(target_os = "macos")]
+pub fn trusted_root_subjects() -> Vec<String> {
+    let output = Command::new("security").args(["find-certificate", "-a", "-p", MAC_SYSTEM_KEYCHAIN]).output();
+    match output {
+        Ok(o) => pem_certificates(&String::from_utf8_lossy(&o.stdout))
+            .iter()
+            .filter_map(|der| der_subject(der))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Only the locally added anchors; the distribution bundle holds public CAs.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn trusted_root_subjects() -> Vec<String> {
+    const ANCHOR_DIRS: &[&str] = &["/usr/local/share/ca-certificates", "/etc/pki/ca-trust/source/anchors"];
+    let mut subjects = Vec::new();
+    for dir in ANCHOR_DIRS {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_file()) {
+            let pem = fs::read_to_string(&path).unwrap_or_default();
+            subjects.extend(pem_certificates(&pem).iter().filter_map(|der| der_subject(der)));
+        }
+    }
+    subjects
+}
@@ -0,0 +1,59 @@
+//! The environment the proxy child runs with. Nothing is inherited except a
+//! short whitelist, so NODE_OPTIONS, HTTP(S)_PROXY and the like from the
+//! machine image can't change how the proxy behaves.
+
+use crate::config::ProxyEnv;
+use std::ffi::OsString;
+use std::path::Path;
+
+/// What the OS and node need to run at all.
+#[cfg(target_os = "windows")]
+const INHERITED: &[&str] = &[
+    "APPDATA",
+    "COMSPEC",
+    "HOMEDRIVE",
+    "HOMEPATH",
+    "LOCALAPPDATA",
+    "NUMBER_OF_PROCESSORS",
+    "PATH",
+    "PATHEXT",
+    "PROGRAMDATA",
+    "SYSTEMDRIVE",
+    "SYSTEMROOT",
+    "TEMP",
+    "TMP",
+    "USERPROFILE",
+    "WINDIR",
+];
+#[cfg(not(target_os = "windows"))]
+const INHERITED: &[&str] = &["HOME", "LANG", "LC_ALL", "PATH", "TMPDIR", "USER"];
+
+/// Loopback must never go through an upstream proxy.
+const NO_PROXY: &str = "127.0.0.1,localhost,::1";
+
+/// The whole environment for the child, to replace its inherited one: the
+/// whitelist plus `extra.pass_through` from ours, then our CA as
+/// NODE_EXTRA_CA_CERTS, NO_PROXY, and `extra.set`.
+pub fn vars(ca_file: &Path, extra: &ProxyEnv) -> Vec<(OsString, OsString)> {
+    let mut vars = Vec::new();
+    for name in INHERITED.iter().copied().chain(extra.pass_through.iter().map(String::as_str)) {
+        if let Some(value) = std::env::var_os(name) {
+            vars.push((name.into(), value));
+        }
+    }
+    vars.push(("NODE_EXTRA_CA_CERTS".into(), ca_file.into()));
+    vars.push(("NO_PROXY".into(), NO_PROXY.into()));
+    for (name, value) in &extra.set {
+        vars.push((name.into(), value.into()));
+    }
+    vars
+}
+
+/// Why `name` can't be an environment variable name, if it can't.
+pub fn invalid_name(name: &str) -> Option<String> {
+    if name.is_empty() || name.contains('=') || name.contains('\0') {
+        Some(format!("Invalid environment variable name: {:?}", name))
+    } else {
+        None
+    }
+}
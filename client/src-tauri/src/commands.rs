@@ -0,0 +1,1544 @@
+//! The commands the UI invokes. State-changing ones run under the operation
+//! lock through `exclusive`, read-only ones through `blocking`; the work
+//! they share with the tray, autorun and exit cleanup stays in lib.rs.
+
+use crate::error::ProxyError;
+use crate::hosts::HostsEditor;
+use crate::schedule::{arm_schedule, cancel_schedule};
+use crate::setup::check_setup;
+use crate::watchdog::refresh_child;
+use crate::{
+    agents, allowlist, app_data_dir, app_versions, apply_hosts, audit, autostart, backup_dir, blocking, cached_hosts,
+    cert, cert_installed, certs_path, check_not_service, childenv, config, current_pid, days_until, default_domains,
+    diagnostics, doh, elevate, exclusive, flush_dns, forget_status, gateway, gateway_pin, healthcheck, heartbeat,
+    hosts, install_cert, integrity, lastgood, list_backups, load_allowlist, log_action, logs, messages, net, node,
+    now_millis, port_conflict, process, provision, proxy, proxy_script_path, redirect, refresh_adopted,
+    regenerate_cert, replay, resources_dir, restart_proxy, restore, routing, run_proxy, selftest, service,
+    service_state, session, setup, sidecar_path, status, stop, traffic, uninstall_cert, update, update_dir, upstream,
+    uptime, versions, AppHandle, AutorunOutcome, CertChangeResult, CertRegenerateResult, HistoryEntry, InitReport,
+    ProxyState, ProxyStatus, RestartResult, RestoreOptions, RestoreResult, RunOptions, RunResult, StopOptions,
+    StopResult, SystemHosts, DEFAULT_READY_TIMEOUT,
+};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tauri::{Manager, State};
+
+const DEFAULT_LOG_LINES: usize = 200;
+const DEFAULT_HISTORY_ENTRIES: usize = 50;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanCleanupResult {
+    orphans: Vec<process::OrphanProcess>,
+    terminated: Vec<u32>,
+}
+
+/// Machine facts for the diagnostics bundle.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SystemInfo {
+    app_version: &'static str,
+    os: Option<String>,
+    kernel: Option<String>,
+    node: Option<node::NodeRuntime>,
+    elevated: bool,
+    cert_installed: bool,
+    /// Who listens on 443 (us, the redirect, or something in the way).
+    port_443_owner: Option<net::PortOwner>,
+    proxy_pid: Option<u32>,
+    proxy_port: u16,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleResult {
+    ok: bool,
+    message: String,
+    /// Unix seconds; None once cancelled.
+    stop_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepairResult {
+    ok: bool,
+    message: String,
+    /// The block entries that were removed.
+    removed: Vec<hosts::HostsEntry>,
+    dns_flushed: bool,
+    elevation_requested: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupRestoreResult {
+    ok: bool,
+    message: String,
+    backup: String,
+    dns_flushed: bool,
+    elevation_requested: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditExportResult {
+    ok: bool,
+    message: String,
+    path: String,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsFlushResult {
+    ok: bool,
+    message: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CertRenewResult {
+    ok: bool,
+    message: String,
+    cert_installed: bool,
+    elevation_required: bool,
+    elevation_obtained: bool,
+    ca_expires_at: Option<i64>,
+    cert_expires_at: Option<i64>,
+    /// The proxy was running and was restarted on the new certificate.
+    restart: Option<RestartResult>,
+}
+
+#[tauri::command]
+pub async fn proxy_initialize(app: AppHandle) -> Result<InitReport, ProxyError> {
+    exclusive(app, "Initialize", initialize).await?
+}
+
+/// The pre-flight probe. Every part is computed on its own, so one that
+/// fails (an unreadable hosts file, no resources dir) leaves its fields
+/// empty instead of failing the report.
+fn initialize(app: AppHandle, state: State<'_, ProxyState>) -> Result<InitReport, ProxyError> {
+    refresh_adopted(&state);
+    let session_file = state.session_file.lock().clone();
+    let stale_session = current_pid(&state).is_none() && session_file.as_deref().is_some_and(Path::is_file);
+    let adopted_pid = adopt_session(&app, &state);
+    let mut stale_state_files = Vec::new();
+    if stale_session && adopted_pid.is_none() {
+        stale_state_files.extend(session_file);
+    }
+    if let Some(path) = state.service_state_file.lock().clone() {
+        if path.is_file() && service::load_state(&path).is_none() {
+            stale_state_files.push(path);
+        }
+    }
+    let domains = state.inner.lock().domains.clone();
+    let (hosts, hosts_error) = match cached_hosts(&state, &domains) {
+        Ok((hosts, _)) => (Some(hosts), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    let hosts_write_probe = state.hosts.probe_writable();
+    let certs = certs_path(&app).ok();
+    let own_pid = current_pid(&state);
+    let (port_available, port_owner) = if own_pid.is_some() {
+        (true, None)
+    } else {
+        match port_conflict(state.config.lock().port) {
+            Some(owner) => (false, owner),
+            None => (true, None),
+        }
+    };
+    // Our own proxy, or the redirect to it, holding 443 is no conflict
+    let (port_443_free, port_443_owner) = match port_conflict(redirect::HTTPS_PORT) {
+        Some(_) if own_pid.is_some() => (true, None),
+        Some(owner) => (false, owner),
+        None => (true, None),
+    };
+    let orphan_processes = find_orphans(&app, &state);
+    // Computed up front: a guard taken inside the struct literal below would
+    // live until the end of the statement
+    let proxy_running = state.inner.lock().running();
+    let stale_modification =
+        hosts.as_ref().is_some_and(|h| h.has_block()) && !proxy_running && orphan_processes.is_empty();
+    let cert_expires_at = certs.as_deref().and_then(cert::not_after);
+    let node = resources_dir().ok().and_then(|dir| node::detect(&dir));
+    let integrity = resources_dir().map(|dir| integrity::check(&dir));
+    let allowlist = state.allowlist.lock().clone();
+    let doh = doh::probe();
+    Ok(InitReport {
+        elevated: elevate::is_elevated(),
+        hosts_modified: hosts.as_ref().is_some_and(|h| h.is_modified()),
+        hosts_error,
+        hosts_writable: hosts_write_probe.access == hosts::WriteAccess::Writable,
+        hosts_write_probe,
+        hosts_warnings: hosts.as_ref().map(|h| h.block_warnings()).unwrap_or_default(),
+        stray_entries: hosts.as_ref().map(|h| h.stray_entries()).unwrap_or_default(),
+        stale_entries: hosts.as_ref().map(|h| h.stale_entry_count()).unwrap_or_default(),
+        adopted_pid,
+        stale_modification,
+        hosts_entries: hosts.as_ref().map(|h| h.block_entries()).unwrap_or_default(),
+        proxy_running,
+        cert_installed: certs.as_deref().is_some_and(cert::is_installed),
+        local_ca_exists: certs.as_deref().is_some_and(cert::exists),
+        ca_expires_at: certs.as_deref().and_then(cert::ca_not_after),
+        cert_expires_at,
+        cert_days_remaining: cert_expires_at.map(days_until),
+        orphan_processes,
+        proxy_script_exists: proxy_script_path().is_ok_and(|p| p.is_file()),
+        sidecar_available: sidecar_path(&app).is_some(),
+        certs_dir_exists: certs.as_deref().is_some_and(Path::is_dir),
+        node_found: node.is_some(),
+        node_version: node.as_ref().map(|n| n.version.clone()),
+        node,
+        port_available,
+        port_owner,
+        port_443_free,
+        port_443_owner,
+        stale_state_files,
+        config_error: state.config_error.lock().clone(),
+        allowlist_version: allowlist.as_ref().ok().and_then(|list| list.version),
+        allowlist_unsigned: allowlist.as_ref().is_ok_and(|list| list.unsigned),
+        allowlist_error: allowlist.as_ref().err().cloned(),
+        system_proxy: upstream::detect(),
+        integrity_ok: integrity.as_ref().is_ok_and(Vec::is_empty),
+        integrity_mismatches: integrity.unwrap_or_default(),
+        hosts_watch: state.hosts_watch.mode(),
+        doh_enabled: doh.enabled,
+        doh,
+        conflicting_agents: agents::detect(),
+    })
+}
+
+/// Report proxies left running by a previous session and, with `terminate`,
+/// kill them.
+#[tauri::command]
+pub async fn proxy_cleanup_orphans(terminate: Option<bool>, app: AppHandle) -> Result<OrphanCleanupResult, ProxyError> {
+    exclusive(app, "Orphan cleanup", move |app, state| cleanup_orphans(terminate, app, state)).await
+}
+
+fn cleanup_orphans(terminate: Option<bool>, app: AppHandle, state: State<'_, ProxyState>) -> OrphanCleanupResult {
+    let orphans = find_orphans(&app, &state);
+    let terminated = if terminate.unwrap_or(false) {
+        orphans
+            .iter()
+            .filter(|o| process::terminate_pid(o.pid))
+            .map(|o| o.pid)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    for pid in &terminated {
+        log_action(&state, &format!("Terminated orphaned proxy (pid {})", pid));
+    }
+    OrphanCleanupResult { orphans, terminated }
+}
+
+/// Pick up the proxy a previous session of the app left running, if its pid
+/// still belongs to our proxy (by command line); otherwise drop the record.
+fn adopt_session(app: &AppHandle, state: &ProxyState) -> Option<u32> {
+    if current_pid(state).is_some() {
+        return None;
+    }
+    let path = state.session_file.lock().clone()?;
+    let recorded = session::load(&path)?;
+    if !find_orphans(app, state).iter().any(|o| o.pid == recorded.pid) {
+        session::clear(&path);
+        log_action(state, &format!("Recorded proxy (pid {}) is gone; session record removed", recorded.pid));
+        return None;
+    }
+    log_action(
+        state,
+        &format!("Re-adopted proxy from a previous session (pid {}, port {})", recorded.pid, recorded.listen_port),
+    );
+    let pid = recorded.pid;
+    let mut inner = state.inner.lock();
+    inner.port = recorded.listen_port;
+    inner.domains = recorded.domains.clone();
+    inner.started_at = Some(recorded.started_at);
+    inner.gateway_url = Some(recorded.gateway_url.clone());
+    inner.profile = recorded.profile.clone();
+    inner.upstream_proxy = recorded.upstream_proxy.clone();
+    inner.routes = recorded.routes.clone();
+    inner.bypass_paths = recorded.bypass_paths.clone();
+    inner.replay = recorded.replay.clone();
+    inner.adopted = Some(recorded);
+    Some(pid)
+}
+
+fn find_orphans(app: &AppHandle, state: &ProxyState) -> Vec<process::OrphanProcess> {
+    let exclude = current_pid(state);
+    let update = update_dir(app).ok().and_then(|dir| update::installed(&dir).ok().flatten());
+    let mut orphans: Vec<process::OrphanProcess> = proxy_script_path()
+        .ok()
+        .into_iter()
+        .chain(update.map(|u| u.script))
+        .chain(sidecar_path(app))
+        .chain(native_proxy_command())
+        .flat_map(|path| process::find_orphans(&path, exclude))
+        .collect();
+    orphans.sort_by_key(|o| o.pid);
+    orphans.dedup_by_key(|o| o.pid);
+    orphans
+}
+
+/// How the built-in proxy's command line starts, to find it among processes:
+/// our executable alone would also match the app itself.
+fn native_proxy_command() -> Option<PathBuf> {
+    let mut command = std::env::current_exe().ok()?.into_os_string();
+    command.push(" ");
+    command.push(proxy::HELPER_ARG);
+    Some(command.into())
+}
+
+// Each argument is a separate optional field of the invoke payload
+#[allow(clippy::too_many_arguments)]
+#[tauri::command]
+pub async fn proxy_run(
+    gateway_url: Option<String>,
+    port: Option<u16>,
+    auto_restart: Option<bool>,
+    check_gateway: Option<bool>,
+    ready_timeout_ms: Option<u64>,
+    domains: Option<Vec<String>>,
+    routes: Option<Vec<config::Route>>,
+    bypass_paths: Option<Vec<String>>,
+    profile: Option<String>,
+    duration_minutes: Option<u64>,
+    upstream_proxy: Option<String>,
+    skip_integrity_check: Option<bool>,
+    dry_run: Option<bool>,
+    allow_large_hosts: Option<bool>,
+    mode: Option<replay::Mode>,
+    replay_path: Option<String>,
+    app: AppHandle,
+) -> Result<RunResult, ProxyError> {
+    let replay_path = replay_target(mode, replay_path)?;
+    exclusive(app, "Proxy start", move |app, state| {
+        // Anything not passed comes from the profile, then the stored config
+        let config = state.config.lock().clone();
+        let profile = match profile {
+            Some(_) if gateway_url.is_some() => {
+                return Err(ProxyError::InvalidArgument("Pass either a gateway URL or a profile, not both".into()))
+            }
+            Some(name) => Some(
+                config
+                    .profile(&name)
+                    .cloned()
+                    .ok_or_else(|| ProxyError::InvalidArgument(format!("No profile named {:?}", name)))?,
+            ),
+            None => None,
+        };
+        let (gateway_url, domains) = match &profile {
+            Some(p) => (Some(p.gateway_url.clone()), domains.or((!p.domains.is_empty()).then(|| p.domains.clone()))),
+            None => (gateway_url, domains),
+        };
+        let options = RunOptions {
+            gateway_url: gateway_url.or(config.gateway_url).unwrap_or_default(),
+            profile: profile.map(|p| p.name),
+            upstream_proxy: upstream_proxy.or(config.upstream_proxy).unwrap_or_default(),
+            port: port.unwrap_or(config.port),
+            domains: domains.unwrap_or(config.domains),
+            routes: routes.unwrap_or(config.routes),
+            bypass_paths: bypass_paths.unwrap_or(config.bypass_paths),
+            auto_restart: auto_restart.unwrap_or(config.auto_restart),
+            check_gateway: check_gateway.unwrap_or(false),
+            ready_timeout: ready_timeout_ms.map(Duration::from_millis).unwrap_or(DEFAULT_READY_TIMEOUT),
+            duration_minutes,
+            skip_integrity_check: skip_integrity_check.unwrap_or(false),
+            dry_run: dry_run.unwrap_or(false),
+            allow_large_hosts: allow_large_hosts.unwrap_or(false),
+            replay_path,
+        };
+        run_proxy(&app, &state, options)
+    })
+    .await?
+}
+
+/// The bundle to replay for `mode`, which defaults to forward.
+fn replay_target(mode: Option<replay::Mode>, replay_path: Option<String>) -> Result<Option<String>, ProxyError> {
+    match (mode.unwrap_or_default(), replay_path) {
+        (replay::Mode::Forward, None) => Ok(None),
+        (replay::Mode::Forward, Some(_)) => {
+            Err(ProxyError::InvalidArgument("A replay bundle only applies in replay mode".into()))
+        }
+        (replay::Mode::Replay, Some(path)) => Ok(Some(path)),
+        (replay::Mode::Replay, None) => {
+            Err(ProxyError::InvalidArgument("Replay mode needs the path of a session export to replay".into()))
+        }
+    }
+}
+
+/// Check every link from the hosts file to the gateway, for the first
+/// intercepted domain, and report each step.
+#[tauri::command]
+pub async fn proxy_selftest(app: AppHandle) -> Result<selftest::Report, ProxyError> {
+    blocking(app, "Self-test", |app, state| selftest::run(selftest_target(&app, &state))).await
+}
+
+fn selftest_target(app: &AppHandle, state: &ProxyState) -> selftest::Target {
+    let domain = state.inner.lock().domains.first().cloned().or_else(|| default_domains().pop()).unwrap_or_default();
+    let hosts_entry = SystemHosts::new(app, state).load().map_err(|e| e.to_string()).and_then(|hosts| {
+        let missing = hosts.missing_entries(std::slice::from_ref(&domain));
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Missing hosts entries: {}", missing.join(", ")))
+        }
+    });
+    let (gateway_url, upstream_proxy, port) = {
+        let inner = state.inner.lock();
+        let config = state.config.lock();
+        (
+            inner.gateway_url.clone().or_else(|| config.gateway_url.clone()).unwrap_or_default(),
+            inner.upstream_proxy.clone().or_else(|| config.upstream_proxy.clone()).unwrap_or_default(),
+            inner.port,
+        )
+    };
+    let gateway_pin = gateway_pin(state).map_err(|e| e.message().to_string());
+    selftest::Target { domain, port, hosts_entry, gateway_url, upstream_proxy, gateway_pin }
+}
+
+/// Zip up what a support ticket needs: the redacted hosts file and config,
+/// the proxy and audit logs, machine facts and a self-test run.
+#[tauri::command]
+pub async fn diagnostics_export(path: String, app: AppHandle) -> Result<diagnostics::Summary, ProxyError> {
+    let path = PathBuf::from(path);
+    if !path.is_absolute() {
+        return Err(ProxyError::InvalidArgument(format!("Export path {} is not absolute", path.display())));
+    }
+    blocking(app, "Diagnostics export", move |app, state| {
+        let mut bundle = diagnostics::Bundle::new();
+        bundle.add_json("selftest.json", &selftest::run(selftest_target(&app, &state)));
+        bundle.add_json(
+            "system.json",
+            &SystemInfo {
+                app_version: env!("CARGO_PKG_VERSION"),
+                os: sysinfo::System::long_os_version(),
+                kernel: sysinfo::System::kernel_version(),
+                node: resources_dir().ok().and_then(|dir| node::detect(&dir)),
+                elevated: elevate::is_elevated(),
+                cert_installed: cert_installed(&app),
+                port_443_owner: net::port_owner(redirect::HTTPS_PORT),
+                proxy_pid: current_pid(&state),
+                proxy_port: state.inner.lock().port,
+            },
+        );
+        bundle.add_json("versions.json", &app_versions(&app));
+        bundle.add_json("config.json", &diagnostics::redact_config(&state.config.lock()));
+        let domains = state.inner.lock().domains.clone();
+        match SystemHosts::new(&app, &state).load() {
+            Ok(hosts) => bundle.add("hosts.txt", hosts.redacted(&domains).into_bytes(), false),
+            Err(e) => bundle.omit("hosts.txt", &e.to_string()),
+        }
+        let proxy_log = state.file_log.lock().path().map(Path::to_path_buf);
+        bundle.add_tail("proxy.log", proxy_log.as_deref());
+        let audit_log = state.audit.lock().path().map(Path::to_path_buf);
+        bundle.add_tail("audit.jsonl", audit_log.as_deref());
+        bundle.write(&path).map_err(ProxyError::Internal)
+    })
+    .await?
+}
+
+/// Point the running proxy at another gateway, or switch it between forward
+/// and replay mode, without touching hosts.
+#[tauri::command]
+pub async fn proxy_restart(
+    gateway_url: String,
+    ready_timeout_ms: Option<u64>,
+    mode: Option<replay::Mode>,
+    replay_path: Option<String>,
+    app: AppHandle,
+) -> Result<RestartResult, ProxyError> {
+    let ready_timeout = ready_timeout_ms.map(Duration::from_millis).unwrap_or(DEFAULT_READY_TIMEOUT);
+    let replay_path = replay_target(mode, replay_path)?;
+    if replay_path.is_some() && !gateway_url.trim().is_empty() {
+        return Err(ProxyError::InvalidArgument("Replay mode forwards nowhere; leave out the gateway URL".into()));
+    }
+    exclusive(app, "Proxy restart", move |app, state| {
+        let replay = match replay_path {
+            Some(path) => {
+                let domains = state.inner.lock().domains.clone();
+                Some(replay::validate(&path, &domains).map_err(ProxyError::InvalidArgument)?)
+            }
+            None => None,
+        };
+        let reason = if replay.is_some() { "switching to replay" } else { "switching gateway" };
+        restart_proxy(&app, &state, &gateway_url, replay, ready_timeout, reason)
+    })
+    .await?
+}
+
+/// Probe a gateway for the "test connection" button. Independent of the proxy.
+#[tauri::command]
+pub async fn gateway_check(
+    gateway_url: String,
+    health_path: Option<String>,
+    upstream_proxy: Option<String>,
+    app: AppHandle,
+) -> Result<gateway::GatewayCheck, ProxyError> {
+    let health_path = health_path.unwrap_or_else(|| gateway::DEFAULT_HEALTH_PATH.into());
+    blocking(app, "Gateway check", move |_, state| {
+        // The same upstream proxy_run would use
+        let upstream_proxy = upstream_proxy.or_else(|| state.config.lock().upstream_proxy.clone()).unwrap_or_default();
+        let upstream_proxy = upstream::normalize(&upstream_proxy).map_err(ProxyError::InvalidArgument)?;
+        let pin = gateway_pin(&state)?;
+        Ok(gateway::check(&gateway_url, &health_path, gateway::CHECK_TIMEOUT, &upstream_proxy, &pin))
+    })
+    .await?
+}
+
+/// Stop the proxy and, unless `keep_hosts`, remove our hosts block and the
+/// 443 redirect so Windsurf works again. Unlike proxy_restore, stray
+/// entries outside the block are left alone.
+#[tauri::command]
+pub async fn proxy_stop(
+    timeout_ms: Option<u64>,
+    keep_hosts: Option<bool>,
+    allow_large_hosts: Option<bool>,
+    app: AppHandle,
+) -> Result<StopResult, ProxyError> {
+    let options = StopOptions::new(timeout_ms, keep_hosts, allow_large_hosts);
+    exclusive(app, "Proxy stop", move |app, state| stop(options, app, state)).await?
+}
+
+/// Stop the proxy and remove exactly our hosts block. Entries for our domains
+/// outside it are reported and only removed with `remove_stray`; the CA is
+/// removed from the trust store only with `remove_cert`. A failed step
+/// doesn't stop the ones after it, and running it again retries only the
+/// steps that failed unless `force` is set.
+#[tauri::command]
+pub async fn proxy_restore(
+    timeout_ms: Option<u64>,
+    remove_stray: Option<bool>,
+    remove_cert: Option<bool>,
+    force: Option<bool>,
+    allow_large_hosts: Option<bool>,
+    app: AppHandle,
+) -> Result<RestoreResult, ProxyError> {
+    let options = RestoreOptions {
+        timeout_ms,
+        remove_stray: remove_stray.unwrap_or(false),
+        remove_cert: remove_cert.unwrap_or(false),
+        force: force.unwrap_or(false),
+        allow_large_hosts: allow_large_hosts.unwrap_or(false),
+    };
+    exclusive(app, "Restore", move |app, state| restore(options, app, state)).await?
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DohResult {
+    ok: bool,
+    message: String,
+    /// Settings changed by this call.
+    changed: usize,
+    /// The state afterwards. Still enabled when policy enforces it or a
+    /// per-adapter setting overrides ours.
+    doh: doh::Probe,
+}
+
+/// Turn system DNS-over-HTTPS/TLS off for the drill. The original settings
+/// are recorded under the app data dir and proxy_restore puts them back.
+#[tauri::command]
+pub async fn doh_disable(app: AppHandle) -> Result<DohResult, ProxyError> {
+    exclusive(app, "DoH disable", move |app, state| {
+        if cfg!(target_os = "macos") {
+            return Err(ProxyError::Unsupported("Turning off encrypted DNS is not supported on macOS".into()));
+        }
+        if !elevate::is_elevated() {
+            return Err(ProxyError::NotElevated("Changing the system DNS settings needs admin rights".into()));
+        }
+        let file = app_data_dir(&app)?.join(doh::STATE_FILE);
+        let changed = doh::disable(&file).map_err(ProxyError::Internal)?;
+        if changed > 0 {
+            log_action(&state, &format!("System DoH turned off ({} settings); proxy_restore restores it", changed));
+            audit(&state, audit::Event::DohDisabled { settings: changed });
+            let _ = flush_dns(&state);
+        }
+        let doh = doh::probe();
+        let message = match (changed, doh.enabled) {
+            (_, false) => "System DoH is off".into(),
+            (0, true) => "Nothing we can change; system DoH is still on".into(),
+            (_, true) => "Changed what we could; system DoH is still on".into(),
+        };
+        Ok(DohResult { ok: !doh.enabled, message, changed, doh })
+    })
+    .await?
+}
+
+/// Remove a hosts block left behind by a session that didn't clean up (see
+/// `staleModification` in proxy_initialize). Only our marker block goes, as
+/// in proxy_restore; refuses while a proxy of ours is running.
+#[tauri::command]
+pub async fn proxy_repair(allow_large_hosts: Option<bool>, app: AppHandle) -> Result<RepairResult, ProxyError> {
+    exclusive(app, "Repair", move |app, state| repair(allow_large_hosts.unwrap_or(false), app, state)).await?
+}
+
+fn repair(allow_large_hosts: bool, app: AppHandle, state: State<'_, ProxyState>) -> Result<RepairResult, ProxyError> {
+    if current_pid(&state).is_some() {
+        return Err(ProxyError::InvalidState("The proxy is running; use restore to stop it".into()));
+    }
+    let editor = SystemHosts::new(&app, &state).allowing_large(allow_large_hosts);
+    let removed = editor.read_entries()?;
+    let written = editor.remove_entries(&[])?;
+    log_action(
+        &state,
+        if written { "Repair ran: stale hosts block removed" } else { "Repair ran: no hosts block to remove" },
+    );
+    let applied = editor.applied.get();
+
+    Ok(RepairResult {
+        ok: true,
+        message: if written { "Stale hosts entries removed" } else { "Nothing to repair" }.into(),
+        removed: if written { removed } else { Vec::new() },
+        dns_flushed: applied.map_or_else(|| flush_dns(&state).is_ok(), |a| a.dns_flushed),
+        elevation_requested: applied.is_some_and(|a| a.elevation_requested),
+    })
+}
+
+/// A hosts_list_managed entry and where the proxy forwards its domain.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManagedRoute {
+    #[serde(flatten)]
+    entry: hosts::ManagedEntry,
+    /// Empty for passthrough, and for a domain the proxy doesn't intercept.
+    gateway_url: String,
+}
+
+/// Every hosts entry attributable to us, with where it sits in the file:
+/// the lines of our blocks, and entries for the current domains outside them.
+#[tauri::command]
+pub async fn hosts_list_managed(app: AppHandle) -> Result<Vec<ManagedRoute>, ProxyError> {
+    blocking(app, "Hosts list", move |_, state| {
+        let domains = state.inner.lock().domains.clone();
+        let routing = routing(&state, &domains, None);
+        let entries = state.hosts.load()?.managed_entries(&domains);
+        Ok(entries
+            .into_iter()
+            .map(|entry| {
+                let route = routing.iter().find(|r| r.domain == entry.domain);
+                ManagedRoute { gateway_url: route.map(|r| r.gateway_url.clone()).unwrap_or_default(), entry }
+            })
+            .collect())
+    })
+    .await?
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostsPreview {
+    domains: Vec<String>,
+    /// proxy_run would write the hosts file.
+    would_write: bool,
+    changes: Vec<hosts::LineChange>,
+}
+
+/// Dry run of proxy_run's hosts edit for `gateway_domains` (default: the
+/// configured domains). Computed by the same code that makes the edit;
+/// nothing is written.
+#[tauri::command]
+pub async fn hosts_preview_changes(
+    gateway_domains: Option<Vec<String>>,
+    app: AppHandle,
+) -> Result<HostsPreview, ProxyError> {
+    blocking(app, "Hosts preview", move |_, state| {
+        let domains = gateway_domains.unwrap_or_else(|| state.config.lock().intercepted_domains());
+        let domains = hosts::normalize_domains(&domains)?;
+        let text = state.hosts.load()?;
+        Ok(HostsPreview { would_write: text.needs_update(&domains), changes: text.preview_entries(&domains), domains })
+    })
+    .await?
+}
+
+#[tauri::command]
+pub async fn proxy_restore_backup(app: AppHandle) -> Result<BackupRestoreResult, ProxyError> {
+    exclusive(app, "Backup restore", restore_backup).await?
+}
+
+fn restore_backup(app: AppHandle, state: State<'_, ProxyState>) -> Result<BackupRestoreResult, ProxyError> {
+    let dir = backup_dir(&app)?;
+    let latest = list_backups(&dir)
+        .pop()
+        .ok_or_else(|| ProxyError::InvalidState("No hosts backup found".into()))?;
+    let content = fs::read(&latest).map_err(|e| ProxyError::Internal(format!("Read backup failed: {}", e)))?;
+    let applied = {
+        let lock = state.hosts.update_lock()?;
+        apply_hosts(&app, &state, &content, &lock)?
+    };
+
+    Ok(BackupRestoreResult {
+        ok: true,
+        message: "Hosts restored from backup".into(),
+        backup: latest.to_string_lossy().to_string(),
+        dns_flushed: applied.dns_flushed,
+        elevation_requested: applied.elevation_requested,
+    })
+}
+
+/// Whether the app runs as administrator / root. Without it hosts changes
+/// go through an elevation prompt.
+#[tauri::command]
+pub fn is_elevated() -> bool {
+    elevate::is_elevated()
+}
+
+/// Whether closing the app should leave the proxy running and the hosts block
+/// in place instead of restoring.
+#[tauri::command]
+pub async fn set_keep_running_on_exit(keep: bool, app: AppHandle) -> Result<(), ProxyError> {
+    exclusive(app, "Exit setting", move |_, state| {
+        *state.keep_running_on_exit.lock() = keep;
+        log_action(
+            &state,
+            if keep {
+                "Proxy will keep running after the app exits"
+            } else {
+                "Proxy will be restored when the app exits"
+            },
+        );
+    })
+    .await
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceResult {
+    ok: bool,
+    message: String,
+    /// None where there is no service mode.
+    installed: Option<bool>,
+}
+
+/// Register the background service (Windows service, systemd unit) and
+/// start it. It runs proxy_run with the stored config, so that is saved
+/// first, and keeps the proxy up without the app.
+#[tauri::command]
+pub async fn service_install(app: AppHandle) -> Result<ServiceResult, ProxyError> {
+    exclusive(app, "Service install", move |app, state| {
+        if !elevate::is_elevated() {
+            return Err(ProxyError::NotElevated("Installing the service needs the app to run as administrator".into()));
+        }
+        if let Some(pid) = current_pid(&state) {
+            return Err(ProxyError::InvalidState(format!(
+                "Stop the proxy (pid {}) first; the service starts its own",
+                pid
+            )));
+        }
+        let config = state.config.lock().clone();
+        store_config(&state, &config)?;
+        // The service would generate one under its own account, which the app
+        // can't trust for it
+        if !cert::exists(&certs_path(&app)?) {
+            return Err(ProxyError::InvalidState(
+                "Finish setup (or start the proxy once) so the CA exists before installing the service".into(),
+            ));
+        }
+        let config_dir = app
+            .path()
+            .app_config_dir()
+            .map_err(|e| ProxyError::Internal(format!("Failed to resolve app config dir: {}", e)))?;
+        service::install(&app_data_dir(&app)?, &config_dir).map_err(ProxyError::Internal)?;
+        forget_status(&state);
+        log_action(&state, "Background service installed and started");
+        Ok(ServiceResult { ok: true, message: "Service installed and started".into(), installed: service::installed() })
+    })
+    .await?
+}
+
+/// Stop and unregister the background service. It removes its hosts block
+/// and firewall rule as it stops.
+#[tauri::command]
+pub async fn service_uninstall(app: AppHandle) -> Result<ServiceResult, ProxyError> {
+    exclusive(app, "Service uninstall", move |_, state| {
+        if !elevate::is_elevated() {
+            return Err(ProxyError::NotElevated("Removing the service needs the app to run as administrator".into()));
+        }
+        service::uninstall().map_err(ProxyError::Internal)?;
+        forget_status(&state);
+        log_action(&state, "Background service removed");
+        Ok(ServiceResult { ok: true, message: "Service removed".into(), installed: service::installed() })
+    })
+    .await?
+}
+
+#[tauri::command]
+pub fn config_get(state: State<'_, ProxyState>) -> config::Config {
+    state.config.lock().clone()
+}
+
+/// Validate and store `config`; returns it normalized. A running proxy keeps
+/// its settings until the next proxy_run.
+#[tauri::command]
+pub async fn config_set(config: config::Config, app: AppHandle) -> Result<config::Config, ProxyError> {
+    let config = normalize_config(config)?;
+    exclusive(app, "Config save", move |_, state| {
+        let config = {
+            let stored = state.config.lock();
+            config::Config {
+                profiles: stored.profiles.clone(),
+                autorun_proxy: stored.autorun_proxy,
+                locale: stored.locale.clone(),
+                ..config
+            }
+        };
+        // The running proxy, its certificate and the hosts entries are for the
+        // current domains, as are those of a start in progress; a new set
+        // takes effect with the next proxy_run
+        let busy = {
+            let inner = state.inner.lock();
+            inner.running() || inner.starting
+        };
+        if (busy || service_state(&state).is_some())
+            && config.intercepted_domains() != state.config.lock().intercepted_domains()
+        {
+            return Err(ProxyError::InvalidState(
+                "The proxy is running; restore it before changing the intercepted domains".into(),
+            ));
+        }
+        store_config(&state, &config)?;
+        let mut inner = state.inner.lock();
+        if !inner.running() && !inner.starting {
+            inner.configure(&config);
+        }
+        drop(inner);
+        log_action(&state, "Config saved");
+        Ok(config)
+    })
+    .await?
+}
+
+/// `config` validated and in its stored form, for config_set and
+/// state_import.
+fn normalize_config(config: config::Config) -> Result<config::Config, ProxyError> {
+    let gateway_url = match config.gateway_url.as_deref().map(gateway::normalize).transpose() {
+        Ok(url) => url.filter(|u| !u.is_empty()),
+        Err(e) => return Err(ProxyError::GatewayInvalid(e)),
+    };
+    if config.port == 0 {
+        return Err(ProxyError::InvalidArgument("Port must be 1-65535".into()));
+    }
+    if !(1..=3600).contains(&config.health_interval_secs) {
+        return Err(ProxyError::InvalidArgument("The health check interval must be 1-3600 seconds".into()));
+    }
+    let upstream_proxy = config.upstream_proxy.as_deref().map(upstream::normalize).transpose();
+    let upstream_proxy = upstream_proxy.map_err(ProxyError::InvalidArgument)?.filter(|url| !url.is_empty());
+    let env = &config.proxy_env;
+    if let Some(e) = env.pass_through.iter().chain(env.set.keys()).find_map(|n| childenv::invalid_name(n)) {
+        return Err(ProxyError::InvalidArgument(e));
+    }
+    let pin = config.gateway_pin().map_err(ProxyError::InvalidArgument)?;
+    let update_manifest_url = config.update_manifest_url.as_deref().map(str::trim).filter(|u| !u.is_empty());
+    if let Some(url) = update_manifest_url {
+        if !url::Url::parse(url).is_ok_and(|u| u.scheme() == "https" && u.host_str().is_some()) {
+            return Err(ProxyError::InvalidArgument(format!("The update manifest URL {:?} is not an https URL", url)));
+        }
+    }
+    let update_manifest_url = update_manifest_url.map(str::to_string);
+    Ok(config::Config {
+        schema_version: config::SCHEMA_VERSION,
+        gateway_url,
+        upstream_proxy,
+        gateway_ca_pem: pin.ca_pem,
+        gateway_spki_pin: pin.spki_sha256,
+        update_manifest_url,
+        domains: hosts::normalize_domains(&config.domains)?,
+        routes: config::normalize_routes(&config.routes)?,
+        bypass_paths: config::normalize_bypass_paths(&config.bypass_paths)?,
+        health_route: healthcheck::normalize_route(&config.health_route).map_err(ProxyError::InvalidArgument)?,
+        ..config
+    })
+}
+
+/// Put the settings back to their defaults and forget the last working
+/// gateway. Profiles and the login autorun are kept. Refused while the proxy
+/// runs on the current settings.
+#[tauri::command]
+pub async fn config_reset(app: AppHandle) -> Result<config::Config, ProxyError> {
+    exclusive(app, "Config reset", move |_, state| {
+        let busy = {
+            let inner = state.inner.lock();
+            inner.running() || inner.starting
+        };
+        if busy || service_state(&state).is_some() {
+            return Err(ProxyError::InvalidState(
+                "The proxy is running; restore it before resetting the settings".into(),
+            ));
+        }
+        let config = {
+            let stored = state.config.lock();
+            config::Config {
+                profiles: stored.profiles.clone(),
+                autorun_proxy: stored.autorun_proxy,
+                ..Default::default()
+            }
+        };
+        store_config(&state, &config)?;
+        state.inner.lock().configure(&config);
+        messages::set_locale(messages::DEFAULT_LOCALE);
+        if let Some(path) = state.last_good_file.lock().as_deref() {
+            lastgood::clear(path);
+        }
+        *state.last_good.lock() = None;
+        log_action(&state, "Config reset to defaults; the last working gateway was forgotten");
+        Ok(config)
+    })
+    .await?
+}
+
+/// Write `config` to the config file and make it the current one.
+fn store_config(state: &ProxyState, config: &config::Config) -> Result<(), ProxyError> {
+    if let Some(path) = state.config_file.lock().as_deref() {
+        config::save(path, config)
+            .map_err(|e| ProxyError::Internal(format!("Saving the config to {} failed: {}", path.display(), e)))?;
+    }
+    *state.config.lock() = config.clone();
+    *state.config_error.lock() = None;
+    Ok(())
+}
+
+/// Write the config (profiles and gateway pin included) and the gateway
+/// allowlist to `path`, for state_import on other machines. The CA goes in
+/// only with a `passphrase`, its key encrypted with it.
+#[tauri::command]
+pub async fn state_export(
+    path: String,
+    passphrase: Option<String>,
+    app: AppHandle,
+) -> Result<provision::ExportResult, ProxyError> {
+    let path = PathBuf::from(path);
+    if !path.is_absolute() {
+        return Err(ProxyError::InvalidArgument(format!("Export path {} is not absolute", path.display())));
+    }
+    let passphrase = passphrase.filter(|p| !p.is_empty());
+    if passphrase.as_ref().is_some_and(|p| p.chars().count() < provision::MIN_PASSPHRASE_LEN) {
+        return Err(ProxyError::InvalidArgument(format!(
+            "The passphrase must have at least {} characters",
+            provision::MIN_PASSPHRASE_LEN
+        )));
+    }
+    blocking(app, "State export", move |app, state| {
+        let config = serde_json::to_value(&*state.config.lock()).map_err(|e| ProxyError::Internal(e.to_string()))?;
+        let mut bundle = provision::Bundle::new(config);
+        let mut included = vec!["config"];
+        let mut skipped = Vec::new();
+
+        let allowlist = state.allowlist.lock().clone();
+        match allowlist {
+            Ok(allowlist::Allowlist { version: Some(version), dir: Some(dir), unsigned: false, .. }) => {
+                match allowlist::signed_files(&dir) {
+                    Ok((bytes, sig)) => {
+                        bundle.allowlist = Some(provision::SignedAllowlist::new(version, &bytes, sig));
+                        included.push("allowlist");
+                    }
+                    Err(reason) => skipped.push(provision::Skipped { item: "allowlist", reason }),
+                }
+            }
+            Ok(_) => skipped.push(provision::Skipped {
+                item: "allowlist",
+                reason: "This build has no signed allowlist".into(),
+            }),
+            Err(reason) => skipped.push(provision::Skipped { item: "allowlist", reason }),
+        }
+
+        let certs = app_data_dir(&app)?.join("certs");
+        match &passphrase {
+            _ if !cert::exists(&certs) => {
+                skipped.push(provision::Skipped { item: "ca", reason: "No CA has been generated yet".into() });
+            }
+            None => skipped.push(provision::Skipped {
+                item: "ca",
+                reason: "No passphrase given; the CA is only exported with its key encrypted".into(),
+            }),
+            Some(passphrase) => {
+                let cert_path = certs.join(cert::CA_CERT_FILE);
+                let cert_pem = fs::read_to_string(&cert_path)
+                    .map_err(|e| ProxyError::Cert(format!("Failed to read {}: {}", cert_path.display(), e)))?;
+                let key_pem = cert::ca_key_pem(&certs).map_err(ProxyError::Cert)?;
+                let key = provision::encrypt_key(&key_pem, passphrase).map_err(ProxyError::Internal)?;
+                bundle.ca = Some(provision::Ca { cert_pem, key });
+                included.push("ca");
+            }
+        }
+
+        provision::write(&path, &bundle).map_err(ProxyError::Internal)?;
+        let path = path.display().to_string();
+        log_action(&state, &format!("State exported to {} ({})", path, included.join(", ")));
+        audit(&state, audit::Event::StateExported { path: path.clone(), included: included.clone() });
+        Ok(provision::ExportResult { path, included, skipped })
+    })
+    .await?
+}
+
+/// Apply a state_export bundle: everything in it is checked (bundle and
+/// config schema versions, allowlist signature, CA key passphrase) before
+/// anything is written. An allowlist no newer than the one in use and a CA
+/// while another one is trusted by the OS are skipped; the login autorun
+/// stays this machine's. Refused while the proxy runs.
+#[tauri::command]
+pub async fn state_import(
+    path: String,
+    passphrase: Option<String>,
+    app: AppHandle,
+) -> Result<provision::ImportResult, ProxyError> {
+    let path = PathBuf::from(path);
+    exclusive(app, "State import", move |app, state| {
+        if current_pid(&state).is_some() || service_state(&state).is_some() {
+            return Err(ProxyError::InvalidState(
+                "The proxy is running; restore it before importing a state bundle".into(),
+            ));
+        }
+        let bundle = provision::read(&path).map_err(ProxyError::InvalidArgument)?;
+        let schema = bundle.config.get("schemaVersion").and_then(serde_json::Value::as_u64).unwrap_or_default();
+        if schema > u64::from(config::SCHEMA_VERSION) {
+            return Err(ProxyError::InvalidArgument(format!(
+                "The bundle's config was written by a newer version of the app (schema {}, this one understands {})",
+                schema,
+                config::SCHEMA_VERSION
+            )));
+        }
+        let imported_config: config::Config = serde_json::from_value(bundle.config)
+            .map_err(|e| ProxyError::InvalidArgument(format!("The bundle's config is not valid: {}", e)))?;
+        let imported_config = config::Config {
+            autorun_proxy: state.config.lock().autorun_proxy,
+            ..normalize_config(imported_config)?
+        };
+        let mut imported = vec!["config"];
+        let mut skipped = Vec::new();
+
+        let allowlist = match bundle.allowlist {
+            None => {
+                skipped.push(provision::Skipped { item: "allowlist", reason: "Not in the bundle".into() });
+                None
+            }
+            Some(_) if allowlist::UNSIGNED => {
+                let reason = "This build doesn't check allowlist signatures, so it only uses its own list".into();
+                skipped.push(provision::Skipped { item: "allowlist", reason });
+                None
+            }
+            Some(list) => {
+                let bytes = list.bytes().map_err(ProxyError::InvalidArgument)?;
+                let version = allowlist::verify_signed(&bytes, &list.signature)
+                    .map_err(|e| ProxyError::InvalidArgument(format!("The bundle's allowlist was rejected: {}", e)))?;
+                let current = state.allowlist.lock().as_ref().ok().and_then(|l| l.version);
+                match current.filter(|current| version <= *current) {
+                    Some(current) => {
+                        let reason = format!("Version {} is not newer than the one in use ({})", version, current);
+                        skipped.push(provision::Skipped { item: "allowlist", reason });
+                        None
+                    }
+                    None => Some((bytes, list.signature)),
+                }
+            }
+        };
+
+        let data_dir = app_data_dir(&app)?;
+        let certs = data_dir.join("certs");
+        let ca = match bundle.ca {
+            None => {
+                skipped.push(provision::Skipped { item: "ca", reason: "Not in the bundle".into() });
+                None
+            }
+            Some(_) if passphrase.as_deref().unwrap_or_default().is_empty() => {
+                skipped.push(provision::Skipped { item: "ca", reason: "No passphrase given for its key".into() });
+                None
+            }
+            Some(ca) => {
+                let key_pem = provision::decrypt_key(&ca.key, passphrase.as_deref().unwrap_or_default())
+                    .map_err(ProxyError::InvalidArgument)?;
+                let fingerprint = cert::pem_fingerprint(&ca.cert_pem);
+                let current = cert::ca_fingerprint(&certs);
+                if fingerprint.is_some() && fingerprint == current {
+                    skipped.push(provision::Skipped { item: "ca", reason: "Already in use".into() });
+                    None
+                } else if cert::is_installed(&certs) {
+                    // Replacing it would leave the old CA trusted with nothing
+                    // left to remove it by
+                    let reason = "The current CA is trusted by the system; remove it with cert_uninstall first".into();
+                    skipped.push(provision::Skipped { item: "ca", reason });
+                    None
+                } else {
+                    Some((ca.cert_pem, key_pem, fingerprint))
+                }
+            }
+        };
+
+        let mut ca_fingerprint = None;
+        if let Some((cert_pem, key_pem, fingerprint)) = ca {
+            let domains = imported_config.intercepted_domains();
+            let domains: Vec<&str> = domains.iter().map(String::as_str).collect();
+            cert::import(&certs, &cert_pem, &key_pem, &domains).map_err(ProxyError::Cert)?;
+            ca_fingerprint = fingerprint;
+            imported.push("ca");
+        }
+        if let Some((bytes, sig)) = allowlist {
+            allowlist::save_imported(&data_dir, &bytes, &sig)
+                .map_err(|e| ProxyError::Internal(format!("Saving the imported allowlist failed: {}", e)))?;
+            *state.allowlist.lock() = load_allowlist(&app);
+            imported.push("allowlist");
+        }
+        store_config(&state, &imported_config)?;
+        state.inner.lock().configure(&imported_config);
+        messages::set_locale(imported_config.locale.as_deref().unwrap_or(messages::DEFAULT_LOCALE));
+        forget_status(&state);
+
+        let cert_install_needed = ca_fingerprint.is_some() && !cert::is_installed(&certs);
+        let path = path.display().to_string();
+        log_action(&state, &format!("State imported from {} ({})", path, imported.join(", ")));
+        audit(&state, audit::Event::StateImported { path, imported: imported.clone(), ca_fingerprint });
+        Ok(provision::ImportResult { imported, skipped, cert_install_needed })
+    })
+    .await?
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutostartStatus {
+    /// Registered to start at login.
+    enabled: bool,
+    autorun_proxy: bool,
+    /// What autorun did at this launch, in case its event came before the
+    /// UI was listening.
+    last_autorun: Option<AutorunOutcome>,
+}
+
+#[tauri::command]
+pub fn autostart_get(state: State<'_, ProxyState>) -> Result<AutostartStatus, ProxyError> {
+    Ok(AutostartStatus {
+        enabled: autostart::is_enabled().map_err(ProxyError::Internal)?,
+        autorun_proxy: state.config.lock().autorun_proxy,
+        last_autorun: state.autorun.lock().clone(),
+    })
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocaleResult {
+    /// What errors are worded in now; English for a locale without messages.
+    locale: String,
+    available: Vec<String>,
+}
+
+/// Word the errors' userMessage in `locale` (e.g. `de` or `de-DE`) from now
+/// on, and remember it across restarts.
+#[tauri::command]
+pub async fn set_locale(locale: String, app: AppHandle) -> Result<LocaleResult, ProxyError> {
+    exclusive(app, "Locale change", move |_, state| {
+        let locale = messages::set_locale(&locale);
+        let config = config::Config { locale: Some(locale.clone()), ..state.config.lock().clone() };
+        store_config(&state, &config)?;
+        Ok(LocaleResult { locale, available: messages::locales() })
+    })
+    .await?
+}
+
+/// Register the app with the OS login items (or remove it), and choose
+/// whether such a start runs the proxy with the stored settings.
+#[tauri::command]
+pub async fn autostart_set(enabled: bool, autorun_proxy: bool, app: AppHandle) -> Result<AutostartStatus, ProxyError> {
+    exclusive(app, "Autostart change", move |_, state| {
+        autostart::set(enabled).map_err(ProxyError::Internal)?;
+        let config = config::Config { autorun_proxy, ..state.config.lock().clone() };
+        store_config(&state, &config)?;
+        log_action(
+            &state,
+            &format!(
+                "Start at login {}, proxy autorun {}",
+                if enabled { "on" } else { "off" },
+                if autorun_proxy { "on" } else { "off" }
+            ),
+        );
+        autostart_get(state)
+    })
+    .await?
+}
+
+#[tauri::command]
+pub fn profiles_list(state: State<'_, ProxyState>) -> Vec<config::Profile> {
+    state.config.lock().profiles.clone()
+}
+
+/// Add a profile, or replace the one with the same name (ignoring case).
+/// Returns all profiles.
+#[tauri::command]
+pub async fn profiles_save(
+    name: String,
+    gateway_url: String,
+    domains: Option<Vec<String>>,
+    notes: Option<String>,
+    app: AppHandle,
+) -> Result<Vec<config::Profile>, ProxyError> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(ProxyError::InvalidArgument("The profile name is empty".into()));
+    }
+    let domains = match domains.unwrap_or_default() {
+        domains if domains.is_empty() => domains,
+        domains => hosts::normalize_domains(&domains)?,
+    };
+    let profile = config::Profile {
+        gateway_url: gateway::normalize(&gateway_url).map_err(ProxyError::GatewayInvalid)?,
+        domains,
+        notes: notes.unwrap_or_default(),
+        name,
+    };
+    exclusive(app, "Profile save", move |_, state| {
+        let mut config = state.config.lock().clone();
+        match config.profiles.iter_mut().find(|p| p.name.eq_ignore_ascii_case(&profile.name)) {
+            Some(existing) => *existing = profile.clone(),
+            None => config.profiles.push(profile.clone()),
+        }
+        store_config(&state, &config)?;
+        log_action(&state, &format!("Profile {:?} saved ({})", profile.name, profile.gateway_url));
+        Ok(config.profiles)
+    })
+    .await?
+}
+
+/// Remove a profile; refused while the proxy runs with it. Returns the
+/// remaining profiles.
+#[tauri::command]
+pub async fn profiles_delete(name: String, app: AppHandle) -> Result<Vec<config::Profile>, ProxyError> {
+    exclusive(app, "Profile delete", move |_, state| {
+        let mut config = state.config.lock().clone();
+        let Some(profile) = config.profile(&name).cloned() else {
+            return Err(ProxyError::InvalidArgument(format!("No profile named {:?}", name)));
+        };
+        {
+            let inner = state.inner.lock();
+            if inner.running() && inner.profile.as_deref().is_some_and(|p| p.eq_ignore_ascii_case(&profile.name)) {
+                return Err(ProxyError::InvalidState(format!(
+                    "The proxy is running with profile {:?}; stop it first",
+                    profile.name
+                )));
+            }
+        }
+        config.profiles.retain(|p| !p.name.eq_ignore_ascii_case(&profile.name));
+        store_config(&state, &config)?;
+        log_action(&state, &format!("Profile {:?} deleted", profile.name));
+        Ok(config.profiles)
+    })
+    .await?
+}
+
+/// Flush the OS DNS cache on demand; proxy_run and the restores already do
+/// this after every hosts change.
+#[tauri::command]
+pub async fn dns_flush(app: AppHandle) -> Result<DnsFlushResult, ProxyError> {
+    blocking(app, "DNS flush", move |_, state| flush_dns_now(state)).await
+}
+
+fn flush_dns_now(state: State<'_, ProxyState>) -> DnsFlushResult {
+    match flush_dns(&state) {
+        Ok(()) => DnsFlushResult { ok: true, message: "DNS cache flushed".into() },
+        Err(e) => DnsFlushResult { ok: false, message: e },
+    }
+}
+
+/// Which first-run step is due; every step before it is verified again.
+#[tauri::command]
+pub async fn setup_state(app: AppHandle) -> Result<setup::SetupState, ProxyError> {
+    blocking(app, "Setup check", |app, state| check_setup(&app, &state, false)).await?
+}
+
+/// Take the due first-run step, then report as setup_state does.
+#[tauri::command]
+pub async fn setup_next(app: AppHandle) -> Result<setup::SetupState, ProxyError> {
+    exclusive(app, "Setup", |app, state| check_setup(&app, &state, true)).await?
+}
+
+#[tauri::command]
+pub async fn proxy_install_cert(app: AppHandle) -> Result<CertChangeResult, ProxyError> {
+    exclusive(app, "Certificate install", install_cert).await?
+}
+
+#[tauri::command]
+pub async fn proxy_uninstall_cert(app: AppHandle) -> Result<CertChangeResult, ProxyError> {
+    exclusive(app, "Certificate removal", uninstall_cert).await?
+}
+
+/// Rotate the per-install CA. The old CA is removed from the trust store first
+/// so a stale root doesn't stay trusted; the new one has to be installed again.
+/// The server certificate is issued for `domains` (default: the current set).
+#[tauri::command]
+pub async fn cert_regenerate(domains: Option<Vec<String>>, app: AppHandle) -> Result<CertRegenerateResult, ProxyError> {
+    exclusive(app, "Certificate regeneration", move |app, state| regenerate_cert(domains, app, state)).await?
+}
+
+/// Issue a new CA and server certificate for the current domains, trust the
+/// new CA in place of the old one, and restart a running proxy so it serves
+/// the new certificate. Hosts stay as they are throughout.
+#[tauri::command]
+pub async fn cert_renew(app: AppHandle) -> Result<CertRenewResult, ProxyError> {
+    exclusive(app, "Certificate renewal", renew_cert).await?
+}
+
+fn renew_cert(app: AppHandle, state: State<'_, ProxyState>) -> Result<CertRenewResult, ProxyError> {
+    // The service's proxy would keep serving the old certificate
+    check_not_service(&state)?;
+    refresh_child(&app, &state);
+    let domains = state.inner.lock().domains.clone();
+    let dir = certs_path(&app)?;
+    if cert::is_installed(&dir) {
+        cert::uninstall(&dir).map_err(ProxyError::Cert)?;
+        audit(&state, audit::Event::CertRemoved { fingerprint: cert::ca_fingerprint(&dir) });
+    }
+    let names: Vec<&str> = domains.iter().map(String::as_str).collect();
+    cert::generate(&dir, &names).map_err(ProxyError::Cert)?;
+    let change = cert::install(&dir).map_err(ProxyError::Cert)?;
+    forget_status(&state);
+    if !cert::is_installed(&dir) {
+        return Err(ProxyError::Cert(
+            "The certificate was renewed but the new CA is not trusted; install it before starting the proxy".into(),
+        ));
+    }
+    audit(&state, audit::Event::CertInstalled { fingerprint: cert::ca_fingerprint(&dir) });
+    log_action(&state, "Certificate renewed");
+
+    let (running, gateway_url, replay, profile) = {
+        let inner = state.inner.lock();
+        let gateway_url = inner.gateway_url.clone().unwrap_or_default();
+        (inner.running(), gateway_url, inner.replay.clone(), inner.profile.clone())
+    };
+    let restart = if running {
+        let restart =
+            restart_proxy(&app, &state, &gateway_url, replay, DEFAULT_READY_TIMEOUT, "certificate renewed")?;
+        // Same gateway, so it still is the profile's
+        state.inner.lock().profile = profile;
+        Some(restart)
+    } else {
+        None
+    };
+
+    Ok(CertRenewResult {
+        ok: true,
+        message: if restart.is_some() { "Certificate renewed and proxy restarted" } else { "Certificate renewed" }
+            .into(),
+        cert_installed: true,
+        elevation_required: change.elevation_required,
+        elevation_obtained: change.elevation_obtained,
+        ca_expires_at: cert::ca_not_after(&dir),
+        cert_expires_at: cert::not_after(&dir),
+        restart,
+    })
+}
+
+/// The last `limit` (default DEFAULT_HISTORY_ENTRIES) lifecycle transitions,
+/// oldest first.
+#[tauri::command]
+pub fn proxy_history(limit: Option<usize>, state: State<'_, ProxyState>) -> Vec<HistoryEntry> {
+    let inner = state.inner.lock();
+    let limit = limit.unwrap_or(DEFAULT_HISTORY_ENTRIES).min(inner.history.len());
+    inner.history.iter().skip(inner.history.len() - limit).cloned().collect()
+}
+
+/// Arm (or re-arm) the drill timer for the running proxy.
+#[tauri::command]
+pub fn proxy_schedule_stop(
+    minutes: u64,
+    app: AppHandle,
+    state: State<'_, ProxyState>,
+) -> Result<ScheduleResult, ProxyError> {
+    if minutes == 0 {
+        return Err(ProxyError::InvalidArgument("The drill duration must be at least one minute".into()));
+    }
+    if !state.inner.lock().running() {
+        return Err(ProxyError::InvalidState("The proxy is not running".into()));
+    }
+    let stop_at = arm_schedule(&app, &state, minutes);
+    Ok(ScheduleResult {
+        ok: true,
+        message: format!("Proxy will be restored in {} minutes", minutes),
+        stop_at: Some(stop_at),
+    })
+}
+
+#[tauri::command]
+pub fn proxy_cancel_schedule(state: State<'_, ProxyState>) -> ScheduleResult {
+    let cancelled = cancel_schedule(&state);
+    if cancelled {
+        log_action(&state, "Drill timer cancelled");
+    }
+    ScheduleResult {
+        ok: true,
+        message: if cancelled { "Drill timer cancelled" } else { "No drill timer was armed" }.into(),
+        stop_at: None,
+    }
+}
+
+/// The last gateway seen working: its health route answered and a request
+/// was forwarded to it successfully. Never used in place of a gateway the
+/// caller gives; proxy_run's dry run reports gatewayChanged against it.
+#[tauri::command]
+pub fn gateway_last_good(state: State<'_, ProxyState>) -> Option<lastgood::LastGood> {
+    state.last_good.lock().clone()
+}
+
+/// The last `lines` lines the proxy wrote, oldest first.
+#[tauri::command]
+pub fn proxy_logs(lines: Option<usize>, state: State<'_, ProxyState>) -> Vec<logs::LogLine> {
+    state.logs.lock().tail(lines.unwrap_or(DEFAULT_LOG_LINES))
+}
+
+/// Traffic through the current (or last) child since it started, from its
+/// heartbeats. Kept here rather than in the UI so a reload doesn't lose it.
+#[tauri::command]
+pub fn proxy_metrics(state: State<'_, ProxyState>) -> heartbeat::Metrics {
+    let resources = state.usage.lock().clone();
+    state.health.lock().metrics(now_millis(), resources)
+}
+
+/// Whether requests to the intercepted domain reached the proxy, sampled
+/// every 30 seconds through the current (or last) drill.
+#[tauri::command]
+pub fn proxy_uptime_report(state: State<'_, ProxyState>) -> uptime::Report {
+    state.uptime.lock().report()
+}
+
+/// Write the requests of the current (or last) drill to `path` as a HAR
+/// file, a JSON summary or CSV. Works while the proxy runs and after it
+/// stopped, until the next proxy_run.
+#[tauri::command]
+pub async fn session_export(
+    path: String,
+    format: traffic::Format,
+    app: AppHandle,
+) -> Result<traffic::ExportResult, ProxyError> {
+    let path = PathBuf::from(path);
+    if !path.is_absolute() {
+        return Err(ProxyError::InvalidArgument(format!("Export path {} is not absolute", path.display())));
+    }
+    blocking(app, "Session export", move |_, state| {
+        let session = {
+            let inner = state.inner.lock();
+            traffic::SessionInfo {
+                gateway_url: inner.gateway_url.clone().unwrap_or_default(),
+                started_at: state.traffic.lock().started_at(),
+                stopped_at: inner.last_exit.as_ref().filter(|_| !inner.running()).map(|e| e.at),
+                hostname: sysinfo::System::host_name(),
+                uptime: state.uptime.lock().report(),
+            }
+        };
+        let traffic = state.traffic.lock();
+        traffic::export(&traffic, &session, format, &path).map_err(ProxyError::Internal)
+    })
+    .await?
+}
+
+/// Where the persistent proxy log lives, so the UI can open the folder.
+/// The last `lines` audit records (default 200), oldest first.
+#[tauri::command]
+pub fn audit_tail(lines: Option<usize>, state: State<'_, ProxyState>) -> Result<Vec<serde_json::Value>, ProxyError> {
+    state
+        .audit
+        .lock()
+        .tail(lines.unwrap_or(DEFAULT_LOG_LINES))
+        .map_err(|e| ProxyError::Internal(format!("Reading the audit log failed: {}", e)))
+}
+
+/// Every version a support ticket needs; also in the diagnostics bundle and
+/// at each app start in the audit log.
+#[tauri::command]
+pub async fn versions(app: AppHandle) -> Result<versions::Versions, ProxyError> {
+    blocking(app, "Versions", |app, _| app_versions(&app)).await
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateApplyResult {
+    ok: bool,
+    message: String,
+    version: String,
+    sha256: String,
+    /// The version that ran before, update or shipped.
+    previous_version: Option<String>,
+    /// A running local-proxy.js keeps the old script until proxy_restart.
+    restart_needed: bool,
+}
+
+/// The manifest URL and upstream proxy the update commands use.
+fn update_source(state: &ProxyState) -> Result<(String, String), ProxyError> {
+    let config = state.config.lock();
+    let url = config
+        .update_manifest_url
+        .clone()
+        .ok_or_else(|| ProxyError::InvalidState("No update manifest URL is configured".into()))?;
+    Ok((url, config.upstream_proxy.clone().unwrap_or_default()))
+}
+
+/// The version of the script proxy_run would launch.
+fn current_script_version(app: &AppHandle) -> Option<String> {
+    let versions = app_versions(app);
+    versions.proxy_script_update.or(versions.proxy_script)
+}
+
+/// Whether the update manifest offers a newer local-proxy.js.
+#[tauri::command]
+pub async fn update_check(app: AppHandle) -> Result<update::UpdateCheck, ProxyError> {
+    blocking(app, "Update check", |app, state| {
+        let (url, upstream) = update_source(&state)?;
+        update::check(&url, &upstream, current_script_version(&app))
+    })
+    .await?
+}
+
+/// Download, verify and switch to the script the update manifest names.
+/// Anything short of a verified download leaves the current script in place.
+#[tauri::command]
+pub async fn update_apply(app: AppHandle) -> Result<UpdateApplyResult, ProxyError> {
+    exclusive(app, "Update", |app, state| {
+        let (url, upstream) = update_source(&state)?;
+        let previous_version = current_script_version(&app);
+        let manifest = match update::apply(&update_dir(&app)?, &url, &upstream) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                log_action(&state, &format!("Proxy script update failed: {}", e));
+                return Err(e);
+            }
+        };
+        log_action(&state, &format!("Proxy script updated to {}", manifest.version));
+        audit(
+            &state,
+            audit::Event::ProxyScriptUpdated { version: manifest.version.clone(), sha256: manifest.sha256.clone() },
+        );
+        let restart_needed = current_pid(&state).is_some() && state.config.lock().node_proxy;
+        Ok(UpdateApplyResult {
+            ok: true,
+            message: format!("Proxy script updated to {}", manifest.version),
+            version: manifest.version,
+            sha256: manifest.sha256,
+            previous_version,
+            restart_needed,
+        })
+    })
+    .await?
+}
+
+#[tauri::command]
+pub fn audit_export(path: String, state: State<'_, ProxyState>) -> Result<AuditExportResult, ProxyError> {
+    // Under the lock, so the copy can't end in a half-written record
+    let bytes = state
+        .audit
+        .lock()
+        .export(Path::new(&path))
+        .map_err(|e| ProxyError::Internal(format!("Exporting the audit log to {} failed: {}", path, e)))?;
+    Ok(AuditExportResult { ok: true, message: "Audit log exported".into(), path, bytes })
+}
+
+#[tauri::command]
+pub fn get_log_path(state: State<'_, ProxyState>) -> Option<String> {
+    state
+        .file_log
+        .lock()
+        .path()
+        .map(|p| p.to_string_lossy().to_string())
+}
+
+/// Served from cached state: the hosts file is only read again when it
+/// changed on disk, the slow checks only every STATUS_SLOW_REFRESH. The
+/// status watch pushes the same struct as `proxy://status`.
+#[tauri::command]
+pub async fn proxy_status(app: AppHandle) -> Result<ProxyStatus, ProxyError> {
+    blocking(app, "Status", status).await?
+}
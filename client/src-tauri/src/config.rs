@@ -0,0 +1,244 @@
+//! Settings remembered across restarts, stored as JSON in the app config dir.
+
+use crate::error::ProxyError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+const FILE_NAME: &str = "config.json";
+/// Bumped whenever the stored shape changes; `load` is where older versions
+/// get migrated.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Config {
+    pub schema_version: u32,
+    /// Used by proxy_run when it is given no gateway; None means passthrough.
+    pub gateway_url: Option<String>,
+    pub domains: Vec<String>,
+    /// Domains forwarded to a gateway of their own instead of gateway_url.
+    /// They are intercepted along with `domains`.
+    pub routes: Vec<Route>,
+    /// Request paths sent to the real origin instead of a gateway, e.g.
+    /// `/auth*` so logins keep working; see normalize_bypass_paths.
+    pub bypass_paths: Vec<String>,
+    pub auto_restart: bool,
+    pub port: u16,
+    /// Named gateways; managed by the profile commands, not config_set.
+    pub profiles: Vec<Profile>,
+    /// Environment for the proxy child beyond the built-in whitelist.
+    pub proxy_env: ProxyEnv,
+    /// HTTP proxy the gateway is reached through; None connects directly.
+    pub upstream_proxy: Option<String>,
+    /// PEM of the CA the gateway's certificate must chain to, instead of
+    /// the system roots.
+    pub gateway_ca_pem: Option<String>,
+    /// Base64 SHA-256 of the gateway's SubjectPublicKeyInfo.
+    pub gateway_spki_pin: Option<String>,
+    /// Run node + local-proxy.js instead of the built-in proxy.
+    pub node_proxy: bool,
+    /// proxy_run with the stored settings when started at login; managed by
+    /// autostart_set, not config_set.
+    pub autorun_proxy: bool,
+    /// Remove our hosts block when the watchdog gives up on a crash loop.
+    pub crash_loop_remove_hosts: bool,
+    /// https URL of the signed manifest update_check and update_apply read.
+    pub update_manifest_url: Option<String>,
+    /// Language of the errors' userMessage, e.g. `de`; None is English.
+    /// Managed by set_locale, not config_set.
+    pub locale: Option<String>,
+    /// Path the proxy answers itself with its health; see healthcheck.
+    pub health_route: String,
+    /// How often the running proxy's health route is checked.
+    pub health_interval_secs: u64,
+    /// proxy_run refuses, rather than warns, when another agent intercepts
+    /// the domains' real origin; see agents.
+    pub refuse_on_agent_interception: bool,
+    /// `proxy://resource-warning` when the proxy's resident memory crosses
+    /// this many MiB; 0 is off.
+    pub memory_warn_mb: u64,
+    /// Past this many MiB the watchdog, when armed, restarts the proxy; 0 is
+    /// off.
+    pub memory_restart_mb: u64,
+    /// A hosts file over this many MiB is only rewritten with
+    /// allowLargeHosts; 0 is no limit.
+    pub hosts_max_mb: u64,
+}
+
+/// E.g. HTTPS_PROXY, for when the gateway is only reachable through a
+/// corporate proxy.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ProxyEnv {
+    /// Variables passed on from the app's own environment.
+    pub pass_through: Vec<String>,
+    /// Variables set to a fixed value; these win over everything else.
+    pub set: BTreeMap<String, String>,
+}
+
+/// One domain and where the proxy forwards it. Passed to the proxy as the
+/// JSON of a `Vec<Route>` in `--routes`.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Route {
+    pub domain: String,
+    /// Empty in proxy_status for a passthrough domain; never empty in a route.
+    pub gateway_url: String,
+}
+
+/// A gateway environment proxy_run can be pointed at by name.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    /// Unique, ignoring case.
+    pub name: String,
+    pub gateway_url: String,
+    /// Empty: the configured domains.
+    #[serde(default)]
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub notes: String,
+}
+
+impl Config {
+    /// `domains` followed by the route domains not already among them.
+    pub fn intercepted_domains(&self) -> Vec<String> {
+        let mut domains = self.domains.clone();
+        for route in &self.routes {
+            if !domains.iter().any(|d| d.eq_ignore_ascii_case(&route.domain)) {
+                domains.push(route.domain.clone());
+            }
+        }
+        domains
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.name.eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// The gateway pin. Only a hand-edited file can hold an invalid one;
+    /// callers then refuse rather than run unpinned.
+    pub fn gateway_pin(&self) -> Result<crate::gateway::Pin, String> {
+        crate::gateway::Pin::new(self.gateway_ca_pem.as_deref(), self.gateway_spki_pin.as_deref())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            schema_version: SCHEMA_VERSION,
+            gateway_url: None,
+            domains: crate::default_domains(),
+            routes: Vec::new(),
+            bypass_paths: Vec::new(),
+            auto_restart: false,
+            port: crate::DEFAULT_PROXY_PORT,
+            profiles: Vec::new(),
+            proxy_env: ProxyEnv::default(),
+            upstream_proxy: None,
+            gateway_ca_pem: None,
+            gateway_spki_pin: None,
+            node_proxy: false,
+            autorun_proxy: false,
+            crash_loop_remove_hosts: true,
+            update_manifest_url: None,
+            locale: None,
+            health_route: crate::healthcheck::DEFAULT_ROUTE.to_string(),
+            health_interval_secs: crate::healthcheck::DEFAULT_INTERVAL_SECS,
+            refuse_on_agent_interception: false,
+            memory_warn_mb: 1024,
+            memory_restart_mb: 0,
+            hosts_max_mb: 8,
+        }
+    }
+}
+
+/// Routes with domain and gateway normalized. Every route needs a gateway,
+/// and a domain can have only one.
+pub fn normalize_routes(routes: &[Route]) -> Result<Vec<Route>, ProxyError> {
+    let mut out: Vec<Route> = Vec::new();
+    for route in routes {
+        let domain = crate::hosts::normalize_domains(std::slice::from_ref(&route.domain))?.remove(0);
+        let gateway_url = crate::gateway::normalize(&route.gateway_url).map_err(ProxyError::GatewayInvalid)?;
+        if gateway_url.is_empty() {
+            return Err(ProxyError::GatewayInvalid(format!("The route for {} has no gateway", domain)));
+        }
+        if out.iter().any(|r| r.domain == domain) {
+            return Err(ProxyError::InvalidArgument(format!("{} has more than one route", domain)));
+        }
+        out.push(Route { domain, gateway_url });
+    }
+    Ok(out)
+}
+
+/// Bypass paths trimmed and deduplicated. Each starts with `/` and matches
+/// that path exactly, or every path it prefixes when it ends in `*`, the only
+/// wildcard. The query string is never part of the match.
+pub fn normalize_bypass_paths(paths: &[String]) -> Result<Vec<String>, ProxyError> {
+    let mut out: Vec<String> = Vec::new();
+    for raw in paths {
+        let path = raw.trim();
+        let body = path.strip_suffix('*').unwrap_or(path);
+        let valid = path.starts_with('/')
+            && !body.contains('*')
+            && !body.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, ',' | '?' | '#'));
+        if !valid {
+            return Err(ProxyError::InvalidArgument(format!(
+                "Invalid bypass path {:?}: it must start with / and may only end in *",
+                raw
+            )));
+        }
+        if !out.iter().any(|p| p == path) {
+            out.push(path.to_string());
+        }
+    }
+    Ok(out)
+}
+
+/// Whether `path` (without its query) matches one of the bypass paths.
+pub fn bypassed(bypass_paths: &[String], path: &str) -> bool {
+    bypass_paths.iter().any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    })
+}
+
+pub fn path(app_config_dir: &Path) -> PathBuf {
+    app_config_dir.join(FILE_NAME)
+}
+
+/// The stored config; the defaults if there is none yet. Fields missing from
+/// the file take their default.
+pub fn load(path: &Path) -> Result<Config, String> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(format!("Reading {} failed: {}", path.display(), e)),
+    };
+    let config: Config =
+        serde_json::from_slice(&bytes).map_err(|e| format!("{} is not a valid config: {}", path.display(), e))?;
+    if config.schema_version > SCHEMA_VERSION {
+        return Err(format!(
+            "{} was written by a newer version of the app (schema {}, this one understands {})",
+            path.display(),
+            config.schema_version,
+            SCHEMA_VERSION
+        ));
+    }
+    Ok(Config { schema_version: SCHEMA_VERSION, ..config })
+}
+
+/// Written to a temporary file first so a crash mid-write can't leave a
+/// truncated config behind.
+pub fn save(path: &Path, config: &Config) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let json = serde_json::to_vec_pretty(config).map_err(std::io::Error::other)?;
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, json)?;
+    fs::rename(&temp, path)
+}
@@ -0,0 +1,138 @@
+//! The support bundle diagnostics_export writes: one zip with what a ticket
+//! needs, secrets and the user's own hosts lines redacted, capped in size.
+
+use crate::config::Config;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use url::Url;
+use zip::write::SimpleFileOptions;
+
+/// Uncompressed, across all files; what doesn't fit is cut or left out.
+pub const MAX_BUNDLE_BYTES: usize = 8 * 1024 * 1024;
+/// Logs are cut to their last this many bytes.
+pub const LOG_TAIL_BYTES: u64 = 2 * 1024 * 1024;
+const REDACTED: &str = "<redacted>";
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IncludedFile {
+    pub name: String,
+    pub bytes: usize,
+    /// Only the end of the file made it in.
+    pub truncated: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    pub path: String,
+    pub files: Vec<IncludedFile>,
+    /// `name: reason` for what couldn't be collected or didn't fit.
+    pub omitted: Vec<String>,
+}
+
+/// Files collected in order of importance; the size cap cuts from the end.
+pub struct Bundle {
+    files: Vec<(String, Vec<u8>, bool)>,
+    omitted: Vec<String>,
+    size: usize,
+}
+
+impl Bundle {
+    pub fn new() -> Self {
+        Bundle { files: Vec::new(), omitted: Vec::new(), size: 0 }
+    }
+
+    /// Add `content`, keeping only its tail if the cap is near.
+    pub fn add(&mut self, name: &str, mut content: Vec<u8>, mut truncated: bool) {
+        let room = MAX_BUNDLE_BYTES - self.size;
+        if room == 0 {
+            self.omit(name, "bundle size cap reached");
+            return;
+        }
+        if content.len() > room {
+            content.drain(..content.len() - room);
+            truncated = true;
+        }
+        self.size += content.len();
+        self.files.push((name.to_string(), content, truncated));
+    }
+
+    pub fn add_json(&mut self, name: &str, value: &impl Serialize) {
+        match serde_json::to_vec_pretty(value) {
+            Ok(json) => self.add(name, json, false),
+            Err(e) => self.omit(name, &e.to_string()),
+        }
+    }
+
+    /// Add the last LOG_TAIL_BYTES of the file at `path`.
+    pub fn add_tail(&mut self, name: &str, path: Option<&Path>) {
+        let Some(path) = path else {
+            return self.omit(name, "not open");
+        };
+        match tail(path, LOG_TAIL_BYTES) {
+            Ok((content, truncated)) => self.add(name, content, truncated),
+            Err(e) => self.omit(name, &e.to_string()),
+        }
+    }
+
+    pub fn omit(&mut self, name: &str, reason: &str) {
+        self.omitted.push(format!("{}: {}", name, reason));
+    }
+
+    pub fn write(self, path: &Path) -> Result<Summary, String> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| format!("Create {}: {}", dir.display(), e))?;
+        }
+        let file = File::create(path).map_err(|e| format!("Create {}: {}", path.display(), e))?;
+        let mut zip = zip::ZipWriter::new(file);
+        let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        let mut files = Vec::new();
+        for (name, content, truncated) in self.files {
+            zip.start_file(name.as_str(), options).map_err(|e| format!("{}: {}", name, e))?;
+            zip.write_all(&content).map_err(|e| format!("{}: {}", name, e))?;
+            files.push(IncludedFile { name, bytes: content.len(), truncated });
+        }
+        zip.finish().map_err(|e| format!("Write {}: {}", path.display(), e))?;
+        Ok(Summary { path: path.display().to_string(), files, omitted: self.omitted })
+    }
+}
+
+/// The last `max` bytes of the file at `path`, and whether that is less
+/// than all of it.
+fn tail(path: &Path, max: u64) -> std::io::Result<(Vec<u8>, bool)> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let start = len.saturating_sub(max);
+    file.seek(SeekFrom::Start(start))?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    Ok((content, start > 0))
+}
+
+/// The config with passwords in URLs and the values of extra child
+/// environment variables (tokens, proxy credentials) replaced.
+pub fn redact_config(config: &Config) -> Config {
+    let mut config = config.clone();
+    config.gateway_url = config.gateway_url.as_deref().map(redact_url);
+    config.upstream_proxy = config.upstream_proxy.as_deref().map(redact_url);
+    for profile in &mut config.profiles {
+        profile.gateway_url = redact_url(&profile.gateway_url);
+    }
+    for value in config.proxy_env.set.values_mut() {
+        *value = REDACTED.to_string();
+    }
+    config
+}
+
+fn redact_url(raw: &str) -> String {
+    match Url::parse(raw) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some(REDACTED));
+            url.to_string()
+        }
+        _ => raw.to_string(),
+    }
+}
@@ -0,0 +1,136 @@
+//! Flushing the OS resolver cache and checking what a name resolves to.
+//! Without a flush the old answer for the domain can stay cached for
+//! minutes after the hosts file changes.
+
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs, UdpSocket};
+use std::process::Command;
+use std::time::Duration;
+
+/// Asked in turn by `resolve_public`, as local-proxy.js does.
+const PUBLIC_RESOLVERS: [&str; 2] = ["8.8.8.8:53", "1.1.1.1:53"];
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// `ipconfig /flushdns`.
+#[cfg(target_os = "windows")]
+pub fn flush() -> Result<(), String> {
+    run("ipconfig", &["/flushdns"])
+}
+
+/// Clear the directory service cache and make mDNSResponder drop its own.
+#[cfg(target_os = "macos")]
+pub fn flush() -> Result<(), String> {
+    run("dscacheutil", &["-flushcache"])?;
+    run("killall", &["-HUP", "mDNSResponder"])
+}
+
+/// Best effort: systemd-resolved is the only common caching resolver, and
+/// older releases ship `systemd-resolve` instead of `resolvectl`.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn flush() -> Result<(), String> {
+    run("resolvectl", &["flush-caches"]).or_else(|first| {
+        run("systemd-resolve", &["--flush-caches"]).map_err(|second| format!("{}; {}", first, second))
+    })
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let detail = if stderr.trim().is_empty() { String::from_utf8_lossy(&output.stdout) } else { stderr };
+    Err(format!("{} failed: {}", program, detail.trim()))
+}
+
+/// Whether the system resolver answers 127.0.0.1 for `domain`, i.e. the
+/// hosts entry is actually in effect.
+pub fn resolves_to_loopback(domain: &str) -> bool {
+    (domain, 0)
+        .to_socket_addrs()
+        .map(|mut addrs| addrs.any(|a| a.ip() == IpAddr::V4(Ipv4Addr::LOCALHOST)))
+        .unwrap_or(false)
+}
+
+/// The first IPv4 address of `domain` according to public DNS, bypassing
+/// the hosts file that points it at us.
+pub fn resolve_public(domain: &str) -> Result<Ipv4Addr, String> {
+    let mut last_error = String::new();
+    for server in PUBLIC_RESOLVERS {
+        match query_a(server, domain) {
+            Ok(addr) => return Ok(addr),
+            Err(e) => last_error = format!("{}: {}", server, e),
+        }
+    }
+    Err(format!("DNS resolve failed for {}: {}", domain, last_error))
+}
+
+/// One A query over UDP, just enough of RFC 1035 to read the answer.
+fn query_a(server: &str, domain: &str) -> Result<Ipv4Addr, String> {
+    use rand_core::RngCore;
+
+    let id = (rand_core::OsRng.next_u32() & 0xffff) as u16;
+    let mut query = Vec::with_capacity(domain.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid name {:?}", domain));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // Root, type A, class IN
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT)).map_err(|e| e.to_string())?;
+    socket.send_to(&query, server).map_err(|e| e.to_string())?;
+    let mut buf = [0u8; 512];
+    let len = loop {
+        let (len, _) = socket.recv_from(&mut buf).map_err(|e| e.to_string())?;
+        if len >= 12 && buf[..2] == id.to_be_bytes() {
+            break len;
+        }
+    };
+    let reply = &buf[..len];
+    let rcode = reply[3] & 0x0f;
+    if rcode != 0 {
+        return Err(format!("server answered rcode {}", rcode));
+    }
+    let u16_at = |at: usize| reply.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let answers = u16_at(6).unwrap_or(0);
+    // The question is echoed back as sent, so the answers start where our
+    // query ended
+    let mut at = query.len();
+    for _ in 0..answers {
+        at = skip_name(reply, at).ok_or("truncated answer")?;
+        let rtype = u16_at(at).ok_or("truncated answer")?;
+        let rdlen = u16_at(at + 8).ok_or("truncated answer")? as usize;
+        at += 10;
+        let rdata = reply.get(at..at + rdlen).ok_or("truncated answer")?;
+        if rtype == 1 && rdlen == 4 {
+            return Ok(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]));
+        }
+        at += rdlen;
+    }
+    Err("no A record".into())
+}
+
+/// The offset just past the (possibly compressed) name at `at`.
+fn skip_name(msg: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(at)?;
+        if len & 0xc0 == 0xc0 {
+            return Some(at + 2);
+        }
+        at += 1;
+        if len == 0 {
+            return Some(at);
+        }
+        at += len as usize;
+    }
+}
@@ -0,0 +1,319 @@
+//! System-level encrypted DNS (DNS-over-HTTPS on Windows, DNS-over-TLS in
+//! systemd-resolved). Clients that follow it can skip the hosts file, so the
+//! drill silently misses them; proxy_initialize reports it and doh_disable
+//! turns it off until proxy_restore puts the original settings back.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Under the app data dir: what doh_disable changed, for restore.
+pub const STATE_FILE: &str = "doh.json";
+
+#[derive(Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Probe {
+    pub enabled: bool,
+    /// The encrypted resolvers in use, e.g. `1.1.1.1`, or what enables them
+    /// when there is no address to name.
+    pub resolvers: Vec<String>,
+    /// Where it was read; None where nothing could be checked.
+    pub source: Option<&'static str>,
+    /// Why it couldn't be checked.
+    pub error: Option<String>,
+    /// Set by policy, which doh_disable can't change.
+    pub enforced: bool,
+}
+
+/// One setting doh_disable changed and what it held before.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Change {
+    #[serde(rename_all = "camelCase")]
+    Registry { key: String, name: String, value_type: String, original: u64 },
+    /// A resolved.conf drop-in we wrote; restoring removes it.
+    #[serde(rename_all = "camelCase")]
+    DropIn { path: PathBuf },
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Saved {
+    /// Unix seconds of the first doh_disable.
+    disabled_at: u64,
+    changes: Vec<Change>,
+}
+
+pub fn probe() -> Probe {
+    imp::probe()
+}
+
+/// Turn system DoH off and record the original settings in `state_file`.
+/// Settings already recorded by an earlier call keep their first original.
+/// Returns how many settings were changed now.
+pub fn disable(state_file: &Path) -> Result<usize, String> {
+    let mut saved = load(state_file)?.unwrap_or_else(|| Saved { disabled_at: crate::now_secs(), changes: Vec::new() });
+    let changes = imp::disable(&saved.changes)?;
+    let count = changes.len();
+    if count > 0 {
+        saved.changes.extend(changes);
+        store(state_file, &saved)?;
+    }
+    Ok(count)
+}
+
+/// Put back what `disable` changed. Ok(None) when it changed nothing. A
+/// setting that can't be put back stays in `state_file` for the next try.
+pub fn restore(state_file: &Path) -> Result<Option<usize>, String> {
+    let Some(saved) = load(state_file)? else {
+        return Ok(None);
+    };
+    let mut failed = Vec::new();
+    let mut errors = Vec::new();
+    for change in &saved.changes {
+        if let Err(e) = imp::revert(change) {
+            failed.push(change.clone());
+            errors.push(e);
+        }
+    }
+    let restored = saved.changes.len() - failed.len();
+    if failed.is_empty() {
+        match fs::remove_file(state_file) {
+            Ok(()) => Ok(Some(restored)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Some(restored)),
+            Err(e) => Err(format!("Removing {} failed: {}", state_file.display(), e)),
+        }
+    } else {
+        store(state_file, &Saved { changes: failed, ..saved })?;
+        Err(errors.join("; "))
+    }
+}
+
+fn load(path: &Path) -> Result<Option<Saved>, String> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| format!("{} is not a valid DoH record: {}", path.display(), e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Reading {} failed: {}", path.display(), e)),
+    }
+}
+
+fn store(path: &Path, saved: &Saved) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Creating {} failed: {}", dir.display(), e))?;
+    }
+    let json = serde_json::to_vec_pretty(saved).map_err(|e| e.to_string())?;
+    fs::write(path, json).map_err(|e| format!("Writing {} failed: {}", path.display(), e))
+}
+
+#[cfg(not(target_os = "macos"))]
+fn output(program: &str, args: &[&str]) -> Result<String, String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let detail = if stderr.trim().is_empty() { String::from_utf8_lossy(&output.stdout) } else { stderr };
+    Err(format!("{} failed: {}", program, detail.trim()))
+}
+
+#[cfg(target_os = "windows")]
+mod imp {
+    use super::*;
+
+    const PARAMETERS_KEY: &str = r"HKLM\SYSTEM\CurrentControlSet\Services\Dnscache\Parameters";
+    /// Per-adapter servers live under `<interface>\DohInterfaceSettings\Doh[6]\<address>`.
+    const INTERFACES_KEY: &str = r"HKLM\SYSTEM\CurrentControlSet\Services\Dnscache\InterfaceSpecificParameters";
+    const POLICY_KEY: &str = r"HKLM\SOFTWARE\Policies\Microsoft\Windows NT\DNSClient";
+    /// 2: upgrade to DoH wherever the server is a known DoH server.
+    const AUTO_DOH_ON: u64 = 2;
+    /// DoHPolicy 3: require DoH.
+    const POLICY_REQUIRE: u64 = 3;
+
+    struct Value {
+        key: String,
+        name: String,
+        value_type: String,
+        data: u64,
+    }
+
+    /// The numeric values `reg query` printed, with the key each is under.
+    /// reg exits non-zero when nothing matches, which reads as no values.
+    fn query(args: &[&str]) -> Vec<Value> {
+        let Ok(text) = output("reg", args) else {
+            return Vec::new();
+        };
+        let mut key = String::new();
+        let mut values = Vec::new();
+        for line in text.lines() {
+            if line.starts_with("HKEY_") {
+                key = line.trim().replacen("HKEY_LOCAL_MACHINE", "HKLM", 1);
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let [name, value_type, data] = fields[..] {
+                let parsed = match data.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                    None => data.parse().ok(),
+                };
+                if let (true, Some(data)) = (value_type.starts_with("REG_"), parsed) {
+                    values.push(Value { key: key.clone(), name: name.into(), value_type: value_type.into(), data });
+                }
+            }
+        }
+        values
+    }
+
+    /// The values that turn DoH on: nonzero per-adapter DohFlags and
+    /// EnableAutoDoh=2.
+    fn active() -> Vec<Value> {
+        let mut values: Vec<Value> =
+            query(&["query", INTERFACES_KEY, "/s", "/v", "DohFlags"]).into_iter().filter(|v| v.data != 0).collect();
+        values.extend(
+            query(&["query", PARAMETERS_KEY, "/v", "EnableAutoDoh"]).into_iter().filter(|v| v.data == AUTO_DOH_ON),
+        );
+        values
+    }
+
+    pub fn probe() -> Probe {
+        let mut resolvers: Vec<String> = Vec::new();
+        for value in active() {
+            let resolver = if value.name == "DohFlags" {
+                value.key.rsplit('\\').next().unwrap_or_default().to_string()
+            } else {
+                "automatic (known DoH servers)".to_string()
+            };
+            if !resolvers.contains(&resolver) {
+                resolvers.push(resolver);
+            }
+        }
+        let enforced = query(&["query", POLICY_KEY, "/v", "DoHPolicy"]).iter().any(|v| v.data == POLICY_REQUIRE);
+        if enforced {
+            resolvers.push("required by group policy".into());
+        }
+        Probe { enabled: !resolvers.is_empty(), resolvers, source: Some("registry"), error: None, enforced }
+    }
+
+    pub fn disable(recorded: &[Change]) -> Result<Vec<Change>, String> {
+        let mut changes = Vec::new();
+        for value in active() {
+            output("reg", &["add", &value.key, "/v", &value.name, "/t", &value.value_type, "/d", "0", "/f"])?;
+            let known = recorded.iter().any(|c| {
+                matches!(c, Change::Registry { key, name, .. } if key == &value.key && name == &value.name)
+            });
+            if !known {
+                changes.push(Change::Registry {
+                    key: value.key,
+                    name: value.name,
+                    value_type: value.value_type,
+                    original: value.data,
+                });
+            }
+        }
+        Ok(changes)
+    }
+
+    pub fn revert(change: &Change) -> Result<(), String> {
+        match change {
+            Change::Registry { key, name, value_type, original } => {
+                let original = original.to_string();
+                output("reg", &["add", key, "/v", name, "/t", value_type, "/d", &original, "/f"]).map(|_| ())
+            }
+            Change::DropIn { path } => Err(format!("{} is not a Windows setting", path.display())),
+        }
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod imp {
+    use super::*;
+
+    const DROP_IN: &str = "/etc/systemd/resolved.conf.d/90-cyber-drill-no-dot.conf";
+
+    /// `resolvectl status`; older releases ship `systemd-resolve --status`.
+    fn status() -> Result<String, String> {
+        output("resolvectl", &["status"]).or_else(|first| {
+            output("systemd-resolve", &["--status"]).map_err(|second| format!("{}; {}", first, second))
+        })
+    }
+
+    pub fn probe() -> Probe {
+        let text = match status() {
+            Ok(text) => text,
+            Err(e) => return Probe { error: Some(e), ..Probe::default() },
+        };
+        // "Protocols: ... +DNSOverTLS" on current releases, "DNSOverTLS
+        // setting: yes" (or opportunistic) on older ones
+        let enabled = text.lines().map(str::trim).any(|line| {
+            line.contains("+DNSOverTLS")
+                || line.strip_prefix("DNSOverTLS setting:").is_some_and(|v| v.trim() != "no")
+        });
+        let mut resolvers: Vec<String> = Vec::new();
+        if enabled {
+            for line in text.lines() {
+                if let Some(server) = line.trim().strip_prefix("Current DNS Server:") {
+                    let server = server.trim().to_string();
+                    if !resolvers.contains(&server) {
+                        resolvers.push(server);
+                    }
+                }
+            }
+        }
+        Probe { enabled, resolvers, source: Some("systemd-resolved"), error: None, enforced: false }
+    }
+
+    /// A drop-in turning DNSOverTLS off globally. Per-link settings (from
+    /// NetworkManager, say) win over it; the probe after shows those.
+    pub fn disable(recorded: &[Change]) -> Result<Vec<Change>, String> {
+        let path = PathBuf::from(DROP_IN);
+        if recorded.iter().any(|c| matches!(c, Change::DropIn { path: p } if p == &path)) || !probe().enabled {
+            return Ok(Vec::new());
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Creating {} failed: {}", dir.display(), e))?;
+        }
+        fs::write(&path, "[Resolve]\nDNSOverTLS=no\n").map_err(|e| format!("Writing {} failed: {}", DROP_IN, e))?;
+        if let Err(e) = output("systemctl", &["restart", "systemd-resolved"]) {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        Ok(vec![Change::DropIn { path }])
+    }
+
+    pub fn revert(change: &Change) -> Result<(), String> {
+        match change {
+            Change::DropIn { path } => {
+                match fs::remove_file(path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(format!("Removing {} failed: {}", path.display(), e)),
+                }
+                output("systemctl", &["restart", "systemd-resolved"]).map(|_| ())
+            }
+            Change::Registry { key, .. } => Err(format!("{} is not a Linux setting", key)),
+        }
+    }
+}
+
+/// Encrypted DNS is configured through profiles there; not checked.
+#[cfg(target_os = "macos")]
+mod imp {
+    use super::*;
+
+    pub fn probe() -> Probe {
+        Probe { error: Some("Not checked on macOS".into()), ..Probe::default() }
+    }
+
+    pub fn disable(_recorded: &[Change]) -> Result<Vec<Change>, String> {
+        Err("Turning off encrypted DNS is not supported on macOS".into())
+    }
+
+    pub fn revert(_change: &Change) -> Result<(), String> {
+        Err("Nothing to restore on macOS".into())
+    }
+}
@@ -0,0 +1,124 @@
+//! Admin rights: checking for them, and getting them just for the one
+//! privileged step we need (replacing the hosts file) when the app itself
+//! runs unelevated.
+
+use std::path::Path;
+use std::process::Command;
+
+/// argv[1] that turns the app binary into the elevated hosts-writing helper.
+pub const HOSTS_HELPER_ARG: &str = "--write-hosts";
+
+/// argv[1] that makes the app binary exit straight away, for `probe`.
+pub const PROBE_HELPER_ARG: &str = "--elevation-probe";
+
+/// Whether we run with an elevated token (Windows) or as root.
+#[cfg(target_os = "windows")]
+pub fn is_elevated() -> bool {
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY};
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+    let mut token = std::ptr::null_mut();
+    // SAFETY: the pseudo handle from GetCurrentProcess needs no closing; the
+    // token is closed below.
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+        return false;
+    }
+    let mut elevation = TOKEN_ELEVATION { TokenIsElevated: 0 };
+    let mut size = 0u32;
+    // SAFETY: elevation is a TOKEN_ELEVATION of the size we pass.
+    let ok = unsafe {
+        GetTokenInformation(
+            token,
+            TokenElevation,
+            (&mut elevation as *mut TOKEN_ELEVATION).cast(),
+            std::mem::size_of::<TOKEN_ELEVATION>() as u32,
+            &mut size,
+        )
+    };
+    unsafe { CloseHandle(token) };
+    ok != 0 && elevation.TokenIsElevated != 0
+}
+
+#[cfg(unix)]
+pub fn is_elevated() -> bool {
+    // SAFETY: geteuid has no preconditions.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Replace the hosts file at `hosts` with `staged` through a UAC prompt:
+/// our own binary is relaunched elevated in helper mode, which does nothing
+/// but the write.
+#[cfg(target_os = "windows")]
+pub fn write_hosts(staged: &Path, hosts: &Path) -> Result<(), String> {
+    run_self_elevated(&[HOSTS_HELPER_ARG, &staged.to_string_lossy(), &hosts.to_string_lossy()])
+}
+
+/// Relaunch our own binary with `args` through a UAC prompt and wait for it.
+#[cfg(target_os = "windows")]
+fn run_self_elevated(args: &[&str]) -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("Failed to locate the app binary: {}", e))?;
+    let quote = |s: &str| s.replace('\'', "''");
+    // Start-Process joins ArgumentList with spaces, so each argument carries its own quotes
+    let list: Vec<String> = args.iter().map(|a| format!("'\"{}\"'", quote(a))).collect();
+    let script = format!(
+        "$p = Start-Process -FilePath '{}' -ArgumentList {} -Verb RunAs -Wait -PassThru -WindowStyle Hidden; \
+         exit $p.ExitCode",
+        quote(&exe.to_string_lossy()),
+        list.join(","),
+    );
+    run("powershell", &["-NoProfile", "-NonInteractive", "-Command", &script])
+}
+
+/// `cp` onto the existing file keeps its owner and mode.
+#[cfg(target_os = "macos")]
+pub fn write_hosts(staged: &Path, hosts: &Path) -> Result<(), String> {
+    let quote = |p: &Path| format!("'{}'", p.to_string_lossy().replace('\'', "'\\''"));
+    osascript_admin(&format!("cp {} {}", quote(staged), quote(hosts)))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn write_hosts(staged: &Path, hosts: &Path) -> Result<(), String> {
+    let (staged, hosts) = (staged.to_string_lossy(), hosts.to_string_lossy());
+    run("pkexec", &["cp", &staged, &hosts])
+}
+
+/// Show the elevation prompt for a command that does nothing, to learn
+/// whether it would be granted without touching anything.
+#[cfg(target_os = "windows")]
+pub fn probe() -> Result<(), String> {
+    run_self_elevated(&[PROBE_HELPER_ARG])
+}
+
+#[cfg(target_os = "macos")]
+pub fn probe() -> Result<(), String> {
+    osascript_admin("true")
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn probe() -> Result<(), String> {
+    run("pkexec", &["true"])
+}
+
+/// Run a shell command as root behind the macOS admin password dialog.
+#[cfg(target_os = "macos")]
+pub fn osascript_admin(shell: &str) -> Result<(), String> {
+    let script = format!(
+        "do shell script \"{}\" with administrator privileges",
+        shell.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    run("osascript", &["-e", &script])
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let detail = if stderr.trim().is_empty() { String::from_utf8_lossy(&output.stdout) } else { stderr };
+    Err(format!("{} failed ({}): {}", program, output.status, detail.trim()))
+}
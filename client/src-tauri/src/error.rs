@@ -0,0 +1,303 @@
+//! Errors returned to the frontend. Each variant serializes as
+//! `{ "code": "...", "message": "...", "userMessage": "...", "detail": "..." }`
+//! so the UI can branch on `code` and show participants `userMessage` (from
+//! the messages catalog, in the chosen locale) with `detail` (the same as
+//! `message`) for the administrator; some add fields of their own (see
+//! `Serialize`). Codes are a contract with the frontend: add new ones, never
+//! rename.
+
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ProxyError {
+    /// Reading, parsing or writing the hosts file failed.
+    Hosts(String),
+    /// Our hosts entries disappeared after we wrote them (security software
+    /// restoring the file).
+    TamperDetected(String),
+    /// The hosts entry is in place but the resolver doesn't honour it.
+    HostsIgnored(String),
+    /// Writing hosts needs administrator rights we don't have.
+    NotElevated(String),
+    /// Elevated, but the hosts file is read-only and that couldn't be undone.
+    HostsReadOnly(String),
+    /// Elevated, but the hosts file's ACL denies writing.
+    HostsAclDenied(String),
+    /// Another process has the hosts file open (sharing violation).
+    HostsLocked(String),
+    /// The hosts file is over config.hostsMaxMb, so it isn't rewritten
+    /// unless allowLargeHosts is passed.
+    HostsTooLarge(String),
+    /// Generating, locating or trusting the CA failed.
+    Cert(String),
+    /// The CA or server certificate is past its notAfter; cert_renew issues
+    /// a new one.
+    CertExpired(String),
+    /// The running proxy's certificate doesn't verify against our CA, so it
+    /// serves stale certificates; cert_regenerate and a restart fix it.
+    CertMismatch(String),
+    /// The bundled proxy script is missing (broken install).
+    ScriptMissing(String),
+    /// The shipped proxy files don't match the hashes taken at build time.
+    Integrity(String),
+    /// No usable Node.js runtime, bundled or on PATH.
+    NodeMissing {
+        message: String,
+        /// Where we looked, in order.
+        path_tried: Vec<String>,
+    },
+    /// Group policy doesn't allow our firewall rule. Reported alongside a
+    /// successful start, which may still work if loopback isn't filtered.
+    FirewallPolicy(String),
+    /// Something else already listens on the proxy port.
+    PortInUse {
+        message: String,
+        port: u16,
+        /// The listener, when it could be identified.
+        pid: Option<u32>,
+        process: Option<String>,
+    },
+    /// The proxy process could not be started.
+    Spawn(String),
+    /// The proxy was spawned but exited or never started listening.
+    StartFailed(String),
+    /// proxy_run while a proxy is already running or being started.
+    AlreadyRunning(String),
+    /// The gateway URL is malformed.
+    GatewayInvalid(String),
+    /// The gateway's certificate doesn't match the configured CA or SPKI pin.
+    PinMismatch(String),
+    /// Another agent (Zscaler, Netskope, ...) intercepts the domains' real
+    /// origin and config.refuseOnAgentInterception is set.
+    AgentConflict(String),
+    /// The gateway's host isn't on the signed allowlist, or the list is
+    /// missing or fails verification.
+    GatewayNotAllowed(String),
+    /// Fetching or installing a proxy script update failed; the working
+    /// script is unchanged.
+    UpdateFailed(String),
+    /// The update manifest or the script it names fails the signature or hash
+    /// check; nothing was installed.
+    UpdateRejected(String),
+    /// Any other argument from the frontend is malformed.
+    InvalidArgument(String),
+    /// The requested option isn't available on this platform.
+    Unsupported(String),
+    /// The command isn't valid in the current state (e.g. proxy running).
+    InvalidState(String),
+    /// A step of proxy_run or proxy_stop didn't finish in time; what it had
+    /// done so far was undone.
+    TimedOut {
+        message: String,
+        /// agentProbe, hostsRead, hostsWrite, resolution, gatewayProbe, spawn,
+        /// ready or stop.
+        step: &'static str,
+    },
+    /// App data / backup IO and anything else unexpected.
+    Internal(String),
+    /// proxy_run failed after changing something. Code, message and fields
+    /// are those of `error`, plus `rollback`.
+    RolledBack { error: Box<ProxyError>, rollback: Rollback },
+}
+
+/// How undoing a failed proxy_run went. Steps are named hosts, firewall,
+/// redirect and child.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rollback {
+    /// Everything that was changed is back as it was.
+    pub complete: bool,
+    pub undone: Vec<&'static str>,
+    pub failed: Vec<&'static str>,
+}
+
+impl ProxyError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProxyError::Hosts(_) => "HOSTS_IO",
+            ProxyError::TamperDetected(_) => "TAMPER_DETECTED",
+            ProxyError::HostsIgnored(_) => "HOSTS_IGNORED",
+            ProxyError::NotElevated(_) => "NOT_ELEVATED",
+            ProxyError::HostsReadOnly(_) => "HOSTS_READ_ONLY",
+            ProxyError::HostsAclDenied(_) => "HOSTS_ACL_DENIED",
+            ProxyError::HostsLocked(_) => "HOSTS_LOCKED",
+            ProxyError::HostsTooLarge(_) => "HOSTS_TOO_LARGE",
+            ProxyError::Cert(_) => "CERT",
+            ProxyError::CertExpired(_) => "CERT_EXPIRED",
+            ProxyError::CertMismatch(_) => "CERT_MISMATCH",
+            ProxyError::ScriptMissing(_) => "PROXY_SCRIPT_MISSING",
+            ProxyError::Integrity(_) => "INTEGRITY",
+            ProxyError::NodeMissing { .. } => "NODE_NOT_FOUND",
+            ProxyError::FirewallPolicy(_) => "FIREWALL_POLICY",
+            ProxyError::PortInUse { .. } => "PORT_IN_USE",
+            ProxyError::Spawn(_) => "SPAWN_FAILED",
+            ProxyError::StartFailed(_) => "PROXY_START_FAILED",
+            ProxyError::AlreadyRunning(_) => "ALREADY_RUNNING",
+            ProxyError::GatewayInvalid(_) => "GATEWAY_INVALID",
+            ProxyError::PinMismatch(_) => "PIN_MISMATCH",
+            ProxyError::GatewayNotAllowed(_) => "GATEWAY_NOT_ALLOWED",
+            ProxyError::AgentConflict(_) => "AGENT_CONFLICT",
+            ProxyError::UpdateFailed(_) => "UPDATE_FAILED",
+            ProxyError::UpdateRejected(_) => "UPDATE_REJECTED",
+            ProxyError::InvalidArgument(_) => "INVALID_ARGUMENT",
+            ProxyError::Unsupported(_) => "UNSUPPORTED",
+            ProxyError::InvalidState(_) => "INVALID_STATE",
+            ProxyError::TimedOut { .. } => "TIMED_OUT",
+            ProxyError::Internal(_) => "INTERNAL",
+            ProxyError::RolledBack { error, .. } => error.code(),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ProxyError::Hosts(m)
+            | ProxyError::TamperDetected(m)
+            | ProxyError::HostsIgnored(m)
+            | ProxyError::NotElevated(m)
+            | ProxyError::HostsReadOnly(m)
+            | ProxyError::HostsAclDenied(m)
+            | ProxyError::HostsLocked(m)
+            | ProxyError::HostsTooLarge(m)
+            | ProxyError::Cert(m)
+            | ProxyError::CertExpired(m)
+            | ProxyError::CertMismatch(m)
+            | ProxyError::ScriptMissing(m)
+            | ProxyError::Integrity(m)
+            | ProxyError::NodeMissing { message: m, .. }
+            | ProxyError::FirewallPolicy(m)
+            | ProxyError::PortInUse { message: m, .. }
+            | ProxyError::Spawn(m)
+            | ProxyError::StartFailed(m)
+            | ProxyError::AlreadyRunning(m)
+            | ProxyError::GatewayInvalid(m)
+            | ProxyError::PinMismatch(m)
+            | ProxyError::GatewayNotAllowed(m)
+            | ProxyError::AgentConflict(m)
+            | ProxyError::UpdateFailed(m)
+            | ProxyError::UpdateRejected(m)
+            | ProxyError::InvalidArgument(m)
+            | ProxyError::Unsupported(m)
+            | ProxyError::InvalidState(m)
+            | ProxyError::TimedOut { message: m, .. }
+            | ProxyError::Internal(m) => m,
+            ProxyError::RolledBack { error, .. } => error.message(),
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl Serialize for ProxyError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ProxyError", 8)?;
+        s.serialize_field("code", self.code())?;
+        s.serialize_field("message", self.message())?;
+        s.serialize_field("userMessage", &self.user_message())?;
+        s.serialize_field("detail", self.message())?;
+        self.serialize_fields(&mut s)?;
+        s.end()
+    }
+}
+
+impl ProxyError {
+    /// What to tell a participant, in the current locale; the technical
+    /// message if the catalog has nothing.
+    pub fn user_message(&self) -> String {
+        let fields = match self {
+            ProxyError::PortInUse { port, .. } => vec![("port", port.to_string())],
+            ProxyError::TimedOut { step, .. } => vec![("step", step.to_string())],
+            ProxyError::RolledBack { error, .. } => return error.user_message(),
+            _ => Vec::new(),
+        };
+        crate::messages::user_message(self.code(), &fields).unwrap_or_else(|| self.message().to_string())
+    }
+
+    /// The fields beyond code and message.
+    fn serialize_fields<S: SerializeStruct>(&self, s: &mut S) -> Result<(), S::Error> {
+        match self {
+            ProxyError::NodeMissing { path_tried, .. } => s.serialize_field("pathTried", path_tried)?,
+            ProxyError::PortInUse { port, pid, process, .. } => {
+                s.serialize_field("port", port)?;
+                s.serialize_field("pid", pid)?;
+                s.serialize_field("process", process)?;
+            }
+            ProxyError::TimedOut { step, .. } => s.serialize_field("step", step)?,
+            ProxyError::RolledBack { error, rollback } => {
+                error.serialize_fields(s)?;
+                s.serialize_field("rollback", rollback)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn node_missing_carries_the_paths_tried() {
+        let error = ProxyError::NodeMissing {
+            message: "No Node.js runtime found".into(),
+            path_tried: vec!["resources/node/node".into(), "PATH".into()],
+        };
+        let value = serde_json::to_value(&error).unwrap();
+        assert_eq!(value["code"], "NODE_NOT_FOUND");
+        assert_eq!(value["message"], "No Node.js runtime found");
+        assert_eq!(value["detail"], value["message"]);
+        assert_eq!(value["pathTried"], json!(["resources/node/node", "PATH"]));
+    }
+
+    #[test]
+    fn port_in_use_carries_the_listener() {
+        let error = ProxyError::PortInUse {
+            message: "Port 443 is in use".into(),
+            port: 443,
+            pid: Some(4242),
+            process: Some("nginx".into()),
+        };
+        let value = serde_json::to_value(&error).unwrap();
+        assert_eq!(value["code"], "PORT_IN_USE");
+        assert_eq!(value["port"], 443);
+        assert_eq!(value["pid"], 4242);
+        assert_eq!(value["process"], "nginx");
+        assert!(value["userMessage"].as_str().unwrap().contains("443"));
+
+        let unknown = ProxyError::PortInUse { message: "in use".into(), port: 8443, pid: None, process: None };
+        let value = serde_json::to_value(&unknown).unwrap();
+        assert_eq!(value["pid"], json!(null));
+        assert_eq!(value["process"], json!(null));
+    }
+
+    #[test]
+    fn gateway_invalid_has_only_the_common_fields() {
+        let value = serde_json::to_value(ProxyError::GatewayInvalid("not a URL: x".into())).unwrap();
+        let mut keys: Vec<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, ["code", "detail", "message", "userMessage"]);
+        assert_eq!(value["code"], "GATEWAY_INVALID");
+        assert_eq!(value["message"], "not a URL: x");
+    }
+
+    #[test]
+    fn rolled_back_keeps_the_cause_and_adds_the_rollback() {
+        let error = ProxyError::RolledBack {
+            error: Box::new(ProxyError::TimedOut { message: "not ready".into(), step: "ready" }),
+            rollback: Rollback { complete: true, undone: vec!["hosts", "child"], failed: Vec::new() },
+        };
+        let value = serde_json::to_value(&error).unwrap();
+        assert_eq!(value["code"], "TIMED_OUT");
+        assert_eq!(value["step"], "ready");
+        assert_eq!(value["rollback"], json!({ "complete": true, "undone": ["hosts", "child"], "failed": [] }));
+    }
+}
@@ -0,0 +1,139 @@
+//! Known failure signatures in the proxy's output, so an exit can say why
+//! instead of only giving an exit code. Covers both local-proxy.js and the
+//! built-in proxy; add a row to SIGNATURES for anything new.
+
+use serde::Serialize;
+
+/// Longest snippet kept from the matching line.
+const SNIPPET_MAX_CHARS: usize = 300;
+
+/// In order of precedence: a failure seen later in the same run replaces an
+/// earlier one only if its kind comes first here.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Kind {
+    /// Something else holds the port (the same code as ProxyError::PortInUse).
+    PortInUse,
+    /// The certificate or key file couldn't be read.
+    CertMissing,
+    /// An unhandled error took the process down; restarting will hit it again.
+    CrashLoop,
+    /// Connecting to the gateway failed. The proxy keeps running, so this
+    /// only explains an exit when nothing above was seen.
+    GatewayUnreachable,
+}
+
+/// A line matches a row when it contains every needle; the first matching
+/// row counts.
+const SIGNATURES: &[(Kind, &[&str])] = &[
+    (Kind::PortInUse, &["EADDRINUSE"]),
+    (Kind::PortInUse, &["Address already in use"]),
+    (Kind::PortInUse, &["os error 10048"]),
+    (Kind::CertMissing, &["ENOENT", ".pem"]),
+    (Kind::CertMissing, &["ENOENT", ".crt"]),
+    (Kind::CertMissing, &["ENOENT", ".key"]),
+    (Kind::CertMissing, &["ENOENT", "cert"]),
+    (Kind::CrashLoop, &["UnhandledPromiseRejection"]),
+    (Kind::CrashLoop, &["Unhandled promise rejection"]),
+    (Kind::CrashLoop, &["uncaughtException"]),
+    (Kind::CrashLoop, &["FATAL ERROR"]),
+    (Kind::CrashLoop, &["panicked at"]),
+    (Kind::GatewayUnreachable, &["gateway error", "ECONNREFUSED"]),
+    (Kind::GatewayUnreachable, &["gateway error", "ENOTFOUND"]),
+    (Kind::GatewayUnreachable, &["gateway error", "EHOSTUNREACH"]),
+    (Kind::GatewayUnreachable, &["gateway error", "ETIMEDOUT"]),
+    (Kind::GatewayUnreachable, &["gateway error", "EAI_AGAIN"]),
+];
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Failure {
+    #[serde(rename = "code")]
+    pub kind: Kind,
+    /// The line that matched, trimmed.
+    pub snippet: String,
+}
+
+pub fn classify(line: &str) -> Option<Failure> {
+    let (kind, _) = SIGNATURES.iter().find(|(_, needles)| needles.iter().all(|n| line.contains(n)))?;
+    Some(Failure { kind: *kind, snippet: line.trim().chars().take(SNIPPET_MAX_CHARS).collect() })
+}
+
+/// The most telling failure seen in one run's output.
+#[derive(Default)]
+pub struct Tracker {
+    seen: Option<Failure>,
+}
+
+impl Tracker {
+    pub fn observe(&mut self, line: &str) {
+        let Some(failure) = classify(line) else {
+            return;
+        };
+        match &self.seen {
+            Some(seen) if seen.kind <= failure.kind => {}
+            _ => self.seen = Some(failure),
+        }
+    }
+
+    pub fn failure(&self) -> Option<Failure> {
+        self.seen.clone()
+    }
+
+    pub fn clear(&mut self) {
+        self.seen = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_signature_classifies() {
+        let cases: &[(&str, Option<Kind>)] = &[
+            ("Error: listen EADDRINUSE: address already in use :::443", Some(Kind::PortInUse)),
+            ("[proxy] failed to start: Address already in use (os error 98)", Some(Kind::PortInUse)),
+            ("[proxy] failed to start: bind failed (os error 10048)", Some(Kind::PortInUse)),
+            ("Error: ENOENT: no such file or directory, open 'certs/server.pem'", Some(Kind::CertMissing)),
+            ("Error: ENOENT: no such file or directory, open 'ca.crt'", Some(Kind::CertMissing)),
+            ("Error: ENOENT: no such file or directory, open 'server.key'", Some(Kind::CertMissing)),
+            ("Error: ENOENT: no such file or directory, open 'cert'", Some(Kind::CertMissing)),
+            ("[UnhandledPromiseRejection: This error originated ...]", Some(Kind::CrashLoop)),
+            ("(node:42) Unhandled promise rejection: TypeError", Some(Kind::CrashLoop)),
+            ("process.on uncaughtException: boom", Some(Kind::CrashLoop)),
+            ("FATAL ERROR: Reached heap limit Allocation failed", Some(Kind::CrashLoop)),
+            ("thread 'main' panicked at src/proxy.rs:10:5", Some(Kind::CrashLoop)),
+            ("[proxy] gateway error: connect ECONNREFUSED 10.0.0.1:443", Some(Kind::GatewayUnreachable)),
+            ("[proxy] gateway error: getaddrinfo ENOTFOUND gw.example", Some(Kind::GatewayUnreachable)),
+            ("[proxy] gateway error: connect EHOSTUNREACH 10.0.0.1", Some(Kind::GatewayUnreachable)),
+            ("[proxy] gateway error: connect ETIMEDOUT 10.0.0.1:443", Some(Kind::GatewayUnreachable)),
+            ("[proxy] gateway error: getaddrinfo EAI_AGAIN gw.example", Some(Kind::GatewayUnreachable)),
+            // Every needle of a row has to be on the line
+            ("Error: ENOENT: no such file or directory, open 'config.json'", None),
+            ("connect ECONNREFUSED 127.0.0.1:9229", None),
+            ("[proxy] listening on 127.0.0.1:443", None),
+        ];
+        for (line, kind) in cases {
+            assert_eq!(classify(line).map(|f| f.kind), *kind, "{}", line);
+        }
+    }
+
+    #[test]
+    fn snippet_is_trimmed_and_capped() {
+        let failure = classify(&format!("  EADDRINUSE {}\n", "x".repeat(1000))).unwrap();
+        assert!(failure.snippet.starts_with("EADDRINUSE"));
+        assert_eq!(failure.snippet.chars().count(), SNIPPET_MAX_CHARS);
+    }
+
+    #[test]
+    fn tracker_keeps_the_most_telling_failure() {
+        let mut tracker = Tracker::default();
+        tracker.observe("[proxy] gateway error: connect ECONNREFUSED 10.0.0.1:443");
+        tracker.observe("Error: listen EADDRINUSE: address already in use :::443");
+        tracker.observe("FATAL ERROR: Reached heap limit");
+        assert_eq!(tracker.failure().map(|f| f.kind), Some(Kind::PortInUse));
+        tracker.clear();
+        assert!(tracker.failure().is_none());
+    }
+}
@@ -0,0 +1,87 @@
+//! A Defender Firewall allow rule for the proxy. Depending on profile policy
+//! some Windows builds block inbound loopback connections to the proxy, and
+//! the drill then fails without any error. Other platforms need nothing.
+
+use std::path::Path;
+
+/// One rule, found and removed by name.
+#[cfg(target_os = "windows")]
+const RULE_NAME: &str = "cyber-drill-safe local proxy";
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub enum AddError {
+    /// Group policy forbids local firewall rules.
+    Policy(String),
+    Other(String),
+}
+
+/// Allow inbound TCP to `port` for `program`, from and to loopback only.
+/// Replaces a rule left by an earlier run. Returns whether a rule was added.
+#[cfg(target_os = "windows")]
+pub fn add(program: &Path, port: u16) -> Result<bool, AddError> {
+    let _ = remove();
+    netsh(&[
+        "advfirewall",
+        "firewall",
+        "add",
+        "rule",
+        &format!("name={}", RULE_NAME),
+        "dir=in",
+        "action=allow",
+        "protocol=TCP",
+        &format!("localport={}", port),
+        &format!("program={}", program.display()),
+        "localip=127.0.0.1,::1",
+        "remoteip=127.0.0.1,::1",
+        "profile=any",
+        "enable=yes",
+    ])
+    .map(|_| true)
+    .map_err(|e| {
+        if e.to_ascii_lowercase().contains("policy") {
+            AddError::Policy(e)
+        } else {
+            AddError::Other(e)
+        }
+    })
+}
+
+#[cfg(target_os = "windows")]
+pub fn remove() -> Result<(), String> {
+    netsh(&["advfirewall", "firewall", "delete", "rule", &format!("name={}", RULE_NAME)])
+}
+
+/// Whether our rule exists; None where there is no firewall to manage.
+#[cfg(target_os = "windows")]
+pub fn exists() -> Option<bool> {
+    Some(netsh(&["advfirewall", "firewall", "show", "rule", &format!("name={}", RULE_NAME)]).is_ok())
+}
+
+#[cfg(target_os = "windows")]
+fn netsh(args: &[&str]) -> Result<(), String> {
+    let output = std::process::Command::new("netsh")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run netsh: {}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        // netsh reports errors on stdout
+        Err(format!("netsh failed: {}", String::from_utf8_lossy(&output.stdout).trim()))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn add(_program: &Path, _port: u16) -> Result<bool, AddError> {
+    Ok(false)
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn remove() -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn exists() -> Option<bool> {
+    None
+}
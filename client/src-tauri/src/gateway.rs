@@ -0,0 +1,327 @@
+//! Gateway URL handling. An empty URL means passthrough: local-proxy.js
+//! then forwards to the official Windsurf backend.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use url::Url;
+
+pub const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(3);
+/// Overall budget for `check`, so a "test connection" click can't hang.
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(4);
+/// Served by lab-server.js.
+pub const DEFAULT_HEALTH_PATH: &str = "/health";
+/// Where the pinned CA is written for the proxy child, in the certs dir.
+const PINNED_CA_FILE: &str = "gateway-ca.pem";
+
+/// Check `raw` is an http(s) URL with a host and return it normalized
+/// (trimmed, no trailing slash). Empty stays empty.
+pub fn normalize(raw: &str) -> Result<String, String> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Ok(String::new());
+    }
+    let url = Url::parse(raw).map_err(|e| format!("Invalid gateway URL {:?}: {}", raw, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("Invalid gateway URL {:?}: scheme must be http or https", raw));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(format!("Invalid gateway URL {:?}: missing host", raw));
+    }
+    if url.port() == Some(0) {
+        return Err(format!("Invalid gateway URL {:?}: port must be 1-65535", raw));
+    }
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+/// Whether a TCP connection to the gateway's host and port succeeds within
+/// `timeout`, through `upstream` unless it is empty. `url` must already be
+/// normalized and non-empty.
+pub fn is_reachable(url: &str, timeout: Duration, upstream: &str) -> bool {
+    let Ok(url) = Url::parse(url) else {
+        return false;
+    };
+    if !upstream.is_empty() {
+        let port = url.port_or_known_default().unwrap_or(80);
+        return crate::upstream::connect(upstream, url.host_str().unwrap_or_default(), port, timeout).is_ok();
+    }
+    let Ok(addrs) = url.socket_addrs(|| None) else {
+        return false;
+    };
+    addrs.into_iter().any(|addr| TcpStream::connect_timeout(&addr, timeout).is_ok())
+}
+
+/// What the gateway's certificate must match on top of the usual checks, so
+/// whoever controls DNS for the gateway host doesn't get the intercepted
+/// traffic. Enforced here, by the built-in proxy and by local-proxy.js.
+#[derive(Clone, Default)]
+pub struct Pin {
+    /// The CA(s) the chain must lead to, as PEM; the system roots are then
+    /// not trusted for the gateway.
+    pub ca_pem: Option<String>,
+    /// Base64 SHA-256 of the gateway certificate's SubjectPublicKeyInfo.
+    pub spki_sha256: Option<String>,
+}
+
+impl Pin {
+    /// Validate and normalize; empty values count as unset. The SPKI pin
+    /// may carry the `sha256/` prefix HPKP used.
+    pub fn new(ca_pem: Option<&str>, spki_sha256: Option<&str>) -> Result<Self, String> {
+        let ca_pem = ca_pem.map(str::trim).filter(|p| !p.is_empty());
+        if ca_pem.is_some_and(|pem| crate::cert::pem_certificates(pem).is_empty()) {
+            return Err("The gateway CA contains no PEM certificate".into());
+        }
+        let spki = spki_sha256
+            .map(|p| p.trim())
+            .map(|p| p.strip_prefix("sha256/").unwrap_or(p))
+            .filter(|p| !p.is_empty());
+        if let Some(pin) = spki {
+            let hash = STANDARD.decode(pin).map_err(|e| format!("The SPKI pin is not base64: {}", e))?;
+            if hash.len() != 32 {
+                return Err(format!("The SPKI pin is {} bytes long; a SHA-256 hash has 32", hash.len()));
+            }
+        }
+        Ok(Pin { ca_pem: ca_pem.map(|p| format!("{}\n", p)), spki_sha256: spki.map(str::to_string) })
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.ca_pem.is_some() || self.spki_sha256.is_some()
+    }
+
+    /// A connector that trusts only the pinned CA, if there is one.
+    pub fn connector(&self) -> Result<native_tls::TlsConnector, String> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(pem) = &self.ca_pem {
+            builder.disable_built_in_roots(true);
+            for der in crate::cert::pem_certificates(pem) {
+                let ca = native_tls::Certificate::from_der(&der).map_err(|e| format!("Invalid gateway CA: {}", e))?;
+                builder.add_root_certificate(ca);
+            }
+        }
+        builder.build().map_err(|e| format!("TLS client setup failed: {}", e))
+    }
+
+    /// Check the certificate the gateway presented against the SPKI pin.
+    pub fn check_peer(&self, peer: Option<native_tls::Certificate>) -> Result<(), String> {
+        let Some(expected) = &self.spki_sha256 else {
+            return Ok(());
+        };
+        let der = peer
+            .ok_or("The gateway presented no certificate")?
+            .to_der()
+            .map_err(|e| format!("Unreadable gateway certificate: {}", e))?;
+        let actual = spki_sha256(&der)?;
+        if actual == *expected {
+            Ok(())
+        } else {
+            Err(format!("The gateway's key (sha256/{}) doesn't match the pin (sha256/{})", actual, expected))
+        }
+    }
+
+    /// The proxy child's arguments for the pin: `--gateway-ca` with the CA
+    /// written to `dir`, and `--gateway-pin`.
+    pub fn args(&self, dir: &Path) -> Result<Vec<OsString>, String> {
+        let mut args = Vec::new();
+        let path = dir.join(PINNED_CA_FILE);
+        match &self.ca_pem {
+            Some(pem) => {
+                std::fs::write(&path, pem).map_err(|e| format!("Writing {} failed: {}", path.display(), e))?;
+                args.extend([OsString::from("--gateway-ca"), path.into_os_string()]);
+            }
+            // A file from an earlier pin would only confuse
+            None => {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+        if let Some(pin) = &self.spki_sha256 {
+            args.extend([OsString::from("--gateway-pin"), OsString::from(pin)]);
+        }
+        Ok(args)
+    }
+}
+
+/// Base64 SHA-256 of the SubjectPublicKeyInfo of the certificate `der`.
+pub fn spki_sha256(der: &[u8]) -> Result<String, String> {
+    let (_, cert) =
+        x509_parser::parse_x509_certificate(der).map_err(|e| format!("Unreadable gateway certificate: {}", e))?;
+    Ok(STANDARD.encode(Sha256::digest(cert.public_key().raw)))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayCheck {
+    /// A TCP connection to the gateway succeeded.
+    pub reachable: bool,
+    /// Time to the health response, or to connect when no path is checked.
+    pub latency_ms: Option<u64>,
+    /// Certificate chain and host name verified; None for http gateways.
+    pub tls_ok: Option<bool>,
+    /// The certificate matched the configured pin; None without one.
+    pub pin_ok: Option<bool>,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+}
+
+impl GatewayCheck {
+    fn failed(error: String) -> Self {
+        GatewayCheck {
+            reachable: false,
+            latency_ms: None,
+            tls_ok: None,
+            pin_ok: None,
+            status_code: None,
+            error: Some(error),
+        }
+    }
+}
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+/// Probe the gateway within `timeout`: connect (through `upstream` unless it
+/// is empty), verify TLS for https, and `pin` if set, then GET `health_path`
+/// (relative to the gateway URL) unless it is empty.
+pub fn check(raw_url: &str, health_path: &str, timeout: Duration, upstream: &str, pin: &Pin) -> GatewayCheck {
+    let url = match normalize(raw_url) {
+        Ok(url) if url.is_empty() => return GatewayCheck::failed("No gateway URL (passthrough mode)".into()),
+        Ok(url) => url,
+        Err(e) => return GatewayCheck::failed(e),
+    };
+    let Ok(url) = Url::parse(&url) else {
+        return GatewayCheck::failed(format!("Invalid gateway URL {:?}", url));
+    };
+    let host = url.host_str().unwrap_or_default().to_string();
+    if pin.is_set() && url.scheme() != "https" {
+        return GatewayCheck {
+            pin_ok: Some(false),
+            ..GatewayCheck::failed("A pinned gateway has to be https".into())
+        };
+    }
+
+    let started = Instant::now();
+    let deadline = started + timeout;
+    let remaining = || deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+
+    let connected = if upstream.is_empty() {
+        connect_direct(&url, &host, remaining())
+    } else {
+        crate::upstream::connect(upstream, &host, url.port_or_known_default().unwrap_or(80), remaining())
+    };
+    let tcp = match connected {
+        Ok(tcp) => tcp,
+        Err(e) => return GatewayCheck::failed(e),
+    };
+    let mut result = GatewayCheck {
+        reachable: true,
+        latency_ms: Some(started.elapsed().as_millis() as u64),
+        tls_ok: None,
+        pin_ok: None,
+        status_code: None,
+        error: None,
+    };
+    let _ = tcp.set_read_timeout(Some(remaining()));
+    let _ = tcp.set_write_timeout(Some(remaining()));
+
+    let mut stream: Box<dyn Stream> = if url.scheme() == "https" {
+        // Err(message, whether the certificate was rejected)
+        let tls = pin.connector().map_err(|e| (e, false)).and_then(|connector| {
+            connector.connect(&host, tcp).map_err(|e| match e {
+                native_tls::HandshakeError::Failure(e) if pin.ca_pem.is_some() => {
+                    (format!("TLS verification against the pinned CA failed: {}", e), true)
+                }
+                native_tls::HandshakeError::Failure(e) => (format!("TLS verification failed: {}", e), true),
+                native_tls::HandshakeError::WouldBlock(_) => ("TLS handshake timed out".into(), false),
+            })
+        });
+        let tls = match tls {
+            Ok(tls) => tls,
+            Err((e, rejected)) => {
+                result.tls_ok = Some(false);
+                // A chain that doesn't lead to the pinned CA fails the handshake
+                if rejected && pin.ca_pem.is_some() {
+                    result.pin_ok = Some(false);
+                }
+                result.error = Some(e);
+                return result;
+            }
+        };
+        result.tls_ok = Some(true);
+        if pin.is_set() {
+            let pinned = pin.check_peer(tls.peer_certificate().ok().flatten());
+            result.pin_ok = Some(pinned.is_ok());
+            if let Err(e) = pinned {
+                result.error = Some(e);
+                return result;
+            }
+        }
+        Box::new(tls)
+    } else {
+        Box::new(tcp)
+    };
+
+    if health_path.is_empty() {
+        return result;
+    }
+    let path = format!("{}/{}", url.path().trim_end_matches('/'), health_path.trim_start_matches('/'));
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.clone(),
+    };
+    match request_status(&mut stream, &path, &authority) {
+        Ok(code) => {
+            result.status_code = Some(code);
+            result.latency_ms = Some(started.elapsed().as_millis() as u64);
+            if !(200..300).contains(&code) {
+                result.error = Some(format!("{} returned HTTP {}", path, code));
+            }
+        }
+        Err(e) => result.error = Some(format!("GET {} failed: {}", path, e)),
+    }
+    result
+}
+
+pub fn connect_direct(url: &Url, host: &str, timeout: Duration) -> Result<TcpStream, String> {
+    let deadline = Instant::now() + timeout;
+    let remaining = || deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+    let addrs = url.socket_addrs(|| None).map_err(|e| format!("Cannot resolve {}: {}", host, e))?;
+    let mut last_error = format!("No addresses for {}", host);
+    addrs
+        .iter()
+        .find_map(|addr| {
+            TcpStream::connect_timeout(addr, remaining())
+                .map_err(|e| last_error = format!("Connect to {} failed: {}", addr, e))
+                .ok()
+        })
+        .ok_or(last_error)
+}
+
+/// Send a bodiless GET and parse the status code from the response line.
+fn request_status(stream: &mut dyn Stream, path: &str, authority: &str) -> Result<u16, String> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: cyber-drill-safe\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        path, authority
+    );
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+
+    let mut head = Vec::new();
+    let mut buf = [0u8; 512];
+    while !head.windows(2).any(|w| w == b"\r\n") {
+        let n = stream.read(&mut buf).map_err(|e| e.to_string())?;
+        if n == 0 || head.len() > 8192 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let line = String::from_utf8_lossy(&head);
+    let line = line.lines().next().unwrap_or_default();
+    line.strip_prefix("HTTP/")
+        .and_then(|rest| rest.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("Unexpected response {:?}", line))
+}
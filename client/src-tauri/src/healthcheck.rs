@@ -0,0 +1,151 @@
+//! The proxy's own health route, asked directly on its port rather than
+//! through the hosts file: TLS verified against our CA alone (a failure there
+//! means the proxy serves certificates we didn't issue, i.e. stale ones),
+//! then a JSON body with its version, uptime and last upstream outcome.
+
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Passed to the proxy as `--health-route`; requests for it are answered by
+/// the proxy itself and never forwarded.
+pub const DEFAULT_ROUTE: &str = "/__health";
+pub const DEFAULT_INTERVAL_SECS: u64 = 5;
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Caps what is read of a response that isn't ours.
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+/// What the proxy last got from where it forwards to.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Upstream {
+    /// gateway or passthrough.
+    pub mode: String,
+    /// Unix millis of the last forwarded request; None before the first.
+    pub last_at: Option<u64>,
+    /// Whether it got an answer.
+    pub ok: Option<bool>,
+    pub error: Option<String>,
+}
+
+/// The body of the health route.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Body {
+    /// Of local-proxy.js, or of the app for the built-in proxy.
+    pub version: Option<String>,
+    pub uptime_secs: u64,
+    pub upstream: Upstream,
+}
+
+/// One check, as kept for proxy_status.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Check {
+    /// Unix millis.
+    pub at: u64,
+    pub latency_ms: u64,
+    pub ok: bool,
+    pub body: Option<Body>,
+    pub error: Option<String>,
+    /// The TLS handshake failed against our CA.
+    pub cert_mismatch: bool,
+}
+
+pub enum Failure {
+    /// Nothing accepted the connection.
+    NotListening(String),
+    /// The proxy's certificate doesn't verify against our CA.
+    CertMismatch(String),
+    /// Connected, but no healthy answer.
+    Failed(String),
+}
+
+impl Failure {
+    pub fn message(&self) -> &str {
+        match self {
+            Failure::NotListening(m) | Failure::CertMismatch(m) | Failure::Failed(m) => m,
+        }
+    }
+}
+
+/// A path the proxy can match exactly: starts with `/`, no query, fragment,
+/// whitespace or control characters.
+pub fn normalize_route(route: &str) -> Result<String, String> {
+    let route = route.trim();
+    if route.is_empty() {
+        return Ok(DEFAULT_ROUTE.to_string());
+    }
+    if !route.starts_with('/') || route == "/" {
+        return Err(format!("The health route {:?} must start with / and name a path", route));
+    }
+    if route.chars().any(|c| c.is_whitespace() || c.is_control() || c == '?' || c == '#') {
+        return Err(format!("The health route {:?} can't contain whitespace, ? or #", route));
+    }
+    Ok(route.to_string())
+}
+
+/// GET `route` from the proxy on 127.0.0.1:`port`, verifying its certificate
+/// for `domain` against the CA in `ca_pem`.
+pub fn check(port: u16, domain: &str, route: &str, ca_pem: &Path, timeout: Duration) -> Result<Body, Failure> {
+    let ca = std::fs::read(ca_pem)
+        .map_err(|e| Failure::Failed(format!("Reading our CA {} failed: {}", ca_pem.display(), e)))?;
+    let ca = native_tls::Certificate::from_pem(&ca).map_err(|e| Failure::Failed(format!("Our CA: {}", e)))?;
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(ca)
+        .disable_built_in_roots(true)
+        .build()
+        .map_err(|e| Failure::Failed(e.to_string()))?;
+
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let tcp = TcpStream::connect_timeout(&addr, timeout)
+        .map_err(|e| Failure::NotListening(format!("Connect to {} failed: {}", addr, e)))?;
+    let _ = tcp.set_read_timeout(Some(timeout));
+    let _ = tcp.set_write_timeout(Some(timeout));
+    let mut tls = connector.connect(domain, tcp).map_err(|e| match e {
+        native_tls::HandshakeError::Failure(e) => Failure::CertMismatch(format!(
+            "The proxy's certificate for {} doesn't verify against our CA ({}); the certificates are probably stale",
+            domain, e
+        )),
+        native_tls::HandshakeError::WouldBlock(_) => Failure::Failed("TLS handshake timed out".into()),
+    })?;
+
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", route, domain);
+    tls.write_all(request.as_bytes()).map_err(|e| Failure::Failed(format!("Sending the request failed: {}", e)))?;
+    let mut response = Vec::new();
+    // A read timeout after the body arrived still leaves a usable response
+    let read = Read::by_ref(&mut tls).take(MAX_RESPONSE).read_to_end(&mut response);
+    if response.is_empty() {
+        let detail = read.err().map_or("connection closed".to_string(), |e| e.to_string());
+        return Err(Failure::Failed(format!("No response: {}", detail)));
+    }
+
+    let text = String::from_utf8_lossy(&response);
+    let (head, body) = text.split_once("\r\n\r\n").unwrap_or((&text, ""));
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        return Err(Failure::Failed(format!("The health route answered {}", head.lines().next().unwrap_or_default())));
+    }
+    serde_json::from_str(body.trim()).map_err(|e| Failure::Failed(format!("Unreadable health response: {}", e)))
+}
+
+/// `check`, recorded with its time and outcome.
+pub fn sample(port: u16, domain: &str, route: &str, ca_pem: &Path) -> Check {
+    let started = Instant::now();
+    let result = check(port, domain, route, ca_pem, CHECK_TIMEOUT);
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let at = crate::now_millis();
+    match result {
+        Ok(body) => Check { at, latency_ms, ok: true, body: Some(body), error: None, cert_mismatch: false },
+        Err(failure) => Check {
+            at,
+            latency_ms,
+            ok: false,
+            body: None,
+            error: Some(failure.message().to_string()),
+            cert_mismatch: matches!(failure, Failure::CertMismatch(_)),
+        },
+    }
+}
@@ -0,0 +1,161 @@
+//! The line protocol with the proxy child, built-in or local-proxy.js: it
+//! writes a JSON heartbeat carrying its traffic counters to stdout every few
+//! seconds, a JSON summary of every request it answers, and exits on a JSON
+//! shutdown line from stdin. A process that exists but stopped heartbeating
+//! isn't serving.
+
+use crate::traffic::{RequestSummary, SharedTraffic};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Bumped whenever the messages below change shape; reported by `versions`.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The proxy beats every 5s; three missed beats mean it's stuck.
+pub const STALE_AFTER: Duration = Duration::from_secs(15);
+pub const SHUTDOWN_LINE: &[u8] = b"{\"type\":\"shutdown\"}\n";
+const MINUTE_MS: u64 = 60_000;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Message {
+    #[serde(rename_all = "camelCase")]
+    Heartbeat {
+        connections: u64,
+        #[serde(flatten)]
+        totals: Counters,
+        #[serde(default)]
+        domains: BTreeMap<String, DomainCounters>,
+    },
+    Request(RequestSummary),
+}
+
+/// Cumulative since the child started.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Counters {
+    /// Requests answered.
+    pub requests: u64,
+    /// Bytes on the client-side TLS connections, TLS overhead included.
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl Counters {
+    fn since(self, earlier: Counters) -> Counters {
+        Counters {
+            requests: self.requests.saturating_sub(earlier.requests),
+            bytes_in: self.bytes_in.saturating_sub(earlier.bytes_in),
+            bytes_out: self.bytes_out.saturating_sub(earlier.bytes_out),
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DomainCounters {
+    /// Requests received for the domain.
+    pub requests: u64,
+    /// Unix milliseconds.
+    pub last_request_at: Option<u64>,
+}
+
+/// What the heartbeats of the current child said.
+#[derive(Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Health {
+    /// Unix milliseconds; None until the first heartbeat.
+    pub last_heartbeat: Option<u64>,
+    /// Open client connections.
+    pub connections: u64,
+    #[serde(flatten)]
+    pub totals: Counters,
+    pub domains: BTreeMap<String, DomainCounters>,
+    /// (unix ms, totals) of recent heartbeats, oldest first, reaching back
+    /// just past a minute.
+    #[serde(skip)]
+    samples: VecDeque<(u64, Counters)>,
+}
+
+/// proxy_metrics: the cumulative figures plus the last minute's.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metrics {
+    #[serde(flatten)]
+    pub health: Health,
+    pub last_minute: Counters,
+    /// The child's memory and CPU, from its monitor rather than heartbeats.
+    pub resources: crate::usage::Usage,
+}
+
+impl Health {
+    /// A heartbeat arrived within STALE_AFTER of `now_ms`.
+    pub fn is_fresh(&self, now_ms: u64) -> bool {
+        self.last_heartbeat
+            .is_some_and(|at| now_ms.saturating_sub(at) <= STALE_AFTER.as_millis() as u64)
+    }
+
+    /// Traffic in the minute before `now_ms`. While the child is younger
+    /// than a minute, everything since it started.
+    pub fn last_minute(&self, now_ms: u64) -> Counters {
+        let cutoff = now_ms.saturating_sub(MINUTE_MS);
+        let baseline = self
+            .samples
+            .iter()
+            .rev()
+            .find(|(at, _)| *at <= cutoff)
+            .map(|&(_, counters)| counters)
+            .unwrap_or_default();
+        self.totals.since(baseline)
+    }
+
+    pub fn metrics(&self, now_ms: u64, resources: crate::usage::Usage) -> Metrics {
+        Metrics { health: self.clone(), last_minute: self.last_minute(now_ms), resources }
+    }
+
+    fn beat(&mut self, now_ms: u64, connections: u64, totals: Counters, domains: BTreeMap<String, DomainCounters>) {
+        self.last_heartbeat = Some(now_ms);
+        self.connections = connections;
+        self.totals = totals;
+        self.domains = domains;
+        self.samples.push_back((now_ms, totals));
+        // Keep one sample at least a minute old as the baseline
+        let cutoff = now_ms.saturating_sub(MINUTE_MS);
+        while self.samples.get(1).is_some_and(|&(at, _)| at <= cutoff) {
+            self.samples.pop_front();
+        }
+    }
+}
+
+pub type SharedHealth = Arc<Mutex<Health>>;
+
+/// Where the protocol messages on the child's stdout end up.
+#[derive(Clone)]
+pub struct Telemetry {
+    pub health: SharedHealth,
+    pub traffic: SharedTraffic,
+}
+
+impl Telemetry {
+    /// Record `line` if it is a protocol message. Returns whether it was
+    /// one, so it can be kept out of the log. Anything else node prints,
+    /// JSON or not, is left alone.
+    pub fn record(&self, line: &str, now_ms: u64) -> bool {
+        if !line.starts_with('{') {
+            return false;
+        }
+        match serde_json::from_str(line) {
+            Ok(Message::Heartbeat { connections, totals, domains }) => {
+                self.health.lock().beat(now_ms, connections, totals, domains);
+                true
+            }
+            Ok(Message::Request(summary)) => {
+                self.traffic.lock().push(summary);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
@@ -0,0 +1,1909 @@
+//! The system hosts file: parsing, our marker block of entries, and atomic
+//! writes.
+
+use crate::error::ProxyError;
+use serde::Serialize;
+use std::fs;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+#[cfg(target_os = "windows")]
+const DEFAULT_PATH: &str = r"C:\Windows\System32\drivers\etc\hosts";
+#[cfg(not(target_os = "windows"))]
+const DEFAULT_PATH: &str = "/etc/hosts";
+
+/// Points the app at a different hosts file (test runners, managed images
+/// that relocate it).
+pub const PATH_ENV: &str = "CYBER_DRILL_HOSTS";
+
+/// Our entries live between these two comment lines.
+const BLOCK_BEGIN: &str = "# cyber-drill-safe BEGIN";
+const BLOCK_END: &str = "# cyber-drill-safe END";
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+const UTF16LE_BOM: &[u8] = b"\xFF\xFE";
+const UTF16BE_BOM: &[u8] = b"\xFE\xFF";
+
+/// Antivirus and EDR agents hold the file exclusively for a few hundred
+/// milliseconds while scanning it; reads and writes are retried that long.
+const LOCK_ATTEMPTS: u32 = 5;
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// In the app data dir, which the app, the service (it is passed the app's)
+/// and a second copy of the app share. Held around every read-modify-write.
+const UPDATE_LOCK_FILE: &str = "hosts.cyber-drill.lock";
+const UPDATE_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+const UPDATE_LOCK_POLL: Duration = Duration::from_millis(50);
+
+/// Each domain gets one entry per address family, so resolvers that prefer
+/// AAAA don't fall through to real DNS.
+pub const LOOPBACKS: [&str; 2] = ["127.0.0.1", "::1"];
+
+/// Lowercase, strip a trailing dot, validate and deduplicate the domains
+/// proxy_run should intercept, keeping the caller's order.
+pub fn normalize_domains(domains: &[String]) -> Result<Vec<String>, ProxyError> {
+    let mut out: Vec<String> = Vec::new();
+    for raw in domains {
+        let domain = raw.trim().trim_end_matches('.').to_ascii_lowercase();
+        let valid = !domain.is_empty()
+            && domain.len() <= 253
+            && domain.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !valid {
+            return Err(ProxyError::InvalidArgument(format!("Invalid domain: {:?}", raw)));
+        }
+        if !out.contains(&domain) {
+            out.push(domain);
+        }
+    }
+    if out.is_empty() {
+        return Err(ProxyError::InvalidArgument("At least one domain is required".into()));
+    }
+    Ok(out)
+}
+
+/// `(address, host)` when the line maps a loopback address to exactly one
+/// hostname, i.e. the shape of the entries proxy_run writes. Inline comments
+/// and any whitespace between fields are tolerated; commented-out lines and
+/// lines carrying extra hostnames are not ours.
+fn loopback_entry(line: &str) -> Option<(&str, &str)> {
+    let active = line.split('#').next().unwrap_or("");
+    let mut fields = active.split_whitespace();
+    let addr = fields.next().filter(|a| LOOPBACKS.contains(a))?;
+    let host = fields.next()?;
+    fields.next().is_none().then_some((addr, host))
+}
+
+fn is_managed_entry(line: &str, domains: &[String]) -> bool {
+    loopback_entry(line).is_some_and(|(_, host)| domains.iter().any(|d| host.eq_ignore_ascii_case(d)))
+}
+
+/// Address and first host of a loopback line, for claiming the lines under
+/// a BEGIN marker.
+fn entry_fields(line: &str) -> Option<(&str, &str)> {
+    let active = line.split('#').next().unwrap_or("");
+    let mut fields = active.split_whitespace();
+    let addr = fields.next().filter(|a| LOOPBACKS.contains(a))?;
+    Some((addr, fields.next()?))
+}
+
+fn entry_host(line: &str) -> Option<&str> {
+    entry_fields(line).map(|(_, host)| host)
+}
+
+fn is_marker(line: &str, marker: &str) -> bool {
+    line.trim() == marker
+}
+
+/// How the hosts file was stored on disk; it is written back the same way.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Encoding {
+    Utf8,
+    Utf8Bom,
+    /// Notepad's "Unicode" (with BOM).
+    Utf16Le,
+    Utf16Be,
+    /// Not valid UTF-8, e.g. Latin-1 comments from old tooling. Each byte is
+    /// read as the char of the same value and written back as that byte, so
+    /// the lines we don't touch survive unchanged.
+    Latin1,
+}
+
+/// The hosts file split into lines that keep their own terminators, so lines
+/// we don't touch are written back byte-for-byte.
+#[derive(Clone)]
+pub struct HostsText {
+    encoding: Encoding,
+    line_ending: &'static str,
+    lines: Vec<String>,
+    /// Why decoding lost bytes, so writing the text back would change lines
+    /// we never meant to touch.
+    unwritable: Option<&'static str>,
+}
+
+impl HostsText {
+    pub fn parse(bytes: &[u8]) -> Self {
+        let (encoding, text, unwritable) = decode(bytes);
+
+        let crlf = text.matches("\r\n").count();
+        let lf = text.matches('\n').count() - crlf;
+        let line_ending = if crlf > lf || (crlf == lf && cfg!(target_os = "windows")) {
+            "\r\n"
+        } else {
+            "\n"
+        };
+
+        HostsText {
+            encoding,
+            line_ending,
+            lines: text.split_inclusive('\n').map(str::to_string).collect(),
+            unwritable,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Lines inside our marker blocks, markers included.
+    pub fn block_line_count(&self) -> usize {
+        self.scan_blocks().ranges.iter().map(|r| r.len()).sum()
+    }
+
+    /// Err when the file couldn't be decoded without loss; it is read, but
+    /// never rewritten.
+    pub fn check_writable(&self) -> Result<(), ProxyError> {
+        match self.unwritable {
+            Some(reason) => Err(ProxyError::Hosts(format!(
+                "Refusing to write hosts: {}, so rewriting it would change lines we don't own; re-save it as \
+                 UTF-8 and try again",
+                reason
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let text = self.lines.concat();
+        match self.encoding {
+            Encoding::Utf8 => text.into_bytes(),
+            Encoding::Utf8Bom => [UTF8_BOM, text.as_bytes()].concat(),
+            Encoding::Utf16Le => {
+                let mut out = UTF16LE_BOM.to_vec();
+                out.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+                out
+            }
+            Encoding::Utf16Be => {
+                let mut out = UTF16BE_BOM.to_vec();
+                out.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+                out
+            }
+            // Only chars that came from single bytes (or our ASCII entries) are in here
+            Encoding::Latin1 => text.chars().map(|c| u8::try_from(c).unwrap_or(b'?')).collect(),
+        }
+    }
+
+    /// Every marker block, markers included, plus what was wrong with them.
+    /// A BEGIN without an END only claims the entry lines right after it, so a
+    /// crash mid-write can't swallow the rest of the user's file; a lone END
+    /// is a one-line block of its own.
+    fn scan_blocks(&self) -> BlockScan {
+        let mut scan = BlockScan::default();
+        let mut i = 0;
+        while i < self.lines.len() {
+            if is_marker(&self.lines[i], BLOCK_END) {
+                scan.ranges.push(i..i + 1);
+                scan.orphan_ends += 1;
+                i += 1;
+                continue;
+            }
+            if !is_marker(&self.lines[i], BLOCK_BEGIN) {
+                i += 1;
+                continue;
+            }
+            let rest = &self.lines[i + 1..];
+            let next_marker = rest
+                .iter()
+                .position(|l| is_marker(l, BLOCK_BEGIN) || is_marker(l, BLOCK_END))
+                .map(|p| p + i + 1);
+            match next_marker {
+                Some(end) if is_marker(&self.lines[end], BLOCK_END) => {
+                    scan.ranges.push(i..end + 1);
+                    i = end + 1;
+                }
+                _ => {
+                    let entries = rest.iter().take_while(|l| entry_host(l).is_some()).count();
+                    scan.ranges.push(i..i + 1 + entries);
+                    scan.unterminated += 1;
+                    i += 1 + entries;
+                }
+            }
+        }
+        scan
+    }
+
+    /// The entry lines inside our marker blocks.
+    pub fn block_entries(&self) -> Vec<HostsEntry> {
+        self.scan_blocks()
+            .ranges
+            .into_iter()
+            .flat_map(|range| self.lines[range].iter().filter_map(|l| entry_fields(l)))
+            .map(|(address, host)| HostsEntry { address: address.to_string(), host: host.to_ascii_lowercase() })
+            .collect()
+    }
+
+    /// Whether any of our marker blocks (even an empty or broken one) is present.
+    pub fn has_block(&self) -> bool {
+        !self.scan_blocks().ranges.is_empty()
+    }
+
+    /// Domains our marker blocks currently map to 127.0.0.1.
+    pub fn block_domains(&self) -> Vec<String> {
+        let mut domains: Vec<String> = Vec::new();
+        for range in self.scan_blocks().ranges {
+            for host in self.lines[range].iter().filter_map(|l| entry_host(l)) {
+                let host = host.to_ascii_lowercase();
+                if !domains.contains(&host) {
+                    domains.push(host);
+                }
+            }
+        }
+        domains
+    }
+
+    /// Problems with our markers worth telling the user about.
+    pub fn block_warnings(&self) -> Vec<String> {
+        let scan = self.scan_blocks();
+        let mut warnings = Vec::new();
+        if scan.unterminated > 0 {
+            warnings.push(format!(
+                "{} BEGIN marker(s) without a matching END; only the entries directly below were treated as ours",
+                scan.unterminated
+            ));
+        }
+        if scan.orphan_ends > 0 {
+            warnings.push(format!("{} END marker(s) without a BEGIN", scan.orphan_ends));
+        }
+        let blocks = scan.ranges.len() - scan.orphan_ends;
+        if blocks > 1 {
+            warnings.push(format!("{} marker blocks found (left by earlier crashes); they are merged into one", blocks));
+        }
+        warnings
+    }
+
+    /// The file for a support bundle: our blocks, entries for `domains` and
+    /// blank lines as they are, every other line replaced by a hash of it so
+    /// the user's own entries and comments don't leave the machine.
+    pub fn redacted(&self, domains: &[String]) -> String {
+        let ranges = self.scan_blocks().ranges;
+        let mut out = String::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            let content = line.trim_end_matches(['\r', '\n']);
+            if content.trim().is_empty() || ranges.iter().any(|r| r.contains(&i)) || is_managed_entry(line, domains) {
+                out.push_str(content);
+            } else {
+                out.push_str(&format!("# redacted {}", &crate::audit::sha256_hex(content.as_bytes())[..16]));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Entries for `domains` outside our blocks, e.g. left by older versions or added by hand.
+    pub fn stray_entries(&self, domains: &[String]) -> Vec<String> {
+        let ranges = self.scan_blocks().ranges;
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(i, l)| !ranges.iter().any(|r| r.contains(i)) && is_managed_entry(l, domains))
+            .map(|(_, l)| l.trim().to_string())
+            .collect()
+    }
+
+    /// Leftovers from earlier runs: entries for `domains` outside our blocks,
+    /// plus entries repeated inside them (whatever their spacing or case).
+    pub fn stale_entry_count(&self, domains: &[String]) -> usize {
+        let ranges = self.scan_blocks().ranges;
+        let mut seen: Vec<(&str, String)> = Vec::new();
+        let mut stale = 0;
+        for (i, line) in self.lines.iter().enumerate() {
+            let Some((addr, host)) = loopback_entry(line) else {
+                continue;
+            };
+            if !domains.iter().any(|d| host.eq_ignore_ascii_case(d)) {
+                continue;
+            }
+            let key = (addr, host.to_ascii_lowercase());
+            if !ranges.iter().any(|r| r.contains(&i)) || seen.contains(&key) {
+                stale += 1;
+            } else {
+                seen.push(key);
+            }
+        }
+        stale
+    }
+
+    /// Every entry attributable to us: the entry lines inside our blocks, and
+    /// entries for `domains` outside them.
+    pub fn managed_entries(&self, domains: &[String]) -> Vec<ManagedEntry> {
+        let ranges = self.scan_blocks().ranges;
+        self.lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                let inside = ranges.iter().any(|r| r.contains(&i));
+                if !inside && !is_managed_entry(line, domains) {
+                    return None;
+                }
+                let (ip, host) = entry_fields(line)?;
+                Some(ManagedEntry {
+                    ip: ip.to_string(),
+                    domain: host.to_ascii_lowercase(),
+                    line_number: i + 1,
+                    inside_marker_block: inside,
+                })
+            })
+            .collect()
+    }
+
+    /// The lines `apply_entries` would remove and add, worked out by applying
+    /// it to a copy. Empty when ensure_entry wouldn't write.
+    pub fn preview_entries(&self, domains: &[String]) -> Vec<LineChange> {
+        if !self.needs_update(domains) {
+            return Vec::new();
+        }
+        let mut after = self.clone();
+        after.apply_entries(domains);
+        let ranges = self.scan_blocks().ranges;
+        let content = |line: &String| line.trim_end_matches(['\r', '\n']).to_string();
+        let mut removed: Vec<LineChange> = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(i, l)| ranges.iter().any(|r| r.contains(i)) || is_managed_entry(l, domains))
+            .map(|(i, l)| LineChange { op: ChangeOp::Remove, line_number: i + 1, text: content(l) })
+            .collect();
+        let block = after.scan_blocks().ranges.into_iter().next().unwrap_or_default();
+        let mut added: Vec<LineChange> = block
+            .map(|i| LineChange { op: ChangeOp::Add, line_number: i + 1, text: content(&after.lines[i]) })
+            .collect();
+        // A single intact block that is rewritten as it was isn't a change;
+        // only the strays around it are
+        if let [range] = ranges.as_slice() {
+            let old_block: Vec<String> = self.lines[range.clone()].iter().map(content).collect();
+            if added.iter().map(|c| &c.text).eq(old_block.iter()) {
+                removed.retain(|c| !range.contains(&(c.line_number - 1)));
+                added.clear();
+            }
+        }
+        removed.extend(added);
+        removed
+    }
+
+    /// Leave exactly one block mapping `domains` (both families) and drop
+    /// copies of those entries elsewhere: what proxy_run writes.
+    pub fn apply_entries(&mut self, domains: &[String]) {
+        self.set_block(domains);
+        self.remove_stray_entries(domains);
+    }
+
+    /// Whether some line maps `addr` to `domain`.
+    pub fn has_entry(&self, addr: &str, domain: &str) -> bool {
+        self.lines
+            .iter()
+            .filter_map(|l| loopback_entry(l))
+            .any(|(a, host)| a == addr && host.eq_ignore_ascii_case(domain))
+    }
+
+    /// The `address domain` entries proxy_run would write that are absent.
+    pub fn missing_entries(&self, domains: &[String]) -> Vec<String> {
+        domains
+            .iter()
+            .flat_map(|d| LOOPBACKS.iter().map(move |addr| (*addr, d)))
+            .filter(|(addr, d)| !self.has_entry(addr, d))
+            .map(|(addr, d)| format!("{} {}", addr, d))
+            .collect()
+    }
+
+    /// Whether the file differs from a single clean block for exactly
+    /// `domains`: a domain or family is missing, the markers are damaged or
+    /// duplicated, or copies of our entries sit elsewhere.
+    pub fn needs_update(&self, domains: &[String]) -> bool {
+        self.block_domains() != domains
+            || !self.missing_entries(domains).is_empty()
+            || !self.block_warnings().is_empty()
+            || self.stale_entry_count(domains) > 0
+    }
+
+    /// Our block is present, or a loose entry for one of `domains` is.
+    pub fn is_modified(&self, domains: &[String]) -> bool {
+        !self.scan_blocks().ranges.is_empty() || self.lines.iter().any(|l| is_managed_entry(l, domains))
+    }
+
+    fn terminate_last_line(&mut self) {
+        if let Some(last) = self.lines.last_mut() {
+            if !last.ends_with('\n') {
+                last.push_str(self.line_ending);
+            }
+        }
+    }
+
+    /// Remove every block, returning where the first one started.
+    fn drain_blocks(&mut self) -> Option<usize> {
+        let ranges = self.scan_blocks().ranges;
+        let first = ranges.first().map(|r| r.start);
+        for range in ranges.into_iter().rev() {
+            self.lines.drain(range);
+        }
+        first
+    }
+
+    /// Replace all of our blocks with a single one holding one entry per
+    /// domain and address family, where the first block was (or at the end).
+    /// Nothing outside the blocks is touched.
+    pub fn set_block(&mut self, domains: &[String]) {
+        let at = self.drain_blocks();
+        let mut block = vec![format!("{}{}", BLOCK_BEGIN, self.line_ending)];
+        for domain in domains {
+            block.extend(LOOPBACKS.iter().map(|addr| format!("{} {}{}", addr, domain, self.line_ending)));
+        }
+        block.push(format!("{}{}", BLOCK_END, self.line_ending));
+        match at {
+            Some(at) => {
+                self.lines.splice(at..at, block);
+            }
+            None => {
+                self.terminate_last_line();
+                self.lines.extend(block);
+            }
+        }
+    }
+
+    /// Drop our blocks, markers included, and nothing else.
+    pub fn remove_block(&mut self) {
+        self.drain_blocks();
+    }
+
+    /// Drop entries for `domains` outside our blocks.
+    pub fn remove_stray_entries(&mut self, domains: &[String]) {
+        let ranges = self.scan_blocks().ranges;
+        let mut i = 0;
+        self.lines.retain(|l| {
+            let keep = ranges.iter().any(|r| r.contains(&i)) || !is_managed_entry(l, domains);
+            i += 1;
+            keep
+        });
+    }
+}
+
+/// Work out the encoding from the BOM, falling back to Latin-1 when the bytes
+/// aren't UTF-8. Also returns why the text can't be written back as it was
+/// read, when it can't: broken UTF-16 (an odd trailing byte, an unpaired
+/// surrogate) decodes to U+FFFD, and a UTF-8 BOM ahead of bytes that aren't
+/// UTF-8 is dropped so the first line still parses.
+fn decode(bytes: &[u8]) -> (Encoding, String, Option<&'static str>) {
+    let latin1 = |body: &[u8]| -> String { body.iter().copied().map(char::from).collect() };
+    if let Some(body) = bytes.strip_prefix(UTF8_BOM) {
+        return match std::str::from_utf8(body) {
+            Ok(text) => (Encoding::Utf8Bom, text.to_string(), None),
+            Err(_) => (Encoding::Latin1, latin1(body), Some("it starts with a UTF-8 BOM but isn't UTF-8")),
+        };
+    }
+    if let Some(body) = bytes.strip_prefix(UTF16LE_BOM) {
+        let (text, unwritable) = decode_utf16(body, u16::from_le_bytes);
+        return (Encoding::Utf16Le, text, unwritable);
+    }
+    if let Some(body) = bytes.strip_prefix(UTF16BE_BOM) {
+        let (text, unwritable) = decode_utf16(body, u16::from_be_bytes);
+        return (Encoding::Utf16Be, text, unwritable);
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (Encoding::Utf8, text.to_string(), None),
+        Err(_) => (Encoding::Latin1, latin1(bytes), None),
+    }
+}
+
+fn decode_utf16(body: &[u8], unit: fn([u8; 2]) -> u16) -> (String, Option<&'static str>) {
+    let units = body.chunks_exact(2).map(|c| unit([c[0], c[1]]));
+    let mut unpaired = false;
+    let mut text: String = char::decode_utf16(units)
+        .map(|c| {
+            c.unwrap_or_else(|_| {
+                unpaired = true;
+                char::REPLACEMENT_CHARACTER
+            })
+        })
+        .collect();
+    if !body.len().is_multiple_of(2) {
+        text.push(char::REPLACEMENT_CHARACTER);
+        return (text, Some("it is UTF-16 with an odd number of bytes"));
+    }
+    (text, unpaired.then_some("it is UTF-16 with an unpaired surrogate"))
+}
+
+/// What the read-only checks (proxy_status, proxy_initialize, the hosts
+/// watch) need from a hosts file: our marker blocks with the entries in them
+/// and the entries for the domains it was read for. Each run of other lines
+/// is kept as one blank line, so a file carrying a blocklist of hundreds of
+/// thousands of lines is streamed through rather than held. There is no way
+/// to write it back.
+#[derive(Clone)]
+pub struct HostsSummary {
+    text: HostsText,
+    domains: Vec<String>,
+}
+
+impl HostsSummary {
+    fn read(mut reader: impl BufRead, domains: &[String]) -> std::io::Result<Self> {
+        let head = reader.fill_buf()?;
+        let utf16 = head.starts_with(UTF16LE_BOM) || head.starts_with(UTF16BE_BOM);
+        let bom = head.starts_with(UTF8_BOM);
+        // UTF-16 can't be split on the newline byte; such files are rare and small
+        if utf16 {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+            let text = HostsText::parse(&bytes);
+            let mut summary = Summarizer::new(text.encoding, domains);
+            text.lines.iter().for_each(|line| summary.push(line));
+            return Ok(summary.finish());
+        }
+        if bom {
+            reader.consume(UTF8_BOM.len());
+        }
+        let mut summary = Summarizer::new(if bom { Encoding::Utf8Bom } else { Encoding::Utf8 }, domains);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            match std::str::from_utf8(&line) {
+                Ok(text) => summary.push(text),
+                Err(_) => {
+                    summary.encoding = Encoding::Latin1;
+                    summary.push(&line.iter().copied().map(char::from).collect::<String>());
+                }
+            }
+            line.clear();
+        }
+        Ok(summary.finish())
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.text.encoding
+    }
+
+    pub fn has_block(&self) -> bool {
+        self.text.has_block()
+    }
+
+    pub fn block_entries(&self) -> Vec<HostsEntry> {
+        self.text.block_entries()
+    }
+
+    pub fn block_domains(&self) -> Vec<String> {
+        self.text.block_domains()
+    }
+
+    pub fn block_warnings(&self) -> Vec<String> {
+        self.text.block_warnings()
+    }
+
+    pub fn stray_entries(&self) -> Vec<String> {
+        self.text.stray_entries(&self.domains)
+    }
+
+    pub fn stale_entry_count(&self) -> usize {
+        self.text.stale_entry_count(&self.domains)
+    }
+
+    pub fn missing_entries(&self) -> Vec<String> {
+        self.text.missing_entries(&self.domains)
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.text.is_modified(&self.domains)
+    }
+}
+
+/// Builds a HostsSummary a line at a time. Inside a block every entry line
+/// is kept, since scan_blocks claims the ones under a BEGIN without an END;
+/// outside, only entries for the domains.
+struct Summarizer<'a> {
+    encoding: Encoding,
+    domains: &'a [String],
+    in_block: bool,
+    lines: Vec<String>,
+}
+
+impl<'a> Summarizer<'a> {
+    fn new(encoding: Encoding, domains: &'a [String]) -> Self {
+        Summarizer { encoding, domains, in_block: false, lines: Vec::new() }
+    }
+
+    fn push(&mut self, line: &str) {
+        let keep = if is_marker(line, BLOCK_BEGIN) || is_marker(line, BLOCK_END) {
+            self.in_block = is_marker(line, BLOCK_BEGIN);
+            true
+        } else if self.in_block {
+            entry_host(line).is_some()
+        } else {
+            is_managed_entry(line, self.domains)
+        };
+        if keep {
+            self.lines.push(line.to_string());
+        } else if self.lines.last().map(String::as_str) != Some("\n") {
+            self.lines.push("\n".to_string());
+        }
+    }
+
+    fn finish(self) -> HostsSummary {
+        let text = HostsText { encoding: self.encoding, line_ending: "\n", lines: self.lines, unwritable: None };
+        HostsSummary { text, domains: self.domains.to_vec() }
+    }
+}
+
+/// An entry hosts_list_managed attributes to us.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ManagedEntry {
+    pub ip: String,
+    pub domain: String,
+    /// 1-based.
+    pub line_number: usize,
+    /// False for a stray entry for one of our domains.
+    pub inside_marker_block: bool,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeOp {
+    Add,
+    Remove,
+}
+
+/// One line of a hosts_preview_changes diff. Removals are numbered as in the
+/// file now, additions as in the file after the change (both 1-based).
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LineChange {
+    pub op: ChangeOp,
+    pub line_number: usize,
+    pub text: String,
+}
+
+/// One `address host` line of our block.
+#[derive(Serialize, Clone)]
+pub struct HostsEntry {
+    pub address: String,
+    pub host: String,
+}
+
+/// The edits the commands make to a hosts file. Implementors only provide the
+/// IO in `load` and `store`; the rules for what gets written live here, so
+/// they behave the same whatever the text is stored in.
+pub trait HostsEditor {
+    fn load(&self) -> Result<HostsText, ProxyError>;
+    /// Called with the lock `lock` returned still held.
+    fn store(&self, text: &HostsText, lock: &UpdateLock) -> Result<(), ProxyError>;
+    /// Held from `load` to `store`, so two editors can't drop each other's
+    /// change.
+    fn lock(&self) -> Result<UpdateLock, ProxyError>;
+
+    /// `store`, but only if `text` has exactly `expected` lines, and checked
+    /// by reading the file back: an edit meant to add or remove a known
+    /// number of lines must not silently cut the user's file short.
+    fn store_lines(&self, text: &HostsText, expected: usize, lock: &UpdateLock) -> Result<(), ProxyError> {
+        text.check_writable()?;
+        if text.line_count() != expected {
+            return Err(ProxyError::Hosts(format!(
+                "Refusing to write hosts: the edit left {} lines where {} were expected",
+                text.line_count(),
+                expected
+            )));
+        }
+        self.store(text, lock)?;
+        let written = self.load()?.line_count();
+        if written != expected {
+            return Err(ProxyError::Hosts(format!(
+                "The hosts file has {} lines after the write where {} were expected; \
+                 put the previous one back with the backup restore",
+                written, expected
+            )));
+        }
+        Ok(())
+    }
+
+    /// The entries currently inside our marker blocks.
+    fn read_entries(&self) -> Result<Vec<HostsEntry>, ProxyError> {
+        Ok(self.load()?.block_entries())
+    }
+
+    /// Leave exactly one block mapping `domains` (both families), dropping
+    /// copies of those entries elsewhere. Returns whether anything was written.
+    fn ensure_entry(&self, domains: &[String]) -> Result<bool, ProxyError> {
+        let lock = self.lock()?;
+        let mut text = self.load()?;
+        if !text.needs_update(domains) {
+            return Ok(false);
+        }
+        let block = 2 + LOOPBACKS.len() * domains.len();
+        let expected = text.line_count() - text.block_line_count() - text.stray_entries(domains).len() + block;
+        text.apply_entries(domains);
+        self.store_lines(&text, expected, &lock)?;
+        Ok(true)
+    }
+
+    /// Remove our blocks, plus entries for `stray` outside them (pass an empty
+    /// slice to leave those alone). Returns whether anything was written.
+    fn remove_entries(&self, stray: &[String]) -> Result<bool, ProxyError> {
+        let lock = self.lock()?;
+        let mut text = self.load()?;
+        let before = text.to_bytes();
+        let expected = text.line_count() - text.block_line_count() - text.stray_entries(stray).len();
+        text.remove_block();
+        text.remove_stray_entries(stray);
+        if text.to_bytes() == before {
+            return Ok(false);
+        }
+        self.store_lines(&text, expected, &lock)?;
+        Ok(true)
+    }
+}
+
+impl HostsEditor for HostsFile {
+    fn load(&self) -> Result<HostsText, ProxyError> {
+        self.read()
+    }
+
+    fn store(&self, text: &HostsText, _lock: &UpdateLock) -> Result<(), ProxyError> {
+        self.write(&text.to_bytes())
+    }
+
+    fn lock(&self) -> Result<UpdateLock, ProxyError> {
+        self.update_lock()
+    }
+}
+
+/// The cross-process lock on a hosts file, released when dropped. Advisory:
+/// it only keeps out writers that take it too, which all of ours do except
+/// the elevated helper, whose launcher holds it for it (`write_elevated`
+/// checks it was given this file's).
+pub struct UpdateLock {
+    file: fs::File,
+    path: PathBuf,
+}
+
+impl Drop for UpdateLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+#[derive(Default)]
+struct BlockScan {
+    ranges: Vec<std::ops::Range<usize>>,
+    unterminated: usize,
+    orphan_ends: usize,
+}
+
+/// The last summary read by `HostsFile::read_cached` and the watch
+/// generation it was read at.
+#[derive(Default)]
+pub struct CachedRead(Option<(u64, HostsSummary)>);
+
+impl CachedRead {
+    pub fn clear(&mut self) {
+        self.0 = None;
+    }
+}
+
+/// What HostsFile::probe_writable found, by the remedy the UI would offer.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum WriteAccess {
+    Writable,
+    /// Needs administrator rights (NOT_ELEVATED).
+    NeedsElevation,
+    /// Elevated, but the read-only attribute can't be undone (HOSTS_READ_ONLY).
+    ReadOnly,
+    /// Elevated, but the ACL denies writing, usually security software
+    /// protecting the file (HOSTS_ACL_DENIED).
+    AccessDenied,
+    /// Another program has the file open (HOSTS_LOCKED).
+    Locked,
+    /// Anything else, e.g. the file is missing.
+    Failed,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteProbe {
+    pub access: WriteAccess,
+    /// The Windows read-only attribute is set; `write` clears it for the
+    /// write and sets it again afterwards.
+    pub read_only: bool,
+    /// The error the write would fail with; None when writable.
+    pub error: Option<ProxyError>,
+}
+
+impl WriteProbe {
+    fn failed(read_only: bool, error: ProxyError) -> Self {
+        let access = match error {
+            ProxyError::NotElevated(_) => WriteAccess::NeedsElevation,
+            ProxyError::HostsReadOnly(_) => WriteAccess::ReadOnly,
+            ProxyError::HostsAclDenied(_) => WriteAccess::AccessDenied,
+            ProxyError::HostsLocked(_) => WriteAccess::Locked,
+            _ => WriteAccess::Failed,
+        };
+        WriteProbe { access, read_only, error: Some(error) }
+    }
+}
+
+/// A hosts file on disk. Every read and write goes through one of these, so
+/// the path is decided once and can point at a scratch file.
+pub struct HostsFile {
+    path: PathBuf,
+    /// The update lock; unset until `set_lock_dir`.
+    lock_file: OnceLock<PathBuf>,
+}
+
+impl HostsFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        HostsFile { path: path.into(), lock_file: OnceLock::new() }
+    }
+
+    /// The file the system resolver reads: `CYBER_DRILL_HOSTS` if set, else
+    /// the registry's DataBasePath on Windows, else the platform default.
+    pub fn system() -> Self {
+        HostsFile::new(system_path_from(std::env::var_os(PATH_ENV)))
+    }
+
+    /// Keep the update lock in `dir`, the app data dir. Until then every
+    /// read-modify-write fails instead of going ahead unlocked. Only the
+    /// first call counts.
+    pub fn set_lock_dir(&self, dir: &Path) {
+        let _ = self.lock_file.set(dir.join(UPDATE_LOCK_FILE));
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `summary`, reused from `cache` while `generation` (from
+    /// watch::HostsWatch) and `domains` are the ones it was read for. Also
+    /// returns whether the file was actually read.
+    pub fn read_cached(
+        &self,
+        cache: &mut CachedRead,
+        generation: u64,
+        domains: &[String],
+    ) -> Result<(HostsSummary, bool), ProxyError> {
+        if let Some((cached, summary)) = &cache.0 {
+            if *cached == generation && summary.domains == domains {
+                return Ok((summary.clone(), false));
+            }
+        }
+        cache.0 = None;
+        let summary = self.summary(domains)?;
+        cache.0 = Some((generation, summary.clone()));
+        Ok((summary, true))
+    }
+
+    /// The file streamed into a HostsSummary for `domains`.
+    pub fn summary(&self, domains: &[String]) -> Result<HostsSummary, ProxyError> {
+        let (file, attempts) = retry_locked(|| fs::File::open(&self.path));
+        file.and_then(|file| HostsSummary::read(std::io::BufReader::new(file), domains)).map_err(|e| {
+            ProxyError::Hosts(format!("Read hosts failed{}: {}", attempts_note(attempts), describe_io_error(&e)))
+        })
+    }
+
+    /// Refuse to rewrite a file over `max_bytes` (0: no limit). Reading,
+    /// splitting and rejoining one that size is slow, and the more lines a
+    /// rewrite carries through, the more a bug in it can lose.
+    pub fn check_size(&self, max_bytes: u64) -> Result<(), ProxyError> {
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if max_bytes == 0 || size <= max_bytes {
+            return Ok(());
+        }
+        Err(ProxyError::HostsTooLarge(format!(
+            "The hosts file is {:.1} MiB, over the {} MiB limit for rewriting it; back it up and move the \
+             blocklist out of it, raise hostsMaxMb in the settings, or retry with allowLargeHosts",
+            size as f64 / (1024.0 * 1024.0),
+            max_bytes >> 20
+        )))
+    }
+
+    pub fn read(&self) -> Result<HostsText, ProxyError> {
+        let (bytes, attempts) = retry_locked(|| fs::read(&self.path));
+        let bytes = bytes.map_err(|e| {
+            ProxyError::Hosts(format!("Read hosts failed{}: {}", attempts_note(attempts), describe_io_error(&e)))
+        })?;
+        Ok(HostsText::parse(&bytes))
+    }
+
+    /// Take the lock every read-modify-write of this file holds, waiting up to
+    /// UPDATE_LOCK_TIMEOUT for another process (or thread) to finish its own.
+    /// Failing to take it is an error: the write must not go ahead unlocked.
+    pub fn update_lock(&self) -> Result<UpdateLock, ProxyError> {
+        let path = self.lock_file.get().ok_or_else(|| {
+            ProxyError::Hosts("The hosts file can't be changed before the app data dir is known".into())
+        })?;
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        // Created by the service, it may only be readable to us; that is
+        // enough to lock it
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .or_else(|_| fs::File::open(path))
+            .map_err(|e| {
+                ProxyError::Hosts(format!("Opening the hosts lock {} failed: {}", path.display(), describe_io_error(&e)))
+            })?;
+        let deadline = std::time::Instant::now() + UPDATE_LOCK_TIMEOUT;
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(UpdateLock { file, path: path.clone() }),
+                Err(fs::TryLockError::WouldBlock) if std::time::Instant::now() < deadline => {
+                    std::thread::sleep(UPDATE_LOCK_POLL);
+                }
+                Err(fs::TryLockError::WouldBlock) => {
+                    return Err(ProxyError::HostsLocked(format!(
+                        "Another process (the service or a second copy of the app) has been changing the hosts \
+                         file for {}s; try again once it is done",
+                        UPDATE_LOCK_TIMEOUT.as_secs()
+                    )))
+                }
+                Err(fs::TryLockError::Error(e)) => {
+                    return Err(ProxyError::Hosts(format!("Locking {} failed: {}", path.display(), e)))
+                }
+            }
+        }
+    }
+
+    /// Write the hosts file atomically: the new content goes to a temp file next
+    /// to it, is renamed over the original, and is read back before reporting success.
+    /// A read-only attribute is cleared for the write and restored afterwards; on
+    /// Unix the original owner, mode and SELinux label are kept. The swap is
+    /// retried while another program has the file locked.
+    pub fn write(&self, content: &[u8]) -> Result<(), ProxyError> {
+        let target = self.path.as_path();
+        let temp = target.with_file_name("hosts.cyber-drill.tmp");
+
+        let read_only = clear_read_only(target)?;
+        let meta = FileMeta::capture(target);
+        let staged = fs::File::create(&temp)
+            .and_then(|mut f| {
+                use std::io::Write;
+                f.write_all(content)?;
+                f.sync_all()
+            })
+            .and_then(|_| meta.as_ref().map_or(Ok(()), |m| m.apply(&temp)));
+        let (replaced, attempts) = match staged {
+            Ok(()) => retry_locked(|| replace_file(&temp, target)),
+            Err(e) => (Err(e), 1),
+        };
+        if read_only {
+            let _ = set_read_only(target);
+        }
+        if let Err(e) = replaced {
+            let _ = fs::remove_file(&temp);
+            return Err(write_error("Write hosts", &e, read_only, attempts));
+        }
+
+        verify(target, content)
+    }
+
+    /// Whether `write` would get through, found out without changing the
+    /// file: it is opened for writing and closed again without a byte
+    /// written, and a scratch file is created and removed next to it as the
+    /// swap needs. A read-only attribute is cleared for the open and set
+    /// again, as `write` does. Failures are classified the way `write`
+    /// reports them.
+    pub fn probe_writable(&self) -> WriteProbe {
+        let target = self.path.as_path();
+        let read_only = match fs::metadata(target) {
+            Ok(meta) => cfg!(target_os = "windows") && meta.permissions().readonly(),
+            Err(e) => {
+                return WriteProbe::failed(
+                    false,
+                    ProxyError::Hosts(format!("Read hosts attributes failed: {}", describe_io_error(&e))),
+                )
+            }
+        };
+        // Opening a read-only file for writing fails whoever we are; `write`
+        // clears the attribute first, which takes administrator rights. With
+        // them the open is still tried, so an ACL denying us shows up here
+        let opened = if read_only && !crate::elevate::is_elevated() {
+            Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        } else {
+            let cleared = match clear_read_only(target) {
+                Ok(cleared) => cleared,
+                Err(e) => return WriteProbe::failed(read_only, e),
+            };
+            let opened = fs::OpenOptions::new().append(true).open(target).map(drop);
+            if cleared {
+                let _ = set_read_only(target);
+            }
+            opened
+        };
+        if let Err(e) = opened {
+            return WriteProbe::failed(read_only, write_error("Opening hosts for writing", &e, read_only, 1));
+        }
+        let probe = target.with_file_name("hosts.cyber-drill.probe");
+        if let Err(e) = fs::File::create(&probe) {
+            return WriteProbe::failed(read_only, write_error("Creating a file next to hosts", &e, false, 1));
+        }
+        let _ = fs::remove_file(&probe);
+        WriteProbe { access: WriteAccess::Writable, read_only, error: None }
+    }
+
+    /// Like `write`, but through the platform's elevation prompt. `content` is
+    /// staged at `staging` (somewhere only we can write) for the elevated copy.
+    /// The helper is a process of its own that can't take the update lock,
+    /// so the caller passes the one it holds for this file; it stays held
+    /// until the helper has exited, which write_hosts waits for.
+    pub fn write_elevated(&self, content: &[u8], staging: &Path, lock: &UpdateLock) -> Result<(), ProxyError> {
+        if self.lock_file.get() != Some(&lock.path) {
+            return Err(ProxyError::Internal("Elevated hosts write without this file's update lock".into()));
+        }
+        let target = self.path.as_path();
+        if let Some(dir) = staging.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        fs::write(staging, content)
+            .map_err(|e| ProxyError::Internal(format!("Staging hosts failed: {}", describe_io_error(&e))))?;
+        let result = crate::elevate::write_hosts(staging, target);
+        let _ = fs::remove_file(staging);
+        result.map_err(|e| ProxyError::NotElevated(format!("Elevated hosts write failed or was declined: {}", e)))?;
+        verify(target, content)
+    }
+}
+
+pub fn describe_io_error(e: &std::io::Error) -> String {
+    match e.kind() {
+        std::io::ErrorKind::PermissionDenied => format!("permission denied ({})", e),
+        std::io::ErrorKind::StorageFull => format!("disk full ({})", e),
+        _ => e.to_string(),
+    }
+}
+
+/// Another process holds the file open without sharing (often AV scanning it).
+#[cfg(target_os = "windows")]
+fn is_locked(e: &std::io::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+    matches!(e.raw_os_error(), Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_locked(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::ResourceBusy
+}
+
+/// Run `op` again while the file is locked, up to LOCK_ATTEMPTS times. Other
+/// errors are returned straight away. Also returns how many attempts were made.
+fn retry_locked<T>(mut op: impl FnMut() -> std::io::Result<T>) -> (std::io::Result<T>, u32) {
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(e) if is_locked(&e) && attempt < LOCK_ATTEMPTS => {
+                std::thread::sleep(LOCK_RETRY_INTERVAL);
+                attempt += 1;
+            }
+            result => return (result, attempt),
+        }
+    }
+}
+
+fn attempts_note(attempts: u32) -> String {
+    if attempts > 1 {
+        format!(" after {} attempts", attempts)
+    } else {
+        String::new()
+    }
+}
+
+/// Turn a failed write into the variant that tells the UI which remedy to show.
+fn write_error(what: &str, e: &std::io::Error, read_only: bool, attempts: u32) -> ProxyError {
+    let detail = format!("{} failed{}: {}", what, attempts_note(attempts), describe_io_error(e));
+    if is_locked(e) {
+        return ProxyError::HostsLocked(format!("{}; another program has the hosts file open", detail));
+    }
+    if e.kind() != std::io::ErrorKind::PermissionDenied {
+        return ProxyError::Hosts(detail);
+    }
+    if !crate::elevate::is_elevated() {
+        ProxyError::NotElevated(format!("{}; run the app as administrator", detail))
+    } else if read_only {
+        ProxyError::HostsReadOnly(format!("{}; the hosts file is marked read-only", detail))
+    } else {
+        ProxyError::HostsAclDenied(format!(
+            "{}; the hosts file's permissions deny writing even as administrator, \
+             security software is probably protecting it",
+            detail
+        ))
+    }
+}
+
+/// Clear the read-only attribute so the file can be replaced. Returns whether
+/// it was set, so `write` can put it back.
+#[cfg(target_os = "windows")]
+fn clear_read_only(target: &Path) -> Result<bool, ProxyError> {
+    let mut perms = fs::metadata(target)
+        .map_err(|e| ProxyError::Hosts(format!("Read hosts attributes failed: {}", describe_io_error(&e))))?
+        .permissions();
+    if !perms.readonly() {
+        return Ok(false);
+    }
+    perms.set_readonly(false);
+    fs::set_permissions(target, perms).map_err(|e| write_error("Clearing the read-only attribute", &e, true, 1))?;
+    Ok(true)
+}
+
+#[cfg(target_os = "windows")]
+fn set_read_only(target: &Path) -> std::io::Result<()> {
+    let mut perms = fs::metadata(target)?.permissions();
+    perms.set_readonly(true);
+    fs::set_permissions(target, perms)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn clear_read_only(_target: &Path) -> Result<bool, ProxyError> {
+    Ok(false)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn set_read_only(_target: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn replace_file(temp: &Path, target: &Path) -> std::io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{ReplaceFileW, REPLACEFILE_IGNORE_MERGE_ERRORS};
+
+    let wide = |p: &Path| -> Vec<u16> { p.as_os_str().encode_wide().chain(Some(0)).collect() };
+    let (target_w, temp_w) = (wide(target), wide(temp));
+    // ReplaceFileW keeps the original's ACLs and attributes; fall back to
+    // MoveFileEx (what fs::rename uses) if the target can't be replaced that way.
+    let ok = unsafe {
+        ReplaceFileW(
+            target_w.as_ptr(),
+            temp_w.as_ptr(),
+            std::ptr::null(),
+            REPLACEFILE_IGNORE_MERGE_ERRORS,
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    if ok != 0 {
+        return Ok(());
+    }
+    fs::rename(temp, target)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn replace_file(temp: &Path, target: &Path) -> std::io::Result<()> {
+    fs::rename(temp, target)
+}
+
+/// Owner, mode and SELinux label of the file being replaced. The temp file is
+/// created with our umask and the default context for new files, so these are
+/// copied onto it before the rename; otherwise /etc/hosts can end up 600 or
+/// lose `net_conf_t` and become unreadable to non-root resolvers.
+#[cfg(unix)]
+struct FileMeta {
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    selinux: Option<Vec<u8>>,
+}
+
+#[cfg(unix)]
+impl FileMeta {
+    fn capture(path: &Path) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        let meta = fs::metadata(path).ok()?;
+        Some(FileMeta {
+            uid: meta.uid(),
+            gid: meta.gid(),
+            mode: meta.mode() & 0o7777,
+            selinux: selinux_label(path),
+        })
+    }
+
+    fn apply(&self, path: &Path) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(self.mode))?;
+        match &self.selinux {
+            Some(label) => set_selinux_label(path, label),
+            None => Ok(()),
+        }
+    }
+}
+
+/// ReplaceFileW already carries the ACLs and attributes over.
+#[cfg(not(unix))]
+struct FileMeta;
+
+#[cfg(not(unix))]
+impl FileMeta {
+    fn capture(_path: &Path) -> Option<Self> {
+        None
+    }
+
+    fn apply(&self, _path: &Path) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+const SELINUX_XATTR: &std::ffi::CStr = c"security.selinux";
+
+/// The `security.selinux` xattr, or None without SELinux (or without a label).
+#[cfg(target_os = "linux")]
+fn selinux_label(path: &Path) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: both strings are NUL-terminated; a null buffer of size 0 asks for the length.
+    let len = unsafe { libc::getxattr(path.as_ptr(), SELINUX_XATTR.as_ptr(), std::ptr::null_mut(), 0) };
+    if len <= 0 {
+        return None;
+    }
+    let mut label = vec![0u8; len as usize];
+    // SAFETY: label is a writable buffer of the size we pass.
+    let len = unsafe { libc::getxattr(path.as_ptr(), SELINUX_XATTR.as_ptr(), label.as_mut_ptr().cast(), label.len()) };
+    if len <= 0 {
+        return None;
+    }
+    label.truncate(len as usize);
+    Some(label)
+}
+
+#[cfg(target_os = "linux")]
+fn set_selinux_label(path: &Path, label: &[u8]) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: both strings are NUL-terminated and label is valid for its length.
+    let rc = unsafe { libc::setxattr(path.as_ptr(), SELINUX_XATTR.as_ptr(), label.as_ptr().cast(), label.len(), 0) };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn selinux_label(_path: &Path) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_selinux_label(_path: &Path, _label: &[u8]) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// `HostsFile::system`'s path, given the value of `CYBER_DRILL_HOSTS`; an
+/// empty one counts as unset.
+fn system_path_from(env: Option<std::ffi::OsString>) -> PathBuf {
+    env.filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(database_path)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_PATH))
+}
+
+/// The directory in Tcpip\Parameters\DataBasePath, which Windows lets admins
+/// move away from System32\drivers\etc.
+#[cfg(target_os = "windows")]
+fn database_path() -> Option<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use windows_sys::Win32::System::Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_SZ};
+
+    let wide = |s: &str| -> Vec<u16> { std::ffi::OsStr::new(s).encode_wide().chain(Some(0)).collect() };
+    let key = wide(r"System\CurrentControlSet\Services\Tcpip\Parameters");
+    let value = wide("DataBasePath");
+    let mut buf = vec![0u16; 1024];
+    let mut size = (buf.len() * 2) as u32;
+    // SAFETY: buf holds `size` bytes; RRF_RT_REG_SZ also accepts REG_EXPAND_SZ,
+    // which RegGetValueW expands (the default is %SystemRoot%\...).
+    let rc = unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            value.as_ptr(),
+            RRF_RT_REG_SZ,
+            std::ptr::null_mut(),
+            buf.as_mut_ptr().cast(),
+            &mut size,
+        )
+    };
+    if rc != 0 {
+        return None;
+    }
+    buf.truncate(size as usize / 2);
+    if let Some(nul) = buf.iter().position(|&c| c == 0) {
+        buf.truncate(nul);
+    }
+    if buf.is_empty() {
+        return None;
+    }
+    Some(PathBuf::from(OsString::from_wide(&buf)).join("hosts"))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn database_path() -> Option<PathBuf> {
+    None
+}
+
+/// Read the file back to confirm the write landed.
+fn verify(target: &Path, content: &[u8]) -> Result<(), ProxyError> {
+    let (written, _) = retry_locked(|| fs::read(target));
+    let written =
+        written.map_err(|e| ProxyError::Hosts(format!("Verify hosts failed: {}", describe_io_error(&e))))?;
+    if written != content {
+        return Err(ProxyError::Hosts(
+            "Verify hosts failed: content on disk does not match what was written".into(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const DOMAIN: &str = "server.self-serve.windsurf.com";
+
+    fn domains() -> Vec<String> {
+        vec![DOMAIN.to_string()]
+    }
+
+    fn text(s: &str) -> HostsText {
+        HostsText::parse(s.as_bytes())
+    }
+
+    /// A hosts file in a directory of its own under the temp dir, since the
+    /// swap files go next to it. The update lock goes in `data` below it,
+    /// standing in for the app data dir. Removed on drop.
+    struct Scratch {
+        dir: PathBuf,
+        hosts: HostsFile,
+    }
+
+    impl Scratch {
+        fn new(content: &[u8]) -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "cyber-drill-hosts-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("hosts");
+            fs::write(&path, content).unwrap();
+            let hosts = HostsFile::new(path);
+            hosts.set_lock_dir(&dir.join("data"));
+            Scratch { dir, hosts }
+        }
+
+        /// Another HostsFile for the same file and lock, as a second process
+        /// would have.
+        fn other(&self) -> HostsFile {
+            let hosts = HostsFile::new(self.hosts.path());
+            hosts.set_lock_dir(&self.dir.join("data"));
+            hosts
+        }
+
+        fn bytes(&self) -> Vec<u8> {
+            fs::read(self.hosts.path()).unwrap()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn entry_with_inline_comment_matches() {
+        let hosts = text("127.0.0.1 server.self-serve.windsurf.com # added by hand\n");
+        assert!(hosts.has_entry("127.0.0.1", DOMAIN));
+        assert!(hosts.is_modified(&domains()));
+    }
+
+    #[test]
+    fn commented_out_entry_does_not_match() {
+        let hosts = text("# 127.0.0.1 server.self-serve.windsurf.com\n#127.0.0.1 server.self-serve.windsurf.com\n");
+        assert!(!hosts.has_entry("127.0.0.1", DOMAIN));
+        assert!(!hosts.is_modified(&domains()));
+    }
+
+    #[test]
+    fn tabs_between_fields_match() {
+        let hosts = text("127.0.0.1\tserver.self-serve.windsurf.com\n::1\t \tSERVER.self-serve.windsurf.com\t\n");
+        assert!(hosts.has_entry("127.0.0.1", DOMAIN));
+        assert!(hosts.has_entry("::1", DOMAIN));
+        assert!(hosts.missing_entries(&domains()).is_empty());
+    }
+
+    #[test]
+    fn prefix_and_suffix_hostnames_do_not_match() {
+        let others = "127.0.0.1 xserver.self-serve.windsurf.com\n\
+                      127.0.0.1 server.self-serve.windsurf.com.internal-mirror\n\
+                      # 10.0.0.5 server.self-serve.windsurf.com.internal-mirror\n\
+                      127.0.0.1 xserver.codeium.com\n";
+        let hosts = text(others);
+        assert!(!hosts.has_entry("127.0.0.1", DOMAIN));
+        assert!(!hosts.has_entry("127.0.0.1", "server.codeium.com"));
+        assert!(!hosts.is_modified(&domains()));
+
+        let mut stripped = hosts.clone();
+        stripped.remove_stray_entries(&[DOMAIN.to_string(), "server.codeium.com".to_string()]);
+        assert_eq!(stripped.to_bytes(), others.as_bytes());
+    }
+
+    #[test]
+    fn lines_with_extra_hostnames_are_not_ours() {
+        let hosts = text("127.0.0.1 server.self-serve.windsurf.com localhost\n");
+        assert!(!hosts.has_entry("127.0.0.1", DOMAIN));
+        assert!(hosts.stray_entries(&domains()).is_empty());
+    }
+
+    #[test]
+    fn only_exact_entries_are_removed() {
+        let mut hosts = text(
+            "127.0.0.1 localhost\n\
+             127.0.0.1\tserver.self-serve.windsurf.com # stray\n\
+             127.0.0.1 xserver.self-serve.windsurf.com\n",
+        );
+        hosts.remove_stray_entries(&domains());
+        assert_eq!(hosts.to_bytes(), b"127.0.0.1 localhost\n127.0.0.1 xserver.self-serve.windsurf.com\n");
+    }
+
+    /// Apply our block and take it out again. The block goes at the end with
+    /// `ending`; the bytes before it must be the original ones throughout.
+    fn assert_round_trip(original: &[u8], ending: &str) {
+        let mut hosts = HostsText::parse(original);
+        hosts.apply_entries(&domains());
+        let block = format!(
+            "{begin}{e}127.0.0.1 {d}{e}::1 {d}{e}{end}{e}",
+            begin = BLOCK_BEGIN,
+            end = BLOCK_END,
+            d = DOMAIN,
+            e = ending
+        );
+        assert_eq!(hosts.to_bytes(), [original, block.as_bytes()].concat());
+
+        hosts.remove_block();
+        assert_eq!(hosts.to_bytes(), original);
+    }
+
+    #[test]
+    fn crlf_file_round_trips() {
+        assert_round_trip(b"# Copyright\r\n\r\n127.0.0.1 localhost\r\n", "\r\n");
+    }
+
+    #[test]
+    fn lf_file_round_trips() {
+        assert_round_trip(b"# Copyright\n\n127.0.0.1 localhost\n", "\n");
+    }
+
+    #[test]
+    fn mixed_endings_round_trip_and_the_block_takes_the_dominant_one() {
+        assert_round_trip(b"# a\r\n# b\n127.0.0.1 localhost\r\n::1 localhost\r\n", "\r\n");
+        assert_round_trip(b"# a\r\n# b\n127.0.0.1 localhost\n::1 localhost\n", "\n");
+    }
+
+    #[test]
+    fn utf8_bom_round_trips() {
+        let original = [UTF8_BOM, b"# Copyright\r\n127.0.0.1 localhost\r\n"].concat();
+        assert_eq!(HostsText::parse(&original).encoding(), Encoding::Utf8Bom);
+        assert_round_trip(&original, "\r\n");
+    }
+
+    #[test]
+    fn block_in_the_middle_is_replaced_in_place() {
+        let before = b"# head\r\n127.0.0.1 localhost\n";
+        let after = b"# tail\r\n";
+        let old_block = "# cyber-drill-safe BEGIN\r\n127.0.0.1 old.example.com\r\n# cyber-drill-safe END\r\n";
+        let mut hosts = HostsText::parse(&[&before[..], old_block.as_bytes(), after].concat());
+        hosts.apply_entries(&domains());
+        let written = hosts.to_bytes();
+        assert!(written.starts_with(before));
+        assert!(written.ends_with(after));
+        hosts.remove_block();
+        assert_eq!(hosts.to_bytes(), [&before[..], after].concat());
+    }
+
+    #[test]
+    fn unterminated_last_line_gets_the_file_ending_before_the_block() {
+        let mut hosts = text("# a\r\n127.0.0.1 localhost");
+        hosts.set_block(&domains());
+        assert!(hosts.to_bytes().starts_with(b"# a\r\n127.0.0.1 localhost\r\n# cyber-drill-safe BEGIN\r\n"));
+    }
+
+    /// The label check only bites where SELinux labels the temp dir; elsewhere
+    /// both reads are None.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn write_keeps_mode_owner_and_selinux_label() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let scratch = Scratch::new(b"127.0.0.1 localhost\n");
+        let path = scratch.hosts.path();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o604)).unwrap();
+        let before = fs::metadata(path).unwrap();
+        let label = selinux_label(path);
+
+        assert!(scratch.hosts.ensure_entry(&domains()).unwrap());
+        assert!(HostsText::parse(&scratch.bytes()).has_entry("::1", DOMAIN));
+
+        let after = fs::metadata(path).unwrap();
+        assert_ne!(after.ino(), before.ino(), "the file should have been swapped, not written in place");
+        assert_eq!(after.mode() & 0o7777, 0o604);
+        assert_eq!((after.uid(), after.gid()), (before.uid(), before.gid()));
+        assert_eq!(selinux_label(path), label);
+        assert!(!path.with_file_name("hosts.cyber-drill.tmp").exists());
+    }
+
+    #[test]
+    fn system_path_honours_the_override() {
+        let scratch = Scratch::new(b"");
+        let path = scratch.hosts.path().to_path_buf();
+        assert_eq!(system_path_from(Some(path.clone().into_os_string())), path);
+        assert_ne!(system_path_from(Some(std::ffi::OsString::new())), path);
+        assert_eq!(system_path_from(Some(std::ffi::OsString::new())), system_path_from(None));
+    }
+
+    #[test]
+    fn add_status_and_remove_against_a_file() {
+        let original = b"127.0.0.1 localhost\n::1 localhost\n";
+        let scratch = Scratch::new(original);
+        let domains = domains();
+        assert!(!scratch.hosts.summary(&domains).unwrap().is_modified());
+
+        assert!(scratch.hosts.ensure_entry(&domains).unwrap());
+        assert!(!scratch.hosts.ensure_entry(&domains).unwrap(), "a second run writes nothing");
+        let summary = scratch.hosts.summary(&domains).unwrap();
+        assert!(summary.is_modified());
+        assert!(summary.missing_entries().is_empty());
+        assert_eq!(summary.block_domains(), domains);
+
+        assert!(scratch.hosts.remove_entries(&domains).unwrap());
+        assert!(!scratch.hosts.remove_entries(&domains).unwrap());
+        assert_eq!(scratch.bytes(), original);
+        assert!(!scratch.hosts.summary(&domains).unwrap().is_modified());
+    }
+
+    #[test]
+    fn missing_file_is_a_hosts_error() {
+        let scratch = Scratch::new(b"");
+        let missing = HostsFile::new(scratch.dir.join("absent"));
+        assert!(matches!(missing.read(), Err(ProxyError::Hosts(_))));
+        assert!(matches!(missing.summary(&domains()), Err(ProxyError::Hosts(_))));
+        assert!(missing.probe_writable().access == WriteAccess::Failed);
+    }
+
+    /// ensure_entry then remove_entries through the trait, which must leave
+    /// `original` exactly as it was, plus the block entries read in between.
+    fn edit_cycle(original: &[u8]) -> Vec<HostsEntry> {
+        let scratch = Scratch::new(original);
+        let editor: &dyn HostsEditor = &scratch.hosts;
+        assert!(editor.ensure_entry(&domains()).unwrap());
+        assert!(editor.load().unwrap().is_modified(&domains()));
+        let entries = editor.read_entries().unwrap();
+        assert!(editor.remove_entries(&domains()).unwrap());
+        assert!(!editor.load().unwrap().is_modified(&domains()));
+        let mut expected = original.to_vec();
+        if !expected.is_empty() && !expected.ends_with(b"\n") {
+            expected.push(b'\n');
+        }
+        assert_eq!(scratch.bytes(), expected);
+        entries
+    }
+
+    #[test]
+    fn editor_handles_an_empty_file() {
+        let entries = edit_cycle(b"");
+        let entries: Vec<(&str, &str)> = entries.iter().map(|e| (e.address.as_str(), e.host.as_str())).collect();
+        assert_eq!(entries, [("127.0.0.1", DOMAIN), ("::1", DOMAIN)]);
+    }
+
+    #[test]
+    fn editor_handles_a_missing_trailing_newline() {
+        edit_cycle(b"127.0.0.1 localhost");
+    }
+
+    #[test]
+    fn editor_keeps_commented_entries() {
+        edit_cycle(b"# 127.0.0.1 server.self-serve.windsurf.com\n127.0.0.1 localhost\n");
+    }
+
+    #[test]
+    fn editor_replaces_entries_with_inline_comments() {
+        let scratch = Scratch::new(b"127.0.0.1 localhost\n127.0.0.1 server.self-serve.windsurf.com # old\n");
+        assert!(scratch.hosts.ensure_entry(&domains()).unwrap());
+        let written = HostsText::parse(&scratch.bytes());
+        assert!(written.stray_entries(&domains()).is_empty());
+        assert_eq!(written.stale_entry_count(&domains()), 0);
+        assert!(scratch.hosts.remove_entries(&domains()).unwrap());
+        assert_eq!(scratch.bytes(), b"127.0.0.1 localhost\n");
+    }
+
+    #[test]
+    fn editor_leaves_strays_alone_when_asked_to() {
+        let stray = b"127.0.0.1 server.self-serve.windsurf.com\n";
+        let scratch = Scratch::new(stray);
+        assert!(!scratch.hosts.remove_entries(&[]).unwrap());
+        assert_eq!(scratch.bytes(), stray);
+    }
+
+    #[test]
+    fn editor_handles_a_large_file() {
+        let original: String = (0..100_000).map(|i| format!("0.0.0.0 ads{}.example.com\n", i)).collect();
+        edit_cycle(original.as_bytes());
+    }
+
+    #[test]
+    fn editor_keeps_non_utf8_bytes() {
+        let entries = edit_cycle(b"# caf\xe9 \xff\xfe tooling\r\n127.0.0.1 localhost\r\n");
+        assert_eq!(entries.len(), 2);
+    }
+
+    fn utf16(text: &str, bom: &[u8], unit: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        let mut out = bom.to_vec();
+        out.extend(text.encode_utf16().flat_map(unit));
+        out
+    }
+
+    /// Each encoding is detected, edited and written back in kind.
+    #[test]
+    fn every_encoding_round_trips() {
+        let body = "# Copyright\r\n127.0.0.1 localhost\r\n";
+        let fixtures = [
+            (body.as_bytes().to_vec(), Encoding::Utf8),
+            ([UTF8_BOM, body.as_bytes()].concat(), Encoding::Utf8Bom),
+            (utf16(body, UTF16LE_BOM, u16::to_le_bytes), Encoding::Utf16Le),
+            (utf16(body, UTF16BE_BOM, u16::to_be_bytes), Encoding::Utf16Be),
+            (b"# caf\xe9\r\n127.0.0.1 localhost\r\n".to_vec(), Encoding::Latin1),
+        ];
+        for (original, encoding) in fixtures {
+            let scratch = Scratch::new(&original);
+            let read = scratch.hosts.read().unwrap();
+            assert_eq!(read.encoding(), encoding);
+            assert_eq!(scratch.hosts.summary(&domains()).unwrap().encoding(), encoding);
+
+            assert!(scratch.hosts.ensure_entry(&domains()).unwrap());
+            let written = scratch.hosts.read().unwrap();
+            assert_eq!(written.encoding(), encoding);
+            assert!(written.has_entry("127.0.0.1", DOMAIN), "{:?}", encoding);
+
+            assert!(scratch.hosts.remove_entries(&domains()).unwrap());
+            assert_eq!(scratch.bytes(), original, "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn odd_length_utf16_is_read_but_not_rewritten() {
+        let mut original = utf16("127.0.0.1 server.self-serve.windsurf.com\r\n", UTF16LE_BOM, u16::to_le_bytes);
+        original.push(b'x');
+        let scratch = Scratch::new(&original);
+        assert!(scratch.hosts.read().unwrap().has_entry("127.0.0.1", DOMAIN));
+        assert!(matches!(scratch.hosts.ensure_entry(&domains()), Err(ProxyError::Hosts(_))));
+        assert!(matches!(scratch.hosts.remove_entries(&domains()), Err(ProxyError::Hosts(_))));
+        assert_eq!(scratch.bytes(), original);
+    }
+
+    #[test]
+    fn unpaired_utf16_surrogate_is_not_rewritten() {
+        let mut original = UTF16BE_BOM.to_vec();
+        original.extend([0xD8, 0x00, 0x00, b'\n']);
+        let scratch = Scratch::new(&original);
+        assert!(matches!(scratch.hosts.ensure_entry(&domains()), Err(ProxyError::Hosts(_))));
+        assert_eq!(scratch.bytes(), original);
+    }
+
+    #[test]
+    fn utf8_bom_over_latin1_is_stripped_and_not_rewritten() {
+        let original = [UTF8_BOM, b"127.0.0.1 server.self-serve.windsurf.com\n# caf\xe9\n"].concat();
+        let hosts = HostsText::parse(&original);
+        assert_eq!(hosts.encoding(), Encoding::Latin1);
+        assert!(hosts.has_entry("127.0.0.1", DOMAIN), "the BOM must not stick to the first line");
+        assert!(hosts.check_writable().is_err());
+
+        let scratch = Scratch::new(&original);
+        assert!(matches!(scratch.hosts.ensure_entry(&domains()), Err(ProxyError::Hosts(_))));
+        assert_eq!(scratch.bytes(), original);
+    }
+
+    #[test]
+    fn unwritable_file_without_our_entries_is_left_alone() {
+        let mut original = utf16("127.0.0.1 localhost\r\n", UTF16LE_BOM, u16::to_le_bytes);
+        original.push(0);
+        let scratch = Scratch::new(&original);
+        assert!(!scratch.hosts.remove_entries(&domains()).unwrap());
+        assert_eq!(scratch.bytes(), original);
+    }
+
+    #[test]
+    fn summary_streams_a_large_blocklist() {
+        let mut original: String = (0..250_000).map(|i| format!("0.0.0.0 ads{}.example.com\n", i)).collect();
+        original.push_str("# cyber-drill-safe BEGIN\n127.0.0.1 server.self-serve.windsurf.com\n");
+        original.push_str("# cyber-drill-safe END\n");
+        original.extend((250_000..500_000).map(|i| format!("0.0.0.0 ads{}.example.com\n", i)));
+        original.push_str("::1 server.self-serve.windsurf.com\n");
+        let scratch = Scratch::new(original.as_bytes());
+
+        let summary = scratch.hosts.summary(&domains()).unwrap();
+        assert_eq!(summary.text.line_count(), 6, "runs of other lines are kept as one blank line each");
+        assert_eq!(summary.block_domains(), domains());
+        assert_eq!(summary.stray_entries(), ["::1 server.self-serve.windsurf.com"]);
+        assert!(summary.missing_entries().is_empty());
+        assert!(summary.block_warnings().is_empty());
+    }
+
+    /// The write side at the same size: the block goes in and out of a
+    /// 500k-line file without losing, adding or moving a line of the user's.
+    #[test]
+    fn editor_rewrites_a_large_blocklist() {
+        let ads = |range: std::ops::Range<usize>| -> String {
+            range.map(|i| format!("0.0.0.0 ads{}.example.com\n", i)).collect()
+        };
+        let user = ads(0..500_000);
+        let block = "# cyber-drill-safe BEGIN\n127.0.0.1 server.self-serve.windsurf.com\n# cyber-drill-safe END\n";
+        let scratch = Scratch::new(format!("{}{}{}", ads(0..250_000), block, ads(250_000..500_000)).as_bytes());
+
+        assert!(scratch.hosts.remove_entries(&[]).unwrap());
+        assert_eq!(scratch.bytes(), user.as_bytes());
+
+        assert!(scratch.hosts.ensure_entry(&domains()).unwrap());
+        let written = String::from_utf8(scratch.bytes()).unwrap();
+        let block_lines = scratch.hosts.load().unwrap().block_line_count();
+        assert_eq!(written.lines().count(), 500_000 + block_lines);
+        assert!(written.lines().filter(|l| !l.contains("windsurf") && !l.starts_with('#')).eq(user.lines()));
+        assert!(scratch.hosts.summary(&domains()).unwrap().missing_entries().is_empty());
+
+        assert!(scratch.hosts.remove_entries(&domains()).unwrap());
+        assert_eq!(scratch.bytes(), user.as_bytes());
+    }
+
+    #[test]
+    fn check_size_refuses_files_over_the_limit() {
+        let scratch = Scratch::new(&vec![b'#'; 3 << 20]);
+        let error = scratch.hosts.check_size(1 << 20).unwrap_err();
+        assert_eq!(error.code(), "HOSTS_TOO_LARGE");
+        assert!(scratch.hosts.check_size(4 << 20).is_ok());
+        assert!(scratch.hosts.check_size(0).is_ok(), "0 means no limit");
+    }
+
+    /// A HostsFile whose store appends a line, as a buggy edit would.
+    struct Growing(HostsFile);
+
+    impl HostsEditor for Growing {
+        fn load(&self) -> Result<HostsText, ProxyError> {
+            self.0.load()
+        }
+
+        fn store(&self, text: &HostsText, _lock: &UpdateLock) -> Result<(), ProxyError> {
+            self.0.write(&[text.to_bytes(), b"# extra\n".to_vec()].concat())
+        }
+
+        fn lock(&self) -> Result<UpdateLock, ProxyError> {
+            self.0.lock()
+        }
+    }
+
+    #[test]
+    fn store_lines_refuses_an_unexpected_line_count() {
+        let original = b"127.0.0.1 localhost\n";
+        let scratch = Scratch::new(original);
+        let text = scratch.hosts.read().unwrap();
+        let lock = scratch.hosts.update_lock().unwrap();
+        let error = scratch.hosts.store_lines(&text, 2, &lock).unwrap_err();
+        assert!(matches!(error, ProxyError::Hosts(_)));
+        assert!(error.message().starts_with("Refusing to write hosts"));
+        assert_eq!(scratch.bytes(), original, "nothing is written");
+    }
+
+    #[test]
+    fn store_lines_checks_the_file_after_the_write() {
+        let scratch = Scratch::new(b"127.0.0.1 localhost\n");
+        let growing = Growing(scratch.other());
+        let error = growing.ensure_entry(&domains()).unwrap_err();
+        assert!(matches!(error, ProxyError::Hosts(_)));
+        assert!(error.message().contains("after the write"));
+    }
+
+    /// Two threads appending a line each per read-modify-write: without the
+    /// lock some appends are lost to the other thread's write.
+    #[test]
+    fn update_lock_serializes_read_modify_write() {
+        const ROUNDS: usize = 50;
+        let scratch = Scratch::new(b"127.0.0.1 localhost\n");
+        let workers: Vec<_> = (0..2)
+            .map(|worker| {
+                let hosts = scratch.other();
+                std::thread::spawn(move || {
+                    for round in 0..ROUNDS {
+                        let _lock = hosts.update_lock().unwrap();
+                        let mut text = hosts.read().unwrap();
+                        text.lines.push(format!("# worker {} round {}\n", worker, round));
+                        hosts.write(&text.to_bytes()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let text = scratch.hosts.read().unwrap();
+        assert_eq!(text.line_count(), 1 + 2 * ROUNDS);
+        for worker in 0..2 {
+            for round in 0..ROUNDS {
+                assert!(text.lines.contains(&format!("# worker {} round {}\n", worker, round)));
+            }
+        }
+    }
+
+    #[test]
+    fn update_lock_is_exclusive_until_dropped() {
+        let scratch = Scratch::new(b"");
+        let held = scratch.hosts.update_lock().unwrap();
+        assert!(!scratch.hosts.path().with_file_name(UPDATE_LOCK_FILE).exists(), "the lock is not next to hosts");
+        let path = scratch.dir.join("data").join(UPDATE_LOCK_FILE);
+        let file = fs::OpenOptions::new().read(true).write(true).open(path).unwrap();
+        assert!(matches!(file.try_lock(), Err(fs::TryLockError::WouldBlock)));
+        drop(held);
+        assert!(file.try_lock().is_ok());
+    }
+
+    #[test]
+    fn no_write_without_the_update_lock() {
+        let original = b"127.0.0.1 localhost\n";
+        let scratch = Scratch::new(original);
+        let unlocked = HostsFile::new(scratch.hosts.path());
+        assert!(matches!(unlocked.update_lock(), Err(ProxyError::Hosts(_))));
+        assert!(matches!(unlocked.ensure_entry(&domains()), Err(ProxyError::Hosts(_))));
+        assert_eq!(scratch.bytes(), original);
+    }
+
+    #[test]
+    fn write_elevated_needs_the_files_own_lock() {
+        let scratch = Scratch::new(b"");
+        let elsewhere = Scratch::new(b"");
+        let lock = elsewhere.hosts.update_lock().unwrap();
+        let staging = scratch.dir.join("staged");
+        let error = scratch.hosts.write_elevated(b"127.0.0.1 localhost\n", &staging, &lock).unwrap_err();
+        assert!(matches!(error, ProxyError::Internal(_)));
+        assert!(!staging.exists(), "refused before staging anything");
+    }
+
+    #[test]
+    fn probe_leaves_a_writable_file_as_it_was() {
+        let original = b"127.0.0.1 localhost\n";
+        let scratch = Scratch::new(original);
+        let probe = scratch.hosts.probe_writable();
+        assert!(probe.access == WriteAccess::Writable);
+        assert!(!probe.read_only);
+        assert!(probe.error.is_none());
+        assert_eq!(scratch.bytes(), original);
+        assert!(!scratch.hosts.path().with_file_name("hosts.cyber-drill.probe").exists());
+    }
+}
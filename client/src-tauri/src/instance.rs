@@ -0,0 +1,124 @@
+//! A lock file in the app data dir, held for as long as the app runs. The
+//! single-instance plugin hands a second copy over to the first where it can
+//! (a named mutex, D-Bus); this catches a copy it missed, e.g. without a
+//! session bus, before that copy touches hosts or the proxy. The OS drops
+//! the lock with the process, so a file left by a crash never blocks a start.
+
+use std::fs;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+const FILE_NAME: &str = "instance.lock";
+
+/// Released when dropped (or when the process exits).
+pub struct InstanceLock {
+    file: fs::File,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+pub fn path(app_data_dir: &Path) -> PathBuf {
+    app_data_dir.join(FILE_NAME)
+}
+
+/// Take the lock at `path` and record our pid in it; None while another
+/// copy holds it.
+pub fn acquire(path: &Path) -> std::io::Result<Option<InstanceLock>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => return Ok(None),
+        Err(fs::TryLockError::Error(e)) => return Err(e),
+    }
+    // Whatever a crashed holder left is stale once the lock is ours
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{}", std::process::id())?;
+    file.sync_all()?;
+    Ok(Some(InstanceLock { file }))
+}
+
+/// The pid the holder recorded, for telling the user which copy runs.
+pub fn holder(path: &Path) -> Option<u32> {
+    let mut pid = String::new();
+    fs::File::open(path).ok()?.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn scratch_path() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir()
+            .join(format!("cyber-drill-instance-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)))
+            .join(FILE_NAME)
+    }
+
+    #[test]
+    fn second_acquire_fails_while_held() {
+        let path = scratch_path();
+        let first = acquire(&path).unwrap().expect("the first copy gets the lock");
+        assert!(acquire(&path).unwrap().is_none());
+        assert_eq!(holder(&path), Some(std::process::id()));
+
+        drop(first);
+        assert!(acquire(&path).unwrap().is_some(), "released on drop");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn stale_lock_file_is_reclaimed() {
+        let path = scratch_path();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "4294967294\n").unwrap();
+        assert_eq!(holder(&path), Some(4_294_967_294));
+
+        let lock = acquire(&path).unwrap();
+        assert!(lock.is_some(), "a file nobody holds doesn't block a start");
+        assert_eq!(holder(&path), Some(std::process::id()));
+        drop(lock);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lock_held_by_another_process_blocks() {
+        use std::process::{Command, Stdio};
+        let path = scratch_path();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "").unwrap();
+        // util-linux's flock(1) holds the lock until its command exits; with
+        // -o the command doesn't inherit it, so killing flock releases it.
+        // Skipped where flock(1) isn't installed
+        let Ok(mut child) = Command::new("flock")
+            .arg("-o")
+            .arg(&path)
+            .args(["sleep", "5"])
+            .stdout(Stdio::null())
+            .spawn()
+        else {
+            return;
+        };
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+        let mut blocked = false;
+        while std::time::Instant::now() < deadline && !blocked {
+            blocked = acquire(&path).unwrap().is_none();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+        assert!(blocked);
+        assert!(acquire(&path).unwrap().is_some(), "released when the holder dies");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,54 @@
+//! Checking the shipped proxy files against SHA-256 hashes taken at build
+//! time, so a modified local-proxy.js is never run with our privileges.
+
+use serde::Serialize;
+use std::path::Path;
+
+/// (path relative to the resources dir, lowercase hex SHA-256), written by
+/// build.rs.
+const MANIFEST: &[(&str, &str)] = include!(concat!(env!("OUT_DIR"), "/integrity_manifest.rs"));
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mismatch {
+    /// Relative to the resources dir, with forward slashes.
+    pub file: String,
+    pub expected: String,
+    /// None when the file is missing or unreadable.
+    pub actual: Option<String>,
+}
+
+/// Every shipped file under `resources` whose hash differs from the build's.
+pub fn check(resources: &Path) -> Vec<Mismatch> {
+    MANIFEST
+        .iter()
+        .filter_map(|&(file, expected)| {
+            let actual = std::fs::read(resources.join(file)).ok().map(|bytes| crate::audit::sha256_hex(&bytes));
+            (actual.as_deref() != Some(expected)).then(|| Mismatch {
+                file: file.to_string(),
+                expected: expected.to_string(),
+                actual,
+            })
+        })
+        .collect()
+}
+
+/// Ok when every shipped file matches; otherwise a message naming the ones
+/// that don't.
+pub fn verify(resources: &Path) -> Result<(), String> {
+    let mismatches = check(resources);
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    let files: Vec<String> = mismatches
+        .iter()
+        .map(|m| match m.actual {
+            Some(_) => format!("{} (modified)", m.file),
+            None => format!("{} (missing)", m.file),
+        })
+        .collect();
+    Err(format!(
+        "Shipped proxy files don't match this build: {}; reinstall the app",
+        files.join(", ")
+    ))
+}
@@ -0,0 +1,39 @@
+//! The last gateway seen working (health route answering and a request
+//! forwarded to it successfully), kept in the app data dir so proxy_run's
+//! dry run can flag a different URL before a mistyped one costs a drill.
+//! Only ever reported; nothing substitutes it for the gateway asked for.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const FILE_NAME: &str = "gateway-last-good.json";
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LastGood {
+    pub gateway_url: String,
+    /// Unix seconds it was confirmed working.
+    pub confirmed_at: u64,
+}
+
+pub fn path(app_data_dir: &Path) -> PathBuf {
+    app_data_dir.join(FILE_NAME)
+}
+
+pub fn save(path: &Path, last_good: &LastGood) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let json = serde_json::to_vec_pretty(last_good).map_err(std::io::Error::other)?;
+    fs::write(path, json)
+}
+
+/// The recorded gateway, or None if there is none or it can't be parsed.
+pub fn load(path: &Path) -> Option<LastGood> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+pub fn clear(path: &Path) {
+    let _ = fs::remove_file(path);
+}
@@ -1,134 +1,3830 @@
+mod agents;
+mod allowlist;
+mod audit;
+mod autostart;
+mod cert;
+mod childenv;
+mod commands;
+mod config;
+mod diagnostics;
+mod dns;
+mod doh;
+mod elevate;
+mod error;
+mod failure;
+mod firewall;
+mod gateway;
+mod healthcheck;
+mod heartbeat;
+mod hosts;
+mod instance;
+mod lastgood;
+mod integrity;
+mod logs;
+mod messages;
+mod net;
+mod node;
+mod process;
+mod provision;
+mod proxy;
+mod redirect;
+mod replay;
+mod schedule;
+mod selftest;
+mod service;
+mod session;
+mod setup;
+mod traffic;
+mod tray;
+mod update;
+mod upstream;
+mod uptime;
+mod usage;
+mod versions;
+mod watch;
+mod watchdog;
+
+use error::ProxyError;
+use hosts::HostsEditor;
+use schedule::{arm_schedule, cancel_schedule, Schedule};
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::fs;
-use std::process::{Child, Command};
-use std::sync::Mutex;
-use tauri::State;
-
-#[cfg(target_os = "windows")]
-const HOSTS_PATH: &str = r"C:\Windows\System32\drivers\etc\hosts";
-#[cfg(not(target_os = "windows"))]
-const HOSTS_PATH: &str = "/etc/hosts";
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::{Emitter, Manager, State};
+use tauri_plugin_shell::process::Command as ShellCommand;
+use tauri_plugin_shell::ShellExt;
+use watchdog::{
+    refresh_child, spawn_health_poll, spawn_hosts_watch, spawn_monitor, spawn_uptime_sampler, Watchdog,
+};
+
+/// What the app runs on. The tests drive the commands on tauri's mock
+/// runtime instead.
+#[cfg(not(test))]
+type Runtime = tauri::Wry;
+#[cfg(test)]
+type Runtime = tauri::test::MockRuntime;
+type AppHandle = tauri::AppHandle<Runtime>;
+
+/// Only seeds Config.domains; everything else reads the configured domains,
+/// so a new vendor hostname is a config change rather than a new build.
 const WINDSURF_DOMAIN: &str = "server.self-serve.windsurf.com";
+const HOSTS_BACKUP_PREFIX: &str = "hosts.bak.";
+const HOSTS_BACKUP_KEEP: usize = 5;
+/// New hosts content waiting for the elevated copy, in the app data dir.
+const HOSTS_STAGING_FILE: &str = "hosts.staged";
+const DEFAULT_PROXY_PORT: u16 = redirect::HTTPS_PORT;
+/// Single-binary build of local-proxy.js, shipped next to the app as a sidecar.
+const SIDECAR_NAME: &str = "local-proxy";
+const PORT_PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(10);
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const STDERR_TAIL_LINES: usize = 50;
+/// Pushed with the full ProxyStatus whenever it changes.
+const EVENT_PROXY_STATUS: &str = "proxy://status";
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the status parts that run certutil, netsh or the resolver are
+/// rechecked when nothing we did changed them.
+const STATUS_SLOW_REFRESH: Duration = Duration::from_secs(15);
+/// A login start that was to run the proxy didn't; carries the AutorunOutcome.
+const EVENT_AUTORUN_FAILED: &str = "autostart://autorun-failed";
+/// The CA or server certificate expires within CERT_WARN_DAYS; carries a
+/// CertExpiry. Sent once per certificate.
+const EVENT_CERT_EXPIRING: &str = "cert://expiring";
+const CERT_WARN_DAYS: i64 = 30;
+/// How often the drill timer checks the clock (and for cancellation).
+const SCHEDULE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Resolution may lag the DNS flush briefly; retry this many times.
+const RESOLVE_ATTEMPTS: u32 = 5;
+const RESOLVE_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+/// Limits on the steps of proxy_run (see `timed`). Writing hosts doesn't
+/// count the time an elevation prompt is open, only what follows it.
+const HOSTS_READ_TIMEOUT: Duration = Duration::from_secs(5);
+const HOSTS_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+const RESOLUTION_TIMEOUT: Duration = Duration::from_secs(10);
+const GATEWAY_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Running over only skips the check; see `probe_agents`.
+const AGENT_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+const SPAWN_TIMEOUT: Duration = Duration::from_secs(15);
+/// Added to proxy_stop's graceful wait for the kill and reap after it.
+const STOP_KILL_GRACE: Duration = Duration::from_secs(5);
+/// How often a step's clock checks whether it is paused.
+const STEP_PAUSE_POLL: Duration = Duration::from_millis(200);
+/// How long the exit cleanup waits for a command in progress before going
+/// ahead without it.
+const EXIT_OPERATION_WAIT: Duration = Duration::from_secs(10);
+/// Lifecycle transitions kept for proxy_history.
+const HISTORY_LIMIT: usize = 200;
+
+/// What is running, under one lock so the check in proxy_run and the spawn
+/// can't race and the pieces can't disagree.
+#[derive(Default)]
+struct ProxyInner {
+    child: Option<process::ProxyChild>,
+    /// A proxy started by an earlier session of the app and re-adopted by
+    /// proxy_initialize. We hold no handle to it, only its pid.
+    adopted: Option<session::Session>,
+    /// A start (proxy_run or a watchdog restart) is in progress.
+    starting: bool,
+    /// Starts claimed so far, so a step of a run that finished after timing
+    /// out can tell whether a newer one has claimed the start since.
+    starts: u64,
+    /// proxy_restart is between stopping the old child and the new one
+    /// listening; the proxy counts as running meanwhile.
+    restarting: bool,
+    /// Gateway the current (or last) proxy forwards to.
+    gateway_url: Option<String>,
+    /// Domains it forwards to a gateway of their own instead.
+    routes: Vec<config::Route>,
+    /// Paths it sends to the real origin whatever the gateway.
+    bypass_paths: Vec<String>,
+    /// The recorded session it answers from instead of forwarding; the
+    /// gateway, routes and bypass then don't apply.
+    replay: Option<replay::Replay>,
+    /// The health route the current (or last) proxy was started with.
+    health_route: String,
+    /// Profile that gateway came from, if it was started by profile name.
+    profile: Option<String>,
+    /// HTTP proxy the current (or last) proxy reaches the gateway through.
+    upstream_proxy: Option<String>,
+    /// Run local-proxy.js even if it doesn't match the build (development
+    /// builds only).
+    skip_integrity_check: bool,
+    /// Unix seconds the current child was started.
+    started_at: Option<u64>,
+    last_exit: Option<ExitInfo>,
+    /// Why the next stop_child stops the child, when it isn't a plain stop.
+    stop_reason: Option<(Lifecycle, String)>,
+    /// The last HISTORY_LIMIT lifecycle transitions, oldest first. Here rather
+    /// than in its own lock because the monitor and watchdog write it along
+    /// with the child.
+    history: VecDeque<HistoryEntry>,
+    /// The watchdog gave up; cleared by the next start or stop.
+    failed: Option<Failed>,
+    /// Present while auto-restart is armed; proxy_stop/proxy_restore clear it.
+    watchdog: Option<Watchdog>,
+    /// Port the current (or last) proxy listens on.
+    port: u16,
+    /// Domains the current (or last) proxy_run intercepts.
+    domains: Vec<String>,
+    /// Set while a 443 -> port redirect is installed; proxy_restore removes it.
+    redirect: Option<u16>,
+    /// Set while our firewall rule exists; proxy_stop/proxy_restore remove it.
+    firewall_rule: bool,
+}
+
+/// Why the watchdog stopped restarting the proxy.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Failed {
+    at: u64,
+    reason: String,
+    /// The failure signature of the last exit, if one was recognised.
+    failure: Option<failure::Failure>,
+    /// It gave up on a crash loop rather than on the attempt limit.
+    crash_loop: bool,
+    /// Our hosts block was taken out (config.crashLoopRemoveHosts).
+    hosts_removed: bool,
+}
+
+impl ProxyInner {
+    fn record(&mut self, event: Lifecycle, detail: String, pid: Option<u32>, exit_code: Option<i32>) {
+        if self.history.len() == HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+        self.history.push_back(HistoryEntry { at: now_secs(), event, detail, pid, exit_code });
+    }
+
+    /// No child, and none coming from the watchdog.
+    fn idle(&self) -> bool {
+        self.child.is_none() && self.watchdog.is_none()
+    }
+
+    /// The port and domains of `config`, for the next start.
+    fn configure(&mut self, config: &config::Config) {
+        self.port = config.port;
+        self.domains = config.intercepted_domains();
+    }
+
+    /// Take the child out if it has exited, and record how: with its pid
+    /// and when it was started.
+    fn reap(&mut self) -> Option<(ExitInfo, u32, Option<u64>)> {
+        let mut info = match self.child.as_mut().map(|c| c.try_wait()) {
+            Some(Ok(Some(status))) => ExitInfo::from_exit(status, Lifecycle::Crashed),
+            Some(Err(_)) => {
+                ExitInfo { code: None, signal: None, reason: Lifecycle::Crashed, at: now_secs(), failure: None }
+            }
+            _ => return None,
+        };
+        if info.code == Some(0) {
+            info.reason = Lifecycle::Stopped;
+        }
+        let pid = self.child.take().map(|c| c.id()).unwrap_or_default();
+        let detail = format!("exited on its own (code {:?}, signal {:?})", info.code, info.signal);
+        self.record(info.reason, detail, Some(pid), info.code);
+        Some((info, pid, self.started_at.take()))
+    }
+
+    fn pid(&self) -> Option<u32> {
+        self.child
+            .as_ref()
+            .map(process::ProxyChild::id)
+            .or_else(|| self.adopted.as_ref().map(|s| s.pid))
+    }
+
+    fn running(&self) -> bool {
+        self.pid().is_some()
+    }
+
+    /// Refuse a start while one is in progress, a proxy runs here, or
+    /// `service` runs one; otherwise mark it in progress.
+    fn claim_start(&mut self, service: Option<&service::ServiceState>) -> Result<(), ProxyError> {
+        if self.starting {
+            return Err(ProxyError::AlreadyRunning("The proxy is already being started".into()));
+        }
+        if let Some(pid) = self.pid() {
+            return Err(ProxyError::AlreadyRunning(format!("The proxy is already running (pid {})", pid)));
+        }
+        if let Some(service) = service {
+            return Err(ProxyError::AlreadyRunning(format!(
+                "The background service (pid {}) runs the proxy; uninstall it to run the proxy from here",
+                service.service_pid
+            )));
+        }
+        self.starting = true;
+        self.starts += 1;
+        self.failed = None;
+        Ok(())
+    }
+}
 
 struct ProxyState {
-    child: Mutex<Option<Child>>,
-    running: Mutex<bool>,
+    inner: Mutex<ProxyInner>,
+    /// Held for the whole of anything that starts or stops the proxy or
+    /// changes what it left behind (hosts, redirect, firewall rule, CA), so
+    /// e.g. a restore can't remove the entries a concurrent run just wrote.
+    /// Whoever takes it checks its preconditions only once it has it.
+    operation: tokio::sync::Mutex<()>,
+    /// Set to stop the monitor thread of the current child.
+    monitor_cancel: Mutex<Option<Arc<AtomicBool>>>,
+    /// Set to stop the thread watching our hosts entries.
+    hosts_watch_cancel: Mutex<Option<Arc<AtomicBool>>>,
+    /// Set to stop the interception sampler.
+    uptime_cancel: Mutex<Option<Arc<AtomicBool>>>,
+    /// Set to stop the health route poll.
+    health_poll_cancel: Mutex<Option<Arc<AtomicBool>>>,
+    /// The current child's last health route check; reset when one is spawned.
+    health_check: Mutex<Option<healthcheck::Check>>,
+    logs: logs::SharedLog,
+    file_log: logs::SharedFileLog,
+    /// Heartbeats of the current child; reset when one is spawned.
+    health: heartbeat::SharedHealth,
+    /// Memory and CPU of the current child; reset when one is spawned.
+    usage: Mutex<usage::Usage>,
+    /// Requests of the current (or last) drill; reset by proxy_run.
+    traffic: traffic::SharedTraffic,
+    /// Interception samples of the current (or last) drill; reset by proxy_run.
+    uptime: uptime::SharedUptime,
+    audit: Mutex<audit::AuditLog>,
+    /// The drill timer, if armed; proxy_stop/proxy_restore cancel it.
+    schedule: Mutex<Option<Schedule>>,
+    /// The steps of the last proxy_restore if one failed; the next skips the
+    /// ones that completed. Cleared when a restore finishes or a proxy starts.
+    unfinished_restore: Mutex<Option<Vec<RestoreStep>>>,
+    /// The hosts file every command reads and writes.
+    hosts: hosts::HostsFile,
+    /// A hosts write is waiting on the user at the elevation prompt; the
+    /// hostsWrite step's clock doesn't run meanwhile.
+    hosts_prompt: AtomicBool,
+    /// Tells when `hosts` changed, for hosts_cache and the reversion check.
+    hosts_watch: watch::HostsWatch,
+    /// The hosts file as last read by cached_hosts.
+    hosts_cache: Mutex<hosts::CachedRead>,
+    /// The slow parts of proxy_status; None to check them again.
+    slow_status: Mutex<Option<SlowStatus>>,
+    /// Leave the proxy and our hosts block in place when the app exits.
+    keep_running_on_exit: Mutex<bool>,
+    /// Where the running proxy is recorded for a later session.
+    session_file: Mutex<Option<PathBuf>>,
+    /// Where the last gateway seen working is kept, and what it holds.
+    last_good_file: Mutex<Option<PathBuf>>,
+    last_good: Mutex<Option<lastgood::LastGood>>,
+    /// Where the background service reports that it runs the proxy.
+    service_state_file: Mutex<Option<PathBuf>>,
+    /// The stored settings (the defaults if the file was unreadable).
+    config: Mutex<config::Config>,
+    config_file: Mutex<Option<PathBuf>>,
+    /// Why the stored config couldn't be loaded at startup.
+    config_error: Mutex<Option<String>>,
+    /// The gateway allowlist, verified at startup and after state_import; the
+    /// error leaves only passthrough usable.
+    allowlist: Mutex<Result<allowlist::Allowlist, String>>,
+    /// What config.autorunProxy did at this launch; None unless started at
+    /// login with it set.
+    autorun: Mutex<Option<AutorunOutcome>>,
+    /// Set once the exit cleanup has run, so closing the window and the exit
+    /// that follows don't do it twice.
+    exit_cleaned: AtomicBool,
+    /// The exit cleanup is running on its own thread; closing and exiting
+    /// wait for it.
+    exit_cleanup_running: AtomicBool,
 }
 
-#[tauri::command]
-fn proxy_initialize() -> Result<String, String> {
-    let content = fs::read_to_string(HOSTS_PATH).map_err(|e| format!("Failed to read hosts: {}", e))?;
-    let has_entry = content.lines().any(|line| {
-        let trimmed = line.trim();
-        !trimmed.starts_with('#') && trimmed.contains(WINDSURF_DOMAIN)
-    });
-    Ok(serde_json::json!({
-        "hostsModified": has_entry,
-        "proxyRunning": false,
-        "certInstalled": true
-    }).to_string())
+impl ProxyState {
+    /// Nothing running, with the default port and domains until the config
+    /// is loaded; every command reads and writes `hosts`.
+    fn new(hosts: hosts::HostsFile) -> Self {
+        ProxyState {
+            operation: tokio::sync::Mutex::new(()),
+            inner: Mutex::new(ProxyInner {
+                port: DEFAULT_PROXY_PORT,
+                domains: default_domains(),
+                ..Default::default()
+            }),
+            monitor_cancel: Mutex::new(None),
+            hosts_watch_cancel: Mutex::new(None),
+            uptime_cancel: Mutex::new(None),
+            health_poll_cancel: Mutex::new(None),
+            health_check: Mutex::new(None),
+            logs: Arc::new(Mutex::new(logs::LogBuffer::new())),
+            file_log: Arc::new(Mutex::new(logs::FileLog::new())),
+            health: heartbeat::SharedHealth::default(),
+            usage: Mutex::new(usage::Usage::default()),
+            traffic: Arc::new(Mutex::new(traffic::TrafficLog::new())),
+            uptime: Arc::new(Mutex::new(uptime::UptimeLog::new())),
+            audit: Mutex::new(audit::AuditLog::new()),
+            schedule: Mutex::new(None),
+            hosts_prompt: AtomicBool::new(false),
+            unfinished_restore: Mutex::new(None),
+            hosts_watch: watch::HostsWatch::start(hosts.path()),
+            hosts,
+            hosts_cache: Mutex::new(hosts::CachedRead::default()),
+            slow_status: Mutex::new(None),
+            keep_running_on_exit: Mutex::new(false),
+            exit_cleaned: AtomicBool::new(false),
+            exit_cleanup_running: AtomicBool::new(false),
+            session_file: Mutex::new(None),
+            last_good_file: Mutex::new(None),
+            last_good: Mutex::new(None),
+            service_state_file: Mutex::new(None),
+            config: Mutex::new(config::Config::default()),
+            config_file: Mutex::new(None),
+            config_error: Mutex::new(None),
+            allowlist: Mutex::new(Err("The gateway allowlist is not loaded yet".into())),
+            autorun: Mutex::new(None),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExitInfo {
+    code: Option<i32>,
+    signal: Option<i32>,
+    /// Stopped, AutoStopped or Crashed.
+    reason: Lifecycle,
+    at: u64,
+    /// A known failure signature in the output of the run that ended.
+    failure: Option<failure::Failure>,
 }
 
-#[tauri::command]
-fn proxy_run(gateway_url: String, state: State<'_, ProxyState>) -> Result<String, String> {
-    // 1. Add hosts entry
-    let content = fs::read_to_string(HOSTS_PATH).map_err(|e| format!("Read hosts failed: {}", e))?;
-    let entry = format!("127.0.0.1 {}", WINDSURF_DOMAIN);
-    if !content.lines().any(|l| l.trim() == entry) {
-        let new_content = format!("{}\n{}\n", content.trim_end(), entry);
-        fs::write(HOSTS_PATH, new_content).map_err(|e| format!("Write hosts failed: {}", e))?;
+impl ExitInfo {
+    fn from_exit(exit: process::Exit, reason: Lifecycle) -> Self {
+        ExitInfo { code: exit.code, signal: exit.signal, reason, at: now_secs(), failure: None }
     }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum Lifecycle {
+    Started,
+    /// Stopped on request (proxy_stop, proxy_restore, a gateway switch), or
+    /// exited on its own with code 0.
+    Stopped,
+    /// Exited on its own with an error.
+    Crashed,
+    /// Started again by the watchdog or proxy_restart.
+    Restarted,
+    /// Stopped by the drill timer.
+    AutoStopped,
+    /// The watchdog gave up restarting it.
+    RestartFailed,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryEntry {
+    /// Unix seconds.
+    at: u64,
+    event: Lifecycle,
+    detail: String,
+    pid: Option<u32>,
+    exit_code: Option<i32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InitReport {
+    /// Running as administrator / root, so hosts can be written without a prompt.
+    elevated: bool,
+    hosts_modified: bool,
+    /// Why the hosts file couldn't be read. The hosts fields are then empty.
+    hosts_error: Option<String>,
+    /// A probe that changes nothing found the hosts file writable.
+    hosts_writable: bool,
+    /// What the probe found, classified like a failed write, so the UI can
+    /// ask for elevation or point at security software before Start.
+    hosts_write_probe: hosts::WriteProbe,
+    /// Problems with our hosts markers (unterminated or duplicate blocks).
+    hosts_warnings: Vec<String>,
+    /// Entries for our domains outside the marker block.
+    stray_entries: Vec<String>,
+    /// Duplicate or loose entries left by a previous run; proxy_run cleans them up.
+    stale_entries: usize,
+    /// Pid of a proxy left by a previous session that was just re-adopted.
+    adopted_pid: Option<u32>,
+    /// Our hosts block is present but no proxy of ours is running (e.g. after
+    /// a crash), so the domains point at nothing; proxy_repair removes it.
+    stale_modification: bool,
+    /// The entries of that block.
+    hosts_entries: Vec<hosts::HostsEntry>,
+    proxy_running: bool,
+    cert_installed: bool,
+    local_ca_exists: bool,
+    ca_expires_at: Option<i64>,
+    /// When the first of the CA and the server certificate expires; None
+    /// while there is no CA.
+    cert_expires_at: Option<i64>,
+    /// Whole days until then; negative once expired, when proxy_run refuses.
+    cert_days_remaining: Option<i64>,
+    orphan_processes: Vec<process::OrphanProcess>,
+    proxy_script_exists: bool,
+    /// A packaged local-proxy sidecar is installed; node isn't needed then.
+    sidecar_available: bool,
+    certs_dir_exists: bool,
+    /// The Node.js runtime proxy_run would use, if any.
+    node: Option<node::NodeRuntime>,
+    node_found: bool,
+    node_version: Option<String>,
+    /// Nothing else listens on the proxy port (ignoring our own running proxy).
+    port_available: bool,
+    port_owner: Option<net::PortOwner>,
+    /// The same for 443, which Windsurf connects to whatever the proxy port.
+    port_443_free: bool,
+    port_443_owner: Option<net::PortOwner>,
+    /// Records of a proxy or service that is gone: a session whose proxy
+    /// exited (removed by this call), or a service state no longer updated.
+    stale_state_files: Vec<PathBuf>,
+    /// The stored config couldn't be loaded and the defaults are in use.
+    config_error: Option<String>,
+    /// Version of the gateway allowlist in force; None if there is none.
+    allowlist_version: Option<u64>,
+    /// The allowlist was loaded without checking its signature.
+    allowlist_unsigned: bool,
+    /// Why the allowlist couldn't be loaded; only passthrough works then.
+    allowlist_error: Option<String>,
+    /// The system's https proxy, to pre-fill the upstream proxy setting.
+    system_proxy: Option<String>,
+    /// Shipped proxy files that differ from this build; proxy_run won't run
+    /// local-proxy.js while any do.
+    integrity_mismatches: Vec<integrity::Mismatch>,
+    /// No mismatches, and the resources dir could be found to check.
+    integrity_ok: bool,
+    /// How hosts file changes are noticed: notifications, or mtime polling
+    /// where those don't work.
+    hosts_watch: watch::Mode,
+    /// The system resolves over DNS-over-HTTPS or -TLS, which clients may
+    /// use to skip the hosts file; doh_disable turns it off for the drill.
+    doh_enabled: bool,
+    doh: doh::Probe,
+    /// Other TLS-intercepting software installed here (Zscaler, Netskope,
+    /// ...); proxy_run checks whether one intercepts the domains.
+    conflicting_agents: Vec<agents::Agent>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProxyStatus {
+    /// Which state interception is in; see Activity.
+    activity: Activity,
+    /// Why the watchdog gave up, while activity is Failed.
+    failed: Option<Failed>,
+    /// The proxy_restore steps with something left to undo: what is still in
+    /// place, plus a DNS flush or CA removal the last restore failed.
+    remnants: Vec<&'static str>,
+    /// The proxy isn't running but our domains still point at 127.0.0.1,
+    /// so Windsurf can't connect until the entries go (proxy_stop or
+    /// proxy_restore).
+    hosts_orphaned: bool,
+    /// Every domain has both its 127.0.0.1 and ::1 entry.
+    hosts_modified: bool,
+    /// Some, but not all, of the entries are present.
+    hosts_partial: bool,
+    /// The `address domain` entries that are absent.
+    hosts_missing: Vec<String>,
+    /// How the hosts file is encoded; None if it couldn't be read.
+    hosts_encoding: Option<hosts::Encoding>,
+    /// Why the hosts file couldn't be read. The hosts fields above then say
+    /// nothing about whether our entries are in place.
+    hosts_error: Option<String>,
+    /// Also true while proxy_restart swaps the child, or while the background
+    /// service runs the proxy; `pid` is then None.
+    proxy_running: bool,
+    /// proxy_restart is in progress.
+    restarting: bool,
+    /// The proxy heartbeated within the last heartbeat::STALE_AFTER and its
+    /// health route answered at the last check, which is recent. Always
+    /// false for an adopted proxy, which isn't polled.
+    healthy: bool,
+    /// The current child's last heartbeat and the metrics it carried.
+    health: heartbeat::Health,
+    /// The current child's last health route check.
+    health_check: Option<healthcheck::Check>,
+    /// Memory and CPU of the current child, with their peaks.
+    resources: usage::Usage,
+    cert_installed: bool,
+    /// As in InitReport.
+    cert_expires_at: Option<i64>,
+    cert_days_remaining: Option<i64>,
+    backup_available: bool,
+    pid: Option<u32>,
+    started_at: Option<u64>,
+    uptime_secs: Option<u64>,
+    last_exit: Option<ExitInfo>,
+    port: u16,
+    /// Port 443 is forwarded to `port`.
+    redirected: bool,
+    /// Our firewall rule exists; None where there is no firewall to manage.
+    firewall_rule: Option<bool>,
+    /// Domains the proxy intercepts.
+    domains: Vec<String>,
+    /// Each of `domains` with the gateway it goes to; empty for passthrough.
+    /// No entries at all in replay mode.
+    routing: Vec<config::Route>,
+    /// Paths the proxy sends to the real origin instead.
+    bypass_paths: Vec<String>,
+    /// Whether the current (or last) proxy forwards or replays.
+    mode: replay::Mode,
+    /// The recorded session it answers from in replay mode.
+    replay: Option<replay::Replay>,
+    /// Domains our hosts block currently maps to 127.0.0.1.
+    hosts_domains: Vec<String>,
+    /// The lines of our hosts block, as `{address, host}`.
+    hosts_entries: Vec<hosts::HostsEntry>,
+    /// Every domain currently resolves to 127.0.0.1.
+    resolves_to_loopback: bool,
+    /// Set while the background service runs the proxy instead of the app.
+    /// The hosts fields are then about its block.
+    service: Option<service::ServiceState>,
+    /// Unix seconds the drill timer will stop the proxy at.
+    auto_stop_at: Option<u64>,
+    auto_stop_in_secs: Option<u64>,
+}
+
+impl ProxyStatus {
+    /// The status without what moves on its own (uptime, countdown,
+    /// traffic), so the status watch only pushes real changes.
+    fn change_key(&self) -> serde_json::Value {
+        let mut key = serde_json::to_value(self).unwrap_or_default();
+        if let Some(fields) = key.as_object_mut() {
+            for field in ["uptimeSecs", "autoStopInSecs", "health", "healthCheck", "resources"] {
+                fields.remove(field);
+            }
+            if let Some(service) = fields.get_mut("service").and_then(|s| s.as_object_mut()) {
+                service.remove("updatedAt");
+            }
+        }
+        key
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum Activity {
+    /// The proxy runs (here or in the service).
+    Active,
+    /// The watchdog gave up restarting a crashing proxy. hostsOrphaned says
+    /// whether our entries remain.
+    Failed,
+    /// The proxy is stopped but some or all of our hosts entries remain.
+    HostsOnly,
+    /// Neither.
+    Clean,
+}
+
+impl Activity {
+    fn of(proxy_running: bool, failed: bool, hosts_present: bool) -> Self {
+        match (proxy_running, failed, hosts_present) {
+            (true, _, _) => Activity::Active,
+            (false, true, _) => Activity::Failed,
+            (false, false, true) => Activity::HostsOnly,
+            (false, false, false) => Activity::Clean,
+        }
+    }
+}
+
+/// ProxyStatus fields too slow to find out every second.
+struct SlowStatus {
+    checked_at: Instant,
+    cert_installed: bool,
+    cert_expires_at: Option<i64>,
+    backup_available: bool,
+    firewall_rule: Option<bool>,
+    resolves_to_loopback: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RunResult {
+    ok: bool,
+    message: String,
+    address: String,
+    port: u16,
+    pid: u32,
+    /// Gateway URL after normalization; empty in passthrough and replay mode.
+    gateway_url: String,
+    /// The recorded session answered from in replay mode.
+    replay: Option<replay::Replay>,
+    /// Result of the optional TCP pre-check; None when not requested.
+    gateway_reachable: Option<bool>,
+    /// None when the gateway is reached directly.
+    upstream_proxy: Option<String>,
+    /// Whether the DNS cache was flushed; None when hosts was left untouched.
+    dns_flushed: Option<bool>,
+    /// Problems with our hosts markers that this run repaired.
+    hosts_warnings: Vec<String>,
+    /// Duplicate or loose entries from earlier runs that were replaced by the block.
+    stale_entries_removed: usize,
+    /// The hosts write went through an elevation prompt.
+    elevation_requested: bool,
+    /// Unix seconds the drill timer will stop the proxy at.
+    auto_stop_at: Option<u64>,
+    /// The firewall rule couldn't be added (FIREWALL_POLICY when group
+    /// policy forbids it); the proxy was started anyway.
+    firewall_error: Option<ProxyError>,
+    /// Domains whose real origin another agent already intercepts; the run
+    /// went ahead (see config.refuseOnAgentInterception). Certificate errors
+    /// in Windsurf are then likely.
+    agent_interception: Vec<agents::OriginCheck>,
+    /// Set by a dry run, which changed nothing: `pid` is then 0 and the
+    /// hosts fields say what the run would do.
+    plan: Option<RunPlan>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RunPlan {
+    checks: Vec<PlanCheck>,
+    /// The hosts lines the run would remove and add.
+    hosts_changes: Vec<hosts::LineChange>,
+    /// The run's gateway isn't the last one seen working, so the UI can ask
+    /// before the drill starts. False for passthrough or with none recorded.
+    gateway_changed: bool,
+    /// As gateway_last_good returns it.
+    last_good_gateway: Option<lastgood::LastGood>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlanCheck {
+    name: &'static str,
+    /// False: the run would fail here (or, for the certificate, Windsurf
+    /// wouldn't trust the proxy).
+    ok: bool,
+    detail: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RestartResult {
+    ok: bool,
+    message: String,
+    pid: u32,
+    port: u16,
+    /// Gateway URL after normalization; empty in passthrough and replay mode.
+    gateway_url: String,
+    previous_gateway_url: String,
+    mode: replay::Mode,
+    /// The recorded session the new proxy answers from in replay mode.
+    replay: Option<replay::Replay>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StopResult {
+    ok: bool,
+    message: String,
+    /// None when no proxy was running.
+    graceful: Option<bool>,
+    /// Nothing is listening on the proxy port any more.
+    port_free: bool,
+    /// Whether the DNS cache was flushed; None when hosts wasn't rewritten.
+    dns_flushed: Option<bool>,
+    /// Whether our hosts block was there to remove; None when it was kept.
+    hosts_removed: Option<bool>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RestoreResult {
+    #[serde(flatten)]
+    stop: StopResult,
+    /// Problems with our hosts markers found while removing them.
+    hosts_warnings: Vec<String>,
+    /// Entries for the domains outside the marker block (left in place
+    /// unless `remove_stray` was set).
+    stray_entries: Vec<String>,
+    stray_removed: bool,
+    elevation_requested: bool,
+    /// Whether the system DoH settings doh_disable changed were put back;
+    /// None when it changed none.
+    doh_restored: Option<bool>,
+    /// Every step in the order it ran; `ok` above is false if any failed.
+    steps: Vec<RestoreStep>,
+}
+
+/// One step of proxy_restore: stopProxy, hostsEntries, dnsFlush, redirect,
+/// firewallRule, doh, then cert if asked for.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RestoreStep {
+    step: &'static str,
+    ok: bool,
+    /// An earlier restore that didn't finish completed it, so it didn't run.
+    skipped: bool,
+    detail: String,
+}
 
-    // 2. Start local proxy (node local-proxy.js --gateway <url>)
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CertChangeResult {
+    ok: bool,
+    message: String,
+    cert_installed: bool,
+    elevation_required: bool,
+    elevation_obtained: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CertRegenerateResult {
+    ok: bool,
+    message: String,
+    previously_installed: bool,
+    cert_installed: bool,
+    ca_expires_at: Option<i64>,
+    /// Names the new server certificate is issued for.
+    domains: Vec<String>,
+}
+
+/// Payload of `cert://expiring`.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CertExpiry {
+    expires_at: i64,
+    days_remaining: i64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whole days from now until `at` (unix seconds), rounded down; negative
+/// once `at` has passed.
+fn days_until(at: i64) -> i64 {
+    (at - now_secs() as i64).div_euclid(24 * 60 * 60)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Outcome of `apply_hosts`.
+#[derive(Clone, Copy)]
+struct HostsApplied {
+    dns_flushed: bool,
+    elevation_requested: bool,
+}
+
+/// Write the hosts file, going through the elevation prompt when we lack the
+/// rights, then flush the DNS cache so the change takes effect now. A failed
+/// flush is logged and reported, not fatal.
+fn apply_hosts(
+    app: &AppHandle,
+    state: &ProxyState,
+    content: &[u8],
+    lock: &hosts::UpdateLock,
+) -> Result<HostsApplied, ProxyError> {
+    let before = fs::read(state.hosts.path()).unwrap_or_default();
+    let elevation_requested = match state.hosts.write(content) {
+        Ok(()) => false,
+        Err(ProxyError::NotElevated(_)) => {
+            log_action(state, "Writing hosts needs admin rights; requesting elevation");
+            let staging = app_data_dir(app)?.join(HOSTS_STAGING_FILE);
+            state.hosts_prompt.store(true, Ordering::SeqCst);
+            let written = state.hosts.write_elevated(content, &staging, lock);
+            state.hosts_prompt.store(false, Ordering::SeqCst);
+            if let Err(ProxyError::NotElevated(_)) = &written {
+                // A grant recorded by setup no longer stands
+                if let Err(e) = setup::forget_elevation(&setup::path(&app_data_dir(app)?)) {
+                    log_action(state, &format!("Forgetting the setup elevation grant failed: {}", e));
+                }
+            }
+            written?;
+            true
+        }
+        Err(e) => return Err(e),
+    };
+    audit(state, hosts_change_event(&before, content));
+    forget_status(state);
+    Ok(HostsApplied { dns_flushed: flush_dns(state).is_ok(), elevation_requested })
+}
+
+fn hosts_change_event(before: &[u8], after: &[u8]) -> audit::Event {
+    let lines = |bytes: &[u8]| -> Vec<String> {
+        hosts::HostsText::parse(bytes)
+            .block_entries()
+            .into_iter()
+            .map(|e| format!("{} {}", e.address, e.host))
+            .collect()
+    };
+    let (old, new) = (lines(before), lines(after));
+    audit::Event::HostsChanged {
+        before_hash: audit::sha256_hex(before),
+        after_hash: audit::sha256_hex(after),
+        added: new.iter().filter(|l| !old.contains(l)).cloned().collect(),
+        removed: old.iter().filter(|l| !new.contains(l)).cloned().collect(),
+    }
+}
+
+/// The system hosts file as the commands edit it: stores go through
+/// `apply_hosts`, and the outcome of the last one is kept for the result.
+/// A file over config.hostsMaxMb is refused unless `allow_large`.
+struct SystemHosts<'a> {
+    app: &'a AppHandle,
+    state: &'a ProxyState,
+    applied: Cell<Option<HostsApplied>>,
+    allow_large: bool,
+}
+
+impl<'a> SystemHosts<'a> {
+    fn new(app: &'a AppHandle, state: &'a ProxyState) -> Self {
+        SystemHosts { app, state, applied: Cell::new(None), allow_large: false }
+    }
+
+    fn allowing_large(self, allow_large: bool) -> Self {
+        SystemHosts { allow_large, ..self }
+    }
+}
+
+impl HostsEditor for SystemHosts<'_> {
+    fn load(&self) -> Result<hosts::HostsText, ProxyError> {
+        self.state.hosts.load()
+    }
+
+    fn store(&self, text: &hosts::HostsText, lock: &hosts::UpdateLock) -> Result<(), ProxyError> {
+        self.applied.set(Some(apply_hosts(self.app, self.state, &text.to_bytes(), lock)?));
+        Ok(())
+    }
+
+    /// Only rewrites take the lock, so this is where a large file stops them.
+    fn lock(&self) -> Result<hosts::UpdateLock, ProxyError> {
+        if !self.allow_large {
+            let max_mb = self.state.config.lock().hosts_max_mb;
+            self.state.hosts.check_size(usage::mib(max_mb))?;
+        }
+        self.state.hosts.update_lock()
+    }
+}
+
+fn flush_dns(state: &ProxyState) -> Result<(), String> {
+    let result = dns::flush();
+    match &result {
+        Ok(()) => log_action(state, "DNS cache flushed"),
+        Err(e) => log_action(state, &format!("Flushing DNS cache failed: {}", e)),
+    }
+    result
+}
+
+/// Bundled resources live next to the executable.
+fn resources_dir() -> Result<PathBuf, ProxyError> {
     let exe_dir = std::env::current_exe()
-        .map_err(|e| e.to_string())?
+        .map_err(|e| ProxyError::Internal(e.to_string()))?
         .parent()
-        .ok_or("no parent dir")?
+        .ok_or_else(|| ProxyError::Internal("no parent dir".into()))?
         .to_path_buf();
+    Ok(exe_dir.join("resources"))
+}
+
+/// Where the per-install CA lives, whether or not it has been generated.
+/// Everything that only looks at the CA goes through this.
+fn certs_path(app: &AppHandle) -> Result<PathBuf, ProxyError> {
+    Ok(app_data_dir(app)?.join("certs"))
+}
+
+/// The per-install CA, generated for the current domains when there is none
+/// (never the bundled one). Only proxy_run and setup's certificate step
+/// create it; cert_regenerate and cert_renew issue a new one outright.
+fn ensure_ca(app: &AppHandle) -> Result<PathBuf, ProxyError> {
+    let dir = certs_path(app)?;
+    let domains = app.state::<ProxyState>().inner.lock().domains.clone();
+    let domains: Vec<&str> = domains.iter().map(String::as_str).collect();
+    cert::ensure(&dir, &domains).map_err(ProxyError::Cert)?;
+    Ok(dir)
+}
+
+/// The shipped gateway allowlist, or a newer one state_import put in the app
+/// data dir.
+fn load_allowlist(app: &AppHandle) -> Result<allowlist::Allowlist, String> {
+    let resources = resources_dir().map_err(|e| e.to_string())?;
+    allowlist::load(&resources, app.path().app_data_dir().ok().as_deref())
+}
+
+fn default_domains() -> Vec<String> {
+    vec![WINDSURF_DOMAIN.to_string()]
+}
+
+/// Whether our CA is trusted; false while there is none.
+fn cert_installed(app: &AppHandle) -> bool {
+    certs_path(app).map(|dir| cert::is_installed(&dir)).unwrap_or(false)
+}
+
+fn app_data_dir(app: &AppHandle) -> Result<PathBuf, ProxyError> {
+    app.path()
+        .app_data_dir()
+        .map_err(|e| ProxyError::Internal(format!("Failed to resolve app data dir: {}", e)))
+}
+
+fn backup_dir(app: &AppHandle) -> Result<PathBuf, ProxyError> {
+    let dir = app_data_dir(app)?.join("backups");
+    fs::create_dir_all(&dir)
+        .map_err(|e| ProxyError::Internal(format!("Failed to create backup dir: {}", e)))?;
+    Ok(dir)
+}
+
+/// Backups sorted oldest first (the timestamp suffix sorts numerically).
+fn list_backups(dir: &Path) -> Vec<PathBuf> {
+    let mut backups: Vec<(u128, PathBuf)> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let name = e.file_name().to_string_lossy().to_string();
+                    let ts = name.strip_prefix(HOSTS_BACKUP_PREFIX)?.parse::<u128>().ok()?;
+                    Some((ts, e.path()))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    backups.sort_by_key(|(ts, _)| *ts);
+    backups.into_iter().map(|(_, p)| p).collect()
+}
+
+fn backup_hosts(app: &AppHandle, state: &ProxyState) -> Result<PathBuf, ProxyError> {
+    let dir = backup_dir(app)?;
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    let target = dir.join(format!("{}{}", HOSTS_BACKUP_PREFIX, ts));
+    fs::copy(state.hosts.path(), &target)
+        .map_err(|e| ProxyError::Hosts(format!("Backup hosts failed: {}", hosts::describe_io_error(&e))))?;
+
+    // Keep only the most recent backups
+    let backups = list_backups(&dir);
+    if backups.len() > HOSTS_BACKUP_KEEP {
+        for old in &backups[..backups.len() - HOSTS_BACKUP_KEEP] {
+            let _ = fs::remove_file(old);
+        }
+    }
+    Ok(target)
+}
+
+fn proxy_script_path() -> Result<PathBuf, ProxyError> {
+    Ok(resources_dir()?.join("proxy").join("local-proxy.js"))
+}
+
+/// Where update_apply keeps the downloaded script.
+fn update_dir(app: &AppHandle) -> Result<PathBuf, ProxyError> {
+    Ok(app_data_dir(app)?.join(update::DIR))
+}
+
+fn current_pid(state: &ProxyState) -> Option<u32> {
+    state.inner.lock().pid()
+}
+
+/// Clears `starting` when the start it guards returns, however it returns.
+struct StartGuard<'a>(&'a ProxyState);
+
+impl Drop for StartGuard<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.0.inner.lock();
+        inner.starting = false;
+        inner.restarting = false;
+    }
+}
+
+/// Check that nothing is running or starting and mark a start in progress,
+/// in one step under the lock.
+fn claim_start(state: &ProxyState) -> Result<StartGuard<'_>, ProxyError> {
+    let service = service_state(state);
+    state.inner.lock().claim_start(service.as_ref())?;
+    Ok(StartGuard(state))
+}
+
+/// The background service's state while it runs the proxy; the hosts block
+/// is then its to remove, not ours.
+fn service_state(state: &ProxyState) -> Option<service::ServiceState> {
+    state.service_state_file.lock().as_deref().and_then(service::load_state)
+}
+
+/// Where each of `domains` is forwarded: its route, else the default gateway
+/// (empty for passthrough). Those of `service` while it runs the proxy, else
+/// of the current (or last) proxy, else of the stored config.
+fn routing(state: &ProxyState, domains: &[String], service: Option<&service::ServiceState>) -> Vec<config::Route> {
+    // Replay forwards nowhere
+    if service.is_none() && state.inner.lock().replay.is_some() {
+        return Vec::new();
+    }
+    let (gateway_url, routes) = match service {
+        Some(service) => (service.gateway_url.clone(), service.routes.clone()),
+        None => {
+            let current = {
+                let inner = state.inner.lock();
+                inner.gateway_url.clone().map(|gateway_url| (gateway_url, inner.routes.clone()))
+            };
+            current.unwrap_or_else(|| {
+                let config = state.config.lock();
+                (config.gateway_url.clone().unwrap_or_default(), config.routes.clone())
+            })
+        }
+    };
+    domains
+        .iter()
+        .map(|domain| config::Route {
+            domain: domain.clone(),
+            gateway_url: routes.iter().find(|r| &r.domain == domain).map_or(&gateway_url, |r| &r.gateway_url).clone(),
+        })
+        .collect()
+}
+
+/// Refuse to stop or restore what the service runs, unless we have a child
+/// of our own to stop.
+fn check_not_service(state: &ProxyState) -> Result<(), ProxyError> {
+    match service_state(state) {
+        Some(service) if current_pid(state).is_none() => Err(ProxyError::InvalidState(format!(
+            "The background service (pid {}) runs the proxy; uninstall it to stop interception",
+            service.service_pid
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn save_session(state: &ProxyState, session: &session::Session) {
+    let Some(path) = state.session_file.lock().clone() else {
+        return;
+    };
+    if let Err(e) = session::save(&path, session) {
+        log_action(state, &format!("Recording the proxy session failed: {}", e));
+    }
+}
+
+fn clear_session(state: &ProxyState) {
+    if let Some(path) = state.session_file.lock().as_deref() {
+        session::clear(path);
+    }
+}
+
+/// Notice an adopted proxy exiting; there is no handle to wait on, so this
+/// checks that the pid still exists.
+fn refresh_adopted(state: &ProxyState) {
+    let mut inner = state.inner.lock();
+    let Some(pid) = inner.adopted.as_ref().map(|s| s.pid) else {
+        return;
+    };
+    if process::process_name(pid).is_some() {
+        return;
+    }
+    inner.adopted = None;
+    inner.started_at = None;
+    drop(inner);
+    clear_session(state);
+    log_action(state, &format!("Adopted proxy (pid {}) exited", pid));
+}
+
+/// Record one of our own actions in the persistent log.
+fn log_action(state: &ProxyState, message: &str) {
+    state.file_log.lock().write("app", message);
+}
+
+fn audit(state: &ProxyState, event: audit::Event) {
+    let result = state.audit.lock().record(&event);
+    if let Err(e) = result {
+        log_action(state, &format!("Writing the audit log failed: {}", e));
+    }
+}
+
+/// Spawn the proxy for `gateway_url` on `port` and start monitoring it.
+/// `event` is what the start is recorded as in the history: Started, or
+/// Restarted for a watchdog restart or gateway switch.
+fn spawn_proxy(
+    app: &AppHandle,
+    state: &ProxyState,
+    gateway_url: &str,
+    port: u16,
+    domains: &[String],
+    event: Lifecycle,
+) -> Result<u32, ProxyError> {
+    let launcher = resolve_launcher(app, state)?;
+    let certs_dir = certs_path(app)?;
+    let (upstream_proxy, routes, bypass_paths, replay) = {
+        let inner = state.inner.lock();
+        (inner.upstream_proxy.clone(), inner.routes.clone(), inner.bypass_paths.clone(), inner.replay.clone())
+    };
+    // Replay answers every request itself
+    let (routes, bypass_paths) = match replay {
+        Some(_) => (Vec::new(), Vec::new()),
+        None => (routes, bypass_paths),
+    };
+    let health_route = state.config.lock().health_route.clone();
+    let mut command = launcher.command(app);
+    if let Some(replay) = &replay {
+        command = command.arg("--replay").arg(&replay.path);
+    }
+    if let Some(upstream) = &upstream_proxy {
+        command = command.arg("--upstream-proxy").arg(upstream);
+    }
+    // Passthrough goes to the official backend, which the pin isn't for;
+    // routed domains go to gateways, which it is
+    if !gateway_url.is_empty() || !routes.is_empty() {
+        command = command.args(gateway_pin(state)?.args(&certs_dir).map_err(ProxyError::Internal)?);
+    }
+    if !routes.is_empty() {
+        let routes = serde_json::to_string(&routes).map_err(|e| ProxyError::Internal(e.to_string()))?;
+        command = command.arg("--routes").arg(routes);
+    }
+    if !bypass_paths.is_empty() {
+        command = command.arg("--bypass").arg(bypass_paths.join(","));
+        command = command.arg("--origin-ip").arg(origin_ips(state, domains).join(","));
+    }
+    let env = childenv::vars(&certs_dir.join(cert::CA_CERT_FILE), &state.config.lock().proxy_env);
+    command = command.env_clear().envs(env);
+    if let Some(dir) = launcher.working_dir() {
+        command = command.current_dir(dir);
+    }
+    let command = command
+        .arg("--gateway")
+        .arg(gateway_url)
+        .arg("--port")
+        .arg(port.to_string())
+        .arg("--domain")
+        .arg(domains.first().map(String::as_str).unwrap_or_default())
+        .arg("--domains")
+        .arg(domains.join(","))
+        .arg("--ipv6")
+        .arg("--heartbeat")
+        .arg("--health-route")
+        .arg(&health_route)
+        .arg("--cert-dir")
+        .arg(&certs_dir);
+
+    // The child's output starts arriving as soon as it is spawned
+    state.logs.lock().clear();
+    *state.health.lock() = heartbeat::Health::default();
+    let telemetry = heartbeat::Telemetry { health: state.health.clone(), traffic: state.traffic.clone() };
+    let output = logs::sink(app.clone(), state.logs.clone(), state.file_log.clone(), telemetry);
+    let child = process::ProxyChild::spawn_shell(command, output)
+        .map_err(|e| ProxyError::Spawn(format!("Failed to start proxy: {}", e)))?;
+
+    state.inner.lock().health_route = health_route;
+    *state.health_check.lock() = None;
+    *state.usage.lock() = usage::Usage::default();
+    // Whatever an unfinished restore did is undone by this start
+    *state.unfinished_restore.lock() = None;
+
+    let pid = child.id();
+    log_action(
+        state,
+        &format!("Proxy started (pid {}, port {}, gateway {}, {})", pid, port, gateway_url, launcher.describe()),
+    );
+    for route in &routes {
+        log_action(state, &format!("Routing {} to {}", route.domain, route.gateway_url));
+    }
+    if let Some(replay) = &replay {
+        log_action(state, &format!("Replaying {} ({} recorded paths)", replay.path, replay.paths));
+    }
+    audit(state, audit::Event::ProxyStarted { gateway_url: gateway_url.to_string(), pid, port });
+    let started_at = now_secs();
+    let profile = {
+        let mut inner = state.inner.lock();
+        inner.child = Some(child);
+        inner.started_at = Some(started_at);
+        inner.gateway_url = Some(gateway_url.to_string());
+        inner.port = port;
+        inner.record(event, format!("port {}, gateway {}", port, gateway_url), Some(pid), None);
+        inner.profile.clone()
+    };
+    save_session(
+        state,
+        &session::Session {
+            pid,
+            gateway_url: gateway_url.to_string(),
+            started_at,
+            listen_port: port,
+            domains: domains.to_vec(),
+            profile,
+            upstream_proxy,
+            routes,
+            bypass_paths,
+            replay,
+        },
+    );
+    spawn_monitor(app.clone(), state);
+    Ok(pid)
+}
+
+/// `domain=ip` for each of `domains`, from public DNS: our hosts entries
+/// point the system resolver at ourselves. The bypass needs them to reach
+/// the real origin; a domain that doesn't resolve only loses its bypass.
+fn origin_ips(state: &ProxyState, domains: &[String]) -> Vec<String> {
+    domains
+        .iter()
+        .filter_map(|domain| match dns::resolve_public(domain) {
+            Ok(ip) => Some(format!("{}={}", domain, ip)),
+            Err(e) => {
+                log_action(state, &format!("No bypass for {}: {}", domain, e));
+                None
+            }
+        })
+        .collect()
+}
+
+fn require_proxy_script() -> Result<PathBuf, ProxyError> {
+    let script = proxy_script_path()?;
+    if !script.is_file() {
+        return Err(ProxyError::ScriptMissing(format!(
+            "Proxy script not found at {}; reinstall the app",
+            script.display()
+        )));
+    }
+    Ok(script)
+}
+
+fn require_node() -> Result<node::NodeRuntime, ProxyError> {
+    node::resolve(&resources_dir()?)
+}
+
+/// How the proxy gets launched: our own executable in proxy mode, unless
+/// config.nodeProxy asks for local-proxy.js, which runs as the packaged
+/// sidecar when it is installed and under node otherwise.
+enum Launcher {
+    Native(PathBuf),
+    Sidecar(PathBuf),
+    Node { runtime: node::NodeRuntime, script: PathBuf },
+}
+
+impl Launcher {
+    fn command(&self, app: &AppHandle) -> ShellCommand {
+        let shell = app.shell();
+        match self {
+            Launcher::Native(exe) => shell.command(exe).arg(proxy::HELPER_ARG),
+            Launcher::Sidecar(path) => shell.command(path),
+            Launcher::Node { runtime, script } => shell.command(&runtime.path).arg(script),
+        }
+    }
+
+    /// The directory the proxy runs in, so it doesn't depend on ours.
+    fn working_dir(&self) -> Option<&Path> {
+        match self {
+            Launcher::Native(path) | Launcher::Sidecar(path) => path.parent(),
+            Launcher::Node { script, .. } => script.parent(),
+        }
+    }
+
+    /// The executable that listens, for the firewall rule.
+    fn program(&self) -> &Path {
+        match self {
+            Launcher::Native(path) | Launcher::Sidecar(path) => path,
+            Launcher::Node { runtime, .. } => &runtime.path,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Launcher::Native(_) => "built-in proxy".to_string(),
+            Launcher::Sidecar(path) => format!("sidecar {}", path.display()),
+            Launcher::Node { runtime, .. } => format!("node {} at {}", runtime.version, runtime.path.display()),
+        }
+    }
+}
+
+/// Where the shell plugin resolves the local-proxy sidecar, if it exists.
+fn sidecar_path(app: &AppHandle) -> Option<PathBuf> {
+    let command: Command = app.shell().sidecar(SIDECAR_NAME).ok()?.into();
+    let path = PathBuf::from(command.get_program());
+    path.is_file().then_some(path)
+}
+
+/// Refuses to run a local-proxy.js that differs from the one this build
+/// shipped, unless the check was skipped.
+fn resolve_launcher(app: &AppHandle, state: &ProxyState) -> Result<Launcher, ProxyError> {
+    if !state.config.lock().node_proxy {
+        let exe = std::env::current_exe()
+            .map_err(|e| ProxyError::Spawn(format!("Cannot locate the app executable: {}", e)))?;
+        return Ok(Launcher::Native(exe));
+    }
+    if let Some(path) = sidecar_path(app) {
+        return Ok(Launcher::Sidecar(path));
+    }
+    // A verified update replaces the shipped script and its integrity check
+    match update_dir(app).map_err(|e| e.to_string()).and_then(|dir| update::installed(&dir)) {
+        Ok(Some(update)) => {
+            return Ok(Launcher::Node { script: update.script, runtime: require_node()? });
+        }
+        Ok(None) => {}
+        Err(e) => log_action(state, &format!("{}; running the shipped proxy script", e)),
+    }
+    let script = require_proxy_script()?;
+    if state.inner.lock().skip_integrity_check {
+        log_action(state, "Integrity check of the proxy files skipped");
+    } else {
+        integrity::verify(&resources_dir()?).map_err(ProxyError::Integrity)?;
+    }
+    Ok(Launcher::Node { script, runtime: require_node()? })
+}
+
+/// Block until the proxy answers its health route. Fails if the child exits
+/// first (the monitor reaps it), with CertMismatch if its certificate doesn't
+/// verify against our CA, or with TimedOut once `timeout` elapses; the child
+/// is stopped in the last two cases.
+fn wait_ready(app: &AppHandle, state: &ProxyState, pid: u32, port: u16, timeout: Duration) -> Result<(), ProxyError> {
+    let deadline = Instant::now() + timeout;
+    let (domain, route, ca) = health_target(app, state)?;
+    let mut last_error = None;
+    loop {
+        if net::is_listening(port, READY_POLL_INTERVAL) {
+            let check = healthcheck::sample(port, &domain, &route, &ca);
+            if check.ok {
+                *state.health_check.lock() = Some(check);
+                return Ok(());
+            }
+            let error = check.error.clone().unwrap_or_default();
+            if check.cert_mismatch {
+                state.inner.lock().stop_reason = Some((Lifecycle::Stopped, "serves stale certificates".into()));
+                stop_child(state, process::DEFAULT_SHUTDOWN_TIMEOUT);
+                log_action(state, &format!("Proxy stopped: {}", error));
+                return Err(ProxyError::CertMismatch(error));
+            }
+            last_error = Some(error);
+        }
+        if current_pid(state) != Some(pid) {
+            // Give the pipe readers a moment to drain the last stderr lines.
+            std::thread::sleep(READY_POLL_INTERVAL);
+            let code = state.inner.lock().last_exit.as_ref().and_then(|e| e.code);
+            return Err(ProxyError::StartFailed(with_stderr_tail(
+                state,
+                format!("Proxy failed to start (exit code {:?})", code),
+            )));
+        }
+        if Instant::now() >= deadline {
+            state.inner.lock().stop_reason = Some((Lifecycle::Stopped, "did not start listening in time".into()));
+            stop_child(state, process::DEFAULT_SHUTDOWN_TIMEOUT);
+            log_action(state, "Proxy did not start listening in time; stopped it");
+            return Err(ProxyError::TimedOut {
+                message: with_stderr_tail(
+                    state,
+                    match &last_error {
+                        Some(e) => format!(
+                            "Proxy failed to start: its health route on port {} didn't answer within {}s ({})",
+                            port,
+                            timeout.as_secs_f32(),
+                            e
+                        ),
+                        None => format!(
+                            "Proxy failed to start: nothing listening on port {} after {}s",
+                            port,
+                            timeout.as_secs_f32()
+                        ),
+                    },
+                ),
+                step: "ready",
+            });
+        }
+        std::thread::sleep(READY_POLL_INTERVAL);
+    }
+}
+
+/// What the health route of the current child is checked with: the first
+/// intercepted domain (its certificate's name), the route it was started
+/// with and our CA.
+fn health_target(app: &AppHandle, state: &ProxyState) -> Result<(String, String, PathBuf), ProxyError> {
+    let (domain, route) = {
+        let inner = state.inner.lock();
+        (inner.domains.first().cloned(), inner.health_route.clone())
+    };
+    let domain = domain.or_else(|| default_domains().pop()).unwrap_or_default();
+    Ok((domain, route, certs_path(app)?.join(cert::CA_CERT_FILE)))
+}
+
+/// The last health route check passed and is recent enough to still count:
+/// within three poll intervals.
+fn health_check_passed(state: &ProxyState, now_ms: u64) -> bool {
+    let interval = state.config.lock().health_interval_secs.max(1);
+    let check = state.health_check.lock();
+    check.as_ref().is_some_and(|c| c.ok && now_ms.saturating_sub(c.at) <= interval * 3 * 1000)
+}
+
+/// Some(owner) when the proxy port is taken; the owner is None if it can't
+/// be identified.
+fn port_conflict(port: u16) -> Option<Option<net::PortOwner>> {
+    net::is_listening(port, PORT_PROBE_TIMEOUT).then(|| net::port_owner(port))
+}
+
+fn ensure_port_free(port: u16) -> Result<(), ProxyError> {
+    let Some(owner) = port_conflict(port) else {
+        return Ok(());
+    };
+    let by = match &owner {
+        Some(net::PortOwner { pid, name }) => format!("{} (pid {})", name.as_deref().unwrap_or("unknown process"), pid),
+        None => "another process".into(),
+    };
+    Err(ProxyError::PortInUse {
+        message: format!("Port {} is already in use by {}", port, by),
+        port,
+        pid: owner.as_ref().map(|o| o.pid),
+        process: owner.and_then(|o| o.name),
+    })
+}
+
+/// Forward 443 to `port` and remember it for proxy_restore.
+fn install_redirect(state: &ProxyState, port: u16) -> Result<(), ProxyError> {
+    redirect::add(port).map_err(ProxyError::Internal)?;
+    state.inner.lock().redirect = Some(port);
+    log_action(state, &format!("Port {} redirected to {}", redirect::HTTPS_PORT, port));
+    Ok(())
+}
+
+/// Allow inbound loopback connections to `program` on `port`. A failure
+/// doesn't stop the start: it is returned for RunResult instead, since
+/// loopback usually isn't filtered.
+fn install_firewall_rule(state: &ProxyState, program: &Path, port: u16) -> Option<ProxyError> {
+    match firewall::add(program, port) {
+        Ok(added) => {
+            if added {
+                state.inner.lock().firewall_rule = true;
+                forget_status(state);
+                log_action(state, &format!("Firewall rule added for {} on port {}", program.display(), port));
+            }
+            None
+        }
+        Err(firewall::AddError::Policy(e)) => {
+            log_action(state, &format!("Firewall rule refused by group policy: {}", e));
+            Some(ProxyError::FirewallPolicy(format!(
+                "Group policy doesn't allow a firewall rule for the proxy; if Windsurf can't connect, ask IT to \
+                 allow inbound loopback connections on port {} ({})",
+                port, e
+            )))
+        }
+        Err(firewall::AddError::Other(e)) => {
+            log_action(state, &format!("Adding the firewall rule failed: {}", e));
+            Some(ProxyError::Internal(format!("Adding the firewall rule failed: {}", e)))
+        }
+    }
+}
+
+/// False if there was a rule and removing it failed.
+fn remove_firewall_rule(state: &ProxyState) -> bool {
+    if !std::mem::take(&mut state.inner.lock().firewall_rule) {
+        return true;
+    }
+    let removed = match firewall::remove() {
+        Ok(()) => {
+            log_action(state, "Firewall rule removed");
+            true
+        }
+        Err(e) => {
+            log_action(state, &format!("Removing the firewall rule failed: {}", e));
+            false
+        }
+    };
+    forget_status(state);
+    removed
+}
+
+/// False if there was a redirect and removing it failed.
+fn remove_redirect(state: &ProxyState) -> bool {
+    let Some(port) = state.inner.lock().redirect.take() else {
+        return true;
+    };
+    match redirect::remove() {
+        Ok(()) => {
+            log_action(state, &format!("Port {} redirect to {} removed", redirect::HTTPS_PORT, port));
+            true
+        }
+        Err(e) => {
+            log_action(state, &format!("Removing port {} redirect failed: {}", redirect::HTTPS_PORT, e));
+            false
+        }
+    }
+}
+
+fn tamper_error(missing: &[String]) -> ProxyError {
+    ProxyError::TamperDetected(format!(
+        "The hosts entries {} were removed right after they were written; \
+         security software is probably protecting the hosts file",
+        missing.join(", ")
+    ))
+}
+
+/// Confirm the hosts entries stuck and are in effect. The file is re-read
+/// first; if a domain then doesn't resolve to 127.0.0.1, it is re-read again
+/// to tell a reverted entry from an ignored one.
+fn verify_resolution(state: &ProxyState, domains: &[String]) -> Result<(), ProxyError> {
+    let missing = state.hosts.read()?.missing_entries(domains);
+    if !missing.is_empty() {
+        return Err(tamper_error(&missing));
+    }
+    let mut unresolved: Vec<String> = domains.to_vec();
+    for attempt in 0..RESOLVE_ATTEMPTS {
+        if attempt > 0 {
+            std::thread::sleep(RESOLVE_RETRY_INTERVAL);
+        }
+        unresolved.retain(|d| !dns::resolves_to_loopback(d));
+        if unresolved.is_empty() {
+            return Ok(());
+        }
+    }
+    let hosts = state.hosts.read()?;
+    if unresolved.iter().all(|d| hosts.has_entry("127.0.0.1", d)) {
+        Err(ProxyError::HostsIgnored(format!(
+            "{} does not resolve to 127.0.0.1 although the hosts entry is present; \
+             a DNS client policy or security software is overriding the hosts file",
+            unresolved.join(", ")
+        )))
+    } else {
+        Err(tamper_error(&hosts.missing_entries(domains)))
+    }
+}
+
+/// Undo the hosts block proxy_run just wrote; `why` prefixes the log line.
+/// False if that failed.
+fn rollback_hosts_entry(app: &AppHandle, state: &ProxyState, why: &str) -> bool {
+    // Whatever the size, the block that was just written has to go again
+    match SystemHosts::new(app, state).allowing_large(true).remove_entries(&[]) {
+        Ok(_) => {
+            log_action(state, &format!("{}; hosts entry rolled back", why));
+            true
+        }
+        Err(e) => {
+            log_action(state, &format!("{}; rolling back hosts failed: {}", why, e));
+            false
+        }
+    }
+}
+
+/// What proxy_run has changed so far, so a failure undoes exactly that.
+#[derive(Default)]
+struct RunChanges {
+    hosts: bool,
+    firewall: bool,
+    redirect: bool,
+    child: Option<u32>,
+}
+
+impl RunChanges {
+    /// Undo the changes, newest first, and attach the outcome to `error`.
+    fn roll_back(&self, app: &AppHandle, state: &ProxyState, error: ProxyError) -> ProxyError {
+        let mut rollback = error::Rollback::default();
+        let mut undo = |step, ok| if ok { rollback.undone.push(step) } else { rollback.failed.push(step) };
+        if let Some(pid) = self.child {
+            if current_pid(state) == Some(pid) {
+                state.inner.lock().stop_reason = Some((Lifecycle::Stopped, "start rolled back".into()));
+                stop_child(state, process::DEFAULT_SHUTDOWN_TIMEOUT);
+            }
+            undo("child", current_pid(state) != Some(pid));
+        }
+        if self.redirect {
+            undo("redirect", remove_redirect(state));
+        }
+        if self.firewall {
+            undo("firewall", remove_firewall_rule(state));
+        }
+        if self.hosts {
+            undo("hosts", rollback_hosts_entry(app, state, &format!("Proxy start failed ({})", error.code())));
+        }
+        rollback.complete = rollback.failed.is_empty();
+        audit(
+            state,
+            audit::Event::ProxyRunRolledBack {
+                code: error.code(),
+                message: error.message().to_string(),
+                undone: rollback.undone.clone(),
+                failed: rollback.failed.clone(),
+            },
+        );
+        ProxyError::RolledBack { error: Box::new(error), rollback }
+    }
+}
+
+fn with_stderr_tail(state: &ProxyState, message: String) -> String {
+    let tail = state.logs.lock().stderr_tail(STDERR_TAIL_LINES);
+    if tail.is_empty() {
+        message
+    } else {
+        format!("{}\n{}", message, tail.join("\n"))
+    }
+}
+
+/// What proxy_run was asked to do, before validation.
+struct RunOptions {
+    gateway_url: String,
+    /// The profile `gateway_url` was taken from.
+    profile: Option<String>,
+    /// Empty: connect to the gateway directly.
+    upstream_proxy: String,
+    port: u16,
+    domains: Vec<String>,
+    /// Their domains are intercepted whether or not `domains` lists them.
+    routes: Vec<config::Route>,
+    bypass_paths: Vec<String>,
+    auto_restart: bool,
+    check_gateway: bool,
+    ready_timeout: Duration,
+    /// Arm the drill timer for this many minutes.
+    duration_minutes: Option<u64>,
+    skip_integrity_check: bool,
+    /// Only check; see plan_run.
+    dry_run: bool,
+    /// Write the entries even into a hosts file over config.hostsMaxMb.
+    allow_large_hosts: bool,
+    /// Replay this session export instead of forwarding; see replay.rs.
+    replay_path: Option<String>,
+}
 
-    let proxy_script = exe_dir.join("resources").join("proxy").join("local-proxy.js");
-    let certs_dir = exe_dir.join("resources").join("certs");
+fn run_proxy(app: &AppHandle, state: &ProxyState, options: RunOptions) -> Result<RunResult, ProxyError> {
+    let RunOptions {
+        gateway_url,
+        profile,
+        upstream_proxy,
+        port,
+        domains,
+        routes,
+        bypass_paths,
+        auto_restart,
+        check_gateway,
+        ready_timeout,
+        duration_minutes,
+        skip_integrity_check,
+        dry_run,
+        allow_large_hosts,
+        replay_path,
+    } = options;
+    if duration_minutes == Some(0) {
+        return Err(ProxyError::InvalidArgument("The drill duration must be at least one minute".into()));
+    }
+    if skip_integrity_check && !cfg!(debug_assertions) {
+        return Err(ProxyError::Unsupported(
+            "Skipping the integrity check is only possible in development builds".into(),
+        ));
+    }
+    // Replay answers from the recording: no gateway, routes or bypass to check or pass on
+    let (gateway_url, routes, bypass_paths) = match replay_path {
+        Some(_) => (String::new(), Vec::new(), Vec::new()),
+        None => (gateway_url, routes, bypass_paths),
+    };
+    let routes = config::normalize_routes(&routes)?;
+    let bypass_paths = config::normalize_bypass_paths(&bypass_paths)?;
+    let domains: Vec<String> = domains.into_iter().chain(routes.iter().map(|r| r.domain.clone())).collect();
+    if dry_run {
+        return plan_run(
+            app,
+            state,
+            &gateway_url,
+            &upstream_proxy,
+            port,
+            &domains,
+            &routes,
+            replay_path.as_deref(),
+            allow_large_hosts,
+        );
+    }
+    // Held until we return, so a second proxy_run can't start another child meanwhile
+    refresh_child(app, state);
+    let _start = claim_start(state)?;
+    let start = state.inner.lock().starts;
+    {
+        let mut inner = state.inner.lock();
+        inner.profile = profile;
+        inner.skip_integrity_check = skip_integrity_check;
+    }
+    let gateway_url = gateway::normalize(&gateway_url).map_err(ProxyError::GatewayInvalid)?;
+    let upstream_proxy = upstream::normalize(&upstream_proxy).map_err(ProxyError::InvalidArgument)?;
+    let domains = hosts::normalize_domains(&domains)?;
+    let replay = match &replay_path {
+        Some(path) => Some(replay::validate(path, &domains).map_err(ProxyError::InvalidArgument)?),
+        None => None,
+    };
+    check_gateway_allowed(state, &gateway_url)?;
+    for route in &routes {
+        check_gateway_allowed(state, &route.gateway_url)?;
+    }
+    let agent_interception = probe_agents(state, &domains);
+    if let Some(check) = agent_interception.first() {
+        if state.config.lock().refuse_on_agent_interception {
+            return Err(ProxyError::AgentConflict(format!(
+                "{} already intercepts {} (certificate issued by {}); ours would stack on it",
+                check.intercepted_by.unwrap_or_default(),
+                check.domain,
+                check.issuer.as_deref().unwrap_or_default()
+            )));
+        }
+    }
+    let gateway_reachable = {
+        let (app, gateway_url, upstream_proxy) = (app.clone(), gateway_url.clone(), upstream_proxy.clone());
+        let routes = routes.clone();
+        timed(
+            "gatewayProbe",
+            GATEWAY_PROBE_TIMEOUT,
+            move || {
+                let reachable = (check_gateway && !gateway_url.is_empty())
+                    .then(|| gateway::is_reachable(&gateway_url, gateway::REACHABILITY_TIMEOUT, &upstream_proxy));
+                let state = app.state::<ProxyState>();
+                verify_gateway_pin(&state, &gateway_url, &upstream_proxy)?;
+                for route in &routes {
+                    verify_gateway_pin(&state, &route.gateway_url, &upstream_proxy)?;
+                }
+                Ok(reachable)
+            },
+            |_| {},
+        )?
+    };
+    {
+        let mut inner = state.inner.lock();
+        inner.upstream_proxy = (!upstream_proxy.is_empty()).then(|| upstream_proxy.clone());
+        inner.routes = routes.clone();
+        inner.bypass_paths = bypass_paths.clone();
+        inner.replay = replay.clone();
+        inner.domains = domains.clone();
+    }
+
+    // Refuse up front rather than pointing Windsurf at a port nobody listens on
+    let launcher = resolve_launcher(app, state)?;
+    let certs = ensure_ca(app)?;
+    ensure_cert_current(&certs)?;
+    if !cert::covers(&certs, &domains) {
+        return Err(ProxyError::Cert(format!(
+            "The server certificate is not issued for all of {}; regenerate it with these domains",
+            domains.join(", ")
+        )));
+    }
+    let redirected = port != redirect::HTTPS_PORT;
+    if redirected {
+        redirect::check_supported(port).map_err(ProxyError::Unsupported)?;
+    }
+    // A redirect left by an earlier run would occupy 443 itself
+    if current_pid(state).is_none() {
+        remove_redirect(state);
+    }
+    ensure_port_free(port)?;
+    if redirected && state.inner.lock().redirect.is_none() {
+        ensure_port_free(redirect::HTTPS_PORT)?;
+    }
+
+    // Everything above only checked. From here on each change is noted in
+    // `changes`, and any failure undoes them before returning. Each step is
+    // bounded by `timed`; one that runs over fails with TimedOut.
+    audit(
+        state,
+        audit::Event::ProxyRunAttempted {
+            gateway_url: gateway_url.clone(),
+            port,
+            domains: domains.clone(),
+            routes,
+            bypass_paths,
+        },
+    );
+    let mut changes = RunChanges::default();
+
+    // 1. Write our hosts block (backing up the original first)
+    let hosts = {
+        let app = app.clone();
+        timed("hostsRead", HOSTS_READ_TIMEOUT, move || app.state::<ProxyState>().hosts.load(), |_| {})
+    }
+    .map_err(|e| changes.roll_back(app, state, e))?;
+    let hosts_warnings = hosts.block_warnings();
+    let stale = hosts.stale_entry_count(&domains);
+    for warning in &hosts_warnings {
+        log_action(state, &format!("Hosts markers: {}", warning));
+    }
+    let added = hosts.needs_update(&domains);
+    let applied = if added {
+        let (write_app, late_app, write_domains) = (app.clone(), app.clone(), domains.clone());
+        let prompt_app = app.clone();
+        let applied = timed_unless(
+            "hostsWrite",
+            HOSTS_WRITE_TIMEOUT,
+            // However long the user takes to answer the prompt
+            move || prompt_app.state::<ProxyState>().hosts_prompt.load(Ordering::SeqCst),
+            move || {
+                let state = write_app.state::<ProxyState>();
+                backup_hosts(&write_app, &state)?;
+                // Exactly one canonical entry per domain and family, inside the block
+                let editor = SystemHosts::new(&write_app, &state).allowing_large(allow_large_hosts);
+                editor.ensure_entry(&write_domains)?;
+                Ok(editor.applied.get())
+            },
+            // Once the run has rolled back, and only if no newer start has
+            // claimed the block since
+            move |_| {
+                let state = late_app.state::<ProxyState>();
+                let _operation = state.operation.blocking_lock();
+                if state.inner.lock().starts == start {
+                    rollback_hosts_entry(&late_app, &state, "Hosts write finished after timing out");
+                }
+            },
+        )
+        .map_err(|e| changes.roll_back(app, state, e))?;
+        changes.hosts = true;
+        log_action(state, &format!("Hosts entries added for {}", domains.join(", ")));
+        if stale > 0 {
+            log_action(state, &format!("Removed {} stale hosts entries from a previous run", stale));
+        }
+        applied
+    } else {
+        None
+    };
+    let dns_flushed = applied.map(|a| a.dns_flushed);
+    let elevation_requested = applied.is_some_and(|a| a.elevation_requested);
+    let resolved = {
+        let (app, domains) = (app.clone(), domains.clone());
+        timed(
+            "resolution",
+            RESOLUTION_TIMEOUT,
+            move || verify_resolution(&app.state::<ProxyState>(), &domains),
+            |_| {},
+        )
+    };
+    if let Err(e) = resolved {
+        log_action(state, &format!("Hosts entry not in effect: {}", e));
+        return Err(changes.roll_back(app, state, e));
+    }
+
+    state.traffic.lock().restart(now_secs());
+    state.uptime.lock().clear();
+    changes.firewall = true;
+    let firewall_error = install_firewall_rule(state, launcher.program(), port);
+
+    // 2. Forward 443 when listening elsewhere (the hosts file can't carry a port)
+    // 3. Start local proxy (sidecar, or node local-proxy.js --gateway <url> --port <port> --domains <list>) and
+    // 4. wait until it is actually accepting connections
+    changes.redirect = redirected;
+    let pid = {
+        let (app, late_app, gateway_url, domains) = (app.clone(), app.clone(), gateway_url.clone(), domains.clone());
+        timed(
+            "spawn",
+            SPAWN_TIMEOUT,
+            move || {
+                let state = app.state::<ProxyState>();
+                if redirected {
+                    install_redirect(&state, port)?;
+                }
+                spawn_proxy(&app, &state, &gateway_url, port, &domains, Lifecycle::Started)
+            },
+            // Only while nothing newer replaced it
+            move |pid| {
+                let state = late_app.state::<ProxyState>();
+                let _operation = state.operation.blocking_lock();
+                if current_pid(&state) == Some(pid) {
+                    state.inner.lock().stop_reason = Some((Lifecycle::Stopped, "spawn timed out".into()));
+                    stop_child(&state, process::DEFAULT_SHUTDOWN_TIMEOUT);
+                    remove_redirect(&state);
+                    log_action(&state, "Proxy spawn finished after timing out; stopped it");
+                }
+            },
+        )
+    }
+    .map_err(|e| changes.roll_back(app, state, e))?;
+    changes.child = Some(pid);
+    wait_ready(app, state, pid, port, ready_timeout).map_err(|e| changes.roll_back(app, state, e))?;
+
+    // 5. Arm the watchdog if requested, and watch the hosts entries
+    state.exit_cleaned.store(false, Ordering::SeqCst);
+    spawn_hosts_watch(app.clone(), state, domains.clone());
+    spawn_uptime_sampler(app.clone(), state, domains.clone());
+    spawn_health_poll(app.clone(), state);
+    state.inner.lock().watchdog =
+        auto_restart.then(|| Watchdog { gateway_url: gateway_url.clone(), port, domains, attempts: 0, quick_exits: 0 });
+    // An earlier run's timer doesn't carry over
+    cancel_schedule(state);
+    let auto_stop_at = duration_minutes.map(|minutes| arm_schedule(app, state, minutes));
+
+    Ok(RunResult {
+        ok: true,
+        message: "Proxy started".into(),
+        address: format!("127.0.0.1:{}", port),
+        port,
+        pid,
+        gateway_url,
+        replay,
+        gateway_reachable,
+        upstream_proxy: (!upstream_proxy.is_empty()).then_some(upstream_proxy),
+        dns_flushed,
+        hosts_warnings,
+        stale_entries_removed: stale,
+        elevation_requested,
+        auto_stop_at,
+        firewall_error,
+        agent_interception,
+        plan: None,
+    })
+}
+
+/// The domains whose real origin presents a certificate from another
+/// agent's CA, each one logged. A probe that runs over is skipped.
+fn probe_agents(state: &ProxyState, domains: &[String]) -> Vec<agents::OriginCheck> {
+    let probe = {
+        let domains = domains.to_vec();
+        timed(
+            "agentProbe",
+            AGENT_PROBE_TIMEOUT,
+            move || Ok(domains.iter().map(|d| agents::check_origin(d)).collect::<Vec<_>>()),
+            |_| {},
+        )
+    };
+    let checks = match probe {
+        Ok(checks) => checks,
+        Err(e) => {
+            log_action(state, &format!("Interception by other agents not checked: {}", e));
+            return Vec::new();
+        }
+    };
+    let intercepted: Vec<agents::OriginCheck> = checks.into_iter().filter(|c| c.intercepted_by.is_some()).collect();
+    for check in &intercepted {
+        log_action(
+            state,
+            &format!(
+                "Warning: {} intercepts {} (certificate issued by {})",
+                check.intercepted_by.unwrap_or_default(),
+                check.domain,
+                check.issuer.as_deref().unwrap_or_default()
+            ),
+        );
+    }
+    intercepted
+}
+
+/// proxy_run's checks without its effects: nothing is written, spawned,
+/// installed or generated. Malformed arguments fail as in the real run;
+/// everything else is reported as a check so all problems show at once.
+#[allow(clippy::too_many_arguments)]
+fn plan_run(
+    app: &AppHandle,
+    state: &ProxyState,
+    gateway_url: &str,
+    upstream_proxy: &str,
+    port: u16,
+    domains: &[String],
+    routes: &[config::Route],
+    replay_path: Option<&str>,
+    allow_large_hosts: bool,
+) -> Result<RunResult, ProxyError> {
+    let gateway_url = gateway::normalize(gateway_url).map_err(ProxyError::GatewayInvalid)?;
+    let upstream_proxy = upstream::normalize(upstream_proxy).map_err(ProxyError::InvalidArgument)?;
+    let domains = hosts::normalize_domains(domains)?;
+    let mut checks = Vec::new();
+    let mut check = |name, result: Result<String, String>| {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        checks.push(PlanCheck { name, ok, detail });
+    };
+
+    check(
+        "notRunning",
+        match (current_pid(state), service_state(state)) {
+            (Some(pid), _) => Err(format!("The proxy is already running (pid {})", pid)),
+            (None, Some(service)) => Err(format!("The background service (pid {}) runs the proxy", service.service_pid)),
+            (None, None) => Ok("No proxy running".into()),
+        },
+    );
+    let launcher = resolve_launcher(app, state);
+    check("launcher", launcher.as_ref().map(Launcher::describe).map_err(|e| e.message().to_string()));
+    let certs = app_data_dir(app)?.join("certs");
+    check(
+        "certificate",
+        if !cert::exists(&certs) {
+            Err("No CA yet; the run generates one, which then has to be installed".into())
+        } else if let Err(e) = ensure_cert_current(&certs) {
+            Err(e.message().to_string())
+        } else if !cert::covers(&certs, &domains) {
+            Err(format!("The server certificate is not issued for all of {}", domains.join(", ")))
+        } else if !cert::is_installed(&certs) {
+            Err("The CA certificate is not installed".into())
+        } else {
+            Ok("Installed, and the server certificate covers every domain".into())
+        },
+    );
+    let redirected = port != redirect::HTTPS_PORT;
+    check(
+        "port",
+        (if redirected { redirect::check_supported(port) } else { Ok(()) })
+            .and_then(|_| ensure_port_free(port).map_err(|e| e.message().to_string()))
+            .and_then(|_| {
+                if redirected && state.inner.lock().redirect.is_none() {
+                    ensure_port_free(redirect::HTTPS_PORT).map_err(|e| e.message().to_string())
+                } else {
+                    Ok(())
+                }
+            })
+            .map(|_| format!("Port {} is free", port)),
+    );
+    let replay = replay_path.map(|path| replay::validate(path, &domains));
+    if let Some(replay) = &replay {
+        check(
+            "replay",
+            replay
+                .as_ref()
+                .map(|r| format!("{} recorded path(s) for {}", r.paths, r.domains.join(", ")))
+                .map_err(String::clone),
+        );
+    }
+    let gateway_reachable = (!gateway_url.is_empty())
+        .then(|| gateway::is_reachable(&gateway_url, gateway::REACHABILITY_TIMEOUT, &upstream_proxy));
+    check(
+        "gateway",
+        match gateway_reachable {
+            None if replay.is_some() => Ok("No gateway: replay mode".into()),
+            None => Ok("No gateway: passthrough mode".into()),
+            Some(true) => Ok(format!("{} is reachable", gateway_url)),
+            Some(false) => Err(format!("{} is not reachable", gateway_url)),
+        },
+    );
+    check(
+        "allowlist",
+        check_gateway_allowed(state, &gateway_url)
+            .map(|_| {
+                if gateway_url.is_empty() {
+                    "Passthrough needs no allowlist entry".into()
+                } else {
+                    format!("{} is on the allowlist", gateway_url)
+                }
+            })
+            .map_err(|e| e.message().to_string()),
+    );
+    check(
+        "gatewayPin",
+        match verify_gateway_pin(state, &gateway_url, &upstream_proxy) {
+            Ok(Some(_)) => Ok("The gateway's certificate matches the pin".into()),
+            Ok(None) => Ok("Nothing to check: no pin, no gateway, or the gateway is unreachable".into()),
+            Err(e) => Err(e.message().to_string()),
+        },
+    );
+    check(
+        "routes",
+        routes
+            .iter()
+            .try_for_each(|route| {
+                check_gateway_allowed(state, &route.gateway_url)?;
+                verify_gateway_pin(state, &route.gateway_url, &upstream_proxy).map(|_| ())
+            })
+            .map(|_| match routes.len() {
+                0 => "No per-domain routes".into(),
+                n => format!("{} route(s) on the allowlist and matching any pin", n),
+            })
+            .map_err(|e| e.message().to_string()),
+    );
+    let agent_interception = probe_agents(state, &domains);
+    let refuse = state.config.lock().refuse_on_agent_interception;
+    check(
+        "agents",
+        match agent_interception.first() {
+            None => Ok("No other agent intercepts the domains".into()),
+            Some(c) => {
+                let detail = format!("{} intercepts {}", c.intercepted_by.unwrap_or_default(), c.domain);
+                if refuse {
+                    Err(detail)
+                } else {
+                    Ok(format!("{}; the run would go ahead with a warning", detail))
+                }
+            }
+        },
+    );
+    let hosts = state.hosts.load();
+    let hosts_changes = hosts.as_ref().map(|h| h.preview_entries(&domains)).unwrap_or_default();
+    let max_mb = if allow_large_hosts { 0 } else { state.config.lock().hosts_max_mb };
+    let too_large = state.hosts.check_size(usage::mib(max_mb)).err();
+    check(
+        "hosts",
+        match (&hosts, too_large) {
+            (Err(e), _) => Err(e.message().to_string()),
+            (Ok(_), _) if hosts_changes.is_empty() => Ok("Our entries are already in place".into()),
+            (Ok(_), Some(e)) => Err(e.message().to_string()),
+            (Ok(_), None) => Ok(format!("{} line(s) would change", hosts_changes.len())),
+        },
+    );
+    // Not a failure: the write asks for admin rights through a prompt
+    let elevated = elevate::is_elevated();
+    let elevation_requested = !elevated && !hosts_changes.is_empty();
+    check(
+        "elevation",
+        Ok(match (elevated, elevation_requested) {
+            (true, _) => "Running with admin rights".into(),
+            (false, true) => "Not elevated; writing hosts will prompt for admin rights".into(),
+            (false, false) => "Not elevated; no hosts write needed".into(),
+        }),
+    );
+
+    let last_good_gateway = state.last_good.lock().clone();
+    let gateway_changed =
+        !gateway_url.is_empty() && last_good_gateway.as_ref().is_some_and(|g| g.gateway_url != gateway_url);
+
+    let failed = checks.iter().filter(|c| !c.ok).count();
+    let (hosts_warnings, stale) = match &hosts {
+        Ok(h) => (h.block_warnings(), h.stale_entry_count(&domains)),
+        Err(_) => (Vec::new(), 0),
+    };
+    log_action(state, &format!("Dry run of proxy_run: {} check(s) failed", failed));
+    Ok(RunResult {
+        ok: failed == 0,
+        message: if failed == 0 {
+            "Dry run: every check passed; nothing was changed".into()
+        } else {
+            format!("Dry run: {} check(s) failed; nothing was changed", failed)
+        },
+        address: format!("127.0.0.1:{}", port),
+        port,
+        pid: 0,
+        gateway_url,
+        replay: replay.and_then(Result::ok),
+        gateway_reachable,
+        upstream_proxy: (!upstream_proxy.is_empty()).then_some(upstream_proxy),
+        dns_flushed: None,
+        hosts_warnings,
+        stale_entries_removed: stale,
+        elevation_requested,
+        auto_stop_at: None,
+        firewall_error: None,
+        agent_interception,
+        plan: Some(RunPlan { checks, hosts_changes, gateway_changed, last_good_gateway }),
+    })
+}
+
+/// Run a command's body on the blocking pool. Hosts IO, process spawning and
+/// readiness polling would otherwise stall the window while they run.
+async fn blocking<T, F>(app: AppHandle, what: &'static str, f: F) -> Result<T, ProxyError>
+where
+    T: Send + 'static,
+    F: FnOnce(AppHandle, State<'_, ProxyState>) -> T + Send + 'static,
+{
+    tauri::async_runtime::spawn_blocking(move || f(app.clone(), app.state::<ProxyState>()))
+        .await
+        .map_err(|e| ProxyError::Internal(format!("{} task failed: {}", what, e)))
+}
+
+/// `blocking` once the operation lock is ours, for the commands that change
+/// state. Waiting for it doesn't tie up a blocking pool thread.
+async fn exclusive<T, F>(app: AppHandle, what: &'static str, f: F) -> Result<T, ProxyError>
+where
+    T: Send + 'static,
+    F: FnOnce(AppHandle, State<'_, ProxyState>) -> T + Send + 'static,
+{
+    let state = app.state::<ProxyState>();
+    let _operation = state.operation.lock().await;
+    blocking(app.clone(), what, f).await
+}
+
+/// Run one step of a command on its own thread and give up on it after
+/// `timeout` with TimedOut. The thread can't be cancelled: should `f` still
+/// succeed after we gave up, its value goes to `late` so whatever it did can
+/// be undone.
+fn timed<T, F, L>(step: &'static str, timeout: Duration, f: F, late: L) -> Result<T, ProxyError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, ProxyError> + Send + 'static,
+    L: FnOnce(T) + Send + 'static,
+{
+    timed_unless(step, timeout, || false, f, late)
+}
+
+/// `timed`, but the clock is stopped while `paused` says so (the step waits
+/// on the user) and starts over with the whole `timeout` once it doesn't,
+/// give or take STEP_PAUSE_POLL.
+fn timed_unless<T, P, F, L>(step: &'static str, timeout: Duration, paused: P, f: F, late: L) -> Result<T, ProxyError>
+where
+    T: Send + 'static,
+    P: Fn() -> bool,
+    F: FnOnce() -> Result<T, ProxyError> + Send + 'static,
+    L: FnOnce(T) + Send + 'static,
+{
+    let abandoned = Arc::new(Mutex::new(false));
+    let (tx, rx) = std::sync::mpsc::channel();
+    let flag = abandoned.clone();
+    std::thread::spawn(move || {
+        let result = f();
+        // Held across the send, so the timeout can't slip in between
+        let abandoned = flag.lock();
+        if !*abandoned {
+            let _ = tx.send(result);
+        } else if let Ok(value) = result {
+            late(value);
+        }
+    });
+    let mut deadline = Instant::now() + timeout;
+    loop {
+        match rx.recv_timeout(STEP_PAUSE_POLL.min(deadline.saturating_duration_since(Instant::now()))) {
+            Ok(result) => return result,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                if paused() {
+                    deadline = Instant::now() + timeout;
+                } else if Instant::now() >= deadline {
+                    break;
+                }
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    *abandoned.lock() = true;
+    // It may have finished between the timeout and taking the lock
+    rx.try_recv().unwrap_or_else(|_| {
+        Err(ProxyError::TimedOut {
+            message: format!("Gave up on step {} after {}s", step, timeout.as_secs_f32()),
+            step,
+        })
+    })
+}
+
+/// Mark a restart in progress, in one step with the check that a proxy is
+/// running and nothing else is starting one. Returns its gateway.
+fn claim_restart(state: &ProxyState) -> Result<(StartGuard<'_>, String), ProxyError> {
+    let mut inner = state.inner.lock();
+    if inner.starting {
+        return Err(ProxyError::AlreadyRunning("The proxy is already being started".into()));
+    }
+    if !inner.running() {
+        return Err(ProxyError::InvalidState("The proxy is not running; use proxy_run to start it".into()));
+    }
+    inner.starting = true;
+    inner.starts += 1;
+    inner.restarting = true;
+    let previous = inner.gateway_url.clone().unwrap_or_default();
+    Ok((StartGuard(state), previous))
+}
+
+/// Start a child for `gateway_url`, or replaying `replay`, on the current
+/// port and wait for it to listen.
+fn respawn(
+    app: &AppHandle,
+    state: &ProxyState,
+    gateway_url: &str,
+    replay: Option<replay::Replay>,
+    ready_timeout: Duration,
+) -> Result<u32, ProxyError> {
+    let (port, domains) = {
+        let inner = state.inner.lock();
+        (inner.port, inner.domains.clone())
+    };
+    ensure_port_free(port)?;
+    state.inner.lock().replay = replay;
+    let pid = spawn_proxy(app, state, gateway_url, port, &domains, Lifecycle::Restarted)?;
+    wait_ready(app, state, pid, port, ready_timeout)?;
+    Ok(pid)
+}
+
+/// Swap the running proxy for one forwarding to `gateway_url`, or replaying
+/// `replay`. Hosts and the port redirect stay as they are. If the new child
+/// doesn't come up, the old gateway is started again; if that fails too, the
+/// hosts block and redirect are removed so Windsurf isn't left pointing at
+/// nothing.
+fn restart_proxy(
+    app: &AppHandle,
+    state: &ProxyState,
+    gateway_url: &str,
+    replay: Option<replay::Replay>,
+    ready_timeout: Duration,
+    reason: &str,
+) -> Result<RestartResult, ProxyError> {
+    let gateway_url = gateway::normalize(gateway_url).map_err(ProxyError::GatewayInvalid)?;
+    let upstream_proxy = state.inner.lock().upstream_proxy.clone().unwrap_or_default();
+    check_gateway_allowed(state, &gateway_url)?;
+    verify_gateway_pin(state, &gateway_url, &upstream_proxy)?;
+    refresh_child(app, state);
+    let (_start, previous) = claim_restart(state)?;
+    let (previous_replay, previous_profile, watchdog) = {
+        let mut inner = state.inner.lock();
+        inner.stop_reason = Some((Lifecycle::Stopped, reason.to_string()));
+        // The new gateway is no longer the profile's; stop_child disarms the
+        // watchdog and the hosts watch, and both come back below
+        (inner.replay.clone(), inner.profile.take(), inner.watchdog.take())
+    };
+    stop_child(state, process::DEFAULT_SHUTDOWN_TIMEOUT);
+
+    let target = target_label(&gateway_url, replay.as_ref());
+    let previous_target = target_label(&previous, previous_replay.as_ref());
+    let pid = match respawn(app, state, &gateway_url, replay.clone(), ready_timeout) {
+        Ok(pid) => pid,
+        Err(e) => {
+            log_action(state, &format!("Restart with {} failed: {}", target, e));
+            state.inner.lock().profile = previous_profile;
+            return Err(match respawn(app, state, &previous, previous_replay, ready_timeout) {
+                Ok(pid) => {
+                    log_action(state, &format!("Previous {} restored (pid {})", previous_target, pid));
+                    let domains = state.inner.lock().domains.clone();
+                    spawn_hosts_watch(app.clone(), state, domains.clone());
+                    spawn_uptime_sampler(app.clone(), state, domains);
+                    spawn_health_poll(app.clone(), state);
+                    state.inner.lock().watchdog = watchdog;
+                    ProxyError::StartFailed(format!("{}; the proxy is running with the previous gateway again", e))
+                }
+                Err(again) => {
+                    remove_redirect(state);
+                    rollback_hosts_entry(app, state, "Restoring the previous gateway failed");
+                    ProxyError::StartFailed(format!(
+                        "{}; restarting with the previous gateway failed too ({}), so the hosts entries were removed",
+                        e, again
+                    ))
+                }
+            });
+        }
+    };
+
+    let (port, domains) = {
+        let inner = state.inner.lock();
+        (inner.port, inner.domains.clone())
+    };
+    log_action(state, &format!("Proxy restarted ({}): {} -> {}", reason, previous_target, target));
+    state.exit_cleaned.store(false, Ordering::SeqCst);
+    spawn_hosts_watch(app.clone(), state, domains.clone());
+    spawn_uptime_sampler(app.clone(), state, domains);
+    spawn_health_poll(app.clone(), state);
+    state.inner.lock().watchdog =
+        watchdog.map(|wd| Watchdog { gateway_url: gateway_url.clone(), attempts: 0, quick_exits: 0, ..wd });
+
+    Ok(RestartResult {
+        ok: true,
+        message: "Proxy restarted".into(),
+        pid,
+        port,
+        gateway_url,
+        previous_gateway_url: previous,
+        mode: if replay.is_some() { replay::Mode::Replay } else { replay::Mode::Forward },
+        replay,
+    })
+}
+
+/// How a proxy's target reads in the log.
+fn target_label(gateway_url: &str, replay: Option<&replay::Replay>) -> String {
+    match replay {
+        Some(replay) => format!("replay of {}", replay.path),
+        None if gateway_url.is_empty() => "passthrough".into(),
+        None => format!("gateway {}", gateway_url),
+    }
+}
+
+/// Refuse a gateway the signed allowlist doesn't name.
+fn check_gateway_allowed(state: &ProxyState, gateway_url: &str) -> Result<(), ProxyError> {
+    allowlist::check(&state.allowlist.lock(), gateway_url).map_err(ProxyError::GatewayNotAllowed)
+}
+
+/// The configured gateway pin.
+fn gateway_pin(state: &ProxyState) -> Result<gateway::Pin, ProxyError> {
+    state.config.lock().gateway_pin().map_err(|e| ProxyError::InvalidArgument(format!("Invalid gateway pin: {}", e)))
+}
+
+/// Check the gateway's certificate against the configured pin before
+/// pointing anything at it. Some(true) once it matched; None without a pin,
+/// in passthrough mode, or when the gateway can't be reached, in which case
+/// the proxy enforces the pin on its first connection.
+fn verify_gateway_pin(state: &ProxyState, gateway_url: &str, upstream_proxy: &str) -> Result<Option<bool>, ProxyError> {
+    let pin = gateway_pin(state)?;
+    if !pin.is_set() || gateway_url.is_empty() {
+        return Ok(None);
+    }
+    let check = gateway::check(gateway_url, "", gateway::REACHABILITY_TIMEOUT, upstream_proxy, &pin);
+    match check.pin_ok {
+        Some(false) => Err(ProxyError::PinMismatch(
+            check.error.unwrap_or_else(|| "The gateway's certificate doesn't match the pin".into()),
+        )),
+        pin_ok => Ok(pin_ok),
+    }
+}
+
+/// Intentionally stop the child. The monitor is cancelled first so the
+/// shutdown isn't reported as a crash. Returns whether it exited gracefully,
+/// or None if nothing was running.
+fn stop_child(state: &ProxyState, timeout: Duration) -> Option<bool> {
+    state.inner.lock().watchdog = None;
+    let watches = [&state.monitor_cancel, &state.hosts_watch_cancel, &state.uptime_cancel, &state.health_poll_cancel];
+    for cancel in watches {
+        if let Some(cancel) = cancel.lock().take() {
+            cancel.store(true, Ordering::SeqCst);
+        }
+    }
+    let (child, adopted, (event, detail)) = {
+        let mut inner = state.inner.lock();
+        inner.started_at = None;
+        inner.failed = None;
+        let reason = inner.stop_reason.take().unwrap_or((Lifecycle::Stopped, "stopped".into()));
+        (inner.child.take(), inner.adopted.take(), reason)
+    };
+    clear_session(state);
+
+    let mut child = match (child, adopted) {
+        (Some(child), _) => child,
+        // Not our child, so no graceful shutdown channel: kill by pid
+        (None, Some(adopted)) => {
+            process::terminate_pid(adopted.pid);
+            log_action(state, &format!("Adopted proxy stopped (pid {}, forced)", adopted.pid));
+            let reason = format!("{} (adopted, killed)", detail);
+            state.inner.lock().record(event, reason.clone(), Some(adopted.pid), None);
+            audit(state, audit::Event::ProxyStopped { pid: adopted.pid, exit_code: None, reason });
+            return Some(false);
+        }
+        (None, None) => return None,
+    };
+    let outcome = process::shutdown(&mut child, timeout);
+    let exit_code = outcome.status.and_then(|s| s.code);
+    let reason = if outcome.graceful { detail } else { format!("{} (forced)", detail) };
+    {
+        let mut inner = state.inner.lock();
+        if let Some(status) = outcome.status {
+            inner.last_exit = Some(ExitInfo::from_exit(status, event));
+        }
+        inner.record(event, reason.clone(), Some(child.id()), exit_code);
+    }
+    audit(state, audit::Event::ProxyStopped { pid: child.id(), exit_code, reason });
+    log_action(
+        state,
+        &format!(
+            "Proxy stopped (pid {}, {})",
+            child.id(),
+            if outcome.graceful { "graceful" } else { "forced" }
+        ),
+    );
+    Some(outcome.graceful)
+}
+
+fn stop_result(state: &ProxyState, message: &str, graceful: Option<bool>, dns_flushed: Option<bool>) -> StopResult {
+    let port_free = !net::is_listening(state.inner.lock().port, PORT_PROBE_TIMEOUT);
+    StopResult { ok: true, message: message.into(), graceful, port_free, dns_flushed, hosts_removed: None }
+}
+
+#[derive(Default)]
+struct StopOptions {
+    timeout_ms: Option<u64>,
+    keep_hosts: bool,
+    allow_large_hosts: bool,
+}
+
+impl StopOptions {
+    /// From proxy_stop's arguments; a stop removes the hosts block unless
+    /// told to keep it.
+    fn new(timeout_ms: Option<u64>, keep_hosts: Option<bool>, allow_large_hosts: Option<bool>) -> Self {
+        StopOptions {
+            timeout_ms,
+            keep_hosts: keep_hosts.unwrap_or(false),
+            allow_large_hosts: allow_large_hosts.unwrap_or(false),
+        }
+    }
+}
+
+fn stop(options: StopOptions, app: AppHandle, state: State<'_, ProxyState>) -> Result<StopResult, ProxyError> {
+    check_not_service(&state)?;
+    let timeout = options.timeout_ms.map(Duration::from_millis).unwrap_or(process::DEFAULT_SHUTDOWN_TIMEOUT);
+    cancel_schedule(&state);
+    // The kill after the graceful wait can hang too (a child stuck in the kernel)
+    let graceful = {
+        let app = app.clone();
+        timed("stop", timeout + STOP_KILL_GRACE, move || Ok(stop_child(&app.state::<ProxyState>(), timeout)), |_| {})?
+    };
+    let editor = SystemHosts::new(&app, &state).allowing_large(options.allow_large_hosts);
+    let Some(removed) = clear_after_stop(&state, &editor, options.keep_hosts)? else {
+        return Ok(stop_result(&state, "Proxy stopped; hosts entries kept", graceful, None));
+    };
+    // Flush even when nothing was written; the cache may still hold our answer
+    let dns_flushed = editor.applied.get().map_or_else(|| flush_dns(&state).is_ok(), |a| a.dns_flushed);
+    Ok(StopResult {
+        hosts_removed: Some(removed),
+        ..stop_result(&state, "Proxy stopped and hosts entries removed", graceful, Some(dns_flushed))
+    })
+}
+
+/// What a stop undoes once the child is gone: the firewall rule and, unless
+/// `keep_hosts`, our hosts block (through `editor`) and the 443 redirect.
+/// Whether there was a block to remove, or None if it was kept.
+fn clear_after_stop(
+    state: &ProxyState,
+    editor: &impl HostsEditor,
+    keep_hosts: bool,
+) -> Result<Option<bool>, ProxyError> {
+    remove_firewall_rule(state);
+    if keep_hosts {
+        log_action(state, "Hosts entries kept after stopping the proxy");
+        return Ok(None);
+    }
+    let removed = editor.remove_entries(&[])?;
+    remove_redirect(state);
+    log_action(state, if removed { "Stop: hosts block removed" } else { "Stop: no hosts block to remove" });
+    Ok(Some(removed))
+}
+
+#[derive(Default)]
+struct RestoreOptions {
+    timeout_ms: Option<u64>,
+    remove_stray: bool,
+    remove_cert: bool,
+    force: bool,
+    allow_large_hosts: bool,
+}
+
+/// The steps of one restore as they run.
+struct RestoreSteps<'a> {
+    state: &'a ProxyState,
+    /// Completed by the unfinished restore before this one.
+    done: Vec<&'static str>,
+    steps: Vec<RestoreStep>,
+}
+
+impl RestoreSteps<'_> {
+    /// Run `step` unless it is done already. A failure is recorded and logged;
+    /// it is up to the caller whether later steps still make sense.
+    fn run(&mut self, step: &'static str, f: impl FnOnce() -> Result<String, String>) {
+        if self.done.contains(&step) {
+            let detail = "Completed by the previous restore".into();
+            self.steps.push(RestoreStep { step, ok: true, skipped: true, detail });
+            return;
+        }
+        let (ok, detail) = match f() {
+            Ok(detail) => (true, detail),
+            Err(detail) => {
+                log_action(self.state, &format!("Restore step {} failed: {}", step, detail));
+                (false, detail)
+            }
+        };
+        self.steps.push(RestoreStep { step, ok, skipped: false, detail });
+    }
+
+    fn failed(&self) -> Vec<&'static str> {
+        self.steps.iter().filter(|s| !s.ok).map(|s| s.step).collect()
+    }
+}
+
+fn restore(options: RestoreOptions, app: AppHandle, state: State<'_, ProxyState>) -> Result<RestoreResult, ProxyError> {
+    check_not_service(&state)?;
+    let timeout = options.timeout_ms.map(Duration::from_millis).unwrap_or(process::DEFAULT_SHUTDOWN_TIMEOUT);
+    cancel_schedule(&state);
+    let done = match (options.force, state.unfinished_restore.lock().as_ref()) {
+        (false, Some(steps)) => steps.iter().filter(|s| s.ok).map(|s| s.step).collect(),
+        _ => Vec::new(),
+    };
+    let mut steps = RestoreSteps { state: &state, done, steps: Vec::new() };
+
+    let mut graceful = None;
+    steps.run("stopProxy", || {
+        graceful = stop_child(&state, timeout);
+        match graceful {
+            None => Ok("No proxy was running".into()),
+            Some(true) => Ok("Proxy stopped".into()),
+            Some(false) => Ok("Proxy killed".into()),
+        }
+    });
+
+    // Remove our hosts block
+    let domains = state.inner.lock().domains.clone();
+    let editor = SystemHosts::new(&app, &state).allowing_large(options.allow_large_hosts);
+    let (mut removed, mut hosts_warnings, mut stray_entries, mut stray_removed) = (None, vec![], vec![], false);
+    steps.run("hostsEntries", || {
+        let hosts = editor.load().map_err(|e| e.to_string())?;
+        hosts_warnings = hosts.block_warnings();
+        stray_entries = hosts.stray_entries(&domains);
+        stray_removed = options.remove_stray && !stray_entries.is_empty();
+        let block = editor.remove_entries(if stray_removed { &domains } else { &[] }).map_err(|e| e.to_string())?;
+        removed = Some(block);
+        log_action(
+            &state,
+            if block { "Restore ran: hosts block removed" } else { "Restore ran: no hosts block to remove" },
+        );
+        if !stray_entries.is_empty() {
+            log_action(
+                &state,
+                &format!(
+                    "{} hosts entr{} for our domains outside the block {}",
+                    stray_entries.len(),
+                    if stray_entries.len() == 1 { "y" } else { "ies" },
+                    if stray_removed { "removed" } else { "left in place" }
+                ),
+            );
+        }
+        Ok(if block { "Hosts block removed" } else { "No hosts block to remove" }.into())
+    });
+
+    // Flush even when nothing was written; the cache may still hold our answer
+    let applied = editor.applied.get();
+    let mut dns_flushed = None;
+    steps.run("dnsFlush", || {
+        let result = match applied {
+            Some(applied) if applied.dns_flushed => Ok(()),
+            Some(_) => Err("Flushing the DNS cache after the hosts write failed".into()),
+            None => flush_dns(&state),
+        };
+        dns_flushed = Some(result.is_ok());
+        result.map(|()| "DNS cache flushed".into())
+    });
+    steps.run("redirect", || {
+        let installed = state.inner.lock().redirect.is_some();
+        match (installed, remove_redirect(&state)) {
+            (false, _) => Ok("No redirect installed".into()),
+            (true, true) => Ok(format!("Port {} redirect removed", redirect::HTTPS_PORT)),
+            (true, false) => Err(format!("Removing the port {} redirect failed; see the log", redirect::HTTPS_PORT)),
+        }
+    });
+    steps.run("firewallRule", || {
+        let existed = state.inner.lock().firewall_rule;
+        match (existed, remove_firewall_rule(&state)) {
+            (false, _) => Ok("No firewall rule to remove".into()),
+            (true, true) => Ok("Firewall rule removed".into()),
+            (true, false) => Err("Removing the firewall rule failed; see the log".into()),
+        }
+    });
+    let mut doh_restored = None;
+    steps.run("doh", || {
+        doh_restored = restore_doh(&app, &state);
+        match doh_restored {
+            None => Ok("No DoH settings were changed".into()),
+            Some(true) => Ok("System DoH settings restored".into()),
+            Some(false) => Err("Restoring the system DoH settings failed; see the log".into()),
+        }
+    });
+    if options.remove_cert {
+        steps.run("cert", || {
+            let result = uninstall_cert(app.clone(), app.state()).map_err(|e| e.to_string())?;
+            if result.ok {
+                Ok(result.message)
+            } else {
+                Err(result.message)
+            }
+        });
+    }
+
+    let failed = steps.failed();
+    let message = if failed.is_empty() {
+        "Restored".to_string()
+    } else {
+        format!("Restore incomplete: {} failed; restore again to retry", failed.join(", "))
+    };
+    log_action(&state, &message);
+    *state.unfinished_restore.lock() = (!failed.is_empty()).then(|| steps.steps.clone());
+    forget_status(&state);
+
+    Ok(RestoreResult {
+        stop: StopResult {
+            ok: failed.is_empty(),
+            hosts_removed: removed,
+            ..stop_result(&state, &message, graceful, dns_flushed)
+        },
+        hosts_warnings,
+        stray_entries,
+        stray_removed,
+        elevation_requested: applied.is_some_and(|a| a.elevation_requested),
+        doh_restored,
+        steps: steps.steps,
+    })
+}
+
+/// Put back what doh_disable changed, if anything. Failures are logged and
+/// the settings stay recorded for the next restore.
+fn restore_doh(app: &AppHandle, state: &ProxyState) -> Option<bool> {
+    let file = app_data_dir(app).ok()?.join(doh::STATE_FILE);
+    match doh::restore(&file) {
+        Ok(None) => None,
+        Ok(Some(settings)) => {
+            log_action(state, &format!("System DoH settings restored ({})", settings));
+            audit(state, audit::Event::DohRestored { settings });
+            Some(true)
+        }
+        Err(e) => {
+            log_action(state, &format!("Restoring the system DoH settings failed: {}", e));
+            Some(false)
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AutorunOutcome {
+    at: u64,
+    started: bool,
+    /// Why it didn't start; empty if it did.
+    reasons: Vec<String>,
+}
+
+/// At a login start with config.autorunProxy: proxy_run with the stored
+/// settings, but only if it can get all the way without a prompt. Nobody is
+/// at the screen yet to answer a UAC dialog or trust a certificate, and a
+/// half-configured machine is worse than an idle one.
+fn autorun(app: &AppHandle) {
+    let state = app.state::<ProxyState>();
+    let mut reasons = Vec::new();
+    if let Some(e) = state.config_error.lock().as_ref() {
+        reasons.push(format!("The stored config couldn't be loaded: {}", e));
+    }
+    if !cert_installed(app) {
+        reasons.push("The CA certificate is not installed".to_string());
+    }
+    if !elevate::is_elevated() {
+        reasons.push("The app was started without admin rights; editing the hosts file would prompt for them".to_string());
+    }
+    if reasons.is_empty() {
+        let config = state.config.lock().clone();
+        let options = RunOptions {
+            gateway_url: config.gateway_url.unwrap_or_default(),
+            profile: None,
+            upstream_proxy: config.upstream_proxy.unwrap_or_default(),
+            port: config.port,
+            domains: config.domains,
+            routes: config.routes,
+            bypass_paths: config.bypass_paths,
+            auto_restart: config.auto_restart,
+            check_gateway: false,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            duration_minutes: None,
+            skip_integrity_check: false,
+            dry_run: false,
+            allow_large_hosts: false,
+            replay_path: None,
+        };
+        let _operation = state.operation.blocking_lock();
+        if let Err(e) = run_proxy(app, &state, options) {
+            reasons.push(format!("Proxy start failed: {}", e));
+        }
+    }
+    let outcome = AutorunOutcome { at: now_secs(), started: reasons.is_empty(), reasons };
+    if outcome.started {
+        log_action(&state, "Autorun started the proxy");
+    } else {
+        log_action(&state, &format!("Autorun refused: {}", outcome.reasons.join("; ")));
+        let _ = app.emit(EVENT_AUTORUN_FAILED, &outcome);
+    }
+    *state.autorun.lock() = Some(outcome);
+}
 
-    if proxy_script.exists() {
-        let child = Command::new("node")
-            .arg(&proxy_script)
-            .arg("--gateway")
-            .arg(&gateway_url)
-            .arg("--cert-dir")
-            .arg(&certs_dir)
-            .spawn()
-            .map_err(|e| format!("Failed to start proxy: {}", e))?;
+/// What proxy_restore does, run when the window closes or the app exits:
+/// stop the child, remove our hosts block and the port redirect. Otherwise
+/// the hosts entry outlives the proxy and Windsurf stays broken.
+///
+/// It runs on its own thread, then calls `done`: it waits for a command in
+/// progress and for the proxy to stop, which on the main thread would
+/// freeze the window. Returns whether the close or exit has to be held back
+/// meanwhile; `done` asks for it again.
+fn exit_cleanup(app: &AppHandle, done: impl FnOnce() + Send + 'static) -> bool {
+    let state = app.state::<ProxyState>();
+    if state.exit_cleanup_running.load(Ordering::SeqCst) {
+        return true;
+    }
+    if !claim_exit_cleanup(&state) {
+        return false;
+    }
+    state.exit_cleanup_running.store(true, Ordering::SeqCst);
+    let app = app.clone();
+    std::thread::spawn(move || {
+        let state = app.state::<ProxyState>();
+        clean_up_for_exit(&app, &state);
+        state.exit_cleanup_running.store(false, Ordering::SeqCst);
+        done();
+    });
+    true
+}
+
+/// Whether the exit cleanup is ours to run: not if the proxy is to keep
+/// running, the service runs it, or the cleanup already ran.
+fn claim_exit_cleanup(state: &ProxyState) -> bool {
+    if *state.keep_running_on_exit.lock() {
+        log_action(state, "App exiting; proxy left running as configured");
+        return false;
+    }
+    if service_state(state).is_some() && current_pid(state).is_none() {
+        log_action(state, "App exiting; the background service keeps the proxy running");
+        return false;
+    }
+    !state.exit_cleaned.swap(true, Ordering::SeqCst)
+}
+
+fn clean_up_for_exit(app: &AppHandle, state: &ProxyState) {
+    // Let a command that is halfway through finish first, but not forever:
+    // the app goes away either way
+    let deadline = Instant::now() + EXIT_OPERATION_WAIT;
+    let _operation = loop {
+        match state.operation.try_lock() {
+            Ok(operation) => break Some(operation),
+            Err(_) if Instant::now() < deadline => std::thread::sleep(READY_POLL_INTERVAL),
+            Err(_) => {
+                log_action(state, "App exiting: a command is still running; cleaning up without waiting for it");
+                break None;
+            }
+        }
+    };
+    stop_child(state, process::DEFAULT_SHUTDOWN_TIMEOUT);
+    // Leaving the entries behind would cut Windsurf off; there's no one to ask
+    match SystemHosts::new(app, state).allowing_large(true).remove_entries(&[]) {
+        Ok(true) => log_action(state, "App exiting: hosts block removed"),
+        Ok(false) => {}
+        Err(e) => log_action(state, &format!("App exiting: removing hosts block failed: {}", e)),
+    }
+    remove_redirect(state);
+    remove_firewall_rule(state);
+}
+
+/// Last-ditch cleanup from the panic hook. Release builds abort on panic, so
+/// no Drop runs and this is the only chance. Locks are only tried: the
+/// panicking thread may hold one. No elevation prompt or DNS flush here.
+fn panic_cleanup(app: &AppHandle) {
+    let state = app.state::<ProxyState>();
+    if state.keep_running_on_exit.try_lock().is_some_and(|keep| *keep) {
+        return;
+    }
+    let service_file = state.service_state_file.try_lock().and_then(|f| f.clone());
+    if service_file.as_deref().and_then(service::load_state).is_some() {
+        return;
+    }
+    if state.exit_cleaned.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let pid = state.inner.try_lock().and_then(|inner| inner.pid());
+    if let Some(pid) = pid {
+        process::terminate_pid(pid);
+    }
+    let before = fs::read(state.hosts.path()).unwrap_or_default();
+    let removed = state.hosts.remove_entries(&[]).unwrap_or(false);
+    // try_lock: the panic may have happened while recording
+    let Some(mut log) = state.audit.try_lock() else {
+        return;
+    };
+    if let Some(pid) = pid {
+        let _ = log.record(&audit::Event::ProxyStopped { pid, exit_code: None, reason: "app panicked".into() });
+    }
+    if removed {
+        let after = fs::read(state.hosts.path()).unwrap_or_default();
+        let _ = log.record(&hosts_change_event(&before, &after));
+    }
+}
 
-        *state.child.lock().unwrap() = Some(child);
+fn install_cert(app: AppHandle, state: State<'_, ProxyState>) -> Result<CertChangeResult, ProxyError> {
+    let dir = certs_path(&app)?;
+    let change = cert::install(&dir).map_err(ProxyError::Cert)?;
+    forget_status(&state);
+    let installed = cert::is_installed(&dir);
+    if !installed {
+        return Err(ProxyError::Cert(
+            "Certificate install reported success but the CA is not trusted".into(),
+        ));
     }
+    audit(&state, audit::Event::CertInstalled { fingerprint: cert::ca_fingerprint(&dir) });
 
-    *state.running.lock().unwrap() = true;
+    Ok(CertChangeResult {
+        ok: true,
+        message: "Certificate installed".into(),
+        cert_installed: installed,
+        elevation_required: change.elevation_required,
+        elevation_obtained: change.elevation_obtained,
+    })
+}
+
+fn uninstall_cert(app: AppHandle, state: State<'_, ProxyState>) -> Result<CertChangeResult, ProxyError> {
+    let dir = certs_path(&app)?;
+    let change = cert::uninstall(&dir).map_err(ProxyError::Cert)?;
+    forget_status(&state);
+    let installed = cert::is_installed(&dir);
+    if !installed {
+        audit(&state, audit::Event::CertRemoved { fingerprint: cert::ca_fingerprint(&dir) });
+    }
 
-    Ok(serde_json::json!({
-        "ok": true,
-        "message": "Proxy started"
-    }).to_string())
+    Ok(CertChangeResult {
+        ok: !installed,
+        message: if installed { "Certificate is still trusted" } else { "Certificate removed" }.into(),
+        cert_installed: installed,
+        elevation_required: change.elevation_required,
+        elevation_obtained: change.elevation_obtained,
+    })
 }
 
-#[tauri::command]
-fn proxy_stop(state: State<'_, ProxyState>) -> Result<String, String> {
-    // Kill proxy process
-    if let Some(ref mut child) = *state.child.lock().unwrap() {
-        let _ = child.kill();
+fn regenerate_cert(
+    domains: Option<Vec<String>>,
+    app: AppHandle,
+    state: State<'_, ProxyState>,
+) -> Result<CertRegenerateResult, ProxyError> {
+    if state.inner.lock().running() {
+        return Err(ProxyError::InvalidState(
+            "Stop the proxy before regenerating the certificate".into(),
+        ));
+    }
+    let domains = hosts::normalize_domains(&domains.unwrap_or_else(|| state.inner.lock().domains.clone()))?;
+    let dir = certs_path(&app)?;
+    let was_installed = cert::is_installed(&dir);
+    if was_installed {
+        cert::uninstall(&dir).map_err(ProxyError::Cert)?;
+        audit(&state, audit::Event::CertRemoved { fingerprint: cert::ca_fingerprint(&dir) });
     }
-    *state.child.lock().unwrap() = None;
-    *state.running.lock().unwrap() = false;
+    let names: Vec<&str> = domains.iter().map(String::as_str).collect();
+    cert::generate(&dir, &names).map_err(ProxyError::Cert)?;
+    forget_status(&state);
 
-    Ok(serde_json::json!({"ok": true, "message": "Proxy stopped"}).to_string())
+    Ok(CertRegenerateResult {
+        ok: true,
+        message: "Certificate regenerated".into(),
+        previously_installed: was_installed,
+        cert_installed: cert::is_installed(&dir),
+        ca_expires_at: cert::ca_not_after(&dir),
+        domains,
+    })
 }
 
-#[tauri::command]
-fn proxy_restore(state: State<'_, ProxyState>) -> Result<String, String> {
-    // Kill proxy
-    if let Some(ref mut child) = *state.child.lock().unwrap() {
-        let _ = child.kill();
+/// Refuse to serve a certificate past its notAfter: clients would reject
+/// every connection with an error that doesn't point at us.
+fn ensure_cert_current(dir: &Path) -> Result<(), ProxyError> {
+    match cert::not_after(dir).map(days_until) {
+        Some(days) if days < 0 => Err(ProxyError::CertExpired(format!(
+            "The certificate expired {} day(s) ago; renew it before starting the proxy",
+            -days
+        ))),
+        _ => Ok(()),
     }
-    *state.child.lock().unwrap() = None;
-    *state.running.lock().unwrap() = false;
+}
+
+fn app_versions(app: &AppHandle) -> versions::Versions {
+    let certs = certs_path(app).ok();
+    versions::collect(resources_dir().ok().as_deref(), certs.as_deref(), update_dir(app).ok().as_deref())
+}
+
+fn status(app: AppHandle, state: State<'_, ProxyState>) -> Result<ProxyStatus, ProxyError> {
+    refresh_child(&app, &state);
+    refresh_adopted(&state);
+    let service = service_state(&state);
+    let domains = match &service {
+        Some(service) => service.domains.clone(),
+        None => state.inner.lock().domains.clone(),
+    };
+    let (hosts, hosts_error) = match cached_hosts(&state, &domains) {
+        Ok((hosts, reread)) => {
+            if reread {
+                // Edited, maybe by someone else; what resolves where may have changed
+                *state.slow_status.lock() = None;
+            }
+            (Some(hosts), None)
+        }
+        Err(e) => (None, Some(e.to_string())),
+    };
+    let hosts_missing = hosts.as_ref().map(|h| h.missing_entries());
+    // Present only when both address families are covered for every domain
+    let hosts_modified = hosts_missing.as_ref().is_some_and(Vec::is_empty);
+    let hosts_partial = hosts_missing
+        .as_ref()
+        .is_some_and(|m| !m.is_empty() && m.len() < domains.len() * hosts::LOOPBACKS.len());
+    let (cert_installed, cert_expires_at, backup_available, firewall_rule, resolves_to_loopback) = {
+        let mut cached = state.slow_status.lock();
+        let slow = cached
+            .take()
+            .filter(|s| s.checked_at.elapsed() < STATUS_SLOW_REFRESH)
+            .unwrap_or_else(|| SlowStatus {
+                checked_at: Instant::now(),
+                cert_installed: cert_installed(&app),
+                cert_expires_at: certs_path(&app).ok().and_then(|dir| cert::not_after(&dir)),
+                backup_available: backup_dir(&app).map(|dir| !list_backups(&dir).is_empty()).unwrap_or(false),
+                firewall_rule: firewall::exists(),
+                resolves_to_loopback: domains.iter().all(|d| dns::resolves_to_loopback(d)),
+            });
+        let fields = (
+            slow.cert_installed,
+            slow.cert_expires_at,
+            slow.backup_available,
+            slow.firewall_rule,
+            slow.resolves_to_loopback,
+        );
+        *cached = Some(slow);
+        fields
+    };
+    let (pid, restarting, started_at, last_exit, failed, bypass_paths, redirected, port) = {
+        let inner = state.inner.lock();
+        let failed = inner.failed.clone();
+        let last_exit = inner.last_exit.clone();
+        let bypass_paths = inner.bypass_paths.clone();
+        let redirected = inner.redirect.is_some();
+        (inner.pid(), inner.restarting, inner.started_at, last_exit, failed, bypass_paths, redirected, inner.port)
+    };
+    // The service always forwards; replay bypasses nothing
+    let replay = service.is_none().then(|| state.inner.lock().replay.clone()).flatten();
+    let bypass_paths = if replay.is_some() { Vec::new() } else { bypass_paths };
+    let auto_stop_at = state.schedule.lock().as_ref().map(|s| s.stop_at);
+    let health = state.health.lock().clone();
+    let now_ms = now_millis();
+    let proxy_running = pid.is_some() || restarting || service.is_some();
+    let hosts_present = hosts_modified || hosts_partial;
+    let activity = Activity::of(proxy_running, failed.is_some(), hosts_present);
+    let unfinished = |step: &str| state.unfinished_restore.lock().iter().flatten().any(|s| s.step == step && !s.ok);
+    let remnants = [
+        ("stopProxy", pid.is_some() || restarting),
+        ("hostsEntries", hosts_present),
+        ("dnsFlush", unfinished("dnsFlush")),
+        ("redirect", redirected),
+        ("firewallRule", firewall_rule == Some(true)),
+        ("doh", app_data_dir(&app).is_ok_and(|dir| dir.join(doh::STATE_FILE).exists())),
+        ("cert", unfinished("cert")),
+    ]
+    .into_iter()
+    .filter_map(|(step, present)| present.then_some(step))
+    .collect();
 
-    // Remove hosts entry
-    let content = fs::read_to_string(HOSTS_PATH).map_err(|e| format!("Read hosts failed: {}", e))?;
-    let filtered: Vec<&str> = content
-        .lines()
-        .filter(|line| !line.contains(WINDSURF_DOMAIN))
-        .collect();
-    fs::write(HOSTS_PATH, filtered.join("\n") + "\n").map_err(|e| format!("Write hosts failed: {}", e))?;
+    Ok(ProxyStatus {
+        activity,
+        failed,
+        remnants,
+        hosts_orphaned: !proxy_running && hosts_present,
+        hosts_modified,
+        hosts_partial,
+        hosts_missing: hosts_missing.unwrap_or_default(),
+        hosts_encoding: hosts.as_ref().map(hosts::HostsSummary::encoding),
+        hosts_error,
+        proxy_running,
+        restarting,
+        healthy: pid.is_some() && health.is_fresh(now_ms) && health_check_passed(&state, now_ms),
+        health,
+        health_check: state.health_check.lock().clone(),
+        resources: state.usage.lock().clone(),
+        cert_installed,
+        cert_expires_at,
+        cert_days_remaining: cert_expires_at.map(days_until),
+        backup_available,
+        pid,
+        started_at,
+        uptime_secs: started_at.map(|t| now_secs().saturating_sub(t)),
+        last_exit,
+        port: service.as_ref().map_or(port, |s| s.port),
+        redirected,
+        firewall_rule,
+        hosts_domains: hosts.as_ref().map(|h| h.block_domains()).unwrap_or_default(),
+        hosts_entries: hosts.map(|h| h.block_entries()).unwrap_or_default(),
+        resolves_to_loopback,
+        routing: routing(&state, &domains, service.as_ref()),
+        bypass_paths: service.as_ref().map_or(bypass_paths, |s| s.bypass_paths.clone()),
+        mode: if replay.is_some() { replay::Mode::Replay } else { replay::Mode::Forward },
+        replay,
+        domains,
+        service,
+        auto_stop_at,
+        auto_stop_in_secs: auto_stop_at.map(|t| t.saturating_sub(now_secs())),
+    })
+}
+
+/// The hosts file, read again only if the watch saw it change. Also returns
+/// whether it was read.
+fn cached_hosts(state: &ProxyState, domains: &[String]) -> Result<(hosts::HostsSummary, bool), ProxyError> {
+    let generation = state.hosts_watch.generation();
+    state.hosts.read_cached(&mut state.hosts_cache.lock(), generation, domains)
+}
 
-    Ok(serde_json::json!({"ok": true, "message": "Restored"}).to_string())
+/// Drop the cached parts of the status after a change of ours, so the next
+/// proxy_status (and the status watch) sees it right away.
+fn forget_status(state: &ProxyState) {
+    state.hosts_cache.lock().clear();
+    *state.slow_status.lock() = None;
 }
 
-#[tauri::command]
-fn proxy_status(state: State<'_, ProxyState>) -> Result<String, String> {
-    let running = *state.running.lock().unwrap();
-    let content = fs::read_to_string(HOSTS_PATH).unwrap_or_default();
-    let hosts_modified = content.lines().any(|l| {
-        let t = l.trim();
-        !t.starts_with('#') && t.contains(WINDSURF_DOMAIN)
+/// Push `proxy://status` whenever the status changes, so a crash or a hosts
+/// edit shows up within STATUS_INTERVAL instead of on the UI's next poll.
+/// Runs for the life of the app.
+fn spawn_status_watch(app: AppHandle) {
+    std::thread::spawn(move || {
+        let mut last: Option<serde_json::Value> = None;
+        let mut warned_for: Option<i64> = None;
+        loop {
+            let Ok(status) = status(app.clone(), app.state::<ProxyState>()) else {
+                std::thread::sleep(STATUS_INTERVAL);
+                continue;
+            };
+            if let (Some(expires_at), Some(days_remaining)) = (status.cert_expires_at, status.cert_days_remaining) {
+                if days_remaining < CERT_WARN_DAYS && warned_for != Some(expires_at) {
+                    let _ = app.emit(EVENT_CERT_EXPIRING, CertExpiry { expires_at, days_remaining });
+                    warned_for = Some(expires_at);
+                }
+            }
+            let key = status.change_key();
+            if last.as_ref() != Some(&key) {
+                let _ = app.emit(EVENT_PROXY_STATUS, &status);
+                if let Some(tray) = app.try_state::<tray::Tray>() {
+                    let (indicator, line) = tray::state(&status);
+                    tray.update(&app, indicator, &line, status.proxy_running);
+                }
+                last = Some(key);
+            }
+            std::thread::sleep(STATUS_INTERVAL);
+        }
     });
+}
+
+/// Bring the main window to the front, e.g. for a second copy of the app
+/// that exits in favour of this one.
+fn focus_main_window(app: &AppHandle) {
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+}
 
-    Ok(serde_json::json!({
-        "hostsModified": hosts_modified,
-        "proxyRunning": running,
-    }).to_string())
+/// Helper mode for the elevated hosts write (`<exe> --write-hosts <staged>
+/// [<hosts>]`, launched through UAC on Windows) and for elevate::probe
+/// (`<exe> --elevation-probe`). The target is passed along because the
+/// elevated process doesn't inherit our environment. Returns the exit code
+/// if this process was started as a helper.
+pub fn run_hosts_helper() -> Option<i32> {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some(elevate::HOSTS_HELPER_ARG) => {}
+        // Elevation was granted, which is all elevate::probe wants to know
+        Some(elevate::PROBE_HELPER_ARG) => return Some(0),
+        _ => return None,
+    }
+    let staged = args.next();
+    let target = args.next().map(hosts::HostsFile::new).unwrap_or_else(hosts::HostsFile::system);
+    let result = staged
+        .ok_or_else(|| "missing staged hosts file".to_string())
+        .and_then(|staged| fs::read(&staged).map_err(|e| format!("Read {} failed: {}", staged, e)))
+        .and_then(|content| target.write(&content).map_err(|e| e.to_string()));
+    Some(match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    })
+}
+
+/// Entry point of the built-in proxy (`<exe> --local-proxy <args>`), which
+/// spawn_proxy starts unless config.nodeProxy is set. Returns the exit code
+/// if this process was started as the proxy.
+pub fn run_proxy_helper() -> Option<i32> {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() != Some(proxy::HELPER_ARG) {
+        return None;
+    }
+    Some(match proxy::run(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("[proxy] failed to start: {}", e);
+            1
+        }
+    })
 }
 
 pub fn run() {
-    tauri::Builder::default()
-        .manage(ProxyState {
-            child: Mutex::new(None),
-            running: Mutex::new(false),
+    // Headless: the service never builds the Tauri app or a window
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some(service::SERVICE_ARG) {
+        if let Err(e) = service::run(args) {
+            eprintln!("[service] {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    tauri::Builder::<Runtime>::new()
+        // First, so a second copy hands over before it touches hosts or the proxy
+        .plugin(tauri_plugin_single_instance::init(|app, _args, _cwd| {
+            log_action(&app.state::<ProxyState>(), "Another copy of the app was started; focused this one instead");
+            focus_main_window(app);
+        }))
+        .plugin(tauri_plugin_shell::init())
+        .manage(ProxyState::new(hosts::HostsFile::system()))
+        .setup(|app| {
+            // Before anything below can touch hosts or the proxy
+            if let Ok(path) = app.path().app_data_dir().map(|d| instance::path(&d)) {
+                match instance::acquire(&path) {
+                    Ok(Some(lock)) => {
+                        app.manage(lock);
+                    }
+                    Ok(None) => {
+                        let pid = instance::holder(&path).map_or_else(|| "unknown".to_string(), |p| p.to_string());
+                        eprintln!("Another copy of the app (pid {}) is already running; exiting", pid);
+                        std::process::exit(0);
+                    }
+                    Err(e) => eprintln!("Taking the instance lock {} failed: {}", path.display(), e),
+                }
+            }
+            let state = app.state::<ProxyState>();
+            // Before anything reads-modifies-writes hosts, which needs the lock
+            if let Ok(dir) = app.path().app_data_dir() {
+                state.hosts.set_lock_dir(&dir);
+            }
+            if let Ok(dir) = app.path().app_data_dir().map(|d| d.join("logs")) {
+                if let Err(e) = state.file_log.lock().open(&dir) {
+                    eprintln!("Failed to open proxy log in {}: {}", dir.display(), e);
+                }
+                let opened = state.audit.lock().open(&dir);
+                match opened {
+                    Ok(()) => audit(&state, audit::Event::AppStarted { versions: app_versions(app.handle()) }),
+                    Err(e) => eprintln!("Failed to open audit log in {}: {}", dir.display(), e),
+                }
+            }
+            *state.allowlist.lock() = load_allowlist(app.handle());
+            *state.session_file.lock() = app.path().app_data_dir().ok().map(|d| session::path(&d));
+            let last_good_file = app.path().app_data_dir().ok().map(|d| lastgood::path(&d));
+            *state.last_good.lock() = last_good_file.as_deref().and_then(lastgood::load);
+            *state.last_good_file.lock() = last_good_file;
+            *state.service_state_file.lock() = app.path().app_data_dir().ok().map(|d| service::state_path(&d));
+            if let Ok(path) = app.path().app_config_dir().map(|d| config::path(&d)) {
+                // A broken config must not keep the app from starting: run on
+                // the defaults and let proxy_initialize report it
+                match config::load(&path) {
+                    Ok(config) => {
+                        state.inner.lock().configure(&config);
+                        if let Some(locale) = &config.locale {
+                            messages::set_locale(locale);
+                        }
+                        *state.config.lock() = config;
+                    }
+                    Err(e) => {
+                        log_action(&state, &format!("Config not loaded, using defaults: {}", e));
+                        *state.config_error.lock() = Some(e);
+                    }
+                }
+                *state.config_file.lock() = Some(path);
+            }
+            // Before the status watch, which keeps it up to date
+            if let Err(e) = tray::create(app.handle(), tray::on_action) {
+                log_action(&state, &format!("Tray icon unavailable: {}", e));
+            }
+            spawn_status_watch(app.handle().clone());
+            if state.config.lock().autorun_proxy && std::env::args().any(|a| a == autostart::LAUNCH_ARG) {
+                let handle = app.handle().clone();
+                tauri::async_runtime::spawn_blocking(move || autorun(&handle));
+            }
+            let handle = app.handle().clone();
+            let previous = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                previous(info);
+                panic_cleanup(&handle);
+            }));
+            Ok(())
+        })
+        .on_window_event(|window, event| {
+            if let tauri::WindowEvent::CloseRequested { api, .. } = event {
+                let closing = window.clone();
+                if exit_cleanup(window.app_handle(), move || {
+                    let _ = closing.close();
+                }) {
+                    api.prevent_close();
+                }
+            }
         })
         .invoke_handler(tauri::generate_handler![
-            proxy_initialize,
-            proxy_run,
-            proxy_stop,
-            proxy_restore,
-            proxy_status
+            commands::proxy_initialize,
+            commands::proxy_run,
+            commands::proxy_restart,
+            commands::proxy_schedule_stop,
+            commands::proxy_history,
+            commands::proxy_cancel_schedule,
+            commands::proxy_stop,
+            commands::proxy_restore,
+            commands::proxy_restore_backup,
+            commands::proxy_repair,
+            commands::doh_disable,
+            commands::hosts_list_managed,
+            commands::hosts_preview_changes,
+            commands::proxy_cleanup_orphans,
+            commands::gateway_check,
+            commands::gateway_last_good,
+            commands::dns_flush,
+            commands::is_elevated,
+            commands::set_keep_running_on_exit,
+            commands::config_get,
+            commands::config_set,
+            commands::config_reset,
+            commands::state_export,
+            commands::state_import,
+            commands::set_locale,
+            commands::autostart_get,
+            commands::autostart_set,
+            commands::service_install,
+            commands::service_uninstall,
+            commands::profiles_list,
+            commands::profiles_save,
+            commands::profiles_delete,
+            commands::proxy_install_cert,
+            commands::proxy_uninstall_cert,
+            commands::cert_regenerate,
+            commands::cert_renew,
+            commands::proxy_logs,
+            commands::proxy_metrics,
+            commands::proxy_uptime_report,
+            commands::proxy_selftest,
+            commands::diagnostics_export,
+            commands::session_export,
+            commands::get_log_path,
+            commands::audit_tail,
+            commands::audit_export,
+            commands::versions,
+            commands::update_check,
+            commands::update_apply,
+            commands::setup_state,
+            commands::setup_next,
+            commands::proxy_status
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::ExitRequested { code, api, .. } = event {
+                let exiting = app.clone();
+                if exit_cleanup(app, move || exiting.exit(code.unwrap_or(0))) {
+                    api.prevent_exit();
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// A state whose hosts file is a scratch one, in a directory of its own
+    /// since the swap files go next to it; it doubles as the app data dir
+    /// for the update lock. Removed on drop.
+    struct Scratch {
+        dir: PathBuf,
+        state: ProxyState,
+    }
+
+    impl Scratch {
+        fn new() -> Self {
+            Scratch::with_hosts("127.0.0.1 localhost\n")
+        }
+
+        fn with_hosts(content: &str) -> Self {
+            let dir = scratch_dir();
+            let state = scratch_state(&dir, content);
+            Scratch { dir, state }
+        }
+
+        fn add_block(&self) {
+            let domains = self.state.inner.lock().domains.clone();
+            assert!(self.state.hosts.ensure_entry(&domains).unwrap());
+        }
+
+        #[cfg(unix)]
+        fn start_child(&self) {
+            let child = process::ProxyChild::spawn_shell(process::tests::shell("sleep", &["30"]), |_, _| {}).unwrap();
+            let mut inner = self.state.inner.lock();
+            inner.child = Some(child);
+            inner.started_at = Some(now_secs());
+        }
+
+        /// What status would report, from the state and the file alone.
+        fn activity(&self) -> Activity {
+            let inner = self.state.inner.lock();
+            let hosts_present = self.state.hosts.load().unwrap().is_modified(&inner.domains);
+            Activity::of(inner.running(), inner.failed.is_some(), hosts_present)
+        }
+
+        /// proxy_stop, short of the DNS flush and the result.
+        fn stop(&self, keep_hosts: bool) -> Option<bool> {
+            stop_child(&self.state, Duration::from_secs(1));
+            clear_after_stop(&self.state, &self.state.hosts, keep_hosts).unwrap()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn scratch_dir() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "cyber-drill-state-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn scratch_state(dir: &Path, content: &str) -> ProxyState {
+        let path = dir.join("hosts");
+        fs::write(&path, content).unwrap();
+        let hosts = hosts::HostsFile::new(path);
+        hosts.set_lock_dir(dir);
+        ProxyState::new(hosts)
+    }
+
+    /// A scratch state managed by a mock app, to call the commands
+    /// themselves, locking included. The scratch dir is also the app's data
+    /// dir. Removed on drop.
+    struct ScratchApp {
+        dir: PathBuf,
+        app: tauri::App<Runtime>,
+    }
+
+    impl ScratchApp {
+        fn with_hosts(content: &str) -> Self {
+            let dir = scratch_dir();
+            let mut context = tauri::test::mock_context(tauri::test::noop_assets());
+            // Joined to the user's data dir, which an absolute path replaces
+            context.config_mut().identifier = dir.display().to_string();
+            let app = tauri::test::mock_builder()
+                .plugin(tauri_plugin_shell::init())
+                .manage(scratch_state(&dir, content))
+                .build(context)
+                .unwrap();
+            ScratchApp { dir, app }
+        }
+
+        fn handle(&self) -> AppHandle {
+            self.app.handle().clone()
+        }
+
+        fn state(&self) -> State<'_, ProxyState> {
+            self.app.state()
+        }
+    }
+
+    impl Drop for ScratchApp {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Wait for a command as the webview's invoke does.
+    fn call<F: std::future::Future>(command: F) -> F::Output {
+        tauri::async_runtime::block_on(command)
+    }
+
+    /// proxy_run replaying `bundle`, with everything else from the config.
+    fn replay_run(app: &AppHandle, bundle: &Path) -> Result<RunResult, ProxyError> {
+        let bundle = Some(bundle.display().to_string());
+        call(commands::proxy_run(
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(replay::Mode::Replay),
+            bundle,
+            app.clone(),
+        ))
+    }
+
+    #[cfg(unix)]
+    fn reap_when_exited(inner: &mut ProxyInner) -> (ExitInfo, u32, Option<u64>) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(reaped) = inner.reap() {
+                return reaped;
+            }
+            assert!(Instant::now() < deadline, "the exit was never noticed");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn status_reports_a_clean_exit_and_clears_the_pid() {
+        let child = process::ProxyChild::spawn_shell(process::tests::shell("true", &[]), |_, _| {}).unwrap();
+        let pid = child.id();
+        let mut inner = ProxyInner { child: Some(child), started_at: Some(now_secs()), ..Default::default() };
+        assert_eq!(inner.pid(), Some(pid));
+
+        let (info, reaped, started_at) = reap_when_exited(&mut inner);
+        assert_eq!((info.code, info.signal), (Some(0), None));
+        assert!(info.reason == Lifecycle::Stopped);
+        assert_eq!(reaped, pid);
+        assert!(started_at.is_some());
+        assert_eq!(inner.pid(), None);
+        assert!(inner.started_at.is_none());
+        let last = inner.history.back().unwrap();
+        assert_eq!((last.pid, last.exit_code), (Some(pid), Some(0)));
+        assert!(inner.reap().is_none(), "reported once");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn status_reports_a_failed_exit_as_a_crash() {
+        let child = process::ProxyChild::spawn_shell(process::tests::shell("false", &[]), |_, _| {}).unwrap();
+        let mut inner = ProxyInner { child: Some(child), ..Default::default() };
+
+        let (info, _, _) = reap_when_exited(&mut inner);
+        assert_eq!(info.code, Some(1));
+        assert!(info.reason == Lifecycle::Crashed);
+        assert_eq!(inner.pid(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn status_leaves_a_running_proxy_alone() {
+        let child = process::ProxyChild::spawn_shell(process::tests::shell("sleep", &["5"]), |_, _| {}).unwrap();
+        let pid = child.id();
+        let mut inner = ProxyInner { child: Some(child), ..Default::default() };
+        assert!(inner.reap().is_none());
+        assert_eq!(inner.pid(), Some(pid));
+        process::shutdown(inner.child.as_mut().unwrap(), Duration::from_secs(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn claim_start_refuses_a_second_start_and_a_running_child() {
+        let mut inner = ProxyInner::default();
+        inner.claim_start(None).unwrap();
+        assert!(inner.starting);
+        assert!(matches!(inner.claim_start(None), Err(ProxyError::AlreadyRunning(_))));
+
+        inner.starting = false;
+        let child = process::ProxyChild::spawn_shell(process::tests::shell("sleep", &["5"]), |_, _| {}).unwrap();
+        inner.child = Some(child);
+        assert!(matches!(inner.claim_start(None), Err(ProxyError::AlreadyRunning(_))));
+        assert!(!inner.starting);
+        process::shutdown(inner.child.as_mut().unwrap(), Duration::from_secs(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_takes_an_active_drill_to_clean() {
+        let scratch = Scratch::new();
+        scratch.add_block();
+        scratch.start_child();
+        assert!(scratch.activity() == Activity::Active);
+
+        assert_eq!(scratch.stop(false), Some(true));
+        assert!(scratch.activity() == Activity::Clean);
+        assert!(!scratch.state.hosts.load().unwrap().has_block());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_keeping_hosts_leaves_hosts_only() {
+        let scratch = Scratch::new();
+        scratch.add_block();
+        scratch.start_child();
+
+        assert_eq!(scratch.stop(true), None);
+        assert!(scratch.activity() == Activity::HostsOnly);
+        assert!(scratch.state.hosts.load().unwrap().has_block());
+    }
+
+    #[test]
+    fn stop_takes_hosts_only_to_clean() {
+        let scratch = Scratch::new();
+        scratch.add_block();
+        assert!(scratch.activity() == Activity::HostsOnly);
+
+        assert_eq!(scratch.stop(false), Some(true));
+        assert!(scratch.activity() == Activity::Clean);
+    }
+
+    #[test]
+    fn stop_when_clean_stays_clean() {
+        let scratch = Scratch::new();
+        assert!(scratch.activity() == Activity::Clean);
+
+        assert_eq!(scratch.stop(false), Some(false));
+        assert!(scratch.activity() == Activity::Clean);
+        assert_eq!(fs::read_to_string(scratch.state.hosts.path()).unwrap(), "127.0.0.1 localhost\n");
+    }
+
+    #[test]
+    fn stop_removes_hosts_unless_told_to_keep_them() {
+        assert!(!StopOptions::new(None, None, None).keep_hosts);
+        assert!(!StopOptions::default().keep_hosts);
+        assert!(StopOptions::new(None, Some(true), None).keep_hosts);
+    }
+
+    #[test]
+    fn exit_cleanup_runs_once_and_not_when_the_proxy_stays() {
+        let scratch = Scratch::new();
+        *scratch.state.keep_running_on_exit.lock() = true;
+        assert!(!claim_exit_cleanup(&scratch.state));
+        *scratch.state.keep_running_on_exit.lock() = false;
+        assert!(claim_exit_cleanup(&scratch.state));
+        assert!(!claim_exit_cleanup(&scratch.state), "the close and the exit after it clean up once");
+    }
+
+    /// The user's lines in order, and how many of our blocks there are.
+    fn hosts_layout(state: &ProxyState) -> (Vec<String>, usize) {
+        let text = fs::read_to_string(state.hosts.path()).unwrap();
+        let mut user = Vec::new();
+        let (mut blocks, mut inside) = (0, false);
+        for line in text.lines() {
+            match line {
+                "# cyber-drill-safe BEGIN" => {
+                    blocks += 1;
+                    inside = true;
+                }
+                "# cyber-drill-safe END" => inside = false,
+                _ if !inside => user.push(line.to_string()),
+                _ => {}
+            }
+        }
+        (user, blocks)
+    }
+
+    /// Starts, stops, restores, backup restores and config saves from
+    /// several threads at once, through the commands and so kept apart only
+    /// by their own locking, with status reading the file alongside.
+    #[test]
+    fn concurrent_run_stop_and_restore_keep_the_hosts_file_whole() {
+        let user: Vec<String> = (1..=20).map(|n| format!("10.0.0.{} host-{}.internal", n, n)).collect();
+        let scratch = ScratchApp::with_hosts(&format!("{}\n", user.join("\n")));
+        let (app, state) = (scratch.handle(), scratch.state());
+        // Status resolves it from the system hosts file, not over the network
+        let config = config::Config { domains: vec!["localhost".into()], ..Default::default() };
+        call(commands::config_set(config.clone(), app.clone())).unwrap();
+        // A backup taken with our block in, for the backup restores to put back
+        state.hosts.ensure_entry(&config.domains).unwrap();
+        fs::copy(state.hosts.path(), backup_dir(&app).unwrap().join(format!("{}1", HOSTS_BACKUP_PREFIX))).unwrap();
+        let missing = scratch.dir.join("missing.json");
+        let writers_done = AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !writers_done.load(Ordering::SeqCst) {
+                    let status = call(commands::proxy_status(app.clone())).unwrap();
+                    assert!(status.hosts_error.is_none(), "status failed to read hosts: {:?}", status.hosts_error);
+                    let (lines, blocks) = hosts_layout(&state);
+                    assert_eq!(lines, user, "a status read saw user lines lost");
+                    assert!(blocks <= 1, "a status read saw {} blocks", blocks);
+                }
+            });
+            let writers: Vec<_> = (0..4)
+                .map(|thread| {
+                    let (app, config, missing) = (app.clone(), &config, &missing);
+                    scope.spawn(move || {
+                        for round in 0..12 {
+                            match (thread + round) % 5 {
+                                0 => {
+                                    call(commands::proxy_restore_backup(app.clone())).unwrap();
+                                }
+                                1 => {
+                                    let stopped = call(commands::proxy_stop(None, None, None, app.clone())).unwrap();
+                                    assert!(stopped.hosts_removed.is_some());
+                                }
+                                2 => {
+                                    // Forced: an earlier restore's DNS flush may have failed here
+                                    call(commands::proxy_restore(None, None, None, Some(true), None, app.clone()))
+                                        .unwrap();
+                                }
+                                // Fails on the bundle, once it has claimed the start
+                                3 => assert!(matches!(replay_run(&app, missing), Err(ProxyError::InvalidArgument(_)))),
+                                _ => {
+                                    let config = config::Config { port: 8443 + round as u16, ..config.clone() };
+                                    call(commands::config_set(config, app.clone())).unwrap();
+                                }
+                            }
+                        }
+                    })
+                })
+                .collect();
+            // Stop the reader before a failed writer ends the test
+            let results: Vec<_> = writers.into_iter().map(|writer| writer.join()).collect();
+            writers_done.store(true, Ordering::SeqCst);
+            for result in results {
+                result.unwrap();
+            }
+        });
+
+        assert!(!state.inner.lock().starting);
+        call(commands::proxy_restore_backup(app.clone())).unwrap();
+        assert_eq!(hosts_layout(&state), (user.clone(), 1));
+        assert!(state.hosts.load().unwrap().block_warnings().is_empty());
+        call(commands::proxy_restore(None, None, None, Some(true), None, app)).unwrap();
+        assert_eq!(hosts_layout(&state), (user, 0));
+    }
+
+    /// The commands that change state wait for a start in progress to end;
+    /// status doesn't. The start is held reading its replay bundle from a
+    /// FIFO until the test writes it.
+    #[cfg(unix)]
+    #[test]
+    fn commands_wait_for_a_start_in_progress() {
+        let scratch = ScratchApp::with_hosts("127.0.0.1 localhost\n");
+        let (app, state) = (scratch.handle(), scratch.state());
+        let config = config::Config { domains: vec!["localhost".into()], ..Default::default() };
+        call(commands::config_set(config, app.clone())).unwrap();
+        let bundle = scratch.dir.join("bundle.json");
+        let fifo = std::ffi::CString::new(bundle.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+        std::thread::scope(|scope| {
+            let run = scope.spawn(|| replay_run(&app, &bundle));
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !state.inner.lock().starting {
+                assert!(Instant::now() < deadline, "the start never began");
+                std::thread::sleep(Duration::from_millis(10));
+            }
+
+            // Each with the error it returned, if any
+            type Command = fn(AppHandle) -> Option<ProxyError>;
+            let (tx, rx) = std::sync::mpsc::channel();
+            let commands: [(&str, Command); 5] = [
+                ("config_set", |app| {
+                    let domains = vec!["localhost".into(), "drill.test".into()];
+                    call(commands::config_set(config::Config { domains, ..Default::default() }, app)).err()
+                }),
+                ("config_reset", |app| call(commands::config_reset(app)).err()),
+                ("profiles_save", |app| {
+                    call(commands::profiles_save("a".into(), String::new(), None, None, app)).err()
+                }),
+                ("proxy_stop", |app| call(commands::proxy_stop(None, None, None, app)).err()),
+                ("proxy_restore", |app| call(commands::proxy_restore(None, None, None, None, None, app)).err()),
+            ];
+            let count = commands.len();
+            for (name, command) in commands {
+                let (tx, app) = (tx.clone(), app.clone());
+                scope.spawn(move || tx.send((name, command(app))).unwrap());
+            }
+            assert!(call(commands::proxy_status(app.clone())).is_ok());
+            std::thread::sleep(Duration::from_millis(300));
+            let early = rx.try_recv().ok();
+
+            // Not a bundle: the start fails and lets the others in
+            fs::write(&bundle, "{").unwrap();
+            assert!(matches!(run.join().unwrap(), Err(ProxyError::InvalidArgument(_))));
+            if let Some((name, error)) = early {
+                panic!("{} ran during the start: {:?}", name, error);
+            }
+            for _ in 0..count {
+                let (name, error) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+                assert!(error.is_none(), "{} failed once the start was over: {:?}", name, error);
+            }
+        });
+        assert!(!state.inner.lock().starting);
+    }
+
+    #[test]
+    fn inner_lock_survives_a_panic_while_held() {
+        let inner = Arc::new(Mutex::new(ProxyInner { port: DEFAULT_PROXY_PORT, ..Default::default() }));
+        let held = inner.clone();
+        let panicked = std::thread::spawn(move || {
+            let mut inner = held.lock();
+            inner.starting = true;
+            inner.redirect = Some(8443);
+            panic!("a command panicked mid-start");
+        })
+        .join();
+        assert!(panicked.is_err());
+
+        // parking_lot doesn't poison: the next command gets the lock, and
+        // sees what was written before the panic
+        let mut inner = inner.try_lock().expect("the lock was released");
+        assert!(inner.starting);
+        assert_eq!(inner.redirect, Some(8443));
+        assert!(inner.idle());
+        inner.configure(&config::Config::default());
+        assert_eq!(inner.port, config::Config::default().port);
+    }
+
+    #[test]
+    fn timed_gives_up_on_a_slow_step_and_hands_it_to_late() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let slow = || {
+            std::thread::sleep(Duration::from_millis(300));
+            Ok(7)
+        };
+        let result = timed("slow", Duration::from_millis(50), slow, move |value| tx.send(value).unwrap());
+        assert!(matches!(result, Err(ProxyError::TimedOut { step: "slow", .. })));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(7));
+    }
+
+    #[test]
+    fn timed_stops_the_clock_while_paused() {
+        let prompt = Arc::new(AtomicBool::new(true));
+        let answer = prompt.clone();
+        let step = move || {
+            // The prompt outlasts the timeout; the write after it doesn't
+            std::thread::sleep(Duration::from_millis(500));
+            answer.store(false, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(50));
+            Ok(7)
+        };
+        let paused = move || prompt.load(Ordering::SeqCst);
+        let result = timed_unless("prompted", Duration::from_millis(250), paused, step, |_| panic!("not late"));
+        assert_eq!(result.unwrap(), 7);
+    }
 }
@@ -0,0 +1,181 @@
+//! Proxy output capture: an in-memory tail for the UI plus a rotating log
+//! file under the app data dir for incident reports.
+
+use crate::{failure, heartbeat, AppHandle};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::Emitter;
+
+pub const EVENT_PROXY_LOG: &str = "proxy://log";
+const LOG_CAPACITY: usize = 2000;
+const LOG_FILE_NAME: &str = "proxy.log";
+const LOG_FILE_MAX_BYTES: u64 = 1024 * 1024;
+/// proxy.log plus proxy.log.1 .. proxy.log.4
+const LOG_FILE_KEEP: usize = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn tag(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct LogLine {
+    /// Unix milliseconds.
+    pub ts: u64,
+    pub stream: Stream,
+    pub line: String,
+}
+
+/// Bounded ring buffer; the oldest lines are dropped once full. Known
+/// failure signatures are noted as lines arrive, so they outlive the lines.
+pub struct LogBuffer {
+    lines: VecDeque<LogLine>,
+    failures: failure::Tracker,
+}
+
+impl LogBuffer {
+    pub fn new() -> Self {
+        LogBuffer { lines: VecDeque::with_capacity(LOG_CAPACITY), failures: failure::Tracker::default() }
+    }
+
+    pub fn push(&mut self, line: LogLine) {
+        self.failures.observe(&line.line);
+        if self.lines.len() == LOG_CAPACITY {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.failures.clear();
+    }
+
+    /// The most telling failure signature since the last `clear`.
+    pub fn failure(&self) -> Option<failure::Failure> {
+        self.failures.failure()
+    }
+
+    /// The last `n` lines, oldest first.
+    pub fn tail(&self, n: usize) -> Vec<LogLine> {
+        self.lines.iter().skip(self.lines.len().saturating_sub(n)).cloned().collect()
+    }
+
+    /// The last `n` stderr lines, oldest first.
+    pub fn stderr_tail(&self, n: usize) -> Vec<String> {
+        let mut tail: Vec<String> = self
+            .lines
+            .iter()
+            .rev()
+            .filter(|l| l.stream == Stream::Stderr)
+            .take(n)
+            .map(|l| l.line.clone())
+            .collect();
+        tail.reverse();
+        tail
+    }
+}
+
+pub type SharedLog = Arc<Mutex<LogBuffer>>;
+
+/// Size-rotated log file. Writes are dropped until `open` has been called.
+pub struct FileLog {
+    path: Option<PathBuf>,
+    file: Option<File>,
+    size: u64,
+}
+
+impl FileLog {
+    pub fn new() -> Self {
+        FileLog { path: None, file: None, size: 0 }
+    }
+
+    pub fn open(&mut self, dir: &Path) -> std::io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(LOG_FILE_NAME);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        self.file = Some(file);
+        self.path = Some(path);
+        Ok(())
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Append one line tagged with its source ("stdout", "stderr", "app").
+    pub fn write(&mut self, source: &str, line: &str) {
+        let ts = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        let record = format!("{} [{}] {}\n", ts, source, line);
+        if self.size + record.len() as u64 > LOG_FILE_MAX_BYTES {
+            self.rotate();
+        }
+        if let Some(file) = self.file.as_mut() {
+            if file.write_all(record.as_bytes()).is_ok() {
+                self.size += record.len() as u64;
+            }
+        }
+    }
+
+    /// Shift proxy.log -> proxy.log.1 -> ... and start a fresh file. The
+    /// child never holds the file itself, so rotating under it is safe.
+    fn rotate(&mut self) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        self.file = None;
+        let numbered = |i: usize| path.with_file_name(format!("{}.{}", LOG_FILE_NAME, i));
+        let _ = fs::remove_file(numbered(LOG_FILE_KEEP - 1));
+        for i in (1..LOG_FILE_KEEP - 1).rev() {
+            let _ = fs::rename(numbered(i), numbered(i + 1));
+        }
+        let _ = fs::rename(&path, numbered(1));
+        self.file = OpenOptions::new().create(true).append(true).open(&path).ok();
+        self.size = 0;
+    }
+}
+
+pub type SharedFileLog = Arc<Mutex<FileLog>>;
+
+/// Where the proxy's output lines go: into `log` and `file`, and out as
+/// `proxy://log`. Protocol messages on stdout go to `telemetry` instead.
+pub fn sink(
+    app: AppHandle,
+    log: SharedLog,
+    file: SharedFileLog,
+    telemetry: heartbeat::Telemetry,
+) -> impl Fn(Stream, String) + Send + 'static {
+    move |stream, line| {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        if stream == Stream::Stdout && telemetry.record(&line, ts) {
+            return;
+        }
+        file.lock().write(stream.tag(), &line);
+        let entry = LogLine { ts, stream, line };
+        let _ = app.emit(EVENT_PROXY_LOG, &entry);
+        log.lock().push(entry);
+    }
+}
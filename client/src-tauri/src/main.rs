@@ -1,5 +1,11 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 fn main() {
+    if let Some(code) = wind_client_lib::run_hosts_helper() {
+        std::process::exit(code);
+    }
+    if let Some(code) = wind_client_lib::run_proxy_helper() {
+        std::process::exit(code);
+    }
     wind_client_lib::run()
 }
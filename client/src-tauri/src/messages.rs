@@ -0,0 +1,52 @@
+//! What an error tells a drill participant, as opposed to the technical
+//! `message` meant for the administrator. Templates are keyed by locale and
+//! error code in messages/errors.json, so translators never touch Rust code;
+//! `{name}` placeholders take the error's own fields.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+type Catalog = HashMap<String, HashMap<String, String>>;
+
+static CATALOG: LazyLock<Catalog> =
+    LazyLock::new(|| serde_json::from_str(include_str!("../messages/errors.json")).unwrap_or_default());
+
+static LOCALE: RwLock<String> = RwLock::new(String::new());
+
+/// Make `locale` (e.g. `de` or `de-AT`) the one errors are worded in.
+/// Returns the locale actually used: the language alone, or DEFAULT_LOCALE
+/// for one the catalog doesn't have.
+pub fn set_locale(locale: &str) -> String {
+    let locale = resolve(locale).to_string();
+    *LOCALE.write().unwrap_or_else(|e| e.into_inner()) = locale.clone();
+    locale
+}
+
+pub fn locale() -> String {
+    let locale = LOCALE.read().unwrap_or_else(|e| e.into_inner());
+    resolve(&locale).to_string()
+}
+
+/// The locales the catalog has, sorted.
+pub fn locales() -> Vec<String> {
+    let mut locales: Vec<String> = CATALOG.keys().cloned().collect();
+    locales.sort();
+    locales
+}
+
+fn resolve(locale: &str) -> &str {
+    let language = locale.trim().split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase();
+    CATALOG.get_key_value(language.as_str()).map_or(DEFAULT_LOCALE, |(key, _)| key.as_str())
+}
+
+/// The text for `code` in the current locale, falling back to English and
+/// then to the generic INTERNAL text. None only if the catalog is broken.
+pub fn user_message(code: &str, fields: &[(&str, String)]) -> Option<String> {
+    let locale = locale();
+    let in_locale = |locale: &str, code: &str| CATALOG.get(locale).and_then(|t| t.get(code));
+    let lookup = |code: &str| in_locale(&locale, code).or_else(|| in_locale(DEFAULT_LOCALE, code));
+    let template = lookup(code).or_else(|| lookup("INTERNAL"))?;
+    Some(fields.iter().fold(template.clone(), |text, (name, value)| text.replace(&format!("{{{}}}", name), value)))
+}
@@ -0,0 +1,104 @@
+//! Local network probes.
+
+use serde::Serialize;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// Whether something accepts TCP connections on 127.0.0.1:`port`.
+pub fn is_listening(port: u16, timeout: Duration) -> bool {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    TcpStream::connect_timeout(&addr, timeout).is_ok()
+}
+
+/// The process listening on a port we want.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortOwner {
+    pub pid: u32,
+    pub name: Option<String>,
+}
+
+/// Who listens on TCP `port` (IPv4), if we can tell.
+pub fn port_owner(port: u16) -> Option<PortOwner> {
+    let pid = listener_pid(port)?;
+    Some(PortOwner { pid, name: crate::process::process_name(pid) })
+}
+
+#[cfg(target_os = "windows")]
+fn listener_pid(port: u16) -> Option<u32> {
+    use windows_sys::Win32::NetworkManagement::IpHelper::{
+        GetExtendedTcpTable, MIB_TCPROW_OWNER_PID, MIB_TCPTABLE_OWNER_PID, TCP_TABLE_OWNER_PID_LISTENER,
+    };
+    use windows_sys::Win32::Networking::WinSock::AF_INET;
+
+    let mut size = 0u32;
+    // SAFETY: a null table asks for the required size only.
+    unsafe {
+        GetExtendedTcpTable(std::ptr::null_mut(), &mut size, 0, AF_INET as u32, TCP_TABLE_OWNER_PID_LISTENER, 0);
+    }
+    // u32 storage keeps the table suitably aligned.
+    let mut buf = vec![0u32; (size as usize).div_ceil(4)];
+    // SAFETY: buf holds at least `size` bytes.
+    let rc = unsafe {
+        GetExtendedTcpTable(buf.as_mut_ptr().cast(), &mut size, 0, AF_INET as u32, TCP_TABLE_OWNER_PID_LISTENER, 0)
+    };
+    if rc != 0 {
+        return None;
+    }
+    // SAFETY: on success buf starts with a MIB_TCPTABLE_OWNER_PID followed by dwNumEntries rows.
+    let rows = unsafe {
+        let table = &*(buf.as_ptr() as *const MIB_TCPTABLE_OWNER_PID);
+        std::slice::from_raw_parts(table.table.as_ptr(), table.dwNumEntries as usize)
+    };
+    rows.iter()
+        .find(|row: &&MIB_TCPROW_OWNER_PID| u16::from_be(row.dwLocalPort as u16) == port)
+        .map(|row| row.dwOwningPid)
+}
+
+/// Match a LISTEN socket in /proc/net/tcp{,6} and find the process holding its inode.
+#[cfg(target_os = "linux")]
+fn listener_pid(port: u16) -> Option<u32> {
+    use std::fs;
+
+    const TCP_LISTEN: &str = "0A";
+    let inodes: Vec<String> = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .flat_map(|table| {
+            table
+                .lines()
+                .skip(1)
+                .filter_map(|line| {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    let local_port = fields.get(1)?.rsplit(':').next()?;
+                    let listening = fields.get(3) == Some(&TCP_LISTEN);
+                    (listening && u16::from_str_radix(local_port, 16).ok()? == port)
+                        .then(|| fields.get(9).map(|inode| format!("socket:[{}]", inode)))
+                        .flatten()
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    if inodes.is_empty() {
+        return None;
+    }
+
+    fs::read_dir("/proc").ok()?.filter_map(|e| e.ok()).find_map(|proc_dir| {
+        let pid: u32 = proc_dir.file_name().to_str()?.parse().ok()?;
+        let holds = fs::read_dir(proc_dir.path().join("fd"))
+            .ok()?
+            .filter_map(|fd| fd.ok())
+            .filter_map(|fd| fs::read_link(fd.path()).ok())
+            .any(|target| inodes.iter().any(|inode| target.as_os_str() == inode.as_str()));
+        holds.then_some(pid)
+    })
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn listener_pid(port: u16) -> Option<u32> {
+    let output = std::process::Command::new("lsof")
+        .args(["-nP", &format!("-iTCP:{}", port), "-sTCP:LISTEN", "-t"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout).lines().next()?.trim().parse().ok()
+}
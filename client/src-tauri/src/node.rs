@@ -0,0 +1,79 @@
+//! Locating a Node.js runtime for local-proxy.js: the copy bundled under
+//! resources/node first, then whatever `node` is on PATH.
+
+use crate::error::ProxyError;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// local-proxy.js is an ES module using `node:` imports.
+pub const MIN_MAJOR: u32 = 16;
+
+#[cfg(target_os = "windows")]
+const BUNDLED_NAME: &str = "node.exe";
+#[cfg(not(target_os = "windows"))]
+const BUNDLED_NAME: &str = "node";
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeRuntime {
+    pub path: PathBuf,
+    pub version: String,
+    pub bundled: bool,
+    /// Meets MIN_MAJOR.
+    pub supported: bool,
+}
+
+/// `resources/node/node(.exe)`.
+pub fn bundled_path(resources: &Path) -> PathBuf {
+    resources.join("node").join(BUNDLED_NAME)
+}
+
+/// The runtime the proxy would be started with: the bundled one if it
+/// runs, otherwise `node` from PATH. None when neither runs.
+pub fn detect(resources: &Path) -> Option<NodeRuntime> {
+    let bundled = bundled_path(resources);
+    probe(&bundled, true).or_else(|| probe(Path::new("node"), false))
+}
+
+/// Like `detect`, but an unsupported version is an error too.
+pub fn resolve(resources: &Path) -> Result<NodeRuntime, ProxyError> {
+    let bundled = bundled_path(resources);
+    match detect(resources) {
+        Some(node) if node.supported => Ok(node),
+        Some(node) => Err(ProxyError::NodeMissing {
+            message: format!(
+                "Node.js {} at {} is too old; version {} or newer is required",
+                node.version,
+                node.path.display(),
+                MIN_MAJOR
+            ),
+            path_tried: vec![node.path.display().to_string()],
+        }),
+        None => Err(ProxyError::NodeMissing {
+            message: format!(
+                "Node.js not found (looked for {} and `node` on PATH); install Node.js or reinstall the app",
+                bundled.display()
+            ),
+            path_tried: vec![bundled.display().to_string(), "node".into()],
+        }),
+    }
+}
+
+fn probe(program: &Path, bundled: bool) -> Option<NodeRuntime> {
+    if bundled && !program.is_file() {
+        return None;
+    }
+    let output = Command::new(program).arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let major = parse_major(&version)?;
+    Some(NodeRuntime { path: program.to_path_buf(), version, bundled, supported: major >= MIN_MAJOR })
+}
+
+/// "v20.11.1" -> 20
+fn parse_major(version: &str) -> Option<u32> {
+    version.strip_prefix('v').unwrap_or(version).split('.').next()?.parse().ok()
+}
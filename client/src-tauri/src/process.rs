@@ -0,0 +1,584 @@
+//! The proxy child process. The app spawns it through the shell plugin; the
+//! service, which has no app, spawns it directly into a process group of its
+//! own. Either way stopping it takes any workers node forked with it (its
+//! process tree on Unix, its job object on Windows); stopping asks nicely
+//! first, forces it after a timeout, and always reaps.
+
+use crate::logs::Stream;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::async_runtime::Receiver;
+use tauri_plugin_shell::process::{Command as ShellCommand, CommandChild, CommandEvent};
+
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long the shell plugin gets to report the exit of a killed child.
+const REAP_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long it gets to report one that is already gone, which only takes
+/// longer while something the child started holds its output open.
+const REPORT_GRACE: Duration = Duration::from_millis(250);
+
+/// How the child ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Exit {
+    pub code: Option<i32>,
+    /// The signal that killed it (Unix only).
+    pub signal: Option<i32>,
+}
+
+impl From<ExitStatus> for Exit {
+    fn from(status: ExitStatus) -> Self {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+        #[cfg(not(unix))]
+        let signal = None;
+        Exit { code: status.code(), signal }
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(f, "exit code {}", code),
+            (None, Some(signal)) => write!(f, "signal {}", signal),
+            (None, None) => write!(f, "unknown exit status"),
+        }
+    }
+}
+
+/// What the shell plugin reported: the exit, or why it couldn't wait for it.
+type SharedExit = Arc<Mutex<Option<Result<Exit, String>>>>;
+
+enum Handle {
+    /// Spawned by the service, into a process group of its own.
+    Std(Child),
+    /// Spawned by the app through the shell plugin, which waits on the
+    /// process itself (so it never lingers as a zombie) and reports the exit
+    /// as an event once both pipes are drained. `child` is `None` once
+    /// killed; `started` tells the process apart from a later one that
+    /// reuses its pid.
+    Shell { child: Option<CommandChild>, exit: SharedExit, started: Option<u64> },
+}
+
+pub struct ProxyChild {
+    pid: u32,
+    handle: Handle,
+    #[cfg(target_os = "windows")]
+    job: Option<job::Job>,
+}
+
+impl ProxyChild {
+    /// Spawn `cmd` directly, for the service.
+    pub fn spawn(cmd: &mut Command) -> std::io::Result<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            cmd.process_group(0);
+        }
+        // No console window for node: participants closing it kill the proxy.
+        // The job object and taskkill /T don't need a console, and the piped
+        // stdio is unaffected. Not DETACHED_PROCESS: without any console,
+        // node's own children would each open one.
+        #[cfg(target_os = "windows")]
+        {
+            use std::os::windows::process::CommandExt;
+            use windows_sys::Win32::System::Threading::CREATE_NO_WINDOW;
+            cmd.creation_flags(CREATE_NO_WINDOW);
+        }
+        let child = cmd.spawn()?;
+        Ok(ProxyChild::new(child.id(), Handle::Std(child)))
+    }
+
+    /// Spawn `command` through the shell plugin, which already hides the
+    /// console window on Windows. Each line the child prints goes to
+    /// `output`, without its line ending.
+    pub fn spawn_shell(
+        command: ShellCommand,
+        output: impl Fn(Stream, String) + Send + 'static,
+    ) -> Result<Self, tauri_plugin_shell::Error> {
+        let (events, child) = command.spawn()?;
+        let exit = SharedExit::default();
+        watch(events, exit.clone(), output);
+        let started = start_time(child.pid());
+        Ok(ProxyChild::new(child.pid(), Handle::Shell { child: Some(child), exit, started }))
+    }
+
+    fn new(pid: u32, handle: Handle) -> Self {
+        ProxyChild {
+            pid,
+            handle,
+            #[cfg(target_os = "windows")]
+            job: job::Job::assign(pid),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.pid
+    }
+
+    /// The exit, once the child is gone. A child that is gone while its
+    /// workers hold its output open exits with an unknown status: the
+    /// plugin reports it only after they let go.
+    pub fn try_wait(&mut self) -> std::io::Result<Option<Exit>> {
+        let (exit, started) = match &mut self.handle {
+            Handle::Std(child) => return Ok(child.try_wait()?.map(Exit::from)),
+            Handle::Shell { exit, started, .. } => (exit, *started),
+        };
+        if let Some(reported) = reported(exit) {
+            return reported.map(Some);
+        }
+        if started.is_none_or(|started| running(self.pid, started)) {
+            return Ok(None);
+        }
+        let deadline = Instant::now() + REPORT_GRACE;
+        while Instant::now() < deadline {
+            std::thread::sleep(REPORT_GRACE / 10);
+            if let Some(reported) = reported(exit) {
+                return reported.map(Some);
+            }
+        }
+        Ok(Some(Exit { code: None, signal: None }))
+    }
+
+    /// Wait for the child after it was killed, or its workers were.
+    fn wait(&mut self) -> Option<Exit> {
+        let exit = match &mut self.handle {
+            Handle::Std(child) => return child.wait().ok().map(Exit::from),
+            Handle::Shell { exit, .. } => exit,
+        };
+        let deadline = Instant::now() + REAP_TIMEOUT;
+        loop {
+            match reported(exit) {
+                Some(reported) => return reported.ok(),
+                None if Instant::now() < deadline => std::thread::sleep(POLL_INTERVAL),
+                None => return None,
+            }
+        }
+    }
+
+    /// Ask the child to exit: a shutdown message on stdin everywhere, plus
+    /// SIGTERM to the whole tree on Unix.
+    fn request_shutdown(&mut self, tree: &Tree) {
+        match &mut self.handle {
+            Handle::Std(child) => {
+                if let Some(mut stdin) = child.stdin.take() {
+                    let _ = stdin.write_all(crate::heartbeat::SHUTDOWN_LINE);
+                    let _ = stdin.flush();
+                }
+            }
+            Handle::Shell { child, .. } => {
+                if let Some(child) = child.as_mut() {
+                    let _ = child.write(crate::heartbeat::SHUTDOWN_LINE);
+                }
+            }
+        }
+        #[cfg(unix)]
+        {
+            self.signal_tree(tree, libc::SIGTERM);
+        }
+        #[cfg(not(unix))]
+        let _ = tree;
+    }
+
+    /// The processes to sweep once the child is gone; taken while it still
+    /// runs, since its workers are reparented when it exits.
+    fn tree(&self) -> Tree {
+        #[cfg(unix)]
+        if let Handle::Shell { .. } = self.handle {
+            return Tree::below(self.pid);
+        }
+        Tree::default()
+    }
+
+    /// Signal the child and everything below it. Spawned directly it leads
+    /// a process group; through the shell plugin it doesn't, so its tree is
+    /// signalled process by process.
+    #[cfg(unix)]
+    fn signal_tree(&self, tree: &Tree, signal: libc::c_int) {
+        match self.handle {
+            Handle::Std(_) => unsafe {
+                libc::killpg(self.pid as libc::pid_t, signal);
+            },
+            Handle::Shell { ref exit, .. } => {
+                tree.union(Tree::below(self.pid)).signal(signal);
+                // Not once the plugin reported it gone: its pid may be reused
+                if exit.lock().is_none() {
+                    unsafe {
+                        libc::kill(self.pid as libc::pid_t, signal);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Kill every process in the tree, falling back to the direct child.
+    fn kill_tree(&mut self, tree: &Tree) {
+        #[cfg(unix)]
+        self.signal_tree(tree, libc::SIGKILL);
+        #[cfg(target_os = "windows")]
+        {
+            let _ = tree;
+            let killed = self.job.as_ref().is_some_and(|j| j.terminate());
+            if !killed {
+                let _ = Command::new("taskkill").args(["/T", "/F", "/PID", &self.pid.to_string()]).output();
+            }
+        }
+        match &mut self.handle {
+            Handle::Std(child) => {
+                let _ = child.kill();
+            }
+            Handle::Shell { child, .. } => {
+                if let Some(child) = child.take() {
+                    let _ = child.kill();
+                }
+            }
+        }
+    }
+}
+
+/// Forward the child's output to `output` and note how it ended. The plugin
+/// reports the exit only after both pipes are drained, and closes the
+/// channel right after; an error instead means it couldn't wait.
+fn watch(mut events: Receiver<CommandEvent>, exit: SharedExit, output: impl Fn(Stream, String) + Send + 'static) {
+    std::thread::spawn(move || {
+        let mut error = None;
+        while let Some(event) = events.blocking_recv() {
+            match event {
+                CommandEvent::Stdout(bytes) => output(Stream::Stdout, line(&bytes)),
+                CommandEvent::Stderr(bytes) => output(Stream::Stderr, line(&bytes)),
+                CommandEvent::Terminated(payload) => {
+                    *exit.lock() = Some(Ok(Exit { code: payload.code, signal: payload.signal }));
+                }
+                CommandEvent::Error(e) => error = Some(e),
+                _ => {}
+            }
+        }
+        exit.lock().get_or_insert_with(|| Err(error.unwrap_or_else(|| "lost track of the proxy process".into())));
+    });
+}
+
+fn reported(exit: &SharedExit) -> Option<std::io::Result<Exit>> {
+    exit.lock().clone().map(|reported| reported.map_err(std::io::Error::other))
+}
+
+fn start_time(pid: u32) -> Option<u64> {
+    use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+    let pid = Pid::from_u32(pid);
+    let mut sys = System::new();
+    sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, ProcessRefreshKind::nothing());
+    sys.process(pid).map(|p| p.start_time())
+}
+
+/// `pid` is still the process that started at `started`, and not a zombie.
+fn running(pid: u32, started: u64) -> bool {
+    use sysinfo::{Pid, ProcessRefreshKind, ProcessStatus, ProcessesToUpdate, System};
+
+    let pid = Pid::from_u32(pid);
+    let mut sys = System::new();
+    sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, ProcessRefreshKind::nothing());
+    sys.process(pid).is_some_and(|p| p.start_time() == started && p.status() != ProcessStatus::Zombie)
+}
+
+/// The plugin splits output at `\n` or `\r` and keeps the terminator.
+fn line(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end_matches(['\r', '\n']).to_string()
+}
+
+/// Processes below the child, each with its start time so a pid reused in
+/// the meantime isn't taken for one of them.
+#[derive(Default)]
+#[cfg_attr(not(unix), allow(dead_code))]
+struct Tree(Vec<(u32, u64)>);
+
+#[cfg(unix)]
+impl Tree {
+    fn below(pid: u32) -> Tree {
+        use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+        let mut sys = System::new();
+        sys.refresh_processes_specifics(ProcessesToUpdate::All, true, ProcessRefreshKind::nothing());
+        let mut members = Vec::new();
+        let mut parents = vec![Pid::from_u32(pid)];
+        while let Some(parent) = parents.pop() {
+            for (pid, process) in sys.processes() {
+                if process.parent() == Some(parent) && process.thread_kind().is_none() {
+                    members.push((pid.as_u32(), process.start_time()));
+                    parents.push(*pid);
+                }
+            }
+        }
+        Tree(members)
+    }
+
+    fn union(&self, other: Tree) -> Tree {
+        let mut members = self.0.clone();
+        members.extend(other.0.into_iter().filter(|m| !self.0.contains(m)));
+        Tree(members)
+    }
+
+    /// Signal the members that are still the processes they were.
+    fn signal(&self, signal: libc::c_int) {
+        use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+        let pids: Vec<Pid> = self.0.iter().map(|&(pid, _)| Pid::from_u32(pid)).collect();
+        let mut sys = System::new();
+        sys.refresh_processes_specifics(ProcessesToUpdate::Some(&pids), true, ProcessRefreshKind::nothing());
+        for &(pid, started) in &self.0 {
+            if sys.process(Pid::from_u32(pid)).is_some_and(|p| p.start_time() == started) {
+                unsafe {
+                    libc::kill(pid as libc::pid_t, signal);
+                }
+            }
+        }
+    }
+}
+
+pub struct ShutdownOutcome {
+    /// The child exited on its own after the shutdown request.
+    pub graceful: bool,
+    pub status: Option<Exit>,
+}
+
+/// Request a graceful exit, wait up to `timeout`, then kill the whole tree.
+pub fn shutdown(proxy: &mut ProxyChild, timeout: Duration) -> ShutdownOutcome {
+    let tree = proxy.tree();
+    if let Ok(Some(status)) = proxy.try_wait() {
+        proxy.kill_tree(&tree);
+        return ShutdownOutcome { graceful: true, status: proxy.wait().or(Some(status)) };
+    }
+
+    proxy.request_shutdown(&tree);
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        match proxy.try_wait() {
+            Ok(Some(status)) => {
+                // Sweep workers that outlived the main process; with them
+                // gone, the plugin can report its status
+                proxy.kill_tree(&tree);
+                return ShutdownOutcome { graceful: true, status: proxy.wait().or(Some(status)) };
+            }
+            Ok(None) => std::thread::sleep(POLL_INTERVAL),
+            Err(_) => break,
+        }
+    }
+
+    // Always reap after killing, otherwise the child lingers as a zombie on
+    // Unix until the app itself exits.
+    proxy.kill_tree(&tree);
+    ShutdownOutcome { graceful: false, status: proxy.wait() }
+}
+
+/// A running proxy we didn't spawn in this session, e.g. left over after
+/// the app crashed.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanProcess {
+    pub pid: u32,
+    pub command_line: String,
+}
+
+/// Processes whose command line references `script`, other than `exclude`.
+pub fn find_orphans(script: &Path, exclude: Option<u32>) -> Vec<OrphanProcess> {
+    use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
+
+    let needle = normalize(&script.to_string_lossy());
+    let mut sys = System::new();
+    sys.refresh_processes_specifics(
+        ProcessesToUpdate::All,
+        true,
+        ProcessRefreshKind::nothing().with_cmd(UpdateKind::Always),
+    );
+    let mut orphans: Vec<OrphanProcess> = sys
+        .processes()
+        .iter()
+        .filter(|(pid, _)| Some(pid.as_u32()) != exclude && pid.as_u32() != std::process::id())
+        .filter_map(|(pid, proc)| {
+            let command_line = proc
+                .cmd()
+                .iter()
+                .map(|a| a.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ");
+            normalize(&command_line)
+                .contains(&needle)
+                .then(|| OrphanProcess { pid: pid.as_u32(), command_line })
+        })
+        .collect();
+    orphans.sort_by_key(|o| o.pid);
+    orphans
+}
+
+/// Windows paths compare case-insensitively and with either separator.
+fn normalize(s: &str) -> String {
+    if cfg!(target_os = "windows") {
+        s.to_lowercase().replace('/', "\\")
+    } else {
+        s.to_string()
+    }
+}
+
+/// Kill a process we don't hold a handle for.
+pub fn terminate_pid(pid: u32) -> bool {
+    use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+    let pid = Pid::from_u32(pid);
+    let mut sys = System::new();
+    sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, ProcessRefreshKind::nothing());
+    sys.process(pid).is_some_and(|p| p.kill())
+}
+
+/// Executable name of `pid`, if it is still running.
+pub fn process_name(pid: u32) -> Option<String> {
+    use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+    let pid = Pid::from_u32(pid);
+    let mut sys = System::new();
+    sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, ProcessRefreshKind::nothing());
+    sys.process(pid).map(|p| p.name().to_string_lossy().into_owned())
+}
+
+/// Executable names of every running process, each once.
+pub fn running_names() -> std::collections::BTreeSet<String> {
+    use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System};
+
+    let mut sys = System::new();
+    sys.refresh_processes_specifics(ProcessesToUpdate::All, true, ProcessRefreshKind::nothing());
+    sys.processes().values().map(|p| p.name().to_string_lossy().into_owned()).collect()
+}
+
+#[cfg(target_os = "windows")]
+mod job {
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW, TerminateJobObject};
+    use windows_sys::Win32::System::Threading::{OpenProcess, PROCESS_SET_QUOTA, PROCESS_TERMINATE};
+
+    /// Job object handle, kept as an integer so ProxyChild stays Send.
+    pub struct Job(isize);
+
+    impl Job {
+        /// By pid: the shell plugin doesn't hand out the process handle.
+        pub fn assign(pid: u32) -> Option<Self> {
+            unsafe {
+                let process = OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, 0, pid);
+                if process.is_null() {
+                    return None;
+                }
+                let handle = CreateJobObjectW(std::ptr::null(), std::ptr::null());
+                let assigned = !handle.is_null() && AssignProcessToJobObject(handle, process) != 0;
+                CloseHandle(process);
+                if !assigned {
+                    if !handle.is_null() {
+                        CloseHandle(handle);
+                    }
+                    return None;
+                }
+                Some(Job(handle as isize))
+            }
+        }
+
+        pub fn terminate(&self) -> bool {
+            unsafe { TerminateJobObject(self.0 as _, 1) != 0 }
+        }
+    }
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            unsafe {
+                CloseHandle(self.0 as _);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tauri_plugin_shell::ShellExt;
+
+    /// A command built the way the app builds the proxy's.
+    pub(crate) fn shell(program: &str, args: &[&str]) -> ShellCommand {
+        let app = tauri::test::mock_builder()
+            .plugin(tauri_plugin_shell::init())
+            .build(tauri::test::mock_context(tauri::test::noop_assets()))
+            .unwrap();
+        app.shell().command(program).args(args)
+    }
+
+    /// Gone from the process table: not running, and not a zombie either.
+    #[cfg(unix)]
+    fn reaped(pid: u32) -> bool {
+        let deadline = Instant::now() + REAP_TIMEOUT;
+        loop {
+            let gone = unsafe { libc::kill(pid as libc::pid_t, 0) } == -1
+                && std::io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH);
+            if gone || Instant::now() >= deadline {
+                return gone;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shutdown_reaps_a_child_that_obeys_sigterm() {
+        let mut child = ProxyChild::spawn_shell(shell("sleep", &["30"]), |_, _| {}).unwrap();
+        let pid = child.id();
+        let outcome = shutdown(&mut child, Duration::from_secs(5));
+        assert!(outcome.graceful);
+        assert_eq!(outcome.status, Some(Exit { code: None, signal: Some(libc::SIGTERM) }));
+        assert!(reaped(pid), "pid {} left behind", pid);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shutdown_kills_and_reaps_a_child_that_ignores_it() {
+        let script = "trap '' TERM; while :; do sleep 1; done";
+        let mut child = ProxyChild::spawn_shell(shell("sh", &["-c", script]), |_, _| {}).unwrap();
+        let pid = child.id();
+        std::thread::sleep(Duration::from_millis(200));
+        let outcome = shutdown(&mut child, Duration::from_millis(300));
+        assert!(!outcome.graceful);
+        assert_eq!(outcome.status, Some(Exit { code: None, signal: Some(libc::SIGKILL) }));
+        assert!(reaped(pid), "pid {} left behind", pid);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shutdown_sweeps_workers_after_a_graceful_exit() {
+        // Prints its worker's pid, then exits on the shutdown line
+        let script = "trap '' TERM; sleep 30 & echo $!; read line; exit 0";
+        let (tx, rx) = std::sync::mpsc::channel();
+        let output = move |stream, line: String| {
+            if stream == Stream::Stdout {
+                let _ = tx.send(line);
+            }
+        };
+        let mut child = ProxyChild::spawn_shell(shell("sh", &["-c", script]), output).unwrap();
+        let worker: u32 = rx.recv_timeout(Duration::from_secs(5)).unwrap().parse().unwrap();
+        let outcome = shutdown(&mut child, Duration::from_secs(5));
+        assert!(outcome.graceful);
+        assert_eq!(outcome.status, Some(Exit { code: Some(0), signal: None }));
+        assert!(reaped(child.id()));
+        assert!(reaped(worker), "worker {} outlived the proxy", worker);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn service_child_is_reaped_with_its_status() {
+        let mut command = Command::new("sleep");
+        command.arg("30").stdin(std::process::Stdio::piped());
+        let mut child = ProxyChild::spawn(&mut command).unwrap();
+        let pid = child.id();
+        let outcome = shutdown(&mut child, Duration::from_secs(5));
+        assert!(outcome.graceful);
+        assert_eq!(outcome.status, Some(Exit { code: None, signal: Some(libc::SIGTERM) }));
+        assert!(reaped(pid), "pid {} left behind", pid);
+    }
+}
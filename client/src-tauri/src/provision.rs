@@ -0,0 +1,189 @@
+//! One install's whole setup as a single file, so a fleet of drill machines
+//! can be provisioned from a reference one: the config with its profiles and
+//! pinned gateway certificate, the signed gateway allowlist, and the CA.
+//! The CA goes in only with a passphrase, its key encrypted with it.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand_core::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::Path;
+
+const FORMAT: &str = "cyber-drill-safe-state";
+/// Of the bundle layout, not of the config inside it.
+pub const BUNDLE_VERSION: u32 = 1;
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+const KDF: &str = "pbkdf2-hmac-sha256";
+const KDF_ITERATIONS: u32 = 600_000;
+/// Binds the ciphertext to its purpose.
+const KEY_AAD: &[u8] = b"cyber-drill-safe ca.key";
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bundle {
+    pub format: String,
+    pub bundle_version: u32,
+    /// Unix seconds.
+    pub exported_at: u64,
+    pub app_version: String,
+    pub hostname: Option<String>,
+    /// As stored, so its schemaVersion is checked before anything is read.
+    pub config: serde_json::Value,
+    pub allowlist: Option<SignedAllowlist>,
+    pub ca: Option<Ca>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedAllowlist {
+    pub version: u64,
+    /// Base64 of the exact bytes that were signed.
+    pub list: String,
+    /// Base64 ed25519 signature.
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Ca {
+    pub cert_pem: String,
+    pub key: EncryptedKey,
+}
+
+/// AES-256-GCM with a key derived from the passphrase.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncryptedKey {
+    pub kdf: String,
+    pub iterations: u32,
+    pub salt: String,
+    pub nonce: String,
+    /// Base64, tag appended.
+    pub ciphertext: String,
+}
+
+/// Something left out of an export or import, and why.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Skipped {
+    pub item: &'static str,
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportResult {
+    pub path: String,
+    pub included: Vec<&'static str>,
+    pub skipped: Vec<Skipped>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportResult {
+    pub imported: Vec<&'static str>,
+    pub skipped: Vec<Skipped>,
+    /// The imported CA isn't in the OS trust store yet; cert_install adds it.
+    pub cert_install_needed: bool,
+}
+
+impl Bundle {
+    pub fn new(config: serde_json::Value) -> Self {
+        Bundle {
+            format: FORMAT.to_string(),
+            bundle_version: BUNDLE_VERSION,
+            exported_at: crate::now_secs(),
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            hostname: sysinfo::System::host_name(),
+            config,
+            allowlist: None,
+            ca: None,
+        }
+    }
+}
+
+impl SignedAllowlist {
+    pub fn new(version: u64, bytes: &[u8], signature: String) -> Self {
+        SignedAllowlist { version, list: STANDARD.encode(bytes), signature }
+    }
+
+    pub fn bytes(&self) -> Result<Vec<u8>, String> {
+        STANDARD.decode(&self.list).map_err(|e| format!("The allowlist in the bundle is not base64: {}", e))
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey, String> {
+    let iterations = NonZeroU32::new(iterations).ok_or("The bundle's key derivation has no iterations")?;
+    let mut key = [0u8; 32];
+    ring::pbkdf2::derive(ring::pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| "Deriving the key failed")?;
+    Ok(LessSafeKey::new(key))
+}
+
+pub fn encrypt_key(pem: &str, passphrase: &str) -> Result<EncryptedKey, String> {
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    rand_core::OsRng.fill_bytes(&mut salt);
+    rand_core::OsRng.fill_bytes(&mut nonce);
+    let key = derive_key(passphrase, &salt, KDF_ITERATIONS)?;
+    let mut data = pem.as_bytes().to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(KEY_AAD), &mut data)
+        .map_err(|_| "Encrypting the CA key failed")?;
+    Ok(EncryptedKey {
+        kdf: KDF.to_string(),
+        iterations: KDF_ITERATIONS,
+        salt: STANDARD.encode(salt),
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(data),
+    })
+}
+
+pub fn decrypt_key(encrypted: &EncryptedKey, passphrase: &str) -> Result<String, String> {
+    if encrypted.kdf != KDF {
+        return Err(format!("The CA key uses an unknown key derivation {:?}", encrypted.kdf));
+    }
+    let decode = |b64: &str| STANDARD.decode(b64).map_err(|e| format!("The encrypted CA key is not base64: {}", e));
+    let salt = decode(&encrypted.salt)?;
+    let nonce = Nonce::try_assume_unique_for_key(&decode(&encrypted.nonce)?)
+        .map_err(|_| "The encrypted CA key has an invalid nonce")?;
+    let mut data = decode(&encrypted.ciphertext)?;
+    let key = derive_key(passphrase, &salt, encrypted.iterations)?;
+    let pem = key
+        .open_in_place(nonce, Aad::from(KEY_AAD), &mut data)
+        .map_err(|_| "The passphrase is wrong or the CA key was modified")?;
+    String::from_utf8(pem.to_vec()).map_err(|_| "The decrypted CA key is not text".to_string())
+}
+
+pub fn write(path: &Path, bundle: &Bundle) -> Result<(), String> {
+    let json = serde_json::to_vec_pretty(bundle).map_err(|e| e.to_string())?;
+    fs::write(path, json).map_err(|e| format!("Writing {} failed: {}", path.display(), e))?;
+    // It may hold the encrypted CA key; keep it to ourselves anyway
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(path, fs::Permissions::from_mode(0o600));
+    }
+    Ok(())
+}
+
+/// The bundle in `path`, if it is one this build understands.
+pub fn read(path: &Path) -> Result<Bundle, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Reading {} failed: {}", path.display(), e))?;
+    let bundle: Bundle =
+        serde_json::from_slice(&bytes).map_err(|e| format!("{} is not a state bundle: {}", path.display(), e))?;
+    if bundle.format != FORMAT {
+        return Err(format!("{} is not a state bundle (format {:?})", path.display(), bundle.format));
+    }
+    if bundle.bundle_version > BUNDLE_VERSION {
+        return Err(format!(
+            "{} was exported by a newer version of the app (bundle {}, this one understands {})",
+            path.display(),
+            bundle.bundle_version,
+            BUNDLE_VERSION
+        ));
+    }
+    Ok(bundle)
+}
@@ -0,0 +1,821 @@
+//! The built-in interception proxy, replacing node + local-proxy.js. It runs
+//! as a mode of our own executable, `<exe> --local-proxy` with the arguments
+//! local-proxy.js takes, so it is started, stopped and watched exactly like
+//! the node child and speaks the same heartbeat protocol on stdout.
+
+use crate::cert;
+use crate::heartbeat::DomainCounters;
+use crate::traffic::RequestSummary;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, HOST};
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use parking_lot::Mutex;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use url::Url;
+
+/// First argument that makes the executable the proxy.
+pub const HELPER_ARG: &str = "--local-proxy";
+/// Set on every response, and on requests to the gateway.
+const PROXY_HEADER: &str = "x-intercepted-by";
+const PROXY_HEADER_VALUE: &str = "cyber-drill-local-proxy";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long open connections get to finish after a shutdown request.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const ORIGINAL_PORT: u16 = 443;
+
+type ProxyBody = UnsyncBoxBody<Bytes, hyper::Error>;
+
+/// The command line, as local-proxy.js understands it.
+struct Options {
+    /// None: passthrough to the real servers.
+    gateway: Option<Url>,
+    /// Domains forwarded to a gateway of their own instead (--routes).
+    routes: HashMap<String, Url>,
+    /// Paths sent to the real origin whatever the gateway (--bypass).
+    bypass: Vec<String>,
+    /// Real addresses the client resolved for us (--origin-ip domain=ip,...).
+    origin_ips: HashMap<String, Ipv4Addr>,
+    port: u16,
+    /// Lowercase; the first is where unknown Host headers go.
+    domains: Vec<String>,
+    ipv6: bool,
+    heartbeat: bool,
+    /// Answered here with our health, never forwarded (--health-route).
+    health_route: String,
+    cert_dir: PathBuf,
+    /// Empty connects directly.
+    upstream_proxy: String,
+    /// What the gateway's certificate must match (--gateway-ca, --gateway-pin).
+    gateway_pin: crate::gateway::Pin,
+    /// Answer from a recorded session instead of forwarding (--replay).
+    replay: Option<crate::replay::Responses>,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            gateway: None,
+            routes: HashMap::new(),
+            bypass: Vec::new(),
+            origin_ips: HashMap::new(),
+            port: ORIGINAL_PORT,
+            domains: crate::default_domains(),
+            ipv6: false,
+            heartbeat: false,
+            health_route: crate::healthcheck::DEFAULT_ROUTE.to_string(),
+            cert_dir: PathBuf::new(),
+            upstream_proxy: String::new(),
+            gateway_pin: crate::gateway::Pin::default(),
+            replay: None,
+        };
+        let (mut gateway_ca, mut gateway_pin, mut primary) = (None, None, None);
+        let mut listed = false;
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            // Like local-proxy.js, a flag without a value is ignored
+            let mut value = || args.next_if(|v| !v.starts_with("--")).filter(|v| !v.is_empty());
+            match arg.as_str() {
+                "--gateway" => {
+                    if let Some(raw) = value() {
+                        options.gateway = Some(parse_gateway(&raw)?);
+                    }
+                }
+                "--routes" => {
+                    if let Some(raw) = value() {
+                        let routes: Vec<crate::config::Route> =
+                            serde_json::from_str(&raw).map_err(|e| format!("Invalid --routes: {}", e))?;
+                        for route in routes {
+                            let gateway = parse_gateway(&route.gateway_url)?;
+                            options.routes.insert(route.domain.trim().to_ascii_lowercase(), gateway);
+                        }
+                    }
+                }
+                "--port" => {
+                    if let Some(port) = value().and_then(|v| v.parse().ok()) {
+                        options.port = port;
+                    }
+                }
+                "--domains" => {
+                    let mut domains: Vec<String> = Vec::new();
+                    for d in value().unwrap_or_default().split(',') {
+                        let d = d.trim().to_ascii_lowercase();
+                        if !d.is_empty() && !domains.contains(&d) {
+                            domains.push(d);
+                        }
+                    }
+                    if !domains.is_empty() {
+                        options.domains = domains;
+                        listed = true;
+                    }
+                }
+                "--domain" => primary = value().map(|d| d.trim().to_ascii_lowercase()),
+                "--bypass" => {
+                    let paths: Vec<String> = value().unwrap_or_default().split(',').map(str::to_string).collect();
+                    options.bypass = crate::config::normalize_bypass_paths(&paths).map_err(|e| e.to_string())?;
+                }
+                "--origin-ip" => {
+                    for pair in value().unwrap_or_default().split(',').filter(|p| !p.trim().is_empty()) {
+                        let parsed = pair.split_once('=').and_then(|(d, ip)| Some((d, ip.trim().parse().ok()?)));
+                        let (domain, ip) = parsed.ok_or_else(|| format!("Invalid --origin-ip {:?}", pair))?;
+                        options.origin_ips.insert(domain.trim().to_ascii_lowercase(), ip);
+                    }
+                }
+                "--cert-dir" => options.cert_dir = value().map(PathBuf::from).unwrap_or_default(),
+                "--upstream-proxy" => options.upstream_proxy = value().unwrap_or_default(),
+                "--gateway-ca" => {
+                    if let Some(path) = value() {
+                        let pem = std::fs::read_to_string(&path);
+                        gateway_ca = Some(pem.map_err(|e| format!("Reading {} failed: {}", path, e))?);
+                    }
+                }
+                "--gateway-pin" => gateway_pin = value(),
+                "--replay" => {
+                    if let Some(path) = value() {
+                        options.replay = Some(crate::replay::Responses::load(std::path::Path::new(&path))?);
+                    }
+                }
+                "--ipv6" => options.ipv6 = true,
+                "--heartbeat" => options.heartbeat = true,
+                "--health-route" => {
+                    if let Some(route) = value() {
+                        options.health_route = crate::healthcheck::normalize_route(&route)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if options.cert_dir.as_os_str().is_empty() {
+            return Err("--cert-dir is required".into());
+        }
+        // --domain is the default target, whatever order --domains has
+        if let Some(primary) = primary.filter(|d| !d.is_empty()) {
+            if !listed {
+                options.domains.clear();
+            }
+            options.domains.retain(|d| *d != primary);
+            options.domains.insert(0, primary);
+        }
+        // Routed domains are intercepted whatever --domains says
+        let mut routed: Vec<&String> = options.routes.keys().collect();
+        routed.sort();
+        for domain in routed {
+            if !options.domains.contains(domain) {
+                options.domains.push(domain.clone());
+            }
+        }
+        options.gateway_pin = crate::gateway::Pin::new(gateway_ca.as_deref(), gateway_pin.as_deref())?;
+        Ok(options)
+    }
+
+    /// Where requests for `domain` go; None for passthrough.
+    fn gateway(&self, domain: &str) -> Option<&Url> {
+        self.routes.get(domain).or(self.gateway.as_ref())
+    }
+
+    /// Where a request for `path` on `domain` goes; None for the real origin.
+    fn destination(&self, domain: &str, path: &str) -> Option<&Url> {
+        if crate::config::bypassed(&self.bypass, path) {
+            return None;
+        }
+        self.gateway(domain)
+    }
+
+    /// The domain a request is for: its Host header if we intercept that
+    /// name, otherwise the first domain.
+    fn target_domain(&self, req: &Request<Incoming>) -> String {
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri().host())
+            .unwrap_or_default();
+        let host = host.rsplit_once(':').filter(|(_, port)| port.parse::<u16>().is_ok()).map_or(host, |(h, _)| h);
+        let host = host.to_ascii_lowercase();
+        if self.domains.contains(&host) {
+            host
+        } else {
+            self.domains[0].clone()
+        }
+    }
+}
+
+fn parse_gateway(raw: &str) -> Result<Url, String> {
+    let url = Url::parse(raw).map_err(|e| format!("Invalid gateway {:?}: {}", raw, e))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return Err(format!("Invalid gateway {:?}: expected an http(s) URL", raw));
+    }
+    Ok(url)
+}
+
+/// Run the proxy until a shutdown line arrives on stdin (or SIGTERM).
+/// `args` are the ones after HELPER_ARG.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let options = Options::parse(args)?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format!("Failed to start the runtime: {}", e))?;
+    let result = runtime.block_on(serve(options));
+    // The stdin reader blocks in a thread of its own; don't wait for it
+    runtime.shutdown_background();
+    result
+}
+
+/// What the connection tasks share.
+struct Shared {
+    options: Options,
+    /// Each passthrough domain's real address, from public DNS.
+    resolved: HashMap<String, Ipv4Addr>,
+    /// For the real servers.
+    connector: tokio_native_tls::TlsConnector,
+    /// For gateways; enforces the pin.
+    gateway_connector: tokio_native_tls::TlsConnector,
+    stats: Stats,
+    started: Instant,
+}
+
+/// Cumulative since start, reported by the heartbeat.
+#[derive(Default)]
+struct Stats {
+    connections: AtomicU64,
+    requests: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    domains: Mutex<BTreeMap<String, DomainCounters>>,
+    /// How the last forwarded request went, for the health route.
+    upstream: Mutex<crate::healthcheck::Upstream>,
+}
+
+impl Stats {
+    fn count_domain(&self, domain: &str, now_ms: u64) {
+        let mut domains = self.domains.lock();
+        let counters = domains.entry(domain.to_string()).or_default();
+        counters.requests += 1;
+        counters.last_request_at = Some(now_ms);
+    }
+
+    /// `target` is where the request went, as forward reports it.
+    fn note_upstream(&self, target: &str, now_ms: u64, error: Option<&str>) {
+        *self.upstream.lock() = crate::healthcheck::Upstream {
+            mode: match target {
+                "gateway" | "replay" => target,
+                _ => "passthrough",
+            }
+            .to_string(),
+            last_at: Some(now_ms),
+            ok: Some(error.is_none()),
+            error: error.map(str::to_string),
+        };
+    }
+
+    fn heartbeat(&self) -> serde_json::Value {
+        json!({
+            "type": "heartbeat",
+            "connections": self.connections.load(Ordering::Relaxed),
+            "requests": self.requests.load(Ordering::Relaxed),
+            "bytesIn": self.bytes_in.load(Ordering::Relaxed),
+            "bytesOut": self.bytes_out.load(Ordering::Relaxed),
+            "domains": *self.domains.lock(),
+        })
+    }
+}
+
+async fn serve(options: Options) -> Result<(), String> {
+    let leaves = LeafCerts {
+        authority: cert::Authority::load(&options.cert_dir)?,
+        domains: options.domains.clone(),
+        issued: Mutex::new(HashMap::new()),
+    };
+    // Issue up front so a broken CA fails the start, not the first request
+    for domain in &options.domains {
+        leaves.get(domain)?;
+    }
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(leaves));
+    tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(tls));
+
+    let mut resolved = options.origin_ips.clone();
+    match (&options.replay, &options.gateway) {
+        (Some(replay), _) => println!("[proxy] mode: replaying {} recorded paths", replay.paths()),
+        (None, Some(gateway)) => println!("[proxy] mode: forwarding to gateway {}", gateway),
+        (None, None) => println!("[proxy] mode: passthrough to the official backend"),
+    }
+    for (domain, gateway) in &options.routes {
+        println!("[proxy] route: {} -> {}", domain, gateway);
+    }
+    if !options.bypass.is_empty() {
+        println!("[proxy] bypass to the real origin: {}", options.bypass.join(", "));
+    }
+    // Replay never reaches the real servers, so there is nothing to resolve
+    let passthrough: Vec<&String> = match options.replay {
+        Some(_) => Vec::new(),
+        None => options.domains.iter().filter(|d| options.gateway(d).is_none()).collect(),
+    };
+    let unresolved: Vec<&String> = passthrough.iter().copied().filter(|d| !resolved.contains_key(*d)).collect();
+    for domain in unresolved {
+        match crate::dns::resolve_public(domain) {
+            Ok(addr) => {
+                println!("[proxy] {} resolved to {}", domain, addr);
+                resolved.insert(domain.to_string(), addr);
+            }
+            Err(e) => eprintln!("[proxy] {}", e),
+        }
+    }
+    if !passthrough.is_empty() && resolved.is_empty() {
+        return Err("none of the intercepted domains could be resolved".into());
+    }
+    // The pin only applies to gateways; passthrough verifies as usual
+    let connector = native_tls::TlsConnector::new().map_err(|e| format!("TLS client setup failed: {}", e))?;
+    let gateway_connector = options.gateway_pin.connector()?;
+    if (options.gateway.is_some() || !options.routes.is_empty()) && options.gateway_pin.is_set() {
+        println!("[proxy] gateway certificate pinned");
+    }
+
+    let port = options.port;
+    let mut listeners = vec![TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .await
+        .map_err(|e| format!("Listen on 127.0.0.1:{} failed: {}", port, e))?];
+    println!("[proxy] listening on https://127.0.0.1:{}", port);
+    if options.ipv6 {
+        // Without IPv6 on the system only warn; IPv4 works as usual
+        match TcpListener::bind((Ipv6Addr::LOCALHOST, port)).await {
+            Ok(listener) => {
+                println!("[proxy] listening on https://[::1]:{}", port);
+                listeners.push(listener);
+            }
+            Err(e) => eprintln!("[proxy] IPv6 listen failed on [::1]:{}: {}", port, e),
+        }
+    }
+    println!("[proxy] intercepting: {}", options.domains.join(", "));
+
+    let shared = Arc::new(Shared {
+        options,
+        resolved,
+        connector: connector.into(),
+        gateway_connector: gateway_connector.into(),
+        stats: Stats::default(),
+        started: Instant::now(),
+    });
+    if shared.options.heartbeat {
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                println!("{}", shared.stats.heartbeat());
+            }
+        });
+    }
+    let (stop_tx, stop_rx) = watch::channel(false);
+    for listener in listeners {
+        tokio::spawn(accept(listener, acceptor.clone(), shared.clone(), stop_rx.clone()));
+    }
+
+    let reason = shutdown_requested().await;
+    println!("[proxy] shutting down ({})", reason);
+    let _ = stop_tx.send(true);
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+    while shared.stats.connections.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+/// A shutdown line on stdin (JSON, or the plain "shutdown" of older
+/// clients) or SIGTERM. EOF on stdin is not a request.
+async fn shutdown_requested() -> &'static str {
+    let stdin = async {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let line = line.trim();
+            let is_json_shutdown = || {
+                serde_json::from_str::<serde_json::Value>(line).is_ok_and(|m| m["type"] == "shutdown")
+            };
+            if line == "shutdown" || is_json_shutdown() {
+                return;
+            }
+        }
+        std::future::pending::<()>().await
+    };
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            return tokio::select! {
+                _ = stdin => "stdin",
+                _ = term.recv() => "SIGTERM",
+            };
+        }
+    }
+    stdin.await;
+    "stdin"
+}
+
+async fn accept(listener: TcpListener, acceptor: TlsAcceptor, shared: Arc<Shared>, mut stop: watch::Receiver<bool>) {
+    loop {
+        let tcp = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((tcp, _)) => tcp,
+                Err(e) => {
+                    eprintln!("[proxy] accept failed: {}", e);
+                    continue;
+                }
+            },
+            _ = stop.changed() => return,
+        };
+        tokio::spawn(connection(tcp, acceptor.clone(), shared.clone(), stop.clone()));
+    }
+}
+
+async fn connection(tcp: TcpStream, acceptor: TlsAcceptor, shared: Arc<Shared>, mut stop: watch::Receiver<bool>) {
+    let Ok(tls) = acceptor.accept(Counted { inner: tcp, shared: shared.clone() }).await else {
+        return;
+    };
+    let _open = OpenConnection::new(shared.clone());
+    let service = hyper::service::service_fn(move |req| handle(req, shared.clone()));
+    let conn = hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(tls), service);
+    tokio::pin!(conn);
+    tokio::select! {
+        _ = conn.as_mut() => {}
+        _ = stop.changed() => {
+            conn.as_mut().graceful_shutdown();
+            let _ = conn.await;
+        }
+    }
+}
+
+/// Counts an established client connection while it lives.
+struct OpenConnection(Arc<Shared>);
+
+impl OpenConnection {
+    fn new(shared: Arc<Shared>) -> Self {
+        shared.stats.connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(shared)
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.stats.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn handle(req: Request<Incoming>, shared: Arc<Shared>) -> Result<Response<ProxyBody>, Infallible> {
+    if req.uri().path() == shared.options.health_route {
+        return Ok(health_response(&shared));
+    }
+    let started = Instant::now();
+    let ts = crate::now_millis();
+    let domain = shared.options.target_domain(&req);
+    shared.stats.count_domain(&domain, ts);
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str()).to_string();
+    let method = req.method().to_string();
+
+    let bytes_in = Arc::new(AtomicU64::new(0));
+    let counter = bytes_in.clone();
+    let req = req.map(|body| {
+        body.map_frame(move |frame| {
+            if let Some(data) = frame.data_ref() {
+                counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            frame
+        })
+        .boxed_unsync()
+    });
+    let forwarded = match &shared.options.replay {
+        Some(replay) => Ok(replayed(replay, &method, &domain, &path)),
+        None => forward(req, &domain, &shared).await.map(|(r, target)| (r.map(BodyExt::boxed_unsync), target)),
+    };
+    let (mut response, target) = match forwarded {
+        Ok((response, target)) => {
+            shared.stats.note_upstream(&target, ts, None);
+            (response, target)
+        }
+        Err((status, error, target)) => {
+            shared.stats.note_upstream(&target, ts, Some(&error));
+            eprintln!("[proxy] {} error: {}", target, error);
+            let message = if target == "gateway" { "gateway unreachable" } else { "upstream unreachable" };
+            (error_response(status, json!({ "error": message, "detail": error })), target)
+        }
+    };
+    response.headers_mut().insert(PROXY_HEADER, HeaderValue::from_static(PROXY_HEADER_VALUE));
+
+    let mut report = Report {
+        shared: shared.clone(),
+        summary: RequestSummary {
+            ts,
+            method,
+            host: domain,
+            path,
+            status: response.status().as_u16(),
+            bytes_in: 0,
+            bytes_out: 0,
+            duration_ms: 0,
+        },
+        target,
+        bytes_in,
+        started,
+    };
+    // The report goes out when hyper drops the body: sent, or abandoned
+    Ok(response.map(|body| {
+        body.map_frame(move |frame| {
+            if let Some(data) = frame.data_ref() {
+                report.sent(data.len());
+            }
+            frame
+        })
+        .boxed_unsync()
+    }))
+}
+
+/// The recorded status with an empty body, or a 404 for a request the
+/// session doesn't have.
+fn replayed(
+    replay: &crate::replay::Responses,
+    method: &str,
+    domain: &str,
+    path: &str,
+) -> (Response<ProxyBody>, String) {
+    let status = replay.status(method, domain, path).and_then(|s| StatusCode::from_u16(s).ok());
+    let response = match status {
+        Some(status) => {
+            let mut response = Response::new(Full::new(Bytes::new()).map_err(|e| match e {}).boxed_unsync());
+            *response.status_mut() = status;
+            response.headers_mut().insert("x-replayed", HeaderValue::from_static("1"));
+            response
+        }
+        None => error_response(StatusCode::NOT_FOUND, json!({ "error": "not in the replay bundle", "path": path })),
+    };
+    (response, "replay".to_string())
+}
+
+/// The body healthcheck::check expects, with the app's version.
+fn health_response(shared: &Shared) -> Response<ProxyBody> {
+    let mode = if shared.options.replay.is_some() {
+        "replay"
+    } else if shared.options.gateway.is_some() || !shared.options.routes.is_empty() {
+        "gateway"
+    } else {
+        "passthrough"
+    };
+    let mut upstream = shared.stats.upstream.lock().clone();
+    if upstream.mode.is_empty() {
+        upstream.mode = mode.to_string();
+    }
+    let body = crate::healthcheck::Body {
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        uptime_secs: shared.started.elapsed().as_secs(),
+        upstream,
+    };
+    let mut response = error_response(StatusCode::OK, serde_json::to_value(body).unwrap_or_default());
+    response.headers_mut().insert(PROXY_HEADER, HeaderValue::from_static(PROXY_HEADER_VALUE));
+    response
+}
+
+fn error_response(status: StatusCode, body: serde_json::Value) -> Response<ProxyBody> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())).map_err(|e| match e {}).boxed_unsync());
+    *response.status_mut() = status;
+    response.headers_mut().insert("content-type", HeaderValue::from_static("application/json"));
+    response
+}
+
+/// Logs a finished request and, with --heartbeat, reports its summary.
+struct Report {
+    shared: Arc<Shared>,
+    summary: RequestSummary,
+    /// Where the request went, for the log.
+    target: String,
+    bytes_in: Arc<AtomicU64>,
+    started: Instant,
+}
+
+impl Report {
+    fn sent(&mut self, bytes: usize) {
+        self.summary.bytes_out += bytes as u64;
+    }
+}
+
+impl Drop for Report {
+    fn drop(&mut self) {
+        let summary = &mut self.summary;
+        summary.bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        summary.duration_ms = self.started.elapsed().as_millis() as u64;
+        let count = self.shared.stats.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        println!(
+            "[proxy] #{} {} https://{}{} <- {} ({}, {}ms)",
+            count, summary.method, summary.host, summary.path, summary.status, self.target, summary.duration_ms
+        );
+        if self.shared.options.heartbeat {
+            if let Ok(serde_json::Value::Object(mut message)) = serde_json::to_value(&*summary) {
+                message.insert("type".into(), "request".into());
+                println!("{}", serde_json::Value::Object(message));
+            }
+        }
+    }
+}
+
+/// Where requests for a domain go.
+struct Upstream {
+    /// Host or IP to connect to.
+    connect_host: String,
+    port: u16,
+    /// The name to verify; None for a plain http gateway.
+    tls_name: Option<String>,
+    host_header: String,
+}
+
+type ForwardError = (StatusCode, String, String);
+
+/// Send `req` on to the gateway, or in passthrough mode to the domain's
+/// real address. Returns the response and where it came from.
+async fn forward(
+    mut req: Request<ProxyBody>,
+    domain: &str,
+    shared: &Shared,
+) -> Result<(Response<Incoming>, String), ForwardError> {
+    let gateway = shared.options.destination(domain, req.uri().path());
+    let upstream = match gateway {
+        Some(gateway) => {
+            let host = gateway.host_str().unwrap_or_default().to_string();
+            let port = gateway.port_or_known_default().unwrap_or(80);
+            let host_header = match gateway.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.clone(),
+            };
+            let tls_name = (gateway.scheme() == "https").then(|| host.trim_matches(['[', ']']).to_string());
+            req.headers_mut().insert("x-original-host", header_value(domain));
+            req.headers_mut().insert(PROXY_HEADER, HeaderValue::from_static(PROXY_HEADER_VALUE));
+            Upstream { connect_host: host, port, tls_name, host_header }
+        }
+        None => {
+            let Some(addr) = shared.resolved.get(domain) else {
+                let error = format!("original IP of {} not resolved", domain);
+                return Err((StatusCode::SERVICE_UNAVAILABLE, error, "official".into()));
+            };
+            Upstream {
+                connect_host: addr.to_string(),
+                port: ORIGINAL_PORT,
+                tls_name: Some(domain.to_string()),
+                host_header: domain.to_string(),
+            }
+        }
+    };
+    let target = match gateway {
+        Some(_) => "gateway".to_string(),
+        None => format!("official {}", upstream.connect_host),
+    };
+    let fail = |e: String| (StatusCode::BAD_GATEWAY, e, target.clone());
+    req.headers_mut().insert(HOST, header_value(&upstream.host_header));
+
+    let tcp = connect(shared, &upstream.connect_host, upstream.port).await.map_err(fail)?;
+    let response = match &upstream.tls_name {
+        Some(name) => {
+            let connector = if gateway.is_some() { &shared.gateway_connector } else { &shared.connector };
+            let tls = connector.connect(name, tcp).await.map_err(|e| fail(format!("TLS to {} failed: {}", name, e)))?;
+            if gateway.is_some() {
+                let peer = tls.get_ref().peer_certificate().ok().flatten();
+                shared.options.gateway_pin.check_peer(peer).map_err(fail)?;
+            }
+            send(tls, req).await
+        }
+        None => send(tcp, req).await,
+    };
+    response.map(|r| (r, target.clone())).map_err(fail)
+}
+
+fn header_value(s: &str) -> HeaderValue {
+    HeaderValue::from_str(s).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+/// A TCP connection to `host:port`, through the upstream proxy if set.
+async fn connect(shared: &Shared, host: &str, port: u16) -> Result<TcpStream, String> {
+    if shared.options.upstream_proxy.is_empty() {
+        return match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await {
+            Ok(Ok(tcp)) => Ok(tcp),
+            Ok(Err(e)) => Err(format!("Connect to {}:{} failed: {}", host, port, e)),
+            Err(_) => Err(format!("Connect to {}:{} timed out", host, port)),
+        };
+    }
+    let (proxy, host) = (shared.options.upstream_proxy.clone(), host.to_string());
+    let tcp = tokio::task::spawn_blocking(move || crate::upstream::connect(&proxy, &host, port, CONNECT_TIMEOUT))
+        .await
+        .map_err(|e| e.to_string())??;
+    let _ = tcp.set_read_timeout(None);
+    let _ = tcp.set_write_timeout(None);
+    tcp.set_nonblocking(true).map_err(|e| e.to_string())?;
+    TcpStream::from_std(tcp).map_err(|e| e.to_string())
+}
+
+/// One request on a fresh HTTP/1.1 connection over `io`.
+async fn send<S>(io: S, req: Request<ProxyBody>) -> Result<Response<Incoming>, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) =
+        hyper::client::conn::http1::handshake(TokioIo::new(io)).await.map_err(|e| e.to_string())?;
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    sender.send_request(req).await.map_err(|e| e.to_string())
+}
+
+/// Leaf certificates per SNI name, issued by our CA on first use. Names we
+/// don't intercept get the first domain's.
+struct LeafCerts {
+    authority: cert::Authority,
+    domains: Vec<String>,
+    issued: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl LeafCerts {
+    fn get(&self, domain: &str) -> Result<Arc<CertifiedKey>, String> {
+        let mut issued = self.issued.lock();
+        if let Some(key) = issued.get(domain) {
+            return Ok(key.clone());
+        }
+        let (chain, key) = self.authority.issue(domain)?;
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key));
+        let signing = rustls::crypto::ring::sign::any_supported_type(&key).map_err(|e| e.to_string())?;
+        let certified = Arc::new(CertifiedKey::new(chain.into_iter().map(CertificateDer::from).collect(), signing));
+        issued.insert(domain.to_string(), certified.clone());
+        Ok(certified)
+    }
+}
+
+impl fmt::Debug for LeafCerts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LeafCerts").field("domains", &self.domains).finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for LeafCerts {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = hello
+            .server_name()
+            .map(str::to_ascii_lowercase)
+            .filter(|name| self.domains.contains(name))
+            .unwrap_or_else(|| self.domains[0].clone());
+        self.get(&name).map_err(|e| eprintln!("[proxy] {}", e)).ok()
+    }
+}
+
+/// A client connection, counted into the heartbeat's byte totals, TLS
+/// included.
+struct Counted {
+    inner: TcpStream,
+    shared: Arc<Shared>,
+}
+
+impl AsyncRead for Counted {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.shared.stats.bytes_in.fetch_add(read, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl AsyncWrite for Counted {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, data);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.shared.stats.bytes_out.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
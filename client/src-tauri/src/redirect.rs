@@ -0,0 +1,94 @@
+//! Forwarding 127.0.0.1:443 to the proxy when it listens elsewhere. The
+//! hosts file can't carry a port, so Windsurf always connects to 443.
+
+/// The port Windsurf connects to.
+pub const HTTPS_PORT: u16 = 443;
+
+/// Whether a listener on `port` can be reached through 443 here.
+#[cfg(target_os = "windows")]
+pub fn check_supported(_port: u16) -> Result<(), String> {
+    Ok(())
+}
+
+/// portproxy rule kind and loopback address for each family the hosts file maps.
+#[cfg(target_os = "windows")]
+const FAMILIES: [(&str, &str); 2] = [("v4tov4", "127.0.0.1"), ("v6tov6", "::1")];
+
+/// Route loopback:443 to loopback:`port` with netsh portproxy rules, for
+/// IPv4 and IPv6.
+#[cfg(target_os = "windows")]
+pub fn add(port: u16) -> Result<(), String> {
+    for (i, &(kind, addr)) in FAMILIES.iter().enumerate() {
+        let added = netsh(&[
+            "interface",
+            "portproxy",
+            "add",
+            kind,
+            &format!("listenaddress={}", addr),
+            &format!("listenport={}", HTTPS_PORT),
+            &format!("connectaddress={}", addr),
+            &format!("connectport={}", port),
+        ]);
+        if let Err(e) = added {
+            for &(kind, addr) in &FAMILIES[..i] {
+                let _ = delete(kind, addr);
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Drop the portproxy rules added by `add`.
+#[cfg(target_os = "windows")]
+pub fn remove() -> Result<(), String> {
+    FAMILIES
+        .iter()
+        .map(|&(kind, addr)| delete(kind, addr))
+        .fold(Ok(()), |first, result| first.and(result))
+}
+
+#[cfg(target_os = "windows")]
+fn delete(kind: &str, addr: &str) -> Result<(), String> {
+    netsh(&[
+        "interface",
+        "portproxy",
+        "delete",
+        kind,
+        &format!("listenaddress={}", addr),
+        &format!("listenport={}", HTTPS_PORT),
+    ])
+}
+
+#[cfg(target_os = "windows")]
+fn netsh(args: &[&str]) -> Result<(), String> {
+    let output = std::process::Command::new("netsh")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run netsh: {}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        // netsh reports errors on stdout
+        Err(format!("netsh failed: {}", String::from_utf8_lossy(&output.stdout).trim()))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn check_supported(port: u16) -> Result<(), String> {
+    Err(format!(
+        "Port {} isn't supported on this platform: Windsurf always connects to {} and there is no automatic \
+         loopback redirect here. Free port {} or forward it to {} yourself (iptables / pf)",
+        port, HTTPS_PORT, HTTPS_PORT, port
+    ))
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn add(port: u16) -> Result<(), String> {
+    check_supported(port)
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn remove() -> Result<(), String> {
+    Ok(())
+}
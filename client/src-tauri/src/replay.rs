@@ -0,0 +1,117 @@
+//! Replay mode: a drill without the gateway. The requests of an earlier
+//! drill, as session_export wrote them in its JSON format, are answered with
+//! the status each got then. The export has no bodies, so neither do the
+//! responses; anything not recorded gets a 404.
+
+use crate::traffic::RequestSummary;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// Where proxy_run sends intercepted requests.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// To the gateway, or the real servers in passthrough.
+    #[default]
+    Forward,
+    /// Answered from a recorded session; nothing leaves the machine.
+    Replay,
+}
+
+#[derive(Deserialize)]
+struct Export {
+    requests: Vec<RequestSummary>,
+}
+
+/// The recorded statuses, by request. A request recorded more than once
+/// answers with its last status.
+pub struct Responses {
+    by_request: HashMap<(String, String, String), u16>,
+    by_path: HashMap<(String, String), u16>,
+    domains: BTreeSet<String>,
+}
+
+impl Responses {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Reading {} failed: {}", path.display(), e))?;
+        let export: Export = serde_json::from_slice(&bytes)
+            .map_err(|e| format!("{} is not a session export in the JSON format: {}", path.display(), e))?;
+        let mut responses =
+            Responses { by_request: HashMap::new(), by_path: HashMap::new(), domains: BTreeSet::new() };
+        for request in export.requests {
+            if request.status == 0 || request.host.is_empty() {
+                continue;
+            }
+            let host = request.host.to_ascii_lowercase();
+            let bare = bare_path(&request.path).to_string();
+            let method = request.method.to_ascii_uppercase();
+            responses.by_request.insert((method.clone(), host.clone(), request.path), request.status);
+            responses.by_request.insert((method, host.clone(), bare.clone()), request.status);
+            responses.by_path.insert((host.clone(), bare), request.status);
+            responses.domains.insert(host);
+        }
+        Ok(responses)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_path.is_empty()
+    }
+
+    /// Distinct (domain, path) pairs, queries aside.
+    pub fn paths(&self) -> usize {
+        self.by_path.len()
+    }
+
+    /// The status recorded for the request: by method, domain and path,
+    /// then without the query, then whatever the method.
+    pub fn status(&self, method: &str, host: &str, path: &str) -> Option<u16> {
+        let key = |path: &str| (method.to_string(), host.to_string(), path.to_string());
+        let bare = bare_path(path);
+        self.by_request
+            .get(&key(path))
+            .or_else(|| self.by_request.get(&key(bare)))
+            .or_else(|| self.by_path.get(&(host.to_string(), bare.to_string())))
+            .copied()
+    }
+}
+
+fn bare_path(path: &str) -> &str {
+    path.split_once('?').map_or(path, |(bare, _)| bare)
+}
+
+/// The bundle a replay drill serves, as proxy_status shows it.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Replay {
+    pub path: String,
+    pub paths: usize,
+    /// Recorded domains the drill intercepts.
+    pub domains: Vec<String>,
+    /// Recorded domains it doesn't; their requests never reach the proxy.
+    pub other_domains: Vec<String>,
+}
+
+/// Check the bundle at `path` before a drill depends on it: an export in
+/// the JSON format with at least one request for the drill's `domains`.
+pub fn validate(path: &str, domains: &[String]) -> Result<Replay, String> {
+    let file = Path::new(path.trim());
+    if !file.is_absolute() {
+        return Err(format!("The replay bundle path must be absolute: {:?}", path));
+    }
+    let responses = Responses::load(file)?;
+    if responses.is_empty() {
+        return Err(format!("{} has no recorded requests to replay", file.display()));
+    }
+    let (ours, others): (Vec<String>, Vec<String>) =
+        responses.domains.iter().cloned().partition(|d| domains.iter().any(|ours| ours.eq_ignore_ascii_case(d)));
+    if ours.is_empty() {
+        return Err(format!(
+            "{} only has requests for {}, none of the intercepted domains",
+            file.display(),
+            others.join(", ")
+        ));
+    }
+    Ok(Replay { path: file.display().to_string(), paths: responses.paths(), domains: ours, other_domains: others })
+}
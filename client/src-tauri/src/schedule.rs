@@ -0,0 +1,79 @@
+//! The drill timer: a full proxy_restore once the drill's time is up,
+//! disarmed by anything that stops the proxy first.
+
+use crate::{log_action, now_secs, restore, AppHandle, Lifecycle, ProxyState, RestoreOptions, SCHEDULE_POLL_INTERVAL};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tauri::{Emitter, Manager};
+
+const EVENT_PROXY_AUTO_STOPPED: &str = "proxy://auto-stopped";
+
+/// A pending automatic proxy_restore.
+pub struct Schedule {
+    /// Unix seconds.
+    pub stop_at: u64,
+    cancel: Arc<AtomicBool>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProxyAutoStoppedEvent {
+    reason: String,
+    /// The restore succeeded; otherwise `message` says why not.
+    ok: bool,
+    message: String,
+}
+
+/// Disarm the drill timer; returns whether one was armed.
+pub fn cancel_schedule(state: &ProxyState) -> bool {
+    match state.schedule.lock().take() {
+        Some(schedule) => {
+            schedule.cancel.store(true, Ordering::SeqCst);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Run the full proxy_restore `minutes` from now, replacing any timer already
+/// armed. Returns the unix second it will fire at. The clock is re-checked
+/// every second rather than slept through, so a suspended machine restores
+/// as soon as it wakes up past the deadline.
+pub fn arm_schedule(app: &AppHandle, state: &ProxyState, minutes: u64) -> u64 {
+    let stop_at = now_secs() + minutes * 60;
+    let cancel = Arc::new(AtomicBool::new(false));
+    if let Some(previous) = state.schedule.lock().replace(Schedule { stop_at, cancel: cancel.clone() }) {
+        previous.cancel.store(true, Ordering::SeqCst);
+    }
+    log_action(state, &format!("Drill timer armed: proxy will be restored in {} minutes", minutes));
+
+    let app = app.clone();
+    std::thread::spawn(move || {
+        while now_secs() < stop_at {
+            std::thread::sleep(SCHEDULE_POLL_INTERVAL);
+            if cancel.load(Ordering::SeqCst) {
+                return;
+            }
+        }
+        let state = app.state::<ProxyState>();
+        let _operation = state.operation.blocking_lock();
+        {
+            // Only if this timer is still the armed one
+            let mut schedule = state.schedule.lock();
+            if cancel.load(Ordering::SeqCst) || !schedule.as_ref().is_some_and(|s| Arc::ptr_eq(&s.cancel, &cancel)) {
+                return;
+            }
+            *schedule = None;
+        }
+        let reason = format!("Drill time of {} minutes elapsed", minutes);
+        log_action(&state, &format!("{}; restoring", reason));
+        state.inner.lock().stop_reason = Some((Lifecycle::AutoStopped, reason.clone()));
+        let event = match restore(RestoreOptions::default(), app.clone(), app.state()) {
+            Ok(result) => ProxyAutoStoppedEvent { reason, ok: result.stop.ok, message: result.stop.message },
+            Err(e) => ProxyAutoStoppedEvent { reason, ok: false, message: e.to_string() },
+        };
+        let _ = app.emit(EVENT_PROXY_AUTO_STOPPED, event);
+    });
+    stop_at
+}
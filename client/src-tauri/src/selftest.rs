@@ -0,0 +1,213 @@
+//! proxy_selftest: walk the interception chain the way Windsurf does and
+//! report each link, so "running but nothing is intercepted" can be pinned
+//! to one step. Every step runs under its own timeout.
+
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(4);
+/// Set by the proxy on every response it sends.
+const PROXY_HEADER: &str = "x-intercepted-by";
+const PROXY_HEADER_VALUE: &str = "cyber-drill-local-proxy";
+const HEALTH_PATH: &str = "/health";
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// Not applicable, e.g. the gateway step in passthrough mode.
+    Skip,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Step {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+    pub duration_ms: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    /// No step failed.
+    pub passed: bool,
+    pub steps: Vec<Step>,
+}
+
+/// What to test, gathered from the proxy state by the command.
+pub struct Target {
+    pub domain: String,
+    pub port: u16,
+    /// Whether our hosts entries for `domain` are in place, checked by the
+    /// caller because it owns the hosts file.
+    pub hosts_entry: Result<(), String>,
+    /// Empty in passthrough mode.
+    pub gateway_url: String,
+    pub upstream_proxy: String,
+    /// The configured pin, or why it is unusable.
+    pub gateway_pin: Result<crate::gateway::Pin, String>,
+}
+
+pub fn run(target: Target) -> Report {
+    let Target { domain, port, hosts_entry, gateway_url, upstream_proxy, gateway_pin } = target;
+    let mut steps = vec![
+        step("hosts_entry", move || hosts_entry.map(|_| "127.0.0.1 and ::1 entries present".into())),
+        step("dns", {
+            let domain = domain.clone();
+            move || {
+                if crate::dns::resolves_to_loopback(&domain) {
+                    Ok(format!("{} resolves to 127.0.0.1", domain))
+                } else {
+                    Err(format!("{} does not resolve to 127.0.0.1", domain))
+                }
+            }
+        }),
+        step("local_port", move || {
+            if crate::net::is_listening(port, STEP_TIMEOUT) {
+                Ok(format!("127.0.0.1:{} accepts connections", port))
+            } else {
+                Err(format!("Nothing listening on 127.0.0.1:{}", port))
+            }
+        }),
+        step("tls", {
+            let domain = domain.clone();
+            move || {
+                let addr = SocketAddr::from(([127, 0, 0, 1], port));
+                tls_connect(&domain, &[addr]).map(|_| format!("Certificate for {} trusted", domain))
+            }
+        }),
+        step("https_request", {
+            let domain = domain.clone();
+            move || intercepted_request(&domain)
+        }),
+    ];
+    if gateway_url.is_empty() {
+        for name in ["gateway", "gateway_pin"] {
+            steps.push(Step {
+                name,
+                outcome: Outcome::Skip,
+                detail: "Passthrough mode, no gateway".into(),
+                duration_ms: 0,
+            });
+        }
+    } else {
+        // Verified the way the proxy will, so a private CA that is pinned
+        // doesn't fail the gateway step
+        let pin = gateway_pin.clone().unwrap_or_default();
+        steps.push(step("gateway", {
+            let (gateway_url, upstream_proxy) = (gateway_url.clone(), upstream_proxy.clone());
+            move || {
+                // Shorter than the step, so its own error wins over ours
+                let timeout = crate::gateway::REACHABILITY_TIMEOUT;
+                let path = crate::gateway::DEFAULT_HEALTH_PATH;
+                let check = crate::gateway::check(&gateway_url, path, timeout, &upstream_proxy, &pin);
+                match check.error {
+                    None if check.reachable => Ok(format!("{} reachable", gateway_url)),
+                    None => Err(format!("{} unreachable", gateway_url)),
+                    Some(e) => Err(e),
+                }
+            }
+        }));
+        steps.push(match gateway_pin {
+            Ok(pin) if !pin.is_set() => Step {
+                name: "gateway_pin",
+                outcome: Outcome::Skip,
+                detail: "No pin configured".into(),
+                duration_ms: 0,
+            },
+            Ok(pin) => step("gateway_pin", move || {
+                let timeout = crate::gateway::REACHABILITY_TIMEOUT;
+                let check = crate::gateway::check(&gateway_url, "", timeout, &upstream_proxy, &pin);
+                match (check.pin_ok, check.error) {
+                    (Some(true), _) => Ok(format!("{} presented the pinned certificate", gateway_url)),
+                    (_, Some(e)) => Err(e),
+                    (_, None) => Err(format!("{} presented no certificate to check", gateway_url)),
+                }
+            }),
+            Err(e) => Step { name: "gateway_pin", outcome: Outcome::Fail, detail: e, duration_ms: 0 },
+        });
+    }
+    Report { passed: steps.iter().all(|s| s.outcome != Outcome::Fail), steps }
+}
+
+/// Run `f` on its own thread and give up on it after STEP_TIMEOUT, so a
+/// resolver or socket that never answers can't hang the report.
+pub fn step(name: &'static str, f: impl FnOnce() -> Result<String, String> + Send + 'static) -> Step {
+    let started = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(f());
+    });
+    let (outcome, detail) = match rx.recv_timeout(STEP_TIMEOUT) {
+        Ok(Ok(detail)) => (Outcome::Pass, detail),
+        Ok(Err(detail)) => (Outcome::Fail, detail),
+        Err(_) => (Outcome::Fail, format!("No answer within {}s", STEP_TIMEOUT.as_secs())),
+    };
+    Step { name, outcome, detail, duration_ms: started.elapsed().as_millis() as u64 }
+}
+
+/// TLS to `domain` at the first of `addrs` that connects, verified against
+/// the system trust store, which our CA must be in.
+fn tls_connect(domain: &str, addrs: &[SocketAddr]) -> Result<native_tls::TlsStream<TcpStream>, String> {
+    let mut last_error = format!("No addresses for {}", domain);
+    let tcp = addrs
+        .iter()
+        .find_map(|addr| {
+            TcpStream::connect_timeout(addr, STEP_TIMEOUT)
+                .map_err(|e| last_error = format!("Connect to {} failed: {}", addr, e))
+                .ok()
+        })
+        .ok_or(last_error)?;
+    let _ = tcp.set_read_timeout(Some(STEP_TIMEOUT));
+    let _ = tcp.set_write_timeout(Some(STEP_TIMEOUT));
+    let connector = native_tls::TlsConnector::new().map_err(|e| e.to_string())?;
+    connector.connect(domain, tcp).map_err(|e| match e {
+        native_tls::HandshakeError::Failure(e) => format!("TLS verification failed: {}", e),
+        native_tls::HandshakeError::WouldBlock(_) => "TLS handshake timed out".into(),
+    })
+}
+
+/// GET https://`domain`/health through the system resolver and port 443,
+/// as Windsurf would, and check the proxy answered it.
+pub fn intercepted_request(domain: &str) -> Result<String, String> {
+    let addrs: Vec<SocketAddr> = (domain, 443)
+        .to_socket_addrs()
+        .map_err(|e| format!("Cannot resolve {}: {}", domain, e))?
+        .collect();
+    let mut stream = tls_connect(domain, &addrs)?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: cyber-drill-safe\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        HEALTH_PATH, domain
+    );
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() <= 16384 {
+        match stream.read(&mut buf).map_err(|e| e.to_string())? {
+            0 => break,
+            n => head.extend_from_slice(&buf[..n]),
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.lines();
+    let status = lines.next().unwrap_or_default().to_string();
+    let intercepted = lines
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| l.split_once(':'))
+        .any(|(name, value)| name.trim().eq_ignore_ascii_case(PROXY_HEADER) && value.trim() == PROXY_HEADER_VALUE);
+    if intercepted {
+        Ok(format!("GET {} answered through the proxy: {}", HEALTH_PATH, status))
+    } else {
+        Err(format!(
+            "GET {} answered ({}) without the {} header; the request bypassed the proxy",
+            HEALTH_PATH, status, PROXY_HEADER
+        ))
+    }
+}
@@ -0,0 +1,544 @@
+//! Headless mode for unattended machines (`<exe> --service --data-dir <dir>
+//! --config-dir <dir>`): proxy_run with the stored config, without the
+//! window, supervised until the service manager stops it. Registered as a
+//! Windows service or a systemd unit so interception survives logoff. The
+//! app's own dirs are passed in because the service runs as another user.
+//!
+//! While it runs, the state file in the data dir tells the app that the
+//! service owns the proxy and the hosts block.
+
+use crate::config;
+use crate::hosts::{self, HostsEditor};
+use crate::{allowlist, cert, childenv, dns, firewall, gateway, process, proxy, upstream};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const SERVICE_ARG: &str = "--service";
+#[cfg(target_os = "windows")]
+const SERVICE_NAME: &str = "WindClientProxy";
+#[cfg(target_os = "linux")]
+const UNIT_PATH: &str = "/etc/systemd/system/wind-client-proxy.service";
+const STATE_FILE: &str = "service-state.json";
+const LOG_FILE: &str = "service.log";
+const SUPERVISE_INTERVAL: Duration = Duration::from_millis(500);
+/// How often the state file is rewritten while nothing changes.
+const STATE_INTERVAL: Duration = Duration::from_secs(5);
+/// A state file not rewritten for this long was left by a service that died.
+const STATE_STALE_AFTER: Duration = Duration::from_secs(20);
+
+/// What the service is doing, for the app to show instead of its own child.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceState {
+    pub service_pid: u32,
+    /// None between a crash and the restart.
+    pub proxy_pid: Option<u32>,
+    pub gateway_url: String,
+    pub port: u16,
+    pub domains: Vec<String>,
+    /// As in config.routes.
+    #[serde(default)]
+    pub routes: Vec<crate::config::Route>,
+    #[serde(default)]
+    pub bypass_paths: Vec<String>,
+    /// Unix seconds the current proxy was started.
+    pub started_at: Option<u64>,
+    pub restarts: u32,
+    /// Why the proxy last exited, if it did.
+    pub last_error: Option<String>,
+    /// Unix seconds.
+    pub updated_at: u64,
+}
+
+pub fn state_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(STATE_FILE)
+}
+
+/// The service's state if it is running, judged by how recently it wrote it.
+pub fn load_state(path: &Path) -> Option<ServiceState> {
+    let state: ServiceState = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+    (crate::now_secs().saturating_sub(state.updated_at) < STATE_STALE_AFTER.as_secs()).then_some(state)
+}
+
+struct Dirs {
+    data: PathBuf,
+    config: PathBuf,
+}
+
+impl Dirs {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let (mut data, mut config) = (None, None);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--data-dir" => data = args.next().map(PathBuf::from),
+                "--config-dir" => config = args.next().map(PathBuf::from),
+                other => return Err(format!("Unknown argument {:?}", other)),
+            }
+        }
+        Ok(Dirs {
+            data: data.ok_or("--data-dir is required")?,
+            config: config.ok_or("--config-dir is required")?,
+        })
+    }
+}
+
+/// Entry point for `--service`. Runs under the service manager where there
+/// is one, in the foreground (until Ctrl+C or SIGTERM) otherwise.
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let dirs = Dirs::parse(args)?;
+    #[cfg(target_os = "windows")]
+    if scm::dispatch(dirs)? {
+        return Ok(());
+    }
+    #[cfg(target_os = "windows")]
+    let dirs = scm::take_dirs();
+    let stop = Arc::new(AtomicBool::new(false));
+    stop_on_signal(stop.clone());
+    supervise(&dirs, &stop)
+}
+
+/// Sets `stop` on Ctrl+C, and on SIGTERM (what systemd sends) on Unix.
+fn stop_on_signal(stop: Arc<AtomicBool>) {
+    std::thread::spawn(move || {
+        let Ok(runtime) = tokio::runtime::Builder::new_current_thread().enable_all().build() else {
+            return;
+        };
+        runtime.block_on(async {
+            #[cfg(unix)]
+            {
+                use tokio::signal::unix::{signal, SignalKind};
+                if let Ok(mut term) = signal(SignalKind::terminate()) {
+                    tokio::select! {
+                        _ = term.recv() => {}
+                        _ = tokio::signal::ctrl_c() => {}
+                    }
+                    return;
+                }
+            }
+            let _ = tokio::signal::ctrl_c().await;
+        });
+        stop.store(true, Ordering::SeqCst);
+    });
+}
+
+fn supervise(dirs: &Dirs, stop: &AtomicBool) -> Result<(), String> {
+    let mut log = ServiceLog::open(&dirs.data.join("logs"));
+    let result = intercept(dirs, stop, &mut log);
+    if let Err(e) = &result {
+        log.line(&format!("Service failed: {}", e));
+    }
+    result
+}
+
+/// Set up interception from the stored config, keep the proxy running
+/// (restarting it with backoff when it exits) until `stop`, then undo
+/// everything. Nothing fails once the hosts block is written.
+fn intercept(dirs: &Dirs, stop: &AtomicBool, log: &mut ServiceLog) -> Result<(), String> {
+    let config = config::load(&config::path(&dirs.config))?;
+    let gateway_url = gateway::normalize(config.gateway_url.as_deref().unwrap_or_default())?;
+    let resources = crate::resources_dir().map_err(|e| e.to_string())?;
+    let allowlist = allowlist::load(&resources, Some(&dirs.data));
+    allowlist::check(&allowlist, &gateway_url)?;
+    let upstream_proxy = upstream::normalize(config.upstream_proxy.as_deref().unwrap_or_default())?;
+    let routes = config::normalize_routes(&config.routes).map_err(|e| e.to_string())?;
+    for route in &routes {
+        allowlist::check(&allowlist, &route.gateway_url)?;
+    }
+    let domains = hosts::normalize_domains(&config.intercepted_domains()).map_err(|e| e.to_string())?;
+    let bypass_paths = config::normalize_bypass_paths(&config.bypass_paths).map_err(|e| e.to_string())?;
+    // The 443 redirect needs the app's bookkeeping; not worth duplicating here
+    if config.port != crate::redirect::HTTPS_PORT {
+        return Err(format!(
+            "The service only listens on port {} directly; the stored config asks for {}",
+            crate::redirect::HTTPS_PORT,
+            config.port
+        ));
+    }
+    let certs = dirs.data.join("certs");
+    cert::ensure(&certs, &domains.iter().map(String::as_str).collect::<Vec<_>>())?;
+    if !cert::covers(&certs, &domains) {
+        return Err(format!("The server certificate is not issued for all of {}", domains.join(", ")));
+    }
+    let pin_args = if gateway_url.is_empty() && routes.is_empty() {
+        Vec::new()
+    } else {
+        config.gateway_pin()?.args(&certs)?
+    };
+    let routes_arg = serde_json::to_string(&routes).map_err(|e| e.to_string())?;
+    // From public DNS, as our hosts entries point the system resolver at us
+    let bypass_args = if bypass_paths.is_empty() {
+        Vec::new()
+    } else {
+        let origin_ips: Vec<String> = domains
+            .iter()
+            .filter_map(|domain| match dns::resolve_public(domain) {
+                Ok(ip) => Some(format!("{}={}", domain, ip)),
+                Err(e) => {
+                    log.line(&format!("No bypass for {}: {}", domain, e));
+                    None
+                }
+            })
+            .collect();
+        vec!["--bypass".to_string(), bypass_paths.join(","), "--origin-ip".into(), origin_ips.join(",")]
+    };
+    let exe = std::env::current_exe().map_err(|e| format!("Failed to locate the app binary: {}", e))?;
+
+    let hosts = hosts::HostsFile::system();
+    hosts.set_lock_dir(&dirs.data);
+    backup_hosts(&hosts, &dirs.data.join("backups"));
+    hosts.ensure_entry(&domains).map_err(|e| e.to_string())?;
+    let _ = dns::flush();
+    if let Err(firewall::AddError::Policy(e) | firewall::AddError::Other(e)) = firewall::add(&exe, config.port) {
+        log.line(&format!("Firewall rule not added: {}", e));
+    }
+    log.line(&format!("Service started (gateway {}, domains {})", gateway_url, domains.join(", ")));
+    for route in &routes {
+        log.line(&format!("Routing {} to {}", route.domain, route.gateway_url));
+    }
+
+    let state_path = state_path(&dirs.data);
+    let mut state = ServiceState {
+        service_pid: std::process::id(),
+        proxy_pid: None,
+        gateway_url: gateway_url.clone(),
+        port: config.port,
+        domains: domains.clone(),
+        routes: routes.clone(),
+        bypass_paths: bypass_paths.clone(),
+        started_at: None,
+        restarts: 0,
+        last_error: None,
+        updated_at: 0,
+    };
+    let mut child: Option<process::ProxyChild> = None;
+    let mut restart_at = Instant::now();
+    let mut backoff = Duration::from_secs(1);
+    let mut last_write = None::<Instant>;
+    while !stop.load(Ordering::SeqCst) {
+        if let Some(running) = child.as_mut() {
+            if let Ok(Some(status)) = running.try_wait() {
+                let uptime = crate::now_secs().saturating_sub(state.started_at.unwrap_or_default());
+                if uptime >= crate::watchdog::RESTART_STABLE_SECS {
+                    backoff = Duration::from_secs(1);
+                }
+                let message = format!("Proxy exited ({}); restarting in {}s", status, backoff.as_secs());
+                log.line(&message);
+                state.last_error = Some(message);
+                state.proxy_pid = None;
+                state.started_at = None;
+                child = None;
+                restart_at = Instant::now() + backoff;
+                backoff = (backoff * 2).min(crate::watchdog::RESTART_BACKOFF_CAP);
+                last_write = None;
+            }
+        } else if Instant::now() >= restart_at {
+            let mut command = Command::new(&exe);
+            command.arg(proxy::HELPER_ARG);
+            if !upstream_proxy.is_empty() {
+                command.arg("--upstream-proxy").arg(&upstream_proxy);
+            }
+            command.env_clear().envs(childenv::vars(&certs.join(cert::CA_CERT_FILE), &config.proxy_env));
+            command
+                .args(&pin_args)
+                .arg("--gateway")
+                .arg(&gateway_url)
+                .arg("--port")
+                .arg(config.port.to_string())
+                .arg("--domain")
+                .arg(domains.first().map(String::as_str).unwrap_or_default())
+                .arg("--domains")
+                .arg(domains.join(","))
+                .arg("--routes")
+                .arg(&routes_arg)
+                .args(&bypass_args)
+                .arg("--ipv6")
+                .arg("--health-route")
+                .arg(&config.health_route)
+                .arg("--cert-dir")
+                .arg(&certs)
+                .stdin(Stdio::piped())
+                .stdout(log.stdio())
+                .stderr(log.stdio());
+            match process::ProxyChild::spawn(&mut command) {
+                Ok(spawned) => {
+                    log.line(&format!("Proxy started (pid {})", spawned.id()));
+                    if state.started_at.is_some() || state.last_error.is_some() {
+                        state.restarts += 1;
+                    }
+                    state.proxy_pid = Some(spawned.id());
+                    state.started_at = Some(crate::now_secs());
+                    child = Some(spawned);
+                }
+                Err(e) => {
+                    let message = format!("Failed to start proxy: {}", e);
+                    log.line(&message);
+                    state.last_error = Some(message);
+                    restart_at = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(crate::watchdog::RESTART_BACKOFF_CAP);
+                }
+            }
+            last_write = None;
+        }
+        if last_write.is_none_or(|t| t.elapsed() >= STATE_INTERVAL) {
+            state.updated_at = crate::now_secs();
+            if let Err(e) = write_state(&state_path, &state) {
+                log.line(&format!("Writing {} failed: {}", state_path.display(), e));
+            }
+            last_write = Some(Instant::now());
+        }
+        std::thread::sleep(SUPERVISE_INTERVAL);
+    }
+
+    log.line("Service stopping");
+    if let Some(mut running) = child {
+        process::shutdown(&mut running, process::DEFAULT_SHUTDOWN_TIMEOUT);
+    }
+    if let Err(e) = hosts.remove_entries(&[]) {
+        log.line(&format!("Removing the hosts block failed: {}", e));
+    }
+    let _ = dns::flush();
+    let _ = firewall::remove();
+    let _ = fs::remove_file(&state_path);
+    log.line("Service stopped");
+    Ok(())
+}
+
+/// Same naming as the app's backups, so proxy_restore_backup finds it.
+fn backup_hosts(hosts: &hosts::HostsFile, dir: &Path) {
+    let _ = fs::create_dir_all(dir);
+    let _ = fs::copy(hosts.path(), dir.join(format!("{}{}", crate::HOSTS_BACKUP_PREFIX, crate::now_millis())));
+}
+
+fn write_state(path: &Path, state: &ServiceState) -> std::io::Result<()> {
+    let json = serde_json::to_vec_pretty(state).map_err(std::io::Error::other)?;
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, json)?;
+    fs::rename(&temp, path)
+}
+
+/// The service's own lines and the proxy's output, in one file. Nobody
+/// reads a service's stderr.
+struct ServiceLog(Option<File>);
+
+impl ServiceLog {
+    fn open(dir: &Path) -> Self {
+        let _ = fs::create_dir_all(dir);
+        ServiceLog(OpenOptions::new().create(true).append(true).open(dir.join(LOG_FILE)).ok())
+    }
+
+    fn line(&mut self, message: &str) {
+        match self.0.as_mut() {
+            Some(file) => {
+                let _ = writeln!(file, "[{}] {}", crate::now_secs(), message);
+            }
+            None => eprintln!("{}", message),
+        }
+    }
+
+    fn stdio(&self) -> Stdio {
+        self.0.as_ref().and_then(|f| f.try_clone().ok()).map(Stdio::from).unwrap_or_else(Stdio::null)
+    }
+}
+
+/// Register the service to start at boot with the app's `data_dir` and
+/// `config_dir`, and start it. Needs admin rights.
+pub fn install(data_dir: &Path, config_dir: &Path) -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("Failed to locate the app binary: {}", e))?;
+    imp::install(&exe, data_dir, config_dir)
+}
+
+/// Stop and unregister the service; it undoes its changes as it stops.
+pub fn uninstall() -> Result<(), String> {
+    imp::uninstall()
+}
+
+/// Whether the service is registered; None where there is no service mode.
+pub fn installed() -> Option<bool> {
+    imp::installed()
+}
+
+#[cfg(target_os = "windows")]
+mod imp {
+    use super::*;
+
+    pub fn install(exe: &Path, data_dir: &Path, config_dir: &Path) -> Result<(), String> {
+        let bin_path = format!(
+            "\"{}\" {} --data-dir \"{}\" --config-dir \"{}\"",
+            exe.display(),
+            SERVICE_ARG,
+            data_dir.display(),
+            config_dir.display()
+        );
+        sc(&["create", SERVICE_NAME, "binPath=", &bin_path, "start=", "auto", "DisplayName=", "Wind Client proxy"])?;
+        let _ = sc(&["description", SERVICE_NAME, "Keeps the Wind Client interception proxy running"]);
+        sc(&["start", SERVICE_NAME])
+    }
+
+    pub fn uninstall() -> Result<(), String> {
+        // Not running is fine; delete still has to wait for a stop to finish
+        if sc(&["stop", SERVICE_NAME]).is_ok() {
+            let deadline = Instant::now() + process::DEFAULT_SHUTDOWN_TIMEOUT * 2;
+            while Instant::now() < deadline && !query_state().is_some_and(|s| s.contains("STOPPED")) {
+                std::thread::sleep(SUPERVISE_INTERVAL);
+            }
+        }
+        sc(&["delete", SERVICE_NAME])
+    }
+
+    pub fn installed() -> Option<bool> {
+        Some(query_state().is_some())
+    }
+
+    fn query_state() -> Option<String> {
+        let output = Command::new("sc").args(["query", SERVICE_NAME]).output().ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn sc(args: &[&str]) -> Result<(), String> {
+        let output = Command::new("sc").args(args).output().map_err(|e| format!("Failed to run sc: {}", e))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            // sc reports errors on stdout
+            Err(format!("sc {} failed: {}", args[0], String::from_utf8_lossy(&output.stdout).trim()))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::*;
+
+    pub fn install(exe: &Path, data_dir: &Path, config_dir: &Path) -> Result<(), String> {
+        let quote = |p: &Path| format!("\"{}\"", p.display().to_string().replace('\\', "\\\\").replace('"', "\\\""));
+        let unit = format!(
+            "[Unit]\nDescription=Wind Client proxy\nWants=network-online.target\nAfter=network-online.target\n\n\
+             [Service]\nExecStart={} {} --data-dir {} --config-dir {}\nRestart=on-failure\nTimeoutStopSec=20\n\n\
+             [Install]\nWantedBy=multi-user.target\n",
+            quote(exe),
+            SERVICE_ARG,
+            quote(data_dir),
+            quote(config_dir)
+        );
+        fs::write(UNIT_PATH, unit).map_err(|e| format!("Writing {} failed: {}", UNIT_PATH, e))?;
+        systemctl(&["daemon-reload"])?;
+        systemctl(&["enable", "--now", unit_name()])
+    }
+
+    pub fn uninstall() -> Result<(), String> {
+        if Path::new(UNIT_PATH).exists() {
+            let _ = systemctl(&["disable", "--now", unit_name()]);
+            fs::remove_file(UNIT_PATH).map_err(|e| format!("Removing {} failed: {}", UNIT_PATH, e))?;
+        }
+        systemctl(&["daemon-reload"])
+    }
+
+    pub fn installed() -> Option<bool> {
+        Some(Path::new(UNIT_PATH).exists())
+    }
+
+    fn unit_name() -> &'static str {
+        UNIT_PATH.rsplit('/').next().unwrap_or(UNIT_PATH)
+    }
+
+    fn systemctl(args: &[&str]) -> Result<(), String> {
+        let output =
+            Command::new("systemctl").args(args).output().map_err(|e| format!("Failed to run systemctl: {}", e))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(format!("systemctl {} failed: {}", args[0], String::from_utf8_lossy(&output.stderr).trim()))
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+mod imp {
+    use super::*;
+
+    pub fn install(_exe: &Path, _data_dir: &Path, _config_dir: &Path) -> Result<(), String> {
+        Err("Service mode is only available on Windows and Linux".into())
+    }
+
+    pub fn uninstall() -> Result<(), String> {
+        Ok(())
+    }
+
+    pub fn installed() -> Option<bool> {
+        None
+    }
+}
+
+/// The Windows service control manager side: report Running, and turn its
+/// Stop into our stop flag.
+#[cfg(target_os = "windows")]
+mod scm {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::ffi::OsString;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState as ScmState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    /// ERROR_FAILED_SERVICE_CONTROLLER_CONNECT: started from a console.
+    const NOT_A_SERVICE: i32 = 1063;
+
+    static DIRS: Mutex<Option<Dirs>> = Mutex::new(None);
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Hand over to the service control manager. Returns false, with the
+    /// dirs left for `take_dirs`, if we weren't started by it.
+    pub fn dispatch(dirs: Dirs) -> Result<bool, String> {
+        *DIRS.lock() = Some(dirs);
+        match service_dispatcher::start(SERVICE_NAME, ffi_service_main) {
+            Ok(()) => Ok(true),
+            Err(windows_service::Error::Winapi(e)) if e.raw_os_error() == Some(NOT_A_SERVICE) => Ok(false),
+            Err(e) => Err(format!("Service dispatcher failed: {}", e)),
+        }
+    }
+
+    pub fn take_dirs() -> Dirs {
+        DIRS.lock().take().expect("dispatch stores the dirs")
+    }
+
+    fn service_main(_args: Vec<OsString>) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler_stop = stop.clone();
+        let handler = move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                handler_stop.store(true, Ordering::SeqCst);
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        let Ok(handle) = service_control_handler::register(SERVICE_NAME, handler) else {
+            return;
+        };
+        let status = |state, accepted, code| ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: accepted,
+            exit_code: ServiceExitCode::Win32(code),
+            checkpoint: 0,
+            wait_hint: process::DEFAULT_SHUTDOWN_TIMEOUT * 2,
+            process_id: None,
+        };
+        let _ = handle.set_service_status(status(
+            ScmState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            0,
+        ));
+        let result = supervise(&take_dirs(), &stop);
+        let _ = handle.set_service_status(status(ScmState::Stopped, ServiceControlAccept::empty(), result.is_err() as u32));
+    }
+}
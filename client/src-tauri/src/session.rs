@@ -0,0 +1,54 @@
+//! The running proxy, recorded in the app data dir so that an app restart or
+//! frontend reload can find the child again instead of orphaning it.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const FILE_NAME: &str = "proxy-session.json";
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub pid: u32,
+    pub gateway_url: String,
+    /// Unix seconds.
+    pub started_at: u64,
+    pub listen_port: u16,
+    pub domains: Vec<String>,
+    /// The profile proxy_run was started with, if any.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// The HTTP proxy the gateway was reached through, if any.
+    #[serde(default)]
+    pub upstream_proxy: Option<String>,
+    /// Domains forwarded to a gateway other than `gateway_url`.
+    #[serde(default)]
+    pub routes: Vec<crate::config::Route>,
+    #[serde(default)]
+    pub bypass_paths: Vec<String>,
+    /// The recorded session replayed instead of forwarding, if any.
+    #[serde(default)]
+    pub replay: Option<crate::replay::Replay>,
+}
+
+pub fn path(app_data_dir: &Path) -> PathBuf {
+    app_data_dir.join(FILE_NAME)
+}
+
+pub fn save(path: &Path, session: &Session) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let json = serde_json::to_vec_pretty(session).map_err(std::io::Error::other)?;
+    fs::write(path, json)
+}
+
+/// The recorded session, or None if there is none or it can't be parsed.
+pub fn load(path: &Path) -> Option<Session> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+pub fn clear(path: &Path) {
+    let _ = fs::remove_file(path);
+}
@@ -0,0 +1,479 @@
+//! First-run setup as a small state machine, so the UI no longer sequences
+//! elevation, the CA and the proxy runtime itself: setup_state says which
+//! step is due and what to show, setup_next takes it. Progress is kept in
+//! the app data dir, but only as a record: every call verifies the steps
+//! again in order, so a CA removed or a runtime uninstalled since puts setup
+//! back at that step.
+
+use crate::error::ProxyError;
+use crate::{
+    app_data_dir, cert, certs_path, elevate, ensure_ca, ensure_cert_current, hosts, install_cert, log_action, now_secs,
+    regenerate_cert, resolve_launcher, AppHandle, ProxyState,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tauri::Manager;
+
+const FILE_NAME: &str = "setup.json";
+
+/// How long a granted elevation prompt stands in for the elevation step.
+/// Rights can be taken away since, so it is asked again after this; a hosts
+/// write through the prompt failing forgets it sooner (`forget_elevation`).
+pub const ELEVATION_GRANT_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// The step setup is at; each is complete once the ones before it are.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Step {
+    /// The hosts file can't be written, directly or through the prompt.
+    NeedsElevation,
+    /// The CA is missing, expired, not issued for the domains or not trusted.
+    NeedsCert,
+    /// There is nothing to launch the proxy with.
+    NeedsNode,
+    Ready,
+}
+
+impl Step {
+    /// The steps to take, in order.
+    pub const ORDER: [Step; 3] = [Step::NeedsElevation, Step::NeedsCert, Step::NeedsNode];
+}
+
+/// What setup_next does for the step, or what only the user can do.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Action {
+    /// Show the elevation prompt for a command that does nothing.
+    GrantElevation,
+    /// Something other than missing rights blocks the hosts file (security
+    /// software, another program holding it); setup_next only checks again.
+    FixHostsAccess,
+    /// Generate the CA if needed, issue it again if expired or not for the
+    /// domains, and trust it.
+    InstallCert,
+    /// Install Node.js or reinstall the app; setup_next only checks again.
+    InstallNode,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Completed {
+    pub step: Step,
+    /// Unix seconds it was last verified.
+    pub at: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    /// The steps verified at the last call, in order.
+    #[serde(default)]
+    pub completed: Vec<Completed>,
+    /// Unix seconds the elevation prompt was last granted. Whether it would
+    /// be granted again can't be checked without showing it, so this stands
+    /// in for that part of the step, for ELEVATION_GRANT_TTL_SECS; the rest
+    /// is checked each time.
+    #[serde(default)]
+    pub elevation_granted_at: Option<u64>,
+}
+
+impl Progress {
+    /// Whether a grant of the elevation prompt still counts at `now`.
+    pub fn elevation_granted(&self, now: u64) -> bool {
+        self.elevation_granted_at.is_some_and(|at| at <= now && now - at < ELEVATION_GRANT_TTL_SECS)
+    }
+
+    /// Record `verified` as the complete steps, dropping any verified before
+    /// that no longer are. Returns whether that changed which steps.
+    pub fn verified(&mut self, verified: &[Step], at: u64) -> bool {
+        let changed = self.completed.iter().map(|c| c.step).ne(verified.iter().copied());
+        self.completed = verified.iter().map(|&step| Completed { step, at }).collect();
+        changed
+    }
+}
+
+/// Checking and taking the steps on this machine. `advance` decides which
+/// step is due from what these report, so it can be run against fakes.
+pub trait Machine {
+    /// Err with why `step` isn't complete, as the command needing it would fail.
+    fn verify(&self, step: Step, progress: &Progress) -> Result<(), ProxyError>;
+    /// Do what setup_next does for `step`; `advance` verifies it again after.
+    fn take(&self, step: Step, progress: &mut Progress) -> Result<(), ProxyError>;
+}
+
+/// What the UI shows for the current step.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetupState {
+    pub step: Step,
+    /// One line about the step: what it needs, or that setup is done.
+    pub message: String,
+    /// None once Ready.
+    pub action: Option<Action>,
+    /// Why the step isn't complete, as the command that needs it would fail.
+    pub error: Option<ProxyError>,
+    pub completed: Vec<Completed>,
+    /// setup_next completed a step.
+    pub advanced: bool,
+}
+
+pub fn path(app_data_dir: &Path) -> PathBuf {
+    app_data_dir.join(FILE_NAME)
+}
+
+/// The recorded progress; none if there is none or it can't be parsed,
+/// which only means every step is verified from scratch.
+pub fn load(path: &Path) -> Progress {
+    fs::read(path).ok().and_then(|bytes| serde_json::from_slice(&bytes).ok()).unwrap_or_default()
+}
+
+pub fn save(path: &Path, progress: &Progress) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let json = serde_json::to_vec_pretty(progress).map_err(std::io::Error::other)?;
+    fs::write(path, json)
+}
+
+/// The steps verified complete, the first one that isn't (Ready if none),
+/// and why.
+fn due(machine: &impl Machine, progress: &Progress) -> (Vec<Step>, Step, Option<ProxyError>) {
+    let mut verified = Vec::new();
+    for step in Step::ORDER {
+        if let Err(e) = machine.verify(step, progress) {
+            return (verified, step, Some(e));
+        }
+        verified.push(step);
+    }
+    (verified, Step::Ready, None)
+}
+
+/// Verify the steps in order up to the first incomplete one and, with
+/// `take`, perform that one and verify again. Also returns whether
+/// `progress` changed and should be saved.
+pub fn advance(machine: &impl Machine, progress: &mut Progress, take: bool, now: u64) -> (SetupState, bool) {
+    let (mut verified, mut step, mut error) = due(machine, progress);
+    let mut advanced = false;
+    if take && step != Step::Ready {
+        let taken = machine.take(step, progress);
+        let before = step;
+        (verified, step, error) = due(machine, progress);
+        advanced = step != before;
+        // Why the step itself failed says more than the check after it
+        if let (false, Err(e)) = (advanced, taken) {
+            error = Some(e);
+        }
+    }
+    let changed = progress.verified(&verified, now) || take;
+    let action = action(step, error.as_ref());
+    let state = SetupState {
+        step,
+        message: message(action),
+        action,
+        error,
+        completed: progress.completed.clone(),
+        advanced,
+    };
+    (state, changed)
+}
+
+/// Drop the recorded elevation grant at `path`: a write through the prompt
+/// failed, so it can't be counted on any more.
+pub fn forget_elevation(path: &Path) -> std::io::Result<()> {
+    let mut progress = load(path);
+    if progress.elevation_granted_at.take().is_none() {
+        return Ok(());
+    }
+    save(path, &progress)
+}
+
+/// What `step` needs done, given why it isn't complete.
+pub fn action(step: Step, error: Option<&ProxyError>) -> Option<Action> {
+    match step {
+        Step::NeedsElevation if matches!(error, Some(ProxyError::NotElevated(_))) => Some(Action::GrantElevation),
+        Step::NeedsElevation => Some(Action::FixHostsAccess),
+        Step::NeedsCert => Some(Action::InstallCert),
+        Step::NeedsNode => Some(Action::InstallNode),
+        Step::Ready => None,
+    }
+}
+
+pub fn message(action: Option<Action>) -> String {
+    match action {
+        Some(Action::GrantElevation) => "Writing the hosts file needs admin rights; approve the prompt to continue",
+        Some(Action::FixHostsAccess) => "Something besides admin rights blocks the hosts file; fix it and check again",
+        Some(Action::InstallCert) => "The drill CA has to be created and trusted; approve the prompt if one appears",
+        Some(Action::InstallNode) => "The proxy needs Node.js; install it or reinstall the app, then check again",
+        None => "Setup is complete",
+    }
+    .to_string()
+}
+
+/// Verify the setup steps in order up to the first incomplete one and, with
+/// `take`, perform that one and verify again. The verified steps are
+/// recorded in setup.json.
+pub fn check_setup(app: &AppHandle, state: &ProxyState, take: bool) -> Result<SetupState, ProxyError> {
+    let file = path(&app_data_dir(app)?);
+    let mut progress = load(&file);
+    let (setup_state, changed) = advance(&SetupMachine { app, state }, &mut progress, take, now_secs());
+    if changed {
+        if let Err(e) = save(&file, &progress) {
+            log_action(state, &format!("Recording setup progress failed: {}", e));
+        }
+    }
+    Ok(setup_state)
+}
+
+/// The setup steps as they are on this machine.
+struct SetupMachine<'a> {
+    app: &'a AppHandle,
+    state: &'a ProxyState,
+}
+
+impl Machine for SetupMachine<'_> {
+    fn verify(&self, step: Step, progress: &Progress) -> Result<(), ProxyError> {
+        let (app, state) = (self.app, self.state);
+        match step {
+            Step::NeedsElevation => {
+                let probe = state.hosts.probe_writable();
+                match probe.access {
+                    hosts::WriteAccess::Writable => Ok(()),
+                    // Admin rights are still all that's missing, and the prompt provides them
+                    hosts::WriteAccess::NeedsElevation if progress.elevation_granted(now_secs()) => Ok(()),
+                    _ => Err(probe
+                        .error
+                        .unwrap_or_else(|| ProxyError::Hosts("The hosts file can't be written".into()))),
+                }
+            }
+            Step::NeedsCert => {
+                let dir = certs_path(app)?;
+                let domains = state.inner.lock().domains.clone();
+                if !cert::exists(&dir) {
+                    return Err(ProxyError::Cert("No CA has been generated yet".into()));
+                }
+                ensure_cert_current(&dir)?;
+                if !cert::covers(&dir, &domains) {
+                    return Err(ProxyError::Cert(format!(
+                        "The server certificate is not issued for all of {}",
+                        domains.join(", ")
+                    )));
+                }
+                if !cert::is_installed(&dir) {
+                    return Err(ProxyError::Cert("The CA certificate is not installed".into()));
+                }
+                Ok(())
+            }
+            Step::NeedsNode => resolve_launcher(app, state).map(drop),
+            Step::Ready => Ok(()),
+        }
+    }
+
+    fn take(&self, step: Step, progress: &mut Progress) -> Result<(), ProxyError> {
+        let result = self.take_step(step, progress);
+        if let Err(e) = &result {
+            log_action(self.state, &format!("Setup step failed: {}", e));
+        }
+        result
+    }
+}
+
+impl SetupMachine<'_> {
+    fn take_step(&self, step: Step, progress: &mut Progress) -> Result<(), ProxyError> {
+        let (app, state) = (self.app, self.state);
+        match step {
+            Step::NeedsElevation => {
+                // Nothing the prompt can fix; the caller checks again
+                if state.hosts.probe_writable().access != hosts::WriteAccess::NeedsElevation {
+                    return Ok(());
+                }
+                // A prompt for a no-op: the hosts file isn't touched, so there is nothing to lock
+                elevate::probe().map_err(|e| {
+                    ProxyError::NotElevated(format!("The elevation prompt failed or was declined: {}", e))
+                })?;
+                progress.elevation_granted_at = Some(now_secs());
+                log_action(state, "Setup: the elevation prompt was granted");
+                Ok(())
+            }
+            Step::NeedsCert => {
+                let dir = ensure_ca(app)?;
+                let domains = state.inner.lock().domains.clone();
+                if ensure_cert_current(&dir).is_err() || !cert::covers(&dir, &domains) {
+                    regenerate_cert(None, app.clone(), app.state::<ProxyState>())?;
+                }
+                if !cert::is_installed(&dir) {
+                    install_cert(app.clone(), app.state::<ProxyState>())?;
+                }
+                Ok(())
+            }
+            // Only the user can install a runtime
+            Step::NeedsNode | Step::Ready => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    const NOW: u64 = 1_800_000_000;
+
+    /// Steps in `missing` fail to verify; taking one completes it, unless it
+    /// is in `stuck`. Elevation counts as granted the way lib.rs counts it.
+    #[derive(Default)]
+    struct Fake {
+        missing: RefCell<Vec<Step>>,
+        stuck: Vec<Step>,
+        taken: RefCell<Vec<Step>>,
+    }
+
+    impl Fake {
+        fn missing(steps: &[Step]) -> Self {
+            Fake { missing: RefCell::new(steps.to_vec()), ..Fake::default() }
+        }
+    }
+
+    impl Machine for Fake {
+        fn verify(&self, step: Step, progress: &Progress) -> Result<(), ProxyError> {
+            if step == Step::NeedsElevation && progress.elevation_granted(NOW) {
+                return Ok(());
+            }
+            match step {
+                _ if !self.missing.borrow().contains(&step) => Ok(()),
+                Step::NeedsElevation => Err(ProxyError::NotElevated("needs admin".into())),
+                Step::NeedsCert => Err(ProxyError::Cert("no CA".into())),
+                _ => Err(ProxyError::Internal("no runtime".into())),
+            }
+        }
+
+        fn take(&self, step: Step, progress: &mut Progress) -> Result<(), ProxyError> {
+            self.taken.borrow_mut().push(step);
+            if self.stuck.contains(&step) {
+                return Err(ProxyError::Spawn("the step failed".into()));
+            }
+            if step == Step::NeedsElevation {
+                progress.elevation_granted_at = Some(NOW);
+            } else {
+                self.missing.borrow_mut().retain(|s| *s != step);
+            }
+            Ok(())
+        }
+    }
+
+    fn steps(state: &SetupState) -> Vec<Step> {
+        state.completed.iter().map(|c| c.step).collect()
+    }
+
+    #[test]
+    fn nothing_missing_is_ready() {
+        let mut progress = Progress::default();
+        let (state, changed) = advance(&Fake::default(), &mut progress, false, NOW);
+        assert!(state.step == Step::Ready);
+        assert!(state.action.is_none() && state.error.is_none() && !state.advanced);
+        assert!(steps(&state) == Step::ORDER);
+        assert!(changed, "the first verification is recorded");
+
+        let (_, changed) = advance(&Fake::default(), &mut progress, false, NOW);
+        assert!(!changed, "nothing new to record");
+    }
+
+    #[test]
+    fn checking_never_takes_a_step() {
+        let fake = Fake::missing(&[Step::NeedsCert]);
+        let (state, _) = advance(&fake, &mut Progress::default(), false, NOW);
+        assert!(state.step == Step::NeedsCert);
+        assert!(state.action == Some(Action::InstallCert));
+        assert!(steps(&state) == [Step::NeedsElevation]);
+        assert!(fake.taken.borrow().is_empty());
+    }
+
+    #[test]
+    fn granting_elevation_moves_on_to_the_cert() {
+        let fake = Fake::missing(&[Step::NeedsElevation, Step::NeedsCert]);
+        let mut progress = Progress::default();
+        let (state, _) = advance(&fake, &mut progress, false, NOW);
+        assert!(state.action == Some(Action::GrantElevation));
+
+        let (state, changed) = advance(&fake, &mut progress, true, NOW);
+        assert!(state.advanced && changed);
+        assert!(state.step == Step::NeedsCert);
+        assert_eq!(progress.elevation_granted_at, Some(NOW));
+        assert!(*fake.taken.borrow() == [Step::NeedsElevation]);
+    }
+
+    #[test]
+    fn installing_the_cert_moves_on_to_node_then_ready() {
+        let fake = Fake::missing(&[Step::NeedsCert, Step::NeedsNode]);
+        let mut progress = Progress::default();
+        let (state, _) = advance(&fake, &mut progress, true, NOW);
+        assert!(state.advanced && state.step == Step::NeedsNode);
+        assert!(state.action == Some(Action::InstallNode));
+
+        let (state, _) = advance(&fake, &mut progress, true, NOW);
+        assert!(state.advanced && state.step == Step::Ready);
+        assert!(steps(&state) == Step::ORDER);
+        assert!(*fake.taken.borrow() == [Step::NeedsCert, Step::NeedsNode]);
+    }
+
+    #[test]
+    fn a_failed_step_reports_its_own_error() {
+        let fake = Fake { stuck: vec![Step::NeedsCert], ..Fake::missing(&[Step::NeedsCert]) };
+        let (state, changed) = advance(&fake, &mut Progress::default(), true, NOW);
+        assert!(!state.advanced && state.step == Step::NeedsCert);
+        assert_eq!(state.error.map(|e| e.code()), Some("SPAWN_FAILED"));
+        assert!(changed, "setup_next always records");
+    }
+
+    #[test]
+    fn other_hosts_problems_ask_for_a_fix_not_the_prompt() {
+        let denied = ProxyError::HostsAclDenied("security software".into());
+        let not_elevated = ProxyError::NotElevated("admin".into());
+        assert!(action(Step::NeedsElevation, Some(&denied)) == Some(Action::FixHostsAccess));
+        assert!(action(Step::NeedsElevation, Some(&not_elevated)) == Some(Action::GrantElevation));
+        assert!(action(Step::Ready, None).is_none());
+    }
+
+    #[test]
+    fn a_step_undone_since_drops_back() {
+        let fake = Fake::default();
+        let mut progress = Progress::default();
+        advance(&fake, &mut progress, false, NOW);
+        fake.missing.borrow_mut().push(Step::NeedsCert);
+        let (state, changed) = advance(&fake, &mut progress, false, NOW + 60);
+        assert!(changed && state.step == Step::NeedsCert);
+        assert!(steps(&state) == [Step::NeedsElevation]);
+        assert!(progress.completed.iter().all(|c| c.at == NOW + 60));
+    }
+
+    #[test]
+    fn elevation_grant_expires() {
+        let progress = Progress { elevation_granted_at: Some(NOW), ..Progress::default() };
+        assert!(progress.elevation_granted(NOW));
+        assert!(progress.elevation_granted(NOW + ELEVATION_GRANT_TTL_SECS - 1));
+        assert!(!progress.elevation_granted(NOW + ELEVATION_GRANT_TTL_SECS));
+        assert!(!progress.elevation_granted(NOW - 1), "a grant from the future doesn't count");
+        assert!(!Progress::default().elevation_granted(NOW));
+    }
+
+    #[test]
+    fn an_expired_grant_puts_setup_back_at_elevation() {
+        let fake = Fake::missing(&[Step::NeedsElevation]);
+        let granted = NOW - ELEVATION_GRANT_TTL_SECS;
+        let mut progress = Progress { elevation_granted_at: Some(granted), ..Progress::default() };
+        let (state, _) = advance(&fake, &mut progress, false, NOW);
+        assert!(state.step == Step::NeedsElevation);
+    }
+
+    #[test]
+    fn forget_elevation_clears_the_recorded_grant() {
+        let dir = std::env::temp_dir().join(format!("cyber-drill-setup-{}", std::process::id()));
+        let path = path(&dir);
+        let progress = Progress { elevation_granted_at: Some(NOW), ..Progress::default() };
+        save(&path, &progress).unwrap();
+        forget_elevation(&path).unwrap();
+        assert_eq!(load(&path).elevation_granted_at, None);
+        forget_elevation(&path).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
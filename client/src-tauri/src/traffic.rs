@@ -0,0 +1,230 @@
+//! Per-request summaries relayed by local-proxy.js, kept for the drill report
+//! session_export writes. The store is capped; what falls off is counted so
+//! the report can say it is incomplete.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// At a few hundred bytes each, about 5 MB.
+pub const MAX_ENTRIES: usize = 20_000;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestSummary {
+    /// Unix milliseconds the request arrived.
+    pub ts: u64,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub status: u16,
+    /// Request body size.
+    pub bytes_in: u64,
+    /// Response body size.
+    pub bytes_out: u64,
+    #[serde(default)]
+    pub duration_ms: u64,
+}
+
+/// The requests of the current drill, oldest first.
+pub struct TrafficLog {
+    entries: VecDeque<RequestSummary>,
+    /// Entries dropped from the front to stay within MAX_ENTRIES.
+    dropped: u64,
+    /// Unix seconds proxy_run started the drill.
+    started_at: Option<u64>,
+}
+
+impl TrafficLog {
+    pub fn new() -> Self {
+        TrafficLog { entries: VecDeque::new(), dropped: 0, started_at: None }
+    }
+
+    pub fn started_at(&self) -> Option<u64> {
+        self.started_at
+    }
+
+    pub fn push(&mut self, entry: RequestSummary) {
+        if self.entries.len() == MAX_ENTRIES {
+            self.entries.pop_front();
+            self.dropped += 1;
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &RequestSummary> {
+        self.entries.iter()
+    }
+
+    /// Forget the previous drill's requests.
+    pub fn restart(&mut self, started_at: u64) {
+        self.entries.clear();
+        self.dropped = 0;
+        self.started_at = Some(started_at);
+    }
+}
+
+pub type SharedTraffic = Arc<Mutex<TrafficLog>>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Har,
+    Json,
+    Csv,
+}
+
+/// Which drill the requests belong to.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    /// Empty in passthrough mode.
+    pub gateway_url: String,
+    /// Unix seconds.
+    pub started_at: Option<u64>,
+    /// Unix seconds; None while the proxy still runs.
+    pub stopped_at: Option<u64>,
+    pub hostname: Option<String>,
+    /// Whether interception held through the drill.
+    pub uptime: crate::uptime::Report,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportResult {
+    pub path: String,
+    pub entries: usize,
+    /// Requests that fell out of the store and are missing from the file.
+    pub dropped: u64,
+}
+
+/// Write `log` to `path` as `format`.
+pub fn export(log: &TrafficLog, session: &SessionInfo, format: Format, path: &Path) -> Result<ExportResult, String> {
+    let body = match format {
+        Format::Har => serde_json::to_string_pretty(&har(log, session)).map_err(|e| e.to_string())?,
+        Format::Json => serde_json::to_string_pretty(&json!({
+            "session": session,
+            "truncated": log.dropped > 0,
+            "droppedEntries": log.dropped,
+            "requests": log.entries,
+        }))
+        .map_err(|e| e.to_string())?,
+        Format::Csv => csv(log, session),
+    };
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| format!("Create {}: {}", dir.display(), e))?;
+    }
+    fs::write(path, body).map_err(|e| format!("Write {}: {}", path.display(), e))?;
+    Ok(ExportResult { path: path.display().to_string(), entries: log.entries.len(), dropped: log.dropped })
+}
+
+/// HAR 1.2 without headers or bodies, which the proxy doesn't relay. The
+/// session and any truncation go in the log's comment.
+fn har(log: &TrafficLog, session: &SessionInfo) -> serde_json::Value {
+    let mut comment = format!(
+        "Drill on {} via gateway {}",
+        session.hostname.as_deref().unwrap_or("unknown host"),
+        if session.gateway_url.is_empty() { "(passthrough)" } else { session.gateway_url.as_str() }
+    );
+    if log.dropped > 0 {
+        comment.push_str(&format!("; truncated: the first {} requests were dropped", log.dropped));
+    }
+    let entries: Vec<serde_json::Value> = log
+        .entries
+        .iter()
+        .map(|e| {
+            json!({
+                "startedDateTime": rfc3339_millis(e.ts),
+                "time": e.duration_ms,
+                "request": {
+                    "method": e.method,
+                    "url": format!("https://{}{}", e.host, e.path),
+                    "httpVersion": "HTTP/1.1",
+                    "cookies": [],
+                    "headers": [],
+                    "queryString": [],
+                    "headersSize": -1,
+                    "bodySize": e.bytes_in,
+                },
+                "response": {
+                    "status": e.status,
+                    "statusText": "",
+                    "httpVersion": "HTTP/1.1",
+                    "cookies": [],
+                    "headers": [],
+                    "content": { "size": e.bytes_out, "mimeType": "" },
+                    "redirectURL": "",
+                    "headersSize": -1,
+                    "bodySize": e.bytes_out,
+                },
+                "cache": {},
+                "timings": { "send": 0, "wait": e.duration_ms, "receive": 0 },
+            })
+        })
+        .collect();
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": { "name": "wind-client", "version": env!("CARGO_PKG_VERSION") },
+            "comment": comment,
+            "_session": session,
+            "entries": entries,
+        }
+    })
+}
+
+/// The session as `#` comment lines, then one row per request.
+fn csv(log: &TrafficLog, session: &SessionInfo) -> String {
+    let mut out = String::new();
+    out.push_str(&format!("# gateway: {}\n", session.gateway_url));
+    out.push_str(&format!("# started: {}\n", session.started_at.map(|t| rfc3339_millis(t * 1000)).unwrap_or_default()));
+    out.push_str(&format!("# stopped: {}\n", session.stopped_at.map(|t| rfc3339_millis(t * 1000)).unwrap_or_default()));
+    out.push_str(&format!("# hostname: {}\n", session.hostname.as_deref().unwrap_or_default()));
+    if let Some(percent) = session.uptime.availability_percent {
+        out.push_str(&format!("# availability: {:.1}% of {} samples\n", percent, session.uptime.total));
+    }
+    for s in session.uptime.samples.iter().filter(|s| !s.ok) {
+        out.push_str(&format!(
+            "# interception failed: {} {}\n",
+            rfc3339_millis(s.at * 1000),
+            s.error.as_deref().unwrap_or_default()
+        ));
+    }
+    if log.dropped > 0 {
+        out.push_str(&format!("# truncated: {} earlier requests dropped\n", log.dropped));
+    }
+    out.push_str("timestamp,method,host,path,status,bytes_in,bytes_out,duration_ms\n");
+    for e in &log.entries {
+        out.push_str(&format!(
+            "{},{},{},{},{},{},{},{}\n",
+            rfc3339_millis(e.ts),
+            csv_field(&e.method),
+            csv_field(&e.host),
+            csv_field(&e.path),
+            e.status,
+            e.bytes_in,
+            e.bytes_out,
+            e.duration_ms
+        ));
+    }
+    out
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn rfc3339_millis(ms: u64) -> String {
+    time::OffsetDateTime::from_unix_timestamp_nanos(ms as i128 * 1_000_000)
+        .ok()
+        .and_then(|t| t.format(&time::format_description::well_known::Rfc3339).ok())
+        .unwrap_or_default()
+}
@@ -0,0 +1,185 @@
+//! The tray icon: whether interception is on, visible while the window is
+//! minimized, plus Start, Stop and Restore & Quit, which run through the
+//! same functions as proxy_run, proxy_stop and proxy_restore.
+
+use crate::error::ProxyError;
+use crate::{
+    log_action, restore, run_proxy, stop, AppHandle, ProxyState, ProxyStatus, RestoreOptions, RunOptions, Runtime,
+    StopOptions, DEFAULT_READY_TIMEOUT,
+};
+use tauri::image::Image;
+use tauri::menu::{Menu, MenuItem, PredefinedMenuItem};
+use tauri::tray::TrayIconBuilder;
+use tauri::Manager;
+
+const TRAY_ID: &str = "main";
+const ICON_SIZE: u32 = 32;
+const TITLE: &str = "Wind Client";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Indicator {
+    Active,
+    Inactive,
+    /// The proxy and the hosts entries disagree, or the proxy stopped
+    /// answering.
+    Error,
+}
+
+#[derive(Clone, Copy)]
+pub enum Action {
+    Start,
+    Stop,
+    RestoreAndQuit,
+}
+
+impl Action {
+    pub fn label(self) -> &'static str {
+        match self {
+            Action::Start => "Start",
+            Action::Stop => "Stop",
+            Action::RestoreAndQuit => "Restore & Quit",
+        }
+    }
+}
+
+/// The menu items that change with the state, kept as managed state.
+pub struct Tray {
+    status: MenuItem<Runtime>,
+    start: MenuItem<Runtime>,
+    stop: MenuItem<Runtime>,
+}
+
+impl Tray {
+    pub fn update(&self, app: &AppHandle, indicator: Indicator, status: &str, running: bool) {
+        let _ = self.status.set_text(status);
+        let _ = self.start.set_enabled(!running);
+        let _ = self.stop.set_enabled(running);
+        if let Some(tray) = app.tray_by_id(TRAY_ID) {
+            let _ = tray.set_icon(Some(icon(indicator)));
+            let _ = tray.set_tooltip(Some(format!("{}: {}", TITLE, status)));
+        }
+    }
+}
+
+/// Add the tray icon, starting out inactive. `on_action` is called on the
+/// event loop, so it shouldn't block.
+pub fn create(app: &AppHandle, on_action: impl Fn(&AppHandle, Action) + Send + Sync + 'static) -> tauri::Result<()> {
+    let status = MenuItem::with_id(app, "status", "Interception inactive", false, None::<&str>)?;
+    let start = MenuItem::with_id(app, "start", Action::Start.label(), true, None::<&str>)?;
+    let stop = MenuItem::with_id(app, "stop", Action::Stop.label(), false, None::<&str>)?;
+    let quit = MenuItem::with_id(app, "restore_quit", Action::RestoreAndQuit.label(), true, None::<&str>)?;
+    let separator = PredefinedMenuItem::separator(app)?;
+    let menu = Menu::with_items(app, &[&status, &separator, &start, &stop, &quit])?;
+    TrayIconBuilder::with_id(TRAY_ID)
+        .icon(icon(Indicator::Inactive))
+        .tooltip(format!("{}: interception inactive", TITLE))
+        .menu(&menu)
+        .on_menu_event(move |app, event| {
+            let action = match event.id().as_ref() {
+                "start" => Action::Start,
+                "stop" => Action::Stop,
+                "restore_quit" => Action::RestoreAndQuit,
+                _ => return,
+            };
+            on_action(app, action);
+        })
+        .build(app)?;
+    app.manage(Tray { status, start, stop });
+    Ok(())
+}
+
+/// A dot in the indicator's colour, drawn here rather than shipped as one
+/// image file per state.
+fn icon(indicator: Indicator) -> Image<'static> {
+    let [r, g, b] = match indicator {
+        Indicator::Active => [0x2e, 0xb8, 0x4b],
+        Indicator::Inactive => [0x8a, 0x8f, 0x98],
+        Indicator::Error => [0xe0, 0x3e, 0x3e],
+    };
+    let center = (ICON_SIZE as f32 - 1.0) / 2.0;
+    let radius = ICON_SIZE as f32 / 2.0 - 1.0;
+    let mut rgba = Vec::with_capacity((ICON_SIZE * ICON_SIZE * 4) as usize);
+    for y in 0..ICON_SIZE {
+        for x in 0..ICON_SIZE {
+            let distance = (x as f32 - center).hypot(y as f32 - center);
+            // Half a pixel of antialiasing at the edge
+            let alpha = (radius - distance + 0.5).clamp(0.0, 1.0);
+            rgba.extend_from_slice(&[r, g, b, (alpha * 255.0) as u8]);
+        }
+    }
+    Image::new_owned(rgba, ICON_SIZE, ICON_SIZE)
+}
+
+/// The tray's indicator and status line for `status`.
+pub fn state(status: &ProxyStatus) -> (Indicator, String) {
+    if status.hosts_error.is_some() {
+        return (Indicator::Error, "Hosts file unreadable".into());
+    }
+    // Only a child we started is polled; an adopted proxy never counts as stalled
+    let stalled = status.pid.is_some() && status.health_check.is_some() && !status.healthy;
+    match (status.proxy_running, status.hosts_modified) {
+        (true, true) if stalled => (Indicator::Error, "Proxy not responding".into()),
+        (true, true) => (Indicator::Active, format!("Interception active on port {}", status.port)),
+        (true, false) => (Indicator::Error, "Proxy running, hosts entries missing".into()),
+        (false, true) => (Indicator::Error, "Hosts entries left without a proxy".into()),
+        (false, false) => (Indicator::Inactive, "Interception inactive".into()),
+    }
+}
+
+/// proxy_run without arguments, except that the gateway, domains and
+/// upstream proxy are those of the last run: the tray's Start.
+fn last_run_options(state: &ProxyState) -> RunOptions {
+    let config = state.config.lock().clone();
+    let (gateway_url, profile, upstream_proxy, routes, bypass_paths, port, domains) = {
+        let inner = state.inner.lock();
+        let (routes, bypass_paths) = (inner.routes.clone(), inner.bypass_paths.clone());
+        let (port, domains) = (inner.port, inner.domains.clone());
+        let (gateway_url, profile) = (inner.gateway_url.clone(), inner.profile.clone());
+        (gateway_url, profile, inner.upstream_proxy.clone(), routes, bypass_paths, port, domains)
+    };
+    RunOptions {
+        gateway_url: gateway_url.or(config.gateway_url).unwrap_or_default(),
+        profile,
+        upstream_proxy: upstream_proxy.or(config.upstream_proxy).unwrap_or_default(),
+        port,
+        domains,
+        routes,
+        bypass_paths,
+        auto_restart: config.auto_restart,
+        check_gateway: false,
+        ready_timeout: DEFAULT_READY_TIMEOUT,
+        duration_minutes: None,
+        skip_integrity_check: false,
+        dry_run: false,
+        allow_large_hosts: false,
+        replay_path: None,
+    }
+}
+
+/// A tray menu action, run on the blocking pool through the same functions
+/// as proxy_run, proxy_stop and proxy_restore. Failures only go to the log;
+/// the tray's status line shows where things ended up.
+pub fn on_action(app: &AppHandle, action: Action) {
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<ProxyState>();
+        let _operation = state.operation.blocking_lock();
+        let result = match action {
+            Action::Start => run_proxy(&app, &state, last_run_options(&state)).map(|_| ()),
+            Action::Stop => stop(StopOptions::default(), app.clone(), app.state()).map(|_| ()),
+            // Quit only once restored; exit_cleanup would otherwise retry the
+            // same removal on the way out and likely fail the same way
+            Action::RestoreAndQuit => match restore(RestoreOptions::default(), app.clone(), app.state()) {
+                Ok(result) if result.stop.ok => {
+                    app.exit(0);
+                    Ok(())
+                }
+                Ok(result) => Err(ProxyError::InvalidState(result.stop.message)),
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            log_action(&state, &format!("Tray {} failed: {}", action.label(), e));
+        }
+    });
+}
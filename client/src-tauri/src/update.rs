@@ -0,0 +1,246 @@
+//! Updates to local-proxy.js between app releases. A manifest at
+//! config.updateManifestUrl names the latest script's version, URL, SHA-256
+//! and an ed25519 signature over `version\nsha256`, made with
+//! scripts/sign-proxy-update.mjs; the public key is compiled in from
+//! PROXY_UPDATE_KEY. An applied update lives under the app data dir and wins
+//! over the shipped copy as long as it still verifies.
+//!
+//! The record (update.json) is the only thing ever replaced in place, by a
+//! rename, and only once the script it names has been downloaded and checked.
+//! A failed or tampered download therefore leaves the working script alone.
+
+use crate::error::ProxyError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use url::Url;
+
+/// Under the app data dir.
+pub const DIR: &str = "proxy-update";
+const RECORD_FILE: &str = "update.json";
+const STAGING_DIR: &str = "staging";
+const SCRIPT_PREFIX: &str = "local-proxy-";
+const PUBLIC_KEY: Option<&str> = option_env!("PROXY_UPDATE_KEY");
+const FETCH_TIMEOUT: Duration = Duration::from_secs(20);
+const MANIFEST_MAX_BYTES: usize = 64 * 1024;
+const SCRIPT_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// What the manifest says, and what update.json keeps for the installed one.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub version: String,
+    /// https only.
+    pub url: String,
+    /// Lowercase hex SHA-256 of the script.
+    pub sha256: String,
+    /// Base64 ed25519 signature over `version\nsha256`.
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    #[serde(flatten)]
+    manifest: Manifest,
+    /// The script's file name in DIR.
+    file: String,
+    /// Unix seconds.
+    applied_at: u64,
+}
+
+/// An applied update that still verifies.
+pub struct Installed {
+    pub version: String,
+    pub script: PathBuf,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateCheck {
+    /// The applied update's version, else the shipped script's.
+    pub current_version: Option<String>,
+    pub latest_version: String,
+    /// The manifest offers a newer (or, when versions don't compare,
+    /// different) script.
+    pub available: bool,
+}
+
+/// The applied update in `dir`, checked against its hash and signature.
+/// Ok(None) when there is none; Err when it doesn't verify, in which case
+/// the shipped script should run.
+pub fn installed(dir: &Path) -> Result<Option<Installed>, String> {
+    let bytes = match fs::read(dir.join(RECORD_FILE)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Reading the proxy update record failed: {}", e)),
+    };
+    let record: Record =
+        serde_json::from_slice(&bytes).map_err(|e| format!("The proxy update record is invalid: {}", e))?;
+    let script = dir.join(&record.file);
+    let bytes = fs::read(&script).map_err(|e| format!("Reading {} failed: {}", script.display(), e))?;
+    verify(&record.manifest, &bytes).map_err(|e| format!("The applied proxy update no longer verifies: {}", e))?;
+    Ok(Some(Installed { version: record.manifest.version, script }))
+}
+
+/// Compare the manifest at `manifest_url` with `current_version`.
+pub fn check(manifest_url: &str, upstream: &str, current_version: Option<String>) -> Result<UpdateCheck, ProxyError> {
+    let manifest = fetch_manifest(manifest_url, upstream)?;
+    let available = current_version.as_deref().is_none_or(|current| newer(&manifest.version, current));
+    Ok(UpdateCheck { current_version, latest_version: manifest.version, available })
+}
+
+/// Download the script the manifest names into `dir` and make it the applied
+/// update. Returns the manifest it was applied from.
+pub fn apply(dir: &Path, manifest_url: &str, upstream: &str) -> Result<Manifest, ProxyError> {
+    let manifest = fetch_manifest(manifest_url, upstream)?;
+    let url = Url::parse(&manifest.url)
+        .map_err(|e| ProxyError::UpdateRejected(format!("The manifest's script URL is invalid: {}", e)))?;
+    let script = get(&url, upstream, SCRIPT_MAX_BYTES).map_err(ProxyError::UpdateFailed)?;
+    verify(&manifest, &script).map_err(ProxyError::UpdateRejected)?;
+
+    let io = |what: &str, e: std::io::Error| ProxyError::UpdateFailed(format!("{} failed: {}", what, e));
+    let staging = dir.join(STAGING_DIR);
+    fs::create_dir_all(&staging).map_err(|e| io("Creating the staging dir", e))?;
+    let file = format!("{}{}.js", SCRIPT_PREFIX, &manifest.sha256[..16]);
+    stage(&staging, &dir.join(&file), &script).map_err(|e| io("Installing the script", e))?;
+    let record = Record { manifest: manifest.clone(), file: file.clone(), applied_at: crate::now_secs() };
+    let record = serde_json::to_vec_pretty(&record).map_err(|e| ProxyError::Internal(e.to_string()))?;
+    // The switch-over: until this rename the previous update (or the shipped
+    // script) stays in effect
+    stage(&staging, &dir.join(RECORD_FILE), &record).map_err(|e| io("Recording the update", e))?;
+    remove_stale_scripts(dir, &file);
+    Ok(manifest)
+}
+
+fn fetch_manifest(manifest_url: &str, upstream: &str) -> Result<Manifest, ProxyError> {
+    let url = Url::parse(manifest_url)
+        .map_err(|e| ProxyError::InvalidArgument(format!("Invalid update manifest URL: {}", e)))?;
+    let bytes = get(&url, upstream, MANIFEST_MAX_BYTES).map_err(ProxyError::UpdateFailed)?;
+    let manifest: Manifest = serde_json::from_slice(&bytes)
+        .map_err(|e| ProxyError::UpdateRejected(format!("The update manifest is invalid: {}", e)))?;
+    verify_signature(&manifest).map_err(ProxyError::UpdateRejected)?;
+    Ok(manifest)
+}
+
+/// The signature covers version and hash, so neither can be swapped for
+/// another signed release's.
+fn verify_signature(manifest: &Manifest) -> Result<(), String> {
+    if manifest.sha256.len() != 64 || !manifest.sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("The manifest's sha256 is not a hex SHA-256".into());
+    }
+    let key = PUBLIC_KEY.ok_or("This build has no proxy update key (PROXY_UPDATE_KEY)")?;
+    let key = STANDARD
+        .decode(key.trim())
+        .map_err(|e| format!("The built-in proxy update key is not base64: {}", e))?;
+    let signature = STANDARD
+        .decode(manifest.signature.trim())
+        .map_err(|e| format!("The manifest signature is not base64: {}", e))?;
+    let message = format!("{}\n{}", manifest.version, manifest.sha256.to_ascii_lowercase());
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, key)
+        .verify(message.as_bytes(), &signature)
+        .map_err(|_| "The manifest signature doesn't match".to_string())
+}
+
+fn verify(manifest: &Manifest, script: &[u8]) -> Result<(), String> {
+    verify_signature(manifest)?;
+    let actual = crate::audit::sha256_hex(script);
+    if !actual.eq_ignore_ascii_case(&manifest.sha256) {
+        return Err(format!("The script's SHA-256 is {}, the manifest says {}", actual, manifest.sha256));
+    }
+    Ok(())
+}
+
+/// Write `bytes` to a file in `staging`, flush it to disk and rename it to
+/// `target`, which is on the same volume.
+fn stage(staging: &Path, target: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let name = target.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let temp = staging.join(format!("{}.{}.tmp", name, std::process::id()));
+    let written = fs::File::create(&temp).and_then(|mut f| {
+        f.write_all(bytes)?;
+        f.sync_all()
+    });
+    match written.and_then(|_| fs::rename(&temp, target)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+fn remove_stale_scripts(dir: &Path, keep: &str) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(SCRIPT_PREFIX) && name.ends_with(".js") && name != keep {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Dotted numbers compared numerically; anything else counts as newer when
+/// it differs.
+fn newer(latest: &str, current: &str) -> bool {
+    let parse = |v: &str| v.trim_start_matches('v').split('.').map(str::parse::<u64>).collect::<Result<Vec<_>, _>>();
+    match (parse(latest), parse(current)) {
+        (Ok(latest), Ok(current)) => latest > current,
+        _ => latest != current,
+    }
+}
+
+/// A GET over https (through `upstream` unless it is empty) returning the
+/// body of a 200. HTTP/1.0, so the body is never chunked and ends with the
+/// connection.
+fn get(url: &Url, upstream: &str, limit: usize) -> Result<Vec<u8>, String> {
+    if url.scheme() != "https" {
+        return Err(format!("{} is not https", url));
+    }
+    let host = url.host_str().ok_or_else(|| format!("{} has no host", url))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let tcp = if upstream.is_empty() {
+        crate::gateway::connect_direct(url, host, FETCH_TIMEOUT)
+    } else {
+        crate::upstream::connect(upstream, host, port, FETCH_TIMEOUT)
+    }?;
+    let _ = tcp.set_read_timeout(Some(FETCH_TIMEOUT));
+    let _ = tcp.set_write_timeout(Some(FETCH_TIMEOUT));
+    let connector = native_tls::TlsConnector::new().map_err(|e| format!("TLS setup failed: {}", e))?;
+    let mut tls = connector.connect(host, tcp).map_err(|e| format!("TLS to {} failed: {}", host, e))?;
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let path = &url[url::Position::BeforePath..url::Position::AfterQuery];
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: cyber-drill-safe\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        path, authority
+    );
+    tls.write_all(request.as_bytes()).map_err(|e| format!("Requesting {} failed: {}", url, e))?;
+    let mut response = Vec::new();
+    // Headers on top of the body limit
+    tls.take((limit + 16 * 1024) as u64)
+        .read_to_end(&mut response)
+        .map_err(|e| format!("Reading {} failed: {}", url, e))?;
+
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| format!("{} sent no complete response", url))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("{} answered {:?}", url, status));
+    }
+    let body = response.split_off(split + 4);
+    if body.len() > limit {
+        return Err(format!("{} is larger than {} bytes", url, limit));
+    }
+    Ok(body)
+}
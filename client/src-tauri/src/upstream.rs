@@ -0,0 +1,165 @@
+//! A corporate HTTP proxy between us and the gateway. local-proxy.js and the
+//! gateway check both reach the gateway through a CONNECT tunnel, so the check
+//! sees what the proxy will.
+
+use base64::Engine;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Check `raw` is an http:// proxy URL with a host and return it normalized.
+/// Empty stays empty.
+pub fn normalize(raw: &str) -> Result<String, String> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Ok(String::new());
+    }
+    let url = Url::parse(raw).map_err(|e| format!("Invalid upstream proxy {:?}: {}", raw, e))?;
+    if url.scheme() != "http" {
+        return Err(format!("Invalid upstream proxy {:?}: scheme must be http", raw));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(format!("Invalid upstream proxy {:?}: missing host", raw));
+    }
+    if url.port() == Some(0) {
+        return Err(format!("Invalid upstream proxy {:?}: port must be 1-65535", raw));
+    }
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+/// The system's proxy for https, if one is configured: the WinHTTP setting
+/// on Windows, HTTPS_PROXY / HTTP_PROXY elsewhere.
+pub fn detect() -> Option<String> {
+    let raw = system_setting()?;
+    let raw = if raw.contains("://") { raw } else { format!("http://{}", raw) };
+    normalize(&raw).ok().filter(|url| !url.is_empty())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn system_setting() -> Option<String> {
+    ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|v| !v.trim().is_empty()))
+}
+
+/// `netsh winhttp set proxy` stores its setting as a binary blob: version,
+/// counter and flags DWORDs, then the length-prefixed proxy list.
+#[cfg(target_os = "windows")]
+fn system_setting() -> Option<String> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::System::Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_BINARY};
+    const PROXY_TYPE_PROXY: u32 = 0x2;
+
+    let wide = |s: &str| -> Vec<u16> { std::ffi::OsStr::new(s).encode_wide().chain(Some(0)).collect() };
+    let key = wide(r"Software\Microsoft\Windows\CurrentVersion\Internet Settings\Connections");
+    let value = wide("WinHttpSettings");
+    let mut buf = vec![0u8; 4096];
+    let mut size = buf.len() as u32;
+    // SAFETY: buf holds `size` bytes.
+    let rc = unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            value.as_ptr(),
+            RRF_RT_REG_BINARY,
+            std::ptr::null_mut(),
+            buf.as_mut_ptr().cast(),
+            &mut size,
+        )
+    };
+    if rc != 0 {
+        return None;
+    }
+    let blob = &buf[..size as usize];
+    let dword = |at: usize| blob.get(at..at + 4).and_then(|b| b.try_into().ok()).map(u32::from_le_bytes);
+    if dword(8)? & PROXY_TYPE_PROXY == 0 {
+        return None;
+    }
+    let len = dword(12)? as usize;
+    let list = std::str::from_utf8(blob.get(16..16 + len)?).ok()?;
+    // Either "host:port" or per-scheme "http=host:port;https=host:port"
+    let entries: Vec<&str> = list.split(';').map(str::trim).filter(|e| !e.is_empty()).collect();
+    entries
+        .iter()
+        .find_map(|e| e.strip_prefix("https="))
+        .or_else(|| entries.iter().copied().find(|e| !e.contains('=')))
+        .or_else(|| entries.iter().find_map(|e| e.strip_prefix("http=")))
+        .map(str::to_string)
+}
+
+/// Open a tunnel to `host:port` through `proxy` within `timeout`.
+pub fn connect(proxy: &str, host: &str, port: u16, timeout: Duration) -> Result<TcpStream, String> {
+    let url = Url::parse(proxy).map_err(|e| format!("Invalid upstream proxy {:?}: {}", proxy, e))?;
+    let proxy_host = url.host_str().unwrap_or_default();
+    let proxy_port = url.port_or_known_default().unwrap_or(80);
+    let deadline = Instant::now() + timeout;
+    let remaining = || deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+
+    let addrs = (proxy_host, proxy_port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Cannot resolve upstream proxy {}: {}", proxy_host, e))?;
+    let mut last_error = format!("No addresses for upstream proxy {}", proxy_host);
+    let mut tcp = addrs
+        .into_iter()
+        .find_map(|addr| {
+            TcpStream::connect_timeout(&addr, remaining())
+                .map_err(|e| last_error = format!("Connect to upstream proxy {} failed: {}", addr, e))
+                .ok()
+        })
+        .ok_or(last_error)?;
+    let _ = tcp.set_read_timeout(Some(remaining()));
+    let _ = tcp.set_write_timeout(Some(remaining()));
+
+    let authority = format!("{}:{}", host, port);
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if !url.username().is_empty() {
+        let credentials = format!("{}:{}", url.username(), url.password().unwrap_or_default());
+        let credentials = percent_decode(&credentials);
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        ));
+    }
+    request.push_str("\r\n");
+    tcp.write_all(request.as_bytes()).map_err(|e| format!("Upstream proxy: {}", e))?;
+
+    // Byte by byte so nothing past the response head is consumed
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        match tcp.read(&mut byte) {
+            Ok(0) => return Err("Upstream proxy closed the connection".into()),
+            Ok(_) => head.push(byte[0]),
+            Err(e) => return Err(format!("Upstream proxy: {}", e)),
+        }
+        if head.len() > 8192 {
+            return Err("Upstream proxy sent an oversized response".into());
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let line = head.lines().next().unwrap_or_default();
+    match line.split_whitespace().nth(1) {
+        Some("200") => Ok(tcp),
+        _ => Err(format!("Upstream proxy refused CONNECT {}: {}", authority, line)),
+    }
+}
+
+/// Userinfo comes percent-encoded out of the URL.
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(b) = s.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    out
+}
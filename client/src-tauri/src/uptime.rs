@@ -0,0 +1,95 @@
+//! Evidence that interception held for the whole drill, not only at its
+//! start and end: while the proxy runs, a request to the intercepted domain
+//! is sent the way Windsurf would every SAMPLE_INTERVAL, and whether the
+//! proxy answered it is kept for the report.
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(30);
+/// About eight hours at SAMPLE_INTERVAL.
+pub const MAX_SAMPLES: usize = 1000;
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Sample {
+    /// Unix seconds.
+    pub at: u64,
+    pub domain: String,
+    /// The proxy answered.
+    pub ok: bool,
+    /// Until the answer, or until the request failed or timed out.
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    /// Oldest first.
+    pub samples: Vec<Sample>,
+    pub total: usize,
+    pub succeeded: usize,
+    /// Of the samples kept; None before the first.
+    pub availability_percent: Option<f64>,
+    /// Samples that fell out of the buffer.
+    pub dropped: u64,
+}
+
+/// The samples of the current (or last) drill.
+pub struct UptimeLog {
+    samples: VecDeque<Sample>,
+    dropped: u64,
+}
+
+impl UptimeLog {
+    pub fn new() -> Self {
+        UptimeLog { samples: VecDeque::new(), dropped: 0 }
+    }
+
+    pub fn push(&mut self, sample: Sample) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+            self.dropped += 1;
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Forget the previous drill's samples.
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.dropped = 0;
+    }
+
+    pub fn report(&self) -> Report {
+        let total = self.samples.len();
+        let succeeded = self.samples.iter().filter(|s| s.ok).count();
+        Report {
+            samples: self.samples.iter().cloned().collect(),
+            total,
+            succeeded,
+            availability_percent: (total > 0).then(|| succeeded as f64 * 100.0 / total as f64),
+            dropped: self.dropped,
+        }
+    }
+}
+
+pub type SharedUptime = Arc<Mutex<UptimeLog>>;
+
+/// One request through the system resolver to `domain`, as proxy_selftest's
+/// interception step makes it.
+pub fn sample(domain: &str) -> Sample {
+    let target = domain.to_string();
+    let step = crate::selftest::step("uptime", move || crate::selftest::intercepted_request(&target));
+    let ok = step.outcome == crate::selftest::Outcome::Pass;
+    Sample {
+        at: crate::now_secs(),
+        domain: domain.to_string(),
+        ok,
+        latency_ms: step.duration_ms,
+        error: (!ok).then_some(step.detail),
+    }
+}
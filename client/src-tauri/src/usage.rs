@@ -0,0 +1,61 @@
+//! Memory and CPU of the proxy child, sampled by its monitor so a leak in a
+//! long drill shows up in proxy_status long before the machine swaps.
+
+use serde::Serialize;
+use std::time::Duration;
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+const MIB: u64 = 1024 * 1024;
+
+/// The current child's figures; reset when a new child starts.
+#[derive(Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    pub pid: Option<u32>,
+    /// Unix milliseconds of the last sample; None before the first.
+    pub sampled_at: Option<u64>,
+    /// Resident set size.
+    pub rss_bytes: u64,
+    /// Of one core, so above 100 when it uses several; 0 at the first sample.
+    pub cpu_percent: f32,
+    pub peak_rss_bytes: u64,
+    pub peak_cpu_percent: f32,
+}
+
+impl Usage {
+    pub fn record(&mut self, pid: u32, at: u64, rss_bytes: u64, cpu_percent: f32) {
+        if self.pid != Some(pid) {
+            *self = Usage { pid: Some(pid), ..Usage::default() };
+        }
+        self.sampled_at = Some(at);
+        self.rss_bytes = rss_bytes;
+        self.cpu_percent = cpu_percent;
+        self.peak_rss_bytes = self.peak_rss_bytes.max(rss_bytes);
+        self.peak_cpu_percent = self.peak_cpu_percent.max(cpu_percent);
+    }
+}
+
+/// Kept across samples: CPU usage is measured between two refreshes.
+pub struct Sampler {
+    sys: System,
+    pid: Pid,
+}
+
+impl Sampler {
+    pub fn new(pid: u32) -> Self {
+        Sampler { sys: System::new(), pid: Pid::from_u32(pid) }
+    }
+
+    /// (RSS bytes, CPU percent), or None once the process is gone.
+    pub fn sample(&mut self) -> Option<(u64, f32)> {
+        let refresh = ProcessRefreshKind::nothing().with_memory().with_cpu();
+        self.sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[self.pid]), true, refresh);
+        self.sys.process(self.pid).map(|p| (p.memory(), p.cpu_usage()))
+    }
+}
+
+/// Megabytes from the config as bytes; 0 stays 0 (off).
+pub fn mib(megabytes: u64) -> u64 {
+    megabytes.saturating_mul(MIB)
+}
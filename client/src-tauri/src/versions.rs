@@ -0,0 +1,65 @@
+//! Which build of everything is running, for support: the app, Tauri and the
+//! webview, local-proxy.js, the CA and the stdout protocol.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Optional, next to local-proxy.js; wins over the marker in the script.
+const SCRIPT_VERSION_FILE: &str = "version.json";
+/// A comment line `// proxy-version: 1.0.0` near the top of local-proxy.js.
+const SCRIPT_MARKER: &str = "// proxy-version:";
+const MARKER_SEARCH_LINES: usize = 50;
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Versions {
+    pub app: &'static str,
+    pub tauri: &'static str,
+    /// None if it couldn't be determined (no WebView2 runtime, say).
+    pub webview: Option<String>,
+    /// The shipped script; None if it is missing or carries no version.
+    pub proxy_script: Option<String>,
+    /// An applied update_apply, which proxy_run runs instead.
+    pub proxy_script_update: Option<String>,
+    /// Of the per-install CA; None until it is generated.
+    pub ca_fingerprint: Option<String>,
+    /// The heartbeat protocol spoken with the proxy child.
+    pub protocol: u32,
+}
+
+#[derive(Deserialize)]
+struct VersionFile {
+    version: String,
+}
+
+/// The dirs are None when they can't be located.
+pub fn collect(resources: Option<&Path>, certs_dir: Option<&Path>, update_dir: Option<&Path>) -> Versions {
+    Versions {
+        app: env!("CARGO_PKG_VERSION"),
+        tauri: tauri::VERSION,
+        webview: tauri::webview_version().ok(),
+        proxy_script: resources.and_then(|dir| script_version(&dir.join("proxy"))),
+        proxy_script_update: update_dir
+            .and_then(|dir| crate::update::installed(dir).ok().flatten())
+            .map(|installed| installed.version),
+        ca_fingerprint: certs_dir.and_then(crate::cert::ca_fingerprint),
+        protocol: crate::heartbeat::PROTOCOL_VERSION,
+    }
+}
+
+fn script_version(dir: &Path) -> Option<String> {
+    if let Some(file) = fs::read(dir.join(SCRIPT_VERSION_FILE))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<VersionFile>(&bytes).ok())
+    {
+        return Some(file.version);
+    }
+    let script = fs::read_to_string(dir.join("local-proxy.js")).ok()?;
+    script
+        .lines()
+        .take(MARKER_SEARCH_LINES)
+        .find_map(|line| line.trim().strip_prefix(SCRIPT_MARKER))
+        .map(|version| version.trim().to_string())
+        .filter(|version| !version.is_empty())
+}
@@ -0,0 +1,152 @@
+//! Change notifications for the hosts file. The cached parse behind
+//! proxy_status and the reversion check of a running proxy both key off the
+//! generation counter here instead of reading the file on every round.
+//! OS notifications are used where they work, polling the mtime where not.
+
+use notify::{EventKind, PollWatcher, RecursiveMode, Watcher};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How often the polling fallback compares mtimes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// OS file notifications.
+    Notify,
+    /// A background thread compares mtimes every POLL_INTERVAL.
+    Poll,
+    /// Nothing could be watched; the mtime is compared on every call.
+    Stamp,
+}
+
+/// (mtime, size); None while the file can't be stat'ed.
+type Stamp = Option<(SystemTime, u64)>;
+
+struct Shared {
+    path: PathBuf,
+    generation: AtomicU64,
+    subscribers: Mutex<Vec<mpsc::Sender<()>>>,
+}
+
+impl Shared {
+    fn changed(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        // Receivers that went away are dropped here
+        self.subscribers.lock().retain(|tx| tx.send(()).is_ok());
+    }
+}
+
+pub struct HostsWatch {
+    shared: Arc<Shared>,
+    mode: Mode,
+    /// Kept alive for as long as we watch. Behind a lock only so the app
+    /// state holding this is Sync; it is never touched again.
+    _watcher: Mutex<Option<Box<dyn Watcher + Send>>>,
+    /// Stamp mode: what the file looked like at the last call.
+    last_stamp: Mutex<Stamp>,
+}
+
+impl HostsWatch {
+    /// Watch the hosts file at `path`. The directory is watched rather than
+    /// the file, since writers (ours included) replace the file by rename.
+    pub fn start(path: &Path) -> Self {
+        let shared = Arc::new(Shared {
+            path: path.to_path_buf(),
+            generation: AtomicU64::new(0),
+            subscribers: Mutex::new(Vec::new()),
+        });
+        let (mode, watcher) = match watcher(&shared) {
+            Ok((mode, watcher)) => (mode, Some(watcher)),
+            Err(e) => {
+                eprintln!("Watching {} failed, comparing its mtime instead: {}", path.display(), e);
+                (Mode::Stamp, None)
+            }
+        };
+        HostsWatch { shared, mode, _watcher: Mutex::new(watcher), last_stamp: Mutex::new(stamp(path)) }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Goes up whenever the file may have changed. In Stamp mode this is
+    /// where the check happens.
+    pub fn generation(&self) -> u64 {
+        if self.mode == Mode::Stamp {
+            let now = stamp(&self.shared.path);
+            let mut last = self.last_stamp.lock();
+            if *last != now {
+                *last = now;
+                self.shared.changed();
+            }
+        }
+        self.shared.generation.load(Ordering::SeqCst)
+    }
+
+    /// Receives one message per change. In Stamp mode nothing arrives, so
+    /// receivers should wait with a timeout and check `generation` anyway.
+    pub fn subscribe(&self) -> mpsc::Receiver<()> {
+        let (tx, rx) = mpsc::channel();
+        self.shared.subscribers.lock().push(tx);
+        rx
+    }
+}
+
+fn watcher(shared: &Arc<Shared>) -> Result<(Mode, Box<dyn Watcher + Send>), String> {
+    let dir = shared
+        .path
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .ok_or_else(|| format!("{} has no parent directory", shared.path.display()))?;
+    let name = shared.path.file_name().map(|n| n.to_os_string());
+    let handler = {
+        let shared = shared.clone();
+        move |event: notify::Result<notify::Event>| {
+            let relevant = match event {
+                Ok(event) => {
+                    !matches!(event.kind, EventKind::Access(_))
+                        && (event.paths.is_empty() || event.paths.iter().any(|p| p.file_name() == name.as_deref()))
+                }
+                // A lost or overflowed queue may have hidden a change
+                Err(_) => true,
+            };
+            if relevant {
+                shared.changed();
+            }
+        }
+    };
+
+    // Notifications don't arrive for network shares, which DataBasePath
+    // can point at
+    if !is_remote(&shared.path) {
+        match notify::recommended_watcher(handler.clone()) {
+            Ok(mut watcher) => match watcher.watch(dir, RecursiveMode::NonRecursive) {
+                Ok(()) => return Ok((Mode::Notify, Box::new(watcher) as Box<dyn Watcher + Send>)),
+                Err(e) => eprintln!("Notifications for {} unavailable, polling instead: {}", dir.display(), e),
+            },
+            Err(e) => eprintln!("File notifications unavailable, polling instead: {}", e),
+        }
+    }
+    let config = notify::Config::default().with_poll_interval(POLL_INTERVAL);
+    let mut watcher = PollWatcher::new(handler, config).map_err(|e| e.to_string())?;
+    watcher.watch(dir, RecursiveMode::NonRecursive).map_err(|e| e.to_string())?;
+    Ok((Mode::Poll, Box::new(watcher)))
+}
+
+fn stamp(path: &Path) -> Stamp {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// A UNC path (`\\server\share`, `\\?\UNC\...`).
+fn is_remote(path: &Path) -> bool {
+    let s = path.to_string_lossy();
+    cfg!(target_os = "windows") && (s.starts_with(r"\\?\UNC\") || (s.starts_with(r"\\") && !s.starts_with(r"\\?\")))
+}
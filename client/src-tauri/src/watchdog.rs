@@ -0,0 +1,526 @@
+//! Watching the running proxy: the monitor that restarts it after a crash,
+//! with backoff and a crash-loop guard, and the background checks beside it
+//! (resource use, hosts reverting, uptime and the gateway health poll).
+
+use crate::{
+    audit, cached_hosts, clear_session, config, failure, forget_status, health_target, healthcheck, lastgood,
+    log_action, now_millis, now_secs, process, remove_firewall_rule, remove_redirect, restart_proxy,
+    rollback_hosts_entry, service_state, spawn_proxy, uptime, usage, AppHandle, Failed, Lifecycle, ProxyState,
+    StartGuard, DEFAULT_READY_TIMEOUT, SCHEDULE_POLL_INTERVAL, STDERR_TAIL_LINES,
+};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::{Emitter, Manager};
+
+const EVENT_PROXY_EXITED: &str = "proxy://exited";
+const MONITOR_INTERVAL: Duration = Duration::from_millis(250);
+/// How often the running proxy's hosts entries are checked for reversion
+/// when no change notification wakes the check earlier.
+const HOSTS_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+const EVENT_HOSTS_REVERTED: &str = "hosts://reverted";
+const EVENT_PROXY_RESTARTED: &str = "proxy://restarted";
+const EVENT_PROXY_RESTART_FAILED: &str = "proxy://restart-failed";
+/// The watchdog gave up because the child keeps dying right after starting;
+/// carries the Failed.
+const EVENT_PROXY_CRASH_LOOP: &str = "proxy://crash-loop";
+/// The proxy's resident memory crossed config.memoryWarnMb, or
+/// config.memoryRestartMb and it is being restarted; carries a
+/// ResourceWarningEvent. Sent again only after it dropped below.
+const EVENT_RESOURCE_WARNING: &str = "proxy://resource-warning";
+const RESTART_MAX_ATTEMPTS: u32 = 5;
+pub const RESTART_BACKOFF_CAP: Duration = Duration::from_secs(30);
+/// A child that stayed up this long before crashing resets the attempt count.
+pub const RESTART_STABLE_SECS: u64 = 60;
+/// Exiting within this many seconds of starting, CRASH_LOOP_EXITS times in a
+/// row, is a crash loop; restarting again won't help.
+const CRASH_LOOP_WINDOW_SECS: u64 = 10;
+const CRASH_LOOP_EXITS: u32 = 3;
+
+pub struct Watchdog {
+    pub gateway_url: String,
+    pub port: u16,
+    pub domains: Vec<String>,
+    pub attempts: u32,
+    /// Consecutive exits within CRASH_LOOP_WINDOW_SECS of starting.
+    pub quick_exits: u32,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProxyRestartedEvent {
+    attempt: u32,
+    max_attempts: u32,
+    pid: u32,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProxyRestartFailedEvent {
+    attempts: u32,
+    reason: String,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProxyExitedEvent {
+    code: Option<i32>,
+    signal: Option<i32>,
+    stderr_tail: Vec<String>,
+    /// As in ExitInfo.
+    failure: Option<failure::Failure>,
+}
+
+/// Payload of `proxy://resource-warning`.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceWarningEvent {
+    pid: u32,
+    rss_bytes: u64,
+    threshold_bytes: u64,
+    /// The hard limit was crossed and the watchdog restarts the proxy.
+    restarting: bool,
+}
+
+/// Payload of `hosts://reverted`: the entries that vanished while the proxy ran.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HostsRevertedEvent {
+    missing: Vec<String>,
+}
+
+/// claim_start for a watchdog restart, under the same lock as the check
+/// that the watchdog is still armed: the user may have stopped or restarted
+/// the proxy meanwhile.
+fn claim_watchdog_start(state: &ProxyState) -> Option<StartGuard<'_>> {
+    let service = service_state(state);
+    let mut inner = state.inner.lock();
+    let claimed = inner.watchdog.is_some() && inner.claim_start(service.as_ref()).is_ok();
+    claimed.then(|| StartGuard(state))
+}
+
+/// Reap the child if it has exited on its own, so the state reflects reality
+/// rather than whatever proxy_run last set. Intentional stops take the child
+/// out of the state before killing it, so anything reaped here is unexpected
+/// and gets reported as `proxy://exited`.
+pub fn refresh_child(app: &AppHandle, state: &ProxyState) -> bool {
+    let Some((mut info, pid, started_at)) = state.inner.lock().reap() else {
+        return false;
+    };
+    info.failure = state.logs.lock().failure();
+    clear_session(state);
+
+    let uptime = started_at.map(|t| info.at.saturating_sub(t)).unwrap_or(0);
+    let event = ProxyExitedEvent {
+        code: info.code,
+        signal: info.signal,
+        stderr_tail: state.logs.lock().stderr_tail(STDERR_TAIL_LINES),
+        failure: info.failure.clone(),
+    };
+    let abnormal = info.code != Some(0);
+    let cause = info.failure.as_ref().map(|f| format!(", {:?}: {}", f.kind, f.snippet)).unwrap_or_default();
+    log_action(
+        state,
+        &format!("Proxy exited unexpectedly (code {:?}, signal {:?}{})", info.code, info.signal, cause),
+    );
+    audit(state, audit::Event::ProxyStopped { pid, exit_code: info.code, reason: "exited unexpectedly".into() });
+    state.inner.lock().last_exit = Some(info);
+    let _ = app.emit(EVENT_PROXY_EXITED, event);
+    schedule_restart(app, state, abnormal, uptime);
+    true
+}
+
+/// The watchdog stops trying: record it with the attempts it made, and mark
+/// the proxy failed until the next start or stop.
+fn gave_up(state: &ProxyState, attempts: u32, reason: &str) -> Failed {
+    log_action(state, &format!("Watchdog gave up after {} attempt(s): {}", attempts, reason));
+    let failed = {
+        let mut inner = state.inner.lock();
+        inner.record(Lifecycle::RestartFailed, format!("after {} attempt(s): {}", attempts, reason), None, None);
+        let failed = Failed {
+            at: now_secs(),
+            reason: reason.to_string(),
+            failure: inner.last_exit.as_ref().and_then(|e| e.failure.clone()),
+            crash_loop: false,
+            hosts_removed: false,
+        };
+        inner.failed = Some(failed.clone());
+        failed
+    };
+    audit(state, audit::Event::ProxyRestartFailed { attempts, reason: reason.to_string() });
+    failed
+}
+
+/// Give up on a child that keeps dying right after starting and, unless
+/// configured otherwise, take our hosts block out so Windsurf isn't left
+/// pointed at nothing.
+fn crash_loop(app: &AppHandle, state: &ProxyState, attempts: u32) {
+    let cause = state.inner.lock().last_exit.as_ref().and_then(|e| e.failure.clone());
+    let reason = format!(
+        "Crash loop: exited within {}s of starting {} times in a row{}",
+        CRASH_LOOP_WINDOW_SECS,
+        CRASH_LOOP_EXITS,
+        cause.map(|f| format!(" ({:?}: {})", f.kind, f.snippet)).unwrap_or_default()
+    );
+    let mut failed = gave_up(state, attempts, &reason);
+    failed.crash_loop = true;
+    if state.config.lock().crash_loop_remove_hosts {
+        remove_redirect(state);
+        remove_firewall_rule(state);
+        failed.hosts_removed = rollback_hosts_entry(app, state, "Crash loop");
+    }
+    state.inner.lock().failed = Some(failed.clone());
+    let _ = app.emit(EVENT_PROXY_CRASH_LOOP, failed);
+}
+
+/// Relaunch a crashed proxy with exponential backoff while the watchdog is armed.
+fn schedule_restart(app: &AppHandle, state: &ProxyState, abnormal: bool, uptime: u64) {
+    let mut inner = state.inner.lock();
+    let watchdog = &mut inner.watchdog;
+    let Some(wd) = watchdog.as_mut() else {
+        return;
+    };
+    if !abnormal {
+        *watchdog = None;
+        return;
+    }
+    if uptime >= RESTART_STABLE_SECS {
+        wd.attempts = 0;
+    }
+    wd.quick_exits = if uptime < CRASH_LOOP_WINDOW_SECS { wd.quick_exits + 1 } else { 0 };
+    if wd.quick_exits >= CRASH_LOOP_EXITS {
+        let attempts = wd.attempts;
+        *watchdog = None;
+        drop(inner);
+        crash_loop(app, state, attempts);
+        return;
+    }
+    if wd.attempts >= RESTART_MAX_ATTEMPTS {
+        let attempts = wd.attempts;
+        *watchdog = None;
+        drop(inner);
+        gave_up(state, attempts, "Too many restart attempts");
+        let _ = app.emit(
+            EVENT_PROXY_RESTART_FAILED,
+            ProxyRestartFailedEvent { attempts, reason: "Too many restart attempts".into() },
+        );
+        return;
+    }
+    wd.attempts += 1;
+    let attempt = wd.attempts;
+    let gateway_url = wd.gateway_url.clone();
+    let port = wd.port;
+    let domains = wd.domains.clone();
+    let delay = Duration::from_secs(1 << (attempt - 1)).min(RESTART_BACKOFF_CAP);
+    drop(inner);
+
+    let app = app.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(delay);
+        let state = app.state::<ProxyState>();
+        let _operation = state.operation.blocking_lock();
+        let Some(_start) = claim_watchdog_start(&state) else {
+            return;
+        };
+        match spawn_proxy(&app, &state, &gateway_url, port, &domains, Lifecycle::Restarted) {
+            Ok(pid) => {
+                log_action(&state, &format!("Proxy restarted by watchdog (attempt {})", attempt));
+                let _ = app.emit(
+                    EVENT_PROXY_RESTARTED,
+                    ProxyRestartedEvent { attempt, max_attempts: RESTART_MAX_ATTEMPTS, pid },
+                );
+            }
+            Err(e) => {
+                state.inner.lock().watchdog = None;
+                gave_up(&state, attempt, &e.to_string());
+                let _ = app.emit(
+                    EVENT_PROXY_RESTART_FAILED,
+                    ProxyRestartFailedEvent { attempts: attempt, reason: e.to_string() },
+                );
+            }
+        }
+    });
+}
+
+/// Watch the current child until it exits or the monitor is cancelled, and
+/// sample its memory and CPU every usage::SAMPLE_INTERVAL.
+pub fn spawn_monitor(app: AppHandle, state: &ProxyState) {
+    let cancel = Arc::new(AtomicBool::new(false));
+    if let Some(previous) = state.monitor_cancel.lock().replace(cancel.clone()) {
+        previous.store(true, Ordering::SeqCst);
+    }
+    std::thread::spawn(move || {
+        let mut watch = UsageWatch { sampler: None, next_sample: Instant::now(), warned: false, restarted: false };
+        loop {
+            std::thread::sleep(MONITOR_INTERVAL);
+            if cancel.load(Ordering::SeqCst) {
+                return;
+            }
+            let state = app.state::<ProxyState>();
+            if refresh_child(&app, &state) || state.inner.lock().child.is_none() {
+                return;
+            }
+            if Instant::now() >= watch.next_sample {
+                watch.next_sample = Instant::now() + usage::SAMPLE_INTERVAL;
+                sample_usage(&app, &state, &mut watch);
+            }
+        }
+    });
+}
+
+/// spawn_monitor's usage sampling across rounds.
+struct UsageWatch {
+    sampler: Option<usage::Sampler>,
+    next_sample: Instant,
+    /// `proxy://resource-warning` was sent and memory hasn't dropped since.
+    warned: bool,
+    /// The memory limit restart was tried; once per child is enough.
+    restarted: bool,
+}
+
+/// Record one sample of the child. Crossing config.memoryWarnMb emits
+/// `proxy://resource-warning`; crossing config.memoryRestartMb while the
+/// watchdog is armed restarts the proxy, with the reason in its history.
+fn sample_usage(app: &AppHandle, state: &ProxyState, watch: &mut UsageWatch) {
+    let Some(pid) = state.inner.lock().child.as_ref().map(process::ProxyChild::id) else {
+        return;
+    };
+    let sampler = watch.sampler.get_or_insert_with(|| usage::Sampler::new(pid));
+    let Some((rss, cpu)) = sampler.sample() else {
+        return;
+    };
+    state.usage.lock().record(pid, now_millis(), rss, cpu);
+    let (warn, limit) = {
+        let config = state.config.lock();
+        (usage::mib(config.memory_warn_mb), usage::mib(config.memory_restart_mb))
+    };
+    let over_limit = limit > 0 && rss >= limit && !watch.restarted && state.inner.lock().watchdog.is_some();
+    if warn > 0 && rss >= warn && !watch.warned && !over_limit {
+        watch.warned = true;
+        log_action(state, &format!("Proxy memory at {} MiB, over the {} MiB warning threshold", rss >> 20, warn >> 20));
+        let event = ResourceWarningEvent { pid, rss_bytes: rss, threshold_bytes: warn, restarting: false };
+        let _ = app.emit(EVENT_RESOURCE_WARNING, event);
+    } else if rss < warn {
+        watch.warned = false;
+    }
+    if !over_limit {
+        return;
+    }
+    let _operation = state.operation.blocking_lock();
+    // A stop or restart may have got there first
+    let armed = {
+        let inner = state.inner.lock();
+        inner.pid() == Some(pid) && inner.watchdog.is_some()
+    };
+    if !armed {
+        return;
+    }
+    watch.restarted = true;
+    let reason = format!("memory limit: {} MiB resident, limit {} MiB", rss >> 20, limit >> 20);
+    log_action(state, &format!("Restarting the proxy: {}", reason));
+    let _ = app.emit(
+        EVENT_RESOURCE_WARNING,
+        ResourceWarningEvent { pid, rss_bytes: rss, threshold_bytes: limit, restarting: true },
+    );
+    let (gateway_url, replay, profile) = {
+        let inner = state.inner.lock();
+        (inner.gateway_url.clone().unwrap_or_default(), inner.replay.clone(), inner.profile.clone())
+    };
+    match restart_proxy(app, state, &gateway_url, replay, DEFAULT_READY_TIMEOUT, &reason) {
+        // Same gateway, so it still is the profile's
+        Ok(_) => state.inner.lock().profile = profile,
+        Err(e) => log_action(state, &format!("Memory limit restart failed: {}", e)),
+    }
+}
+
+/// While the proxy runs, check that our hosts entries are still there and
+/// emit `hosts://reverted` when they vanish, so the UI can alert the operator
+/// instead of the drill silently going dark. Emits again only after the
+/// entries have come back in between.
+pub fn spawn_hosts_watch(app: AppHandle, state: &ProxyState, domains: Vec<String>) {
+    let cancel = Arc::new(AtomicBool::new(false));
+    if let Some(previous) = state.hosts_watch_cancel.lock().replace(cancel.clone()) {
+        previous.store(true, Ordering::SeqCst);
+    }
+    let changes = state.hosts_watch.subscribe();
+    std::thread::spawn(move || {
+        let mut reported = false;
+        loop {
+            // Woken by a change; the timeout covers a watch that can't notify
+            let _ = changes.recv_timeout(HOSTS_WATCH_INTERVAL);
+            if cancel.load(Ordering::SeqCst) {
+                return;
+            }
+            let state = app.state::<ProxyState>();
+            if state.inner.lock().idle() {
+                return;
+            }
+            // An unreadable file says nothing either way; try again next round
+            let Ok((hosts, _)) = cached_hosts(&state, &domains) else {
+                continue;
+            };
+            let missing = hosts.missing_entries();
+            if missing.is_empty() {
+                if reported {
+                    log_action(&state, "Hosts entries are back in place");
+                }
+                reported = false;
+            } else if !reported {
+                reported = true;
+                log_action(&state, &format!("Hosts entries reverted while running: {}", missing.join(", ")));
+                let _ = app.emit(EVENT_HOSTS_REVERTED, HostsRevertedEvent { missing });
+            }
+        }
+    });
+}
+
+/// While the proxy runs, every uptime::SAMPLE_INTERVAL send a request to
+/// the first intercepted domain the way Windsurf would and record whether
+/// the proxy answered. A failure isn't only recorded: missing hosts entries
+/// are reported as `hosts://reverted`, and the status is rechecked at once
+/// so `proxy://status` shows what broke.
+pub fn spawn_uptime_sampler(app: AppHandle, state: &ProxyState, domains: Vec<String>) {
+    let cancel = Arc::new(AtomicBool::new(false));
+    if let Some(previous) = state.uptime_cancel.lock().replace(cancel.clone()) {
+        previous.store(true, Ordering::SeqCst);
+    }
+    let Some(domain) = domains.first().cloned() else {
+        return;
+    };
+    std::thread::spawn(move || {
+        let mut failing = false;
+        loop {
+            let due = Instant::now() + uptime::SAMPLE_INTERVAL;
+            while Instant::now() < due && !cancel.load(Ordering::SeqCst) {
+                std::thread::sleep(SCHEDULE_POLL_INTERVAL);
+            }
+            if cancel.load(Ordering::SeqCst) {
+                return;
+            }
+            let state = app.state::<ProxyState>();
+            {
+                let inner = state.inner.lock();
+                if inner.idle() {
+                    return;
+                }
+                // A gateway switch or watchdog restart has the port closed on purpose
+                if inner.restarting {
+                    continue;
+                }
+            }
+            let sample = uptime::sample(&domain);
+            let ok = sample.ok;
+            let error = sample.error.clone().unwrap_or_default();
+            state.uptime.lock().push(sample);
+            if cancel.load(Ordering::SeqCst) {
+                return;
+            }
+            if ok {
+                if failing {
+                    log_action(&state, &format!("Interception of {} works again", domain));
+                }
+                failing = false;
+                continue;
+            }
+            if !failing {
+                log_action(&state, &format!("Interception of {} failed: {}", domain, error));
+            }
+            failing = true;
+            forget_status(&state);
+            if let Ok((hosts, _)) = cached_hosts(&state, &domains) {
+                let missing = hosts.missing_entries();
+                if !missing.is_empty() {
+                    let _ = app.emit(EVENT_HOSTS_REVERTED, HostsRevertedEvent { missing });
+                }
+            }
+        }
+    });
+}
+
+/// While the proxy runs, check its health route every health_interval_secs
+/// and keep the result for proxy_status. Logs when it starts failing and
+/// when it recovers; a certificate mismatch is logged as such, since cert
+/// regeneration rather than a restart fixes it.
+pub fn spawn_health_poll(app: AppHandle, state: &ProxyState) {
+    let cancel = Arc::new(AtomicBool::new(false));
+    if let Some(previous) = state.health_poll_cancel.lock().replace(cancel.clone()) {
+        previous.store(true, Ordering::SeqCst);
+    }
+    std::thread::spawn(move || {
+        let mut failing = false;
+        loop {
+            let interval = app.state::<ProxyState>().config.lock().health_interval_secs.max(1);
+            let due = Instant::now() + Duration::from_secs(interval);
+            while Instant::now() < due && !cancel.load(Ordering::SeqCst) {
+                std::thread::sleep(SCHEDULE_POLL_INTERVAL);
+            }
+            if cancel.load(Ordering::SeqCst) {
+                return;
+            }
+            let state = app.state::<ProxyState>();
+            let restarting = {
+                let inner = state.inner.lock();
+                if inner.idle() {
+                    return;
+                }
+                inner.restarting || inner.child.is_none()
+            };
+            // Between a crash and the watchdog's restart there is nothing to ask
+            if restarting {
+                continue;
+            }
+            let Ok((domain, route, ca)) = health_target(&app, &state) else {
+                continue;
+            };
+            let check = healthcheck::sample(state.inner.lock().port, &domain, &route, &ca);
+            if cancel.load(Ordering::SeqCst) {
+                return;
+            }
+            let error = check.error.as_deref().unwrap_or_default();
+            match (check.ok, failing) {
+                (true, true) => log_action(&state, "Proxy health route answers again"),
+                (false, false) if check.cert_mismatch => {
+                    log_action(&state, &format!("Proxy serves a certificate our CA didn't issue: {}", error))
+                }
+                (false, false) => log_action(&state, &format!("Proxy health route failed: {}", error)),
+                _ => {}
+            }
+            failing = !check.ok;
+            let ok = check.ok;
+            *state.health_check.lock() = Some(check);
+            if ok {
+                remember_good_gateway(&state);
+            }
+        }
+    });
+}
+
+/// Record the running proxy's gateway as the last one seen working once its
+/// health route answers (the caller's check) and the metrics stream has
+/// shown a request it forwarded there succeed.
+fn remember_good_gateway(state: &ProxyState) {
+    let (gateway_url, routes, bypass_paths) = {
+        let inner = state.inner.lock();
+        (inner.gateway_url.clone().unwrap_or_default(), inner.routes.clone(), inner.bypass_paths.clone())
+    };
+    if gateway_url.is_empty() || state.last_good.lock().as_ref().is_some_and(|g| g.gateway_url == gateway_url) {
+        return;
+    }
+    let forwarded = state.traffic.lock().entries().any(|e| {
+        (200..400).contains(&e.status)
+            && !routes.iter().any(|r| r.domain == e.host)
+            && !config::bypassed(&bypass_paths, e.path.split('?').next().unwrap_or_default())
+    });
+    if !forwarded {
+        return;
+    }
+    let last_good = lastgood::LastGood { gateway_url, confirmed_at: now_secs() };
+    if let Some(path) = state.last_good_file.lock().as_deref() {
+        if let Err(e) = lastgood::save(path, &last_good) {
+            log_action(state, &format!("Recording the last working gateway failed: {}", e));
+        }
+    }
+    log_action(state, &format!("Gateway {} confirmed working", last_good.gateway_url));
+    *state.last_good.lock() = Some(last_good);
+}
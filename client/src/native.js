@@ -30,26 +30,110 @@ export async function proxyInitialize() {
   const invoke = await getInvoke();
   if (invoke) return parseResult(await invoke("proxy_initialize"));
   if (window.electronAPI) return window.electronAPI.proxyInitialize();
-  return { hostsModified: false, proxyRunning: false };
+  return { activity: "clean", hostsOrphaned: false, hostsModified: false, proxyRunning: false };
 }
 
-export async function proxyRun(gatewayUrl) {
+export async function proxyRun(gatewayUrl, options = {}) {
   const invoke = await getInvoke();
-  if (invoke) return parseResult(await invoke("proxy_run", { gatewayUrl: gatewayUrl || "" }));
+  if (invoke) return parseResult(await invoke("proxy_run", { gatewayUrl: gatewayUrl ?? null, ...options }));
   if (window.electronAPI) return window.electronAPI.proxyRun(gatewayUrl);
   return { ok: true, message: "simulated" };
 }
 
-export async function proxyStop() {
+export async function proxyRestart(gatewayUrl, options = {}) {
   const invoke = await getInvoke();
-  if (invoke) return parseResult(await invoke("proxy_stop"));
+  if (invoke) return parseResult(await invoke("proxy_restart", { gatewayUrl: gatewayUrl || "", ...options }));
+  return { ok: true, message: "simulated" };
+}
+
+export async function configGet() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("config_get"));
+  return null;
+}
+
+export async function configSet(config) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("config_set", { config }));
+  return config;
+}
+
+export async function autostartGet() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("autostart_get"));
+  return { enabled: false, autorunProxy: false, lastAutorun: null };
+}
+
+export async function autostartSet(enabled, autorunProxy = false) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("autostart_set", { enabled, autorunProxy }));
+  return { enabled, autorunProxy, lastAutorun: null };
+}
+
+export async function serviceInstall() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("service_install"));
+  return { ok: false, message: "not available outside the app", installed: null };
+}
+
+export async function serviceUninstall() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("service_uninstall"));
+  return { ok: false, message: "not available outside the app", installed: null };
+}
+
+export async function profilesList() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("profiles_list"));
+  return [];
+}
+
+export async function profilesSave(name, gatewayUrl, domains, notes) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("profiles_save", { name, gatewayUrl, domains, notes }));
+  return [];
+}
+
+export async function profilesDelete(name) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("profiles_delete", { name }));
+  return [];
+}
+
+export async function auditTail(lines) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("audit_tail", { lines }));
+  return [];
+}
+
+export async function auditExport(path) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("audit_export", { path }));
+  return { ok: true, message: "simulated" };
+}
+
+export async function proxyScheduleStop(minutes) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("proxy_schedule_stop", { minutes }));
+  return { ok: true, message: "simulated" };
+}
+
+export async function proxyCancelSchedule() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("proxy_cancel_schedule"));
+  return { ok: true, message: "simulated" };
+}
+
+export async function proxyStop(options = {}) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("proxy_stop", options));
   if (window.electronAPI) return window.electronAPI.proxyStop();
   return { ok: true, message: "simulated" };
 }
 
-export async function proxyRestore() {
+export async function proxyRestore(options = {}) {
   const invoke = await getInvoke();
-  if (invoke) return parseResult(await invoke("proxy_restore"));
+  if (invoke) return parseResult(await invoke("proxy_restore", options));
   if (window.electronAPI) return window.electronAPI.proxyRestore();
   return { ok: true, message: "simulated" };
 }
@@ -58,5 +142,196 @@ export async function proxyStatus() {
   const invoke = await getInvoke();
   if (invoke) return parseResult(await invoke("proxy_status"));
   if (window.electronAPI) return window.electronAPI.proxyStatus();
-  return { hostsModified: false, proxyRunning: false };
+  return { activity: "clean", hostsOrphaned: false, hostsModified: false, proxyRunning: false };
+}
+
+export async function proxyRestoreBackup() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("proxy_restore_backup"));
+  return { ok: true, message: "simulated" };
+}
+
+export async function proxyRepair(options = {}) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("proxy_repair", options));
+  return { ok: true, message: "simulated" };
+}
+
+export async function proxyInstallCert() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("proxy_install_cert"));
+  return { ok: true, message: "simulated" };
+}
+
+export async function proxyUninstallCert() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("proxy_uninstall_cert"));
+  return { ok: true, message: "simulated" };
+}
+
+export async function certRegenerate(domains) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("cert_regenerate", { domains }));
+  return { ok: true, message: "simulated" };
+}
+
+export async function certRenew() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("cert_renew"));
+  return { ok: true, message: "simulated" };
+}
+
+export async function proxyLogs(lines) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("proxy_logs", { lines }));
+  return [];
+}
+
+export async function sessionExport(path, format = "har") {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("session_export", { path, format }));
+  return { path: null, entries: 0, dropped: 0 };
+}
+
+export async function proxySelftest() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("proxy_selftest"));
+  return { passed: false, steps: [] };
+}
+
+export async function diagnosticsExport(path) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("diagnostics_export", { path }));
+  return { path: null, files: [], omitted: [] };
+}
+
+export async function proxyMetrics() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("proxy_metrics"));
+  return { lastHeartbeat: null, connections: 0, requests: 0, bytesIn: 0, bytesOut: 0, domains: {}, lastMinute: { requests: 0, bytesIn: 0, bytesOut: 0 } };
+}
+
+export async function getLogPath() {
+  const invoke = await getInvoke();
+  if (invoke) return invoke("get_log_path");
+  return null;
+}
+
+export async function hostsListManaged() {
+  const invoke = await getInvoke();
+  if (invoke) return invoke("hosts_list_managed");
+  return [];
+}
+
+export async function hostsPreviewChanges(gatewayDomains) {
+  const invoke = await getInvoke();
+  if (invoke) return invoke("hosts_preview_changes", { gatewayDomains: gatewayDomains ?? null });
+  return { domains: gatewayDomains || [], wouldWrite: false, changes: [] };
+}
+
+export async function proxyHistory(limit) {
+  const invoke = await getInvoke();
+  if (invoke) return invoke("proxy_history", { limit: limit ?? null });
+  return [];
+}
+
+export async function proxyCleanupOrphans(terminate = false) {
+  const invoke = await getInvoke();
+  if (invoke) return invoke("proxy_cleanup_orphans", { terminate });
+  return { orphans: [], terminated: [] };
+}
+
+export async function gatewayCheck(gatewayUrl, healthPath, upstreamProxy) {
+  const invoke = await getInvoke();
+  if (invoke) return invoke("gateway_check", { gatewayUrl: gatewayUrl || "", healthPath, upstreamProxy });
+  return { reachable: false, latencyMs: null, tlsOk: null, statusCode: null, error: "not available outside the app" };
+}
+
+export async function dnsFlush() {
+  const invoke = await getInvoke();
+  if (invoke) return invoke("dns_flush");
+  return { ok: true, message: "simulated" };
+}
+
+export async function isElevated() {
+  const invoke = await getInvoke();
+  if (invoke) return invoke("is_elevated");
+  return false;
+}
+
+export async function setKeepRunningOnExit(keep) {
+  const invoke = await getInvoke();
+  if (invoke) return invoke("set_keep_running_on_exit", { keep });
+}
+
+export async function versions() {
+  const invoke = await getInvoke();
+  if (invoke) return invoke("versions");
+  return { app: null, tauri: null, webview: null, proxyScript: null, caFingerprint: null, protocol: null };
+}
+
+export async function updateCheck() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("update_check"));
+  return { currentVersion: null, latestVersion: null, available: false };
+}
+
+export async function updateApply() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("update_apply"));
+  return { ok: true, message: "simulated" };
+}
+
+export async function dohDisable() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("doh_disable"));
+  return { ok: true, message: "simulated", changed: 0, doh: { enabled: false, resolvers: [] } };
+}
+
+export async function proxyUptimeReport() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("proxy_uptime_report"));
+  return { samples: [], total: 0, succeeded: 0, availabilityPercent: null, dropped: 0 };
+}
+
+export async function setLocale(locale) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("set_locale", { locale }));
+  return { locale: "en", available: ["de", "en"] };
+}
+
+export async function gatewayLastGood() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("gateway_last_good"));
+  return null;
+}
+
+export async function configReset() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("config_reset"));
+  return null;
+}
+
+export async function stateExport(path, passphrase = null) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("state_export", { path, passphrase }));
+  return { path: null, included: [], skipped: [] };
+}
+
+export async function stateImport(path, passphrase = null) {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("state_import", { path, passphrase }));
+  return { imported: [], skipped: [], certInstallNeeded: false };
+}
+
+export async function setupState() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("setup_state"));
+  return { step: "ready", message: "simulated", action: null, error: null, completed: [], advanced: false };
+}
+
+export async function setupNext() {
+  const invoke = await getInvoke();
+  if (invoke) return parseResult(await invoke("setup_next"));
+  return { step: "ready", message: "simulated", action: null, error: null, completed: [], advanced: false };
 }
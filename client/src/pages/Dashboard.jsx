@@ -65,6 +65,8 @@ export default function Dashboard({ user, setUser }) {
     const r = await proxyStop();
     showMsg(isNative() ? "\u4ee3\u7406\u5df2\u505c\u6b62" : "\u4ee3\u7406\u5df2\u505c\u6b62\uff08\u6d4f\u89c8\u5668\u6a21\u62df\uff09");
     setProxyRunning(false);
+    // proxy_stop removes the hosts entries unless asked to keep them
+    setHostsStatus("inactive");
   };
 
   const handleRestore = async () => {
@@ -0,0 +1,46 @@
+/**
+ * Sign the gateway allowlist the client ships (config/gateway-allowlist/allowlist.json)
+ * Usage:
+ *   node scripts/sign-gateway-allowlist.mjs keygen <private-key.pem>
+ *     writes an ed25519 key pair and prints the public key for GATEWAY_ALLOWLIST_KEY
+ *   node scripts/sign-gateway-allowlist.mjs sign <private-key.pem> [allowlist.json]
+ *     writes allowlist.json.sig next to the list
+ * Bump "version" whenever the hosts change; sign after the last edit, the
+ * signature covers the exact bytes of the file.
+ */
+
+import crypto from "node:crypto";
+import fs from "node:fs";
+
+const [command, keyPath, listPath = "config/gateway-allowlist/allowlist.json"] = process.argv.slice(2);
+if (!["keygen", "sign"].includes(command) || !keyPath) {
+  console.error("Usage: node scripts/sign-gateway-allowlist.mjs keygen|sign <private-key.pem> [allowlist.json]");
+  process.exit(1);
+}
+
+// The raw 32-byte key, as the client compiles it in: the last 32 bytes of the SPKI DER
+function rawPublicKey(publicKey) {
+  return publicKey.export({ type: "spki", format: "der" }).subarray(-32).toString("base64");
+}
+
+if (command === "keygen") {
+  if (fs.existsSync(keyPath)) {
+    console.error(`${keyPath} already exists`);
+    process.exit(1);
+  }
+  const { privateKey, publicKey } = crypto.generateKeyPairSync("ed25519");
+  fs.writeFileSync(keyPath, privateKey.export({ type: "pkcs8", format: "pem" }), { mode: 0o600 });
+  console.log(`GATEWAY_ALLOWLIST_KEY=${rawPublicKey(publicKey)}`);
+} else {
+  const privateKey = crypto.createPrivateKey(fs.readFileSync(keyPath));
+  const bytes = fs.readFileSync(listPath);
+  const list = JSON.parse(bytes.toString("utf8"));
+  if (!Number.isInteger(list.version) || !Array.isArray(list.hosts)) {
+    console.error(`${listPath} needs an integer "version" and a "hosts" array`);
+    process.exit(1);
+  }
+  const signature = crypto.sign(null, bytes, privateKey).toString("base64");
+  fs.writeFileSync(`${listPath}.sig`, `${signature}\n`);
+  console.log(`signed ${listPath} (version ${list.version}, ${list.hosts.length} hosts)`);
+  console.log(`public key: ${rawPublicKey(crypto.createPublicKey(privateKey))}`);
+}
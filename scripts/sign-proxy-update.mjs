@@ -0,0 +1,60 @@
+/**
+ * Publish local-proxy.js as an update the client's update_apply accepts
+ * Usage:
+ *   node scripts/sign-proxy-update.mjs keygen <private-key.pem>
+ *     writes an ed25519 key pair and prints the public key for PROXY_UPDATE_KEY
+ *   node scripts/sign-proxy-update.mjs sign <private-key.pem> <script-url> [local-proxy.js] [manifest.json]
+ *     writes the manifest; upload the script unchanged to <script-url>
+ * The version is taken from the "// proxy-version:" line of the script; bump
+ * it with every release, the client only offers newer versions.
+ */
+
+import crypto from "node:crypto";
+import fs from "node:fs";
+
+const [command, keyPath, scriptUrl, scriptPath = "src/local-proxy.js", manifestPath = "proxy-update.json"] =
+  process.argv.slice(2);
+if (!["keygen", "sign"].includes(command) || !keyPath || (command === "sign" && !scriptUrl)) {
+  console.error("Usage: node scripts/sign-proxy-update.mjs keygen <private-key.pem>");
+  console.error("       node scripts/sign-proxy-update.mjs sign <private-key.pem> <script-url> [script] [manifest]");
+  process.exit(1);
+}
+
+// The raw 32-byte key, as the client compiles it in: the last 32 bytes of the SPKI DER
+function rawPublicKey(publicKey) {
+  return publicKey.export({ type: "spki", format: "der" }).subarray(-32).toString("base64");
+}
+
+if (command === "keygen") {
+  if (fs.existsSync(keyPath)) {
+    console.error(`${keyPath} already exists`);
+    process.exit(1);
+  }
+  const { privateKey, publicKey } = crypto.generateKeyPairSync("ed25519");
+  fs.writeFileSync(keyPath, privateKey.export({ type: "pkcs8", format: "pem" }), { mode: 0o600 });
+  console.log(`PROXY_UPDATE_KEY=${rawPublicKey(publicKey)}`);
+} else {
+  if (!scriptUrl.startsWith("https://")) {
+    console.error("The script URL has to be https");
+    process.exit(1);
+  }
+  const privateKey = crypto.createPrivateKey(fs.readFileSync(keyPath));
+  const bytes = fs.readFileSync(scriptPath);
+  const marker = bytes
+    .toString("utf8")
+    .split("\n")
+    .slice(0, 50)
+    .map((line) => line.trim())
+    .find((line) => line.startsWith("// proxy-version:"));
+  const version = marker?.slice("// proxy-version:".length).trim();
+  if (!version) {
+    console.error(`${scriptPath} has no "// proxy-version:" line`);
+    process.exit(1);
+  }
+  const sha256 = crypto.createHash("sha256").update(bytes).digest("hex");
+  // Version and hash together, so neither can be paired with another release's
+  const signature = crypto.sign(null, Buffer.from(`${version}\n${sha256}`), privateKey).toString("base64");
+  fs.writeFileSync(manifestPath, `${JSON.stringify({ version, url: scriptUrl, sha256, signature }, null, 2)}\n`);
+  console.log(`wrote ${manifestPath} for ${scriptPath} ${version} (${sha256})`);
+  console.log(`public key: ${rawPublicKey(crypto.createPublicKey(privateKey))}`);
+}
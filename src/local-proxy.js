@@ -12,7 +12,20 @@
  * 用法：
  *   node src/local-proxy.js                          # 透传到官方后端（抓包模式）
  *   node src/local-proxy.js --gateway http://IP:18790  # 转发到自建网关
+ *   node src/local-proxy.js --port 8443                # 监听其他端口（需另行把 443 转发过来）
+ *   node src/local-proxy.js --domains a.example,b.example  # 拦截多个域名（按 Host 头分发）
+ *   node src/local-proxy.js --domain api.example     # 拦截的默认域名（替代内置的官方域名）
+ *   node src/local-proxy.js --gateway https://gw --bypass '/auth*' --origin-ip a.example=1.2.3.4  # 这些路径直连官方后端
+ *   node src/local-proxy.js --routes '[{"domain":"b.example","gatewayUrl":"https://decoy"}]'  # 按域名转发到各自的网关
+ *   node src/local-proxy.js --ipv6                     # 同时监听 [::1]（hosts 里也写了 ::1 条目）
+ *   node src/local-proxy.js --upstream-proxy http://proxy:8080  # 经企业 HTTP 代理（CONNECT 隧道）连接上游
+ *   node src/local-proxy.js --gateway https://gw --gateway-ca ca.pem    # 网关证书必须由该 CA 签发（不再信任系统根证书）
+ *   node src/local-proxy.js --gateway https://gw --gateway-pin <base64>  # 网关证书公钥（SPKI）的 SHA-256 必须与之相符
+ *   node src/local-proxy.js --health-route /__health     # 本代理自己应答健康检查的路径（默认 /__health）
+ *   node src/local-proxy.js --replay session.json      # 回放模式：按会话导出（JSON）中记录的状态码应答，不连网关
+ *   node src/local-proxy.js --heartbeat                # 每 5 秒向 stdout 写一行 JSON 心跳，并逐请求写摘要（客户端托管时使用）
  */
+// proxy-version: 1.4.0
 
 import https from "node:https";
 import http from "node:http";
@@ -20,6 +33,7 @@ import fs from "node:fs";
 import path from "node:path";
 import { fileURLToPath } from "node:url";
 import tls from "node:tls";
+import crypto from "node:crypto";
 import { Buffer } from "node:buffer";
 
 const __filename = fileURLToPath(import.meta.url);
@@ -28,23 +42,147 @@ const PROJECT_ROOT = path.resolve(__dirname, "..");
 
 // ---- 配置 ----
 const LISTEN_HOST = "127.0.0.1";
-const LISTEN_PORT = 443;
-
-// 原始目标（Windsurf 官方）
+const LISTEN_HOST_V6 = "::1";
+let LISTEN_PORT = 443;
+let LISTEN_IPV6 = false;
+let HEARTBEAT = false;
+const HEARTBEAT_INTERVAL_MS = 5000;
+// 本代理自己应答的健康检查路径（--health-route），不转发
+let HEALTH_ROUTE = "/__health";
+// 与文件头的 proxy-version 标记一致，健康检查响应中上报
+const PROXY_VERSION = (fs.readFileSync(__filename, "utf8").match(/^\/\/ proxy-version:\s*(\S+)/m) || [])[1] || null;
+
+// 原始目标（Windsurf 官方）；仅作未传 --domain/--domains 时的默认值
 const ORIGINAL_HOST = "server.self-serve.windsurf.com";
 const ORIGINAL_PORT = 443;
+// 拦截的域名列表，第一个为默认目标
+let DOMAINS = [ORIGINAL_HOST];
+// --domains 是否给出；--domain 单独使用时只拦截该域名
+let DOMAINS_LISTED = false;
+// 默认目标（--domain），由客户端按配置传入，放在 DOMAINS 首位
+let PRIMARY_DOMAIN = null;
+
+// 各域名的真实 IP（--origin-ip 或启动时解析）
+const resolvedIPs = new Map();
 
 // 解析命令行参数
 const args = process.argv.slice(2);
 let GATEWAY_URL = null;
+// 域名 -> 该域名专用的网关（--routes），优先于 GATEWAY_URL
+const ROUTES = new Map();
+// 不经网关、直连官方后端的路径（--bypass）；以 * 结尾的按前缀匹配
+let BYPASS = [];
+let UPSTREAM_PROXY = null;
+let GATEWAY_CA = null;
+let GATEWAY_PIN = null;
 let CERT_DIR = path.join(PROJECT_ROOT, "certs");
+// 回放模式（--replay）：请求 -> 录制时的状态码，不再转发
+let REPLAY = null;
 for (let i = 0; i < args.length; i++) {
   if (args[i] === "--gateway" && args[i + 1]) {
     GATEWAY_URL = args[i + 1];
   }
+  if (args[i] === "--upstream-proxy" && args[i + 1]) {
+    UPSTREAM_PROXY = new URL(args[i + 1]);
+  }
+  if (args[i] === "--gateway-ca" && args[i + 1]) {
+    GATEWAY_CA = fs.readFileSync(args[i + 1]);
+  }
+  if (args[i] === "--gateway-pin" && args[i + 1]) {
+    GATEWAY_PIN = args[i + 1].replace(/^sha256\//, "");
+  }
   if (args[i] === "--cert-dir" && args[i + 1]) {
     CERT_DIR = args[i + 1];
   }
+  if (args[i] === "--port" && args[i + 1]) {
+    LISTEN_PORT = parseInt(args[i + 1], 10) || LISTEN_PORT;
+  }
+  if (args[i] === "--ipv6") {
+    LISTEN_IPV6 = true;
+  }
+  if (args[i] === "--heartbeat") {
+    HEARTBEAT = true;
+  }
+  if (args[i] === "--health-route" && args[i + 1]) {
+    HEALTH_ROUTE = args[i + 1];
+  }
+  if (args[i] === "--domains" && args[i + 1]) {
+    const list = args[i + 1].split(",").map((d) => d.trim().toLowerCase()).filter(Boolean);
+    if (list.length > 0) {
+      DOMAINS = [...new Set(list)];
+      DOMAINS_LISTED = true;
+    }
+  }
+  if (args[i] === "--domain" && args[i + 1]) {
+    PRIMARY_DOMAIN = args[i + 1].trim().toLowerCase() || null;
+  }
+  if (args[i] === "--bypass" && args[i + 1]) {
+    BYPASS = args[i + 1].split(",").map((p) => p.trim()).filter(Boolean);
+  }
+  if (args[i] === "--origin-ip" && args[i + 1]) {
+    // 客户端已经用公共 DNS 解析好的真实 IP，格式 domain=ip,...
+    for (const pair of args[i + 1].split(",").filter(Boolean)) {
+      const [domain, ip] = pair.split("=");
+      if (domain && ip) resolvedIPs.set(domain.trim().toLowerCase(), ip.trim());
+    }
+  }
+  if (args[i] === "--replay" && args[i + 1]) {
+    REPLAY = loadReplay(args[i + 1]);
+  }
+  if (args[i] === "--routes" && args[i + 1]) {
+    for (const route of JSON.parse(args[i + 1])) {
+      ROUTES.set(route.domain.trim().toLowerCase(), route.gatewayUrl);
+    }
+  }
+}
+if (PRIMARY_DOMAIN) {
+  DOMAINS = [...new Set([PRIMARY_DOMAIN, ...(DOMAINS_LISTED ? DOMAINS : [])])];
+}
+// 有路由的域名总会被拦截，无论 --domains 是否列出
+DOMAINS = [...new Set([...DOMAINS, ...ROUTES.keys()])];
+
+// 会话导出（JSON 格式）只记录了状态码，没有响应体；同一请求录到多次时以最后一次为准
+function loadReplay(file) {
+  const { requests } = JSON.parse(fs.readFileSync(file, "utf8"));
+  const byRequest = new Map();
+  const byPath = new Map();
+  for (const r of requests || []) {
+    if (!r.status || !r.host || !r.path) continue;
+    const host = r.host.toLowerCase();
+    const bare = r.path.split("?")[0];
+    byRequest.set(`${r.method} ${host} ${r.path}`, r.status);
+    byRequest.set(`${r.method} ${host} ${bare}`, r.status);
+    byPath.set(`${host} ${bare}`, r.status);
+  }
+  return { byRequest, byPath, file };
+}
+
+// 录制时的状态码：先按方法、域名和完整路径匹配，再去掉查询串，最后不论方法
+function replayStatus(method, host, url) {
+  const bare = url.split("?")[0];
+  return (
+    REPLAY.byRequest.get(`${method} ${host} ${url}`) ||
+    REPLAY.byRequest.get(`${method} ${host} ${bare}`) ||
+    REPLAY.byPath.get(`${host} ${bare}`) ||
+    null
+  );
+}
+
+// 该域名的请求转发到哪个网关；null 表示透传到官方后端
+function gatewayFor(host) {
+  return ROUTES.get(host) || GATEWAY_URL;
+}
+
+// 与 Rust 侧 config::bypassed 一致，不含查询串
+function isBypassed(url) {
+  const urlPath = url.split("?")[0];
+  return BYPASS.some((p) => (p.endsWith("*") ? urlPath.startsWith(p.slice(0, -1)) : urlPath === p));
+}
+
+// 请求的 Host 头对应的拦截域名；未知 Host 回落到第一个域名
+function targetHost(clientReq) {
+  const host = (clientReq.headers.host || "").replace(/:\d+$/, "").toLowerCase();
+  return DOMAINS.includes(host) ? host : DOMAINS[0];
 }
 
 // TLS 证书
@@ -53,26 +191,70 @@ const tlsOptions = {
   cert: fs.readFileSync(path.join(CERT_DIR, "server.crt")),
 };
 
-// 解析官方域名的真实 IP（因为 hosts 被改了，不能用域名连）
-let resolvedOriginalIP = null;
+// ---- 上游企业代理 ----
+// 所有上游连接（网关和官方后端）都先向代理发 CONNECT 建隧道，http 目标也一样，
+// 与 Rust 侧 gateway_check 的探测方式一致
+function openTunnel(options, callback, secure) {
+  const target = `${options.host}:${options.port}`;
+  const headers = { host: target };
+  if (UPSTREAM_PROXY.username) {
+    const credentials = `${decodeURIComponent(UPSTREAM_PROXY.username)}:${decodeURIComponent(UPSTREAM_PROXY.password)}`;
+    headers["proxy-authorization"] = `Basic ${Buffer.from(credentials).toString("base64")}`;
+  }
+  const req = http.request({
+    host: UPSTREAM_PROXY.hostname,
+    port: UPSTREAM_PROXY.port || 80,
+    method: "CONNECT",
+    path: target,
+    headers,
+  });
+  req.once("connect", (res, socket) => {
+    if (res.statusCode !== 200) {
+      socket.destroy();
+      callback(new Error(`upstream proxy refused CONNECT ${target}: HTTP ${res.statusCode}`));
+      return;
+    }
+    callback(null, secure ? tls.connect({ ...options, socket }) : socket);
+  });
+  req.once("error", (err) => callback(new Error(`upstream proxy: ${err.message}`)));
+  req.end();
+}
+
+class HttpTunnelAgent extends http.Agent {
+  createConnection(options, callback) {
+    openTunnel(options, callback, false);
+  }
+}
 
-async function resolveOriginalIP() {
+class HttpsTunnelAgent extends https.Agent {
+  createConnection(options, callback) {
+    openTunnel(options, callback, true);
+  }
+}
+
+const tunnelAgents = UPSTREAM_PROXY ? { http: new HttpTunnelAgent(), https: new HttpsTunnelAgent() } : null;
+
+// 解析各域名的真实 IP（因为 hosts 被改了，不能用域名连）
+
+async function resolveOriginalIPs() {
   const dns = await import("node:dns");
-  return new Promise((resolve, reject) => {
-    // 用公共 DNS 解析，绕过本地 hosts
-    const resolver = new dns.Resolver();
-    resolver.setServers(["8.8.8.8", "1.1.1.1"]);
-    resolver.resolve4(ORIGINAL_HOST, (err, addresses) => {
-      if (err) {
-        console.error(`[proxy] DNS resolve failed for ${ORIGINAL_HOST}:`, err.message);
-        reject(err);
-      } else {
-        resolvedOriginalIP = addresses[0];
-        console.log(`[proxy] ${ORIGINAL_HOST} resolved to ${resolvedOriginalIP}`);
-        resolve(resolvedOriginalIP);
-      }
+  // 用公共 DNS 解析，绕过本地 hosts
+  const resolver = new dns.Resolver();
+  resolver.setServers(["8.8.8.8", "1.1.1.1"]);
+  const resolveOne = (domain) =>
+    new Promise((resolve) => {
+      resolver.resolve4(domain, (err, addresses) => {
+        if (err) {
+          console.error(`[proxy] DNS resolve failed for ${domain}:`, err.message);
+        } else {
+          resolvedIPs.set(domain, addresses[0]);
+          console.log(`[proxy] ${domain} resolved to ${addresses[0]}`);
+        }
+        resolve();
+      });
     });
-  });
+  await Promise.all(DOMAINS.filter((d) => !gatewayFor(d) && !resolvedIPs.has(d)).map(resolveOne));
+  if (resolvedIPs.size === 0) throw new Error("none of the intercepted domains could be resolved");
 }
 
 // ---- Captures directory ----
@@ -226,33 +408,182 @@ function logRequest(method, url, targetDesc, statusCode, durationMs) {
   console.log(`[${ts}] #${requestCount} ${method} ${url} ${status} (${targetDesc}, ${durationMs}ms)`);
 }
 
+// ---- 健康检查：客户端直连本端口查询，校验证书并读取版本、运行时长和上游状态 ----
+// 最近一次转发的结果
+const lastUpstream = { lastAt: null, ok: null, error: null, mode: null };
+
+function noteUpstream(mode, error) {
+  Object.assign(lastUpstream, { mode, lastAt: Date.now(), ok: !error, error: error || null });
+}
+
+function answerHealth(clientRes) {
+  const mode = REPLAY ? "replay" : GATEWAY_URL || ROUTES.size > 0 ? "gateway" : "passthrough";
+  const body = JSON.stringify({
+    version: PROXY_VERSION,
+    uptimeSecs: Math.floor(process.uptime()),
+    upstream: { ...lastUpstream, mode: lastUpstream.mode || mode },
+  });
+  clientRes.writeHead(200, {
+    "Content-Type": "application/json",
+    "Content-Length": Buffer.byteLength(body),
+    "x-intercepted-by": "cyber-drill-local-proxy",
+  });
+  clientRes.end(body);
+}
+
 // ---- 创建 HTTPS 服务器 ----
-const server = https.createServer(tlsOptions, (clientReq, clientRes) => {
+function handleRequest(clientReq, clientRes) {
+  if (clientReq.url.split("?")[0] === HEALTH_ROUTE) {
+    answerHealth(clientRes);
+    return;
+  }
   const startTime = Date.now();
-  const fullUrl = `https://${ORIGINAL_HOST}${clientReq.url}`;
+  const host = targetHost(clientReq);
+  const fullUrl = `https://${host}${clientReq.url}`;
+  countDomainRequest(host);
+  // 标记响应经过了本代理，客户端自检据此判断拦截链路是否生效
+  clientRes.setHeader("x-intercepted-by", "cyber-drill-local-proxy");
 
   // 收集请求体
   const bodyChunks = [];
   clientReq.on("data", (chunk) => bodyChunks.push(chunk));
   clientReq.on("end", () => {
     const body = Buffer.concat(bodyChunks);
+    if (HEARTBEAT) {
+      const responseBytes = countResponseBytes(clientRes);
+      clientRes.once("finish", () => reportRequest(clientReq, clientRes, host, body.length, responseBytes, startTime));
+    }
 
-    if (GATEWAY_URL) {
+    const gatewayUrl = isBypassed(clientReq.url) ? null : gatewayFor(host);
+    if (REPLAY) {
+      // ---- 回放模式：按录制的状态码应答 ----
+      answerReplay(clientReq, clientRes, host, fullUrl, startTime);
+    } else if (gatewayUrl) {
       // ---- 模式 A：转发到自建网关 ----
-      forwardToGateway(clientReq, clientRes, body, fullUrl, startTime);
+      forwardToGateway(clientReq, clientRes, body, host, fullUrl, startTime, gatewayUrl);
     } else {
       // ---- 模式 B：透传到官方后端（抓包模式） ----
-      forwardToOriginal(clientReq, clientRes, body, fullUrl, startTime);
+      forwardToOriginal(clientReq, clientRes, body, host, fullUrl, startTime);
     }
   });
-});
+}
+
+const server = https.createServer(tlsOptions, handleRequest);
+// IPv6 回环监听，hosts 中的 ::1 条目指向这里
+const serverV6 = LISTEN_IPV6 ? https.createServer(tlsOptions, handleRequest) : null;
+
+// ---- 心跳：客户端据此判断代理是否仍在服务，并汇总流量指标 ----
+// 字节数按客户端 TLS 连接统计（含 TLS 开销），已关闭连接的计入 closedBytes
+const openSockets = new Set();
+const closedBytes = { in: 0, out: 0 };
+// 域名 -> { requests, lastRequestAt }
+const domainStats = new Map();
+for (const s of [server, serverV6].filter(Boolean)) {
+  s.on("secureConnection", (socket) => {
+    openSockets.add(socket);
+    socket.once("close", () => {
+      openSockets.delete(socket);
+      closedBytes.in += socket.bytesRead;
+      closedBytes.out += socket.bytesWritten;
+    });
+  });
+}
+
+function countDomainRequest(host) {
+  const stats = domainStats.get(host) || { requests: 0, lastRequestAt: null };
+  stats.requests++;
+  stats.lastRequestAt = Date.now();
+  domainStats.set(host, stats);
+}
+
+// 每个完成的请求一行摘要，供客户端导出演练报告
+function reportRequest(clientReq, clientRes, host, bodyBytes, responseBytes, startTime) {
+  const message = {
+    type: "request",
+    ts: startTime,
+    method: clientReq.method,
+    host,
+    path: clientReq.url,
+    status: clientRes.statusCode,
+    bytesIn: bodyBytes,
+    bytesOut: responseBytes(),
+    durationMs: Date.now() - startTime,
+  };
+  process.stdout.write(`${JSON.stringify(message)}\n`);
+}
+
+// 包装 write/end，统计写给客户端的响应体字节数
+function countResponseBytes(res) {
+  let bytes = 0;
+  for (const method of ["write", "end"]) {
+    const original = res[method].bind(res);
+    res[method] = (chunk, ...rest) => {
+      if (chunk && typeof chunk !== "function") bytes += Buffer.byteLength(chunk);
+      return original(chunk, ...rest);
+    };
+  }
+  return () => bytes;
+}
+
+function sendHeartbeat() {
+  let bytesIn = closedBytes.in;
+  let bytesOut = closedBytes.out;
+  for (const socket of openSockets) {
+    bytesIn += socket.bytesRead;
+    bytesOut += socket.bytesWritten;
+  }
+  const message = {
+    type: "heartbeat",
+    connections: openSockets.size,
+    requests: requestCount,
+    bytesIn,
+    bytesOut,
+    domains: Object.fromEntries(domainStats),
+  };
+  process.stdout.write(`${JSON.stringify(message)}\n`);
+}
+
+// ---- 网关证书固定 ----
+// 与 Rust 侧一致：--gateway-ca 取代系统根证书，--gateway-pin 比对证书公钥（SPKI）的 SHA-256
+function gatewayTlsOptions() {
+  const options = {};
+  if (GATEWAY_CA) options.ca = GATEWAY_CA;
+  if (GATEWAY_PIN) {
+    options.checkServerIdentity = (host, cert) => {
+      const err = tls.checkServerIdentity(host, cert);
+      if (err) return err;
+      const actual = crypto.createHash("sha256").update(cert.pubkey).digest("base64");
+      if (actual !== GATEWAY_PIN) {
+        return new Error(`gateway key sha256/${actual} does not match the pin sha256/${GATEWAY_PIN}`);
+      }
+      return undefined;
+    };
+  }
+  return options;
+}
+
+// ---- 回放模式：录制中没有的请求返回 404 ----
+function answerReplay(clientReq, clientRes, host, fullUrl, startTime) {
+  const status = replayStatus(clientReq.method, host, clientReq.url);
+  logRequest(clientReq.method, fullUrl, "replay", status || 404, Date.now() - startTime);
+  noteUpstream("replay", null);
+  if (status) {
+    clientRes.writeHead(status, { "Content-Length": 0, "x-replayed": "1" });
+    clientRes.end();
+  } else {
+    clientRes.writeHead(404, { "Content-Type": "application/json" });
+    clientRes.end(JSON.stringify({ error: "not in the replay bundle", path: clientReq.url }));
+  }
+}
 
 // ---- 模式 A：转发到自建网关 ----
-function forwardToGateway(clientReq, clientRes, body, fullUrl, startTime) {
-  const gwUrl = new URL(GATEWAY_URL);
+function forwardToGateway(clientReq, clientRes, body, host, fullUrl, startTime, gatewayUrl) {
+  const gwUrl = new URL(gatewayUrl);
   const transport = gwUrl.protocol === "https:" ? https : http;
 
   const options = {
+    ...(gwUrl.protocol === "https:" ? gatewayTlsOptions() : {}),
+    agent: tunnelAgents ? tunnelAgents[gwUrl.protocol === "https:" ? "https" : "http"] : undefined,
     hostname: gwUrl.hostname,
     port: gwUrl.port || (gwUrl.protocol === "https:" ? 443 : 80),
     path: clientReq.url,
@@ -260,13 +591,14 @@ function forwardToGateway(clientReq, clientRes, body, fullUrl, startTime) {
     headers: {
       ...clientReq.headers,
       host: gwUrl.host,
-      "x-original-host": ORIGINAL_HOST,
+      "x-original-host": host,
       "x-intercepted-by": "cyber-drill-local-proxy",
     },
   };
 
   const proxyReq = transport.request(options, (proxyRes) => {
     logRequest(clientReq.method, fullUrl, `→ gateway ${gwUrl.host}`, proxyRes.statusCode, Date.now() - startTime);
+    noteUpstream("gateway", null);
 
     clientRes.writeHead(proxyRes.statusCode, proxyRes.headers);
     proxyRes.pipe(clientRes);
@@ -274,6 +606,7 @@ function forwardToGateway(clientReq, clientRes, body, fullUrl, startTime) {
 
   proxyReq.on("error", (err) => {
     console.error(`[proxy] gateway error: ${err.message}`);
+    noteUpstream("gateway", err.message);
     clientRes.writeHead(502, { "Content-Type": "application/json" });
     clientRes.end(JSON.stringify({ error: "gateway unreachable", detail: err.message }));
   });
@@ -283,27 +616,30 @@ function forwardToGateway(clientReq, clientRes, body, fullUrl, startTime) {
 }
 
 // ---- 模式 B：透传到官方后端 ----
-function forwardToOriginal(clientReq, clientRes, body, fullUrl, startTime) {
-  if (!resolvedOriginalIP) {
+function forwardToOriginal(clientReq, clientRes, body, host, fullUrl, startTime) {
+  const resolvedIP = resolvedIPs.get(host);
+  if (!resolvedIP) {
     clientRes.writeHead(503, { "Content-Type": "application/json" });
-    clientRes.end(JSON.stringify({ error: "original IP not resolved yet" }));
+    clientRes.end(JSON.stringify({ error: `original IP of ${host} not resolved` }));
     return;
   }
 
   const options = {
-    hostname: resolvedOriginalIP,
+    agent: tunnelAgents ? tunnelAgents.https : undefined,
+    hostname: resolvedIP,
     port: ORIGINAL_PORT,
     path: clientReq.url,
     method: clientReq.method,
     headers: {
       ...clientReq.headers,
-      host: ORIGINAL_HOST, // 保持原始 Host header
+      host, // 保持原始 Host header
     },
-    servername: ORIGINAL_HOST, // SNI
+    servername: host, // SNI
     rejectUnauthorized: true,
   };
 
   const proxyReq = https.request(options, (proxyRes) => {
+    noteUpstream("passthrough", null);
     // Collect response body
     const resChunks = [];
     proxyRes.on("data", (chunk) => resChunks.push(chunk));
@@ -312,7 +648,7 @@ function forwardToOriginal(clientReq, clientRes, body, fullUrl, startTime) {
       const epName = endpointName(clientReq.url);
       const isKey = isKeyEndpoint(clientReq.url);
 
-      logRequest(clientReq.method, fullUrl, `-> official ${resolvedOriginalIP}`, proxyRes.statusCode, Date.now() - startTime);
+      logRequest(clientReq.method, fullUrl, `-> official ${resolvedIP}`, proxyRes.statusCode, Date.now() - startTime);
 
       if (isKey) {
         // Log headers
@@ -374,6 +710,7 @@ function forwardToOriginal(clientReq, clientRes, body, fullUrl, startTime) {
 
   proxyReq.on("error", (err) => {
     console.error(`[proxy] upstream error: ${err.message}`);
+    noteUpstream("passthrough", err.message);
     clientRes.writeHead(502, { "Content-Type": "application/json" });
     clientRes.end(JSON.stringify({ error: "upstream unreachable", detail: err.message }));
   });
@@ -384,29 +721,86 @@ function forwardToOriginal(clientReq, clientRes, body, fullUrl, startTime) {
 
 // ---- 启动 ----
 async function start() {
-  if (!GATEWAY_URL) {
+  if (REPLAY) {
+    console.log(`[proxy] 模式：回放 ${REPLAY.file}（${REPLAY.byPath.size} 个路径）`);
+  } else if (!GATEWAY_URL) {
     console.log("[proxy] 模式：透传到官方后端（抓包模式）");
-    console.log("[proxy] 正在解析官方域名的真实 IP...");
-    await resolveOriginalIP();
   } else {
     console.log(`[proxy] 模式：转发到网关 ${GATEWAY_URL}`);
   }
+  for (const [domain, gatewayUrl] of ROUTES) console.log(`[proxy] 路由：${domain} -> ${gatewayUrl}`);
+  if ((GATEWAY_URL || ROUTES.size > 0) && (GATEWAY_CA || GATEWAY_PIN)) console.log("[proxy] 网关证书已固定");
+  if (BYPASS.length > 0) console.log(`[proxy] 直连官方后端的路径：${BYPASS.join(", ")}`);
+  if (!REPLAY && DOMAINS.some((d) => !gatewayFor(d) && !resolvedIPs.has(d))) {
+    console.log("[proxy] 正在解析官方域名的真实 IP...");
+    await resolveOriginalIPs();
+  }
 
   server.listen(LISTEN_PORT, LISTEN_HOST, () => {
     console.log("");
     console.log("============================================");
     console.log(`  本地代理已启动：https://${LISTEN_HOST}:${LISTEN_PORT}`);
-    console.log(`  拦截域名：${ORIGINAL_HOST}`);
-    if (GATEWAY_URL) {
+    console.log(`  拦截域名：${DOMAINS.join(", ")}`);
+    if (REPLAY) {
+      console.log(`  回放：${REPLAY.file}`);
+    } else if (GATEWAY_URL) {
       console.log(`  转发到：${GATEWAY_URL}`);
     } else {
-      console.log(`  转发到：官方后端 (${resolvedOriginalIP}:${ORIGINAL_PORT})`);
+      const targets = [...resolvedIPs].map(([domain, ip]) => `${domain} -> ${ip}:${ORIGINAL_PORT}`);
+      console.log(`  转发到：官方后端 (${targets.join(", ")})`);
     }
+    for (const [domain, gatewayUrl] of ROUTES) console.log(`  ${domain} 转发到：${gatewayUrl}`);
     console.log("============================================");
     console.log("");
+    if (HEARTBEAT) {
+      sendHeartbeat();
+      setInterval(sendHeartbeat, HEARTBEAT_INTERVAL_MS).unref();
+    }
   });
+
+  if (serverV6) {
+    // 系统禁用 IPv6 时只告警，IPv4 照常工作
+    serverV6.on("error", (err) => console.error(`[proxy] IPv6 listen failed on [${LISTEN_HOST_V6}]:${LISTEN_PORT}: ${err.message}`));
+    serverV6.listen(LISTEN_PORT, LISTEN_HOST_V6, () => {
+      console.log(`[proxy] 同时监听：https://[${LISTEN_HOST_V6}]:${LISTEN_PORT}`);
+    });
+  }
+}
+
+// ---- 优雅退出：SIGTERM 或 stdin 收到 {"type":"shutdown"}（旧版为纯文本 "shutdown"） ----
+let shuttingDown = false;
+function shutdown(reason) {
+  if (shuttingDown) return;
+  shuttingDown = true;
+  console.log(`[proxy] shutting down (${reason})`);
+  if (serverV6) serverV6.close();
+  server.close(() => process.exit(0));
+  // 有长连接时不无限等待
+  setTimeout(() => process.exit(0), 3000).unref();
+}
+
+process.on("SIGTERM", () => shutdown("SIGTERM"));
+
+function parseMessage(line) {
+  try {
+    return JSON.parse(line);
+  } catch {
+    return null;
+  }
 }
 
+let stdinBuffer = "";
+process.stdin.setEncoding("utf8");
+process.stdin.on("data", (chunk) => {
+  stdinBuffer += chunk;
+  let idx;
+  while ((idx = stdinBuffer.indexOf("\n")) >= 0) {
+    const line = stdinBuffer.slice(0, idx).trim();
+    stdinBuffer = stdinBuffer.slice(idx + 1);
+    if (line === "shutdown" || parseMessage(line)?.type === "shutdown") shutdown("stdin");
+  }
+});
+
 start().catch((err) => {
   console.error("[proxy] 启动失败:", err.message);
   process.exit(1);